@@ -15,17 +15,61 @@
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, SeekFrom};
 use std::path::Path;
 
 use base64;
 use sodiumoxide::crypto::sign;
+use time;
 
-use super::hash;
-use super::keys::parse_name_with_rev;
-use super::{SigKeyPair, HART_FORMAT_VERSION, SIG_HASH_TYPE};
+use super::hash::{self, HashAlgorithm};
+use super::keys::{parse_name_with_rev, FsKeyCache, KeyAlgorithm, KeyCache, KeyType};
+use super::{
+    secure_eq, SigKeyPair, DETACHED_SIG_FORMAT_VERSION, HART_FORMAT_VERSION,
+    MULTI_SIG_HART_FORMAT_VERSION, SIG_HASH_TYPE, VERSIONED_HART_FORMAT_VERSION,
+};
 use error::{Error, Result};
 
+/// The most signatures a multiple signature header may carry. The count is read from the
+/// artifact before anything in it can be trusted, so a header can't claim more than this.
+const MAX_SIGNATURES: usize = 64;
+
+/// A single origin key signature carried in an artifact's header.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArtifactSignature {
+    /// The name with revision of the origin key which made the signature
+    pub key_name: String,
+    /// The Base64 signed value of the artifact's payload hash
+    pub signature_raw: String,
+    /// The time the signature was made in seconds since the Unix epoch, if it was timestamped.
+    /// The timestamp is covered by the signature.
+    pub timestamp: Option<i64>,
+    /// A Base64 token from an external timestamp authority, if one was included. The token isn't
+    /// covered by the signature, and isn't validated; it is carried as is for the caller.
+    pub tsa_token: Option<String>,
+}
+
+/// The outcome of verifying an artifact with `verify_timestamped`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArtifactVerification {
+    /// The name with revision of the origin key whose signature is valid
+    pub signer: String,
+    /// The hash of the artifact's payload
+    pub hash: String,
+    /// The signed timestamp of the signature, in seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    /// The Base64 token from an external timestamp authority carried with the signature
+    pub tsa_token: Option<String>,
+}
+
+impl ArtifactVerification {
+    /// Returns whether the signature carries a signed timestamp from before `time`, in seconds
+    /// since the Unix epoch.
+    pub fn signed_before(&self, time: i64) -> bool {
+        self.timestamp.map(|t| t < time).unwrap_or(false)
+    }
+}
+
 /// Generate and sign a package
 pub fn sign<P1: ?Sized, P2: ?Sized>(src: &P1, dst: &P2, pair: &SigKeyPair) -> Result<()>
 where
@@ -51,6 +95,59 @@ where
     Ok(())
 }
 
+/// Generate and sign a package in the `HART-3` format, hashing it with the given algorithm. The
+/// signature algorithm is that of the origin key.
+pub fn sign_with<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    dst: &P2,
+    pair: &SigKeyPair,
+    hash_algorithm: HashAlgorithm,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let hash = hash::hash_file_with(&src, hash_algorithm)?;
+    debug!(
+        "File {} hash for {} = {}",
+        hash_algorithm,
+        src.as_ref().display(),
+        &hash
+    );
+
+    let signature = sign_hash(&hash, pair)?;
+    let output_file = File::create(dst)?;
+    let mut writer = BufWriter::new(&output_file);
+    write_versioned_header(&mut writer, hash_algorithm, pair.algorithm(), &signature)?;
+    let mut file = File::open(src)?;
+    io::copy(&mut file, &mut writer)?;
+    Ok(())
+}
+
+/// Generate and sign a package in the `HART-3` format as `sign_with` does, including the current
+/// time in the signature. A token from an external timestamp authority can be carried alongside.
+pub fn sign_timestamped<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    dst: &P2,
+    pair: &SigKeyPair,
+    hash_algorithm: HashAlgorithm,
+    tsa_token: Option<&[u8]>,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let hash = hash::hash_file_with(&src, hash_algorithm)?;
+    let mut signature = sign_hash_at(&hash, pair, Some(time::get_time().sec))?;
+    signature.tsa_token = tsa_token.map(base64::encode);
+    let output_file = File::create(dst)?;
+    let mut writer = BufWriter::new(&output_file);
+    write_versioned_header(&mut writer, hash_algorithm, pair.algorithm(), &signature)?;
+    let mut file = File::open(src)?;
+    io::copy(&mut file, &mut writer)?;
+    Ok(())
+}
+
 /// return a BufReader to the .tar bytestream, skipping the signed header
 pub fn get_archive_reader<P: AsRef<Path>>(src: &P) -> Result<BufReader<File>> {
     let f = File::open(src)?;
@@ -64,6 +161,14 @@ pub fn get_archive_reader<P: AsRef<Path>>(src: &P) -> Result<BufReader<File>> {
     if reader.read_line(&mut your_format_version)? <= 0 {
         return Err(Error::CryptoError("Can't read format version".to_string()));
     }
+    if your_format_version.trim() == MULTI_SIG_HART_FORMAT_VERSION {
+        read_multi_signature_header(&mut reader)?;
+        return Ok(reader);
+    }
+    if your_format_version.trim() == VERSIONED_HART_FORMAT_VERSION {
+        read_versioned_header(&mut reader)?;
+        return Ok(reader);
+    }
     if reader.read_line(&mut your_key_name)? <= 0 {
         return Err(Error::CryptoError("Can't read keyname".to_string()));
     }
@@ -83,6 +188,9 @@ pub struct ArtifactHeader {
     pub format_version: String,
     pub key_name: String,
     pub hash_type: String,
+    /// The signature algorithm, which is only written in `HART-3` headers and is otherwise
+    /// `ed25519`
+    pub signature_type: String,
     pub signature_raw: String,
 }
 
@@ -97,6 +205,7 @@ impl ArtifactHeader {
             format_version: format_version,
             key_name: key_name,
             hash_type: hash_type,
+            signature_type: KeyAlgorithm::Ed25519.to_string(),
             signature_raw: signature_raw,
         }
     }
@@ -104,7 +213,9 @@ impl ArtifactHeader {
 
 /// Read only the header of the artifact, fails if any of the components
 /// are invalid/missing. Each component of the header has it's whitespace
-/// stripped before returning in an `ArtifactHeader` struct
+/// stripped before returning in an `ArtifactHeader` struct. For an artifact
+/// with multiple signatures, the first signature is returned; use
+/// `artifact_signatures` to read all of them.
 pub fn get_artifact_header<P: ?Sized>(src: &P) -> Result<ArtifactHeader>
 where
     P: AsRef<Path>,
@@ -120,6 +231,27 @@ where
     if reader.read_line(&mut your_format_version)? <= 0 {
         return Err(Error::CryptoError("Can't read format version".to_string()));
     }
+    if your_format_version.trim() == MULTI_SIG_HART_FORMAT_VERSION {
+        let mut signatures = read_multi_signature_header(&mut reader)?;
+        let first = signatures.remove(0);
+        return Ok(ArtifactHeader::new(
+            MULTI_SIG_HART_FORMAT_VERSION.to_string(),
+            first.key_name,
+            SIG_HASH_TYPE.to_string(),
+            first.signature_raw,
+        ));
+    }
+    if your_format_version.trim() == VERSIONED_HART_FORMAT_VERSION {
+        let (hash_algorithm, signature_algorithm, signature) = read_versioned_header(&mut reader)?;
+        let mut header = ArtifactHeader::new(
+            VERSIONED_HART_FORMAT_VERSION.to_string(),
+            signature.key_name,
+            hash_algorithm.to_string(),
+            signature.signature_raw,
+        );
+        header.signature_type = signature_algorithm.to_string();
+        return Ok(header);
+    }
     if reader.read_line(&mut your_key_name)? <= 0 {
         return Err(Error::CryptoError("Can't read keyname".to_string()));
     }
@@ -150,11 +282,64 @@ pub fn verify<P1: ?Sized, P2: ?Sized>(src: &P1, cache_key_path: &P2) -> Result<(
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
+{
+    verify_with_progress(src, cache_key_path, |_, _| ())
+}
+
+/// verify the crypto signature of a .hart file as `verify` does, calling `progress` with the
+/// number of payload bytes hashed so far and the size of the payload as it is hashed
+pub fn verify_with_progress<P1: ?Sized, P2: ?Sized, F>(
+    src: &P1,
+    cache_key_path: &P2,
+    progress: F,
+) -> Result<(String, String)>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    F: FnMut(u64, u64),
+{
+    let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+    verify_with_lookup(
+        src,
+        |name_with_rev| {
+            revoked.check(name_with_rev)?;
+            SigKeyPair::get_pair_for(name_with_rev, cache_key_path)
+        },
+        progress,
+    )
+}
+
+/// verify the crypto signature of a .hart file using origin keys held in a `KeyCache`
+pub fn verify_with_cache<P: ?Sized, C: ?Sized>(src: &P, cache: &C) -> Result<(String, String)>
+where
+    P: AsRef<Path>,
+    C: KeyCache,
+{
+    let revoked = cache.revocation_list()?;
+    verify_with_lookup(
+        src,
+        |name_with_rev| {
+            revoked.check(name_with_rev)?;
+            SigKeyPair::get_pair_from_cache(name_with_rev, cache)
+        },
+        |_, _| (),
+    )
+}
+
+fn verify_with_lookup<P: ?Sized, F, G>(
+    src: &P,
+    get_pair: F,
+    progress: G,
+) -> Result<(String, String)>
+where
+    P: AsRef<Path>,
+    F: Fn(&str) -> Result<SigKeyPair>,
+    G: FnMut(u64, u64),
 {
     let f = File::open(src)?;
     let mut reader = BufReader::new(f);
 
-    let _ = {
+    let format_version = {
         let mut buffer = String::new();
         match reader.read_line(&mut buffer) {
             Ok(0) => {
@@ -163,7 +348,10 @@ where
                 ))
             }
             Ok(_) => {
-                if buffer.trim() != HART_FORMAT_VERSION {
+                if buffer.trim() != HART_FORMAT_VERSION
+                    && buffer.trim() != MULTI_SIG_HART_FORMAT_VERSION
+                    && buffer.trim() != VERSIONED_HART_FORMAT_VERSION
+                {
                     let msg = format!("Unsupported format version: {}", &buffer.trim());
                     return Err(Error::CryptoError(msg));
                 }
@@ -172,6 +360,24 @@ where
         };
         buffer.trim().to_string()
     };
+    if format_version == MULTI_SIG_HART_FORMAT_VERSION {
+        let signatures = read_multi_signature_header(&mut reader)?;
+        let (mut signers, hash) = verify_signatures(
+            &mut reader,
+            &signatures,
+            HashAlgorithm::Blake2b,
+            &get_pair,
+            1,
+            progress,
+        )?;
+        return Ok((signers.remove(0), hash));
+    }
+    if format_version == VERSIONED_HART_FORMAT_VERSION {
+        let (hash_algorithm, _, signature) = read_versioned_header(&mut reader)?;
+        let computed_hash = hash_payload(&mut reader, hash_algorithm, progress)?;
+        verify_signature(&signature, &computed_hash, &get_pair)?;
+        return Ok((signature.key_name, computed_hash));
+    }
     let pair = {
         let mut buffer = String::new();
         if reader.read_line(&mut buffer)? <= 0 {
@@ -179,7 +385,7 @@ where
                 "Corrupt payload, can't read origin key name".to_string(),
             ));
         }
-        SigKeyPair::get_pair_for(buffer.trim(), cache_key_path)?
+        get_pair(buffer.trim())?
     };
     let _ = {
         let mut buffer = String::new();
@@ -224,8 +430,8 @@ where
             .map_err(|_| Error::CryptoError("Error parsing artifact signature".to_string()))?,
         Err(_) => return Err(Error::CryptoError("Verification failed".to_string())),
     };
-    let computed_hash = hash::hash_reader(&mut reader)?;
-    if computed_hash == expected_hash {
+    let computed_hash = hash_payload(&mut reader, HashAlgorithm::Blake2b, progress)?;
+    if secure_eq(&computed_hash, &expected_hash) {
         Ok((pair.name_with_rev(), expected_hash))
     } else {
         let msg = format!(
@@ -237,6 +443,673 @@ where
     }
 }
 
+/// Generate a package signed by each of the given origin keys
+pub fn sign_multi<P1: ?Sized, P2: ?Sized>(src: &P1, dst: &P2, pairs: &[&SigKeyPair]) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    if pairs.is_empty() {
+        return Err(Error::CryptoError(
+            "At least one origin key is required to sign an artifact".to_string(),
+        ));
+    }
+    let hash = hash::hash_file(&src)?;
+    debug!("File hash for {} = {}", src.as_ref().display(), &hash);
+
+    let mut signatures = Vec::with_capacity(pairs.len());
+    for pair in pairs {
+        signatures.push(sign_hash(&hash, pair)?);
+    }
+    let output_file = File::create(dst)?;
+    let mut writer = BufWriter::new(&output_file);
+    write_multi_signature_header(&mut writer, &signatures)?;
+    let mut file = File::open(src)?;
+    io::copy(&mut file, &mut writer)?;
+    Ok(())
+}
+
+/// Add a signature by another origin key to an already signed package, writing the co-signed
+/// package to `dst`. The existing signatures are kept as they are.
+pub fn add_signature<P1: ?Sized, P2: ?Sized>(src: &P1, dst: &P2, pair: &SigKeyPair) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let (hash_algorithm, mut signatures) =
+        read_signature_header(&mut BufReader::new(File::open(src)?))?;
+    if hash_algorithm != HashAlgorithm::Blake2b {
+        return Err(Error::CryptoError(format!(
+            "Can't add a signature to an artifact hashed with {}",
+            hash_algorithm
+        )));
+    }
+    if signatures.iter().any(|s| s.timestamp.is_some()) {
+        return Err(Error::CryptoError(
+            "Can't add a signature to an artifact with a timestamped signature".to_string(),
+        ));
+    }
+    if signatures
+        .iter()
+        .any(|s| s.key_name == pair.name_with_rev())
+    {
+        return Err(Error::CryptoError(format!(
+            "Habitat artifact is already signed by {}",
+            pair.name_with_rev()
+        )));
+    }
+    let hash = hash::hash_reader(&mut get_archive_reader(&src)?)?;
+    signatures.push(sign_hash(&hash, pair)?);
+
+    let output_file = File::create(dst)?;
+    let mut writer = BufWriter::new(&output_file);
+    write_multi_signature_header(&mut writer, &signatures)?;
+    io::copy(&mut get_archive_reader(&src)?, &mut writer)?;
+    Ok(())
+}
+
+/// Replace the signatures of an already signed package with a signature by another origin key,
+/// writing the re-signed package to `dst`. The payload is copied as it is.
+pub fn replace_signatures<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    dst: &P2,
+    pair: &SigKeyPair,
+) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let hash = hash::hash_reader(&mut get_archive_reader(&src)?)?;
+    let signature = sign_hash(&hash, pair)?;
+
+    let output_file = File::create(dst)?;
+    let mut writer = BufWriter::new(&output_file);
+    write!(
+        writer,
+        "{}\n{}\n{}\n{}\n\n",
+        HART_FORMAT_VERSION, signature.key_name, SIG_HASH_TYPE, signature.signature_raw
+    )?;
+    io::copy(&mut get_archive_reader(&src)?, &mut writer)?;
+    Ok(())
+}
+
+/// Read every signature carried in the header of a package
+pub fn artifact_signatures<P: ?Sized>(src: &P) -> Result<Vec<ArtifactSignature>>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(src)?);
+    let (_, signatures) = read_signature_header(&mut reader)?;
+    Ok(signatures)
+}
+
+/// Verify the signatures of a .hart file, requiring that at least `required` of them are made by
+/// keys of distinct origins found in `cache_key_path` and match the package's contents. Two
+/// revisions of one origin's key count as one signer, as they're held by the same principal.
+/// Signatures by keys which aren't present are ignored. `required` must be at least one, and no
+/// more than the number of distinct origins whose keys signed the artifact, as no artifact could
+/// ever meet it otherwise, or every artifact would.
+///
+/// On success, returns the names with revision of the keys with valid signatures, one for each
+/// origin, and the package's hash.
+pub fn verify_threshold<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    cache_key_path: &P2,
+    required: usize,
+) -> Result<(Vec<String>, String)>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(src)?);
+    let (hash_algorithm, signatures) = read_signature_header(&mut reader)?;
+    if required == 0 {
+        return Err(Error::CryptoError(
+            "At least one valid signature must be required".to_string(),
+        ));
+    }
+    let mut origins = Vec::new();
+    for signature in &signatures {
+        origins.push(parse_name_with_rev(&signature.key_name)?.0);
+    }
+    origins.sort();
+    origins.dedup();
+    if required > origins.len() {
+        return Err(Error::CryptoError(format!(
+            "Habitat artifact is signed by keys of {} origin(s) but {} valid signature(s) are \
+             required",
+            origins.len(),
+            required
+        )));
+    }
+    let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+    verify_signatures(
+        &mut reader,
+        &signatures,
+        hash_algorithm,
+        |name_with_rev| {
+            revoked.check(name_with_rev)?;
+            SigKeyPair::get_pair_for(name_with_rev, cache_key_path)
+        },
+        required,
+        |_, _| (),
+    )
+}
+
+/// Verify the crypto signature of a .hart file, returning the signed timestamp and any timestamp
+/// authority token along with the signer and hash. A signature by a revoked key is refused
+/// whatever its timestamp says, as the signer writes the timestamp and nothing validates the
+/// token.
+pub fn verify_timestamped<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    cache_key_path: &P2,
+) -> Result<ArtifactVerification>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(src)?);
+    let (hash_algorithm, signatures) = read_signature_header(&mut reader)?;
+    let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+    let computed_hash = hash_payload(&mut reader, hash_algorithm, |_, _| ())?;
+    let mut last_error = None;
+    for signature in signatures {
+        let result = revoked.check(&signature.key_name).and_then(|_| {
+            verify_signature(&signature, &computed_hash, &|name_with_rev: &str| {
+                SigKeyPair::get_pair_for(name_with_rev, cache_key_path)
+            })
+        });
+        match result {
+            Ok(()) => {
+                return Ok(ArtifactVerification {
+                    signer: signature.key_name,
+                    hash: computed_hash,
+                    timestamp: signature.timestamp,
+                    tsa_token: signature.tsa_token,
+                })
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        Error::CryptoError("Corrupt payload, artifact has no signatures".to_string())
+    }))
+}
+
+/// The payload of a .hart file, hashed as it is read so that the artifact can be verified in the
+/// same pass which consumes the payload, such as while it is being unpacked.
+///
+/// The signatures in the header are opened with their origin keys up front, so an artifact
+/// signed by an unknown or revoked key is refused before any of its payload is read. Nothing
+/// read from the payload can be trusted until `finish` has succeeded, though.
+pub struct VerifyingReader {
+    reader: BufReader<File>,
+    hasher: Option<hash::Hasher>,
+    /// Every signature which could be opened, with the message it signs
+    opened: Vec<(ArtifactSignature, Vec<u8>)>,
+}
+
+impl VerifyingReader {
+    /// Reads the rest of the payload, if any, and verifies the artifact's signature against the
+    /// hash of the whole payload. Returns the signer and hash, as `verify` does.
+    pub fn finish(&mut self) -> Result<(String, String)> {
+        io::copy(self, &mut io::sink())?;
+        let computed_hash = match self.hasher.take() {
+            Some(hasher) => hasher.finish(),
+            None => {
+                return Err(Error::CryptoError(
+                    "Artifact payload has already been verified".to_string(),
+                ))
+            }
+        };
+        for &(ref signature, ref signed_data) in &self.opened {
+            if secure_eq(
+                signed_data,
+                signed_message(&computed_hash, signature.timestamp),
+            ) {
+                return Ok((signature.key_name.clone(), computed_hash));
+            }
+        }
+        Err(Error::CryptoError(format!(
+            "Habitat artifact is invalid, hashes don't match (computed: {})",
+            computed_hash
+        )))
+    }
+}
+
+impl Read for VerifyingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+/// Open the payload of a .hart file for reading, checking its signatures with the origin keys in
+/// `cache_key_path` first. See `VerifyingReader`.
+pub fn open_verifying<P1: ?Sized, P2: ?Sized>(
+    src: &P1,
+    cache_key_path: &P2,
+) -> Result<VerifyingReader>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(src)?);
+    let (hash_algorithm, signatures) = read_signature_header(&mut reader)?;
+    let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+    let mut opened = Vec::new();
+    let mut last_error = None;
+    for signature in signatures {
+        let result = revoked.check(&signature.key_name).and_then(|_| {
+            open_signature(&signature, &|name_with_rev: &str| {
+                SigKeyPair::get_pair_for(name_with_rev, cache_key_path)
+            })
+        });
+        match result {
+            Ok(signed_data) => opened.push((signature, signed_data)),
+            Err(e) => {
+                debug!("Signature by {} can't be opened: {}", signature.key_name, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if opened.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            Error::CryptoError("Corrupt payload, artifact has no signatures".to_string())
+        }));
+    }
+    Ok(VerifyingReader {
+        reader: reader,
+        hasher: Some(hash::Hasher::new(hash_algorithm)?),
+        opened: opened,
+    })
+}
+
+/// Sign a file without modifying it, writing a standalone signature to `sig`
+pub fn sign_detached<P1: ?Sized, P2: ?Sized>(src: &P1, sig: &P2, pair: &SigKeyPair) -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let hash = hash::hash_file(&src)?;
+    debug!("File hash for {} = {}", src.as_ref().display(), &hash);
+
+    let signature = sign_hash(&hash, pair)?;
+    let output_file = File::create(sig)?;
+    let mut writer = BufWriter::new(&output_file);
+    write!(
+        writer,
+        "{}\n{}\n{}\n{}\n",
+        DETACHED_SIG_FORMAT_VERSION, signature.key_name, SIG_HASH_TYPE, signature.signature_raw
+    )?;
+    Ok(())
+}
+
+/// Verify a file against the standalone signature in `sig`, using the origin keys found in
+/// `cache_key_path`. On success, returns the name with revision of the signing key and the
+/// file's hash.
+pub fn verify_detached<P1: ?Sized, P2: ?Sized, P3: ?Sized>(
+    src: &P1,
+    sig: &P2,
+    cache_key_path: &P3,
+) -> Result<(String, String)>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let mut reader = BufReader::new(File::open(sig)?);
+    let format_version = read_header_line(&mut reader, "format version")?;
+    if format_version != DETACHED_SIG_FORMAT_VERSION {
+        return Err(Error::CryptoError(format!(
+            "Unsupported format version: {}",
+            format_version
+        )));
+    }
+    let key_name = read_header_line(&mut reader, "origin key name")?;
+    parse_name_with_rev(&key_name)?;
+    let hash_type = read_header_line(&mut reader, "hash type")?;
+    if hash_type != SIG_HASH_TYPE {
+        return Err(Error::CryptoError(format!(
+            "Unsupported signature type: {}",
+            hash_type
+        )));
+    }
+    let signature = ArtifactSignature {
+        key_name: key_name,
+        signature_raw: read_header_line(&mut reader, "signature")?,
+        timestamp: None,
+        tsa_token: None,
+    };
+
+    let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+    revoked.check(&signature.key_name)?;
+    let computed_hash = hash::hash_file(&src)?;
+    verify_signature(&signature, &computed_hash, &|name_with_rev: &str| {
+        SigKeyPair::get_pair_for(name_with_rev, cache_key_path)
+    })?;
+    Ok((signature.key_name, computed_hash))
+}
+
+fn sign_hash(hash: &str, pair: &SigKeyPair) -> Result<ArtifactSignature> {
+    sign_hash_at(hash, pair, None)
+}
+
+fn sign_hash_at(
+    hash: &str,
+    pair: &SigKeyPair,
+    timestamp: Option<i64>,
+) -> Result<ArtifactSignature> {
+    let signature = sign::sign(signed_message(hash, timestamp).as_bytes(), pair.secret()?);
+    Ok(ArtifactSignature {
+        key_name: pair.name_with_rev(),
+        signature_raw: base64::encode(&signature),
+        timestamp: timestamp,
+        tsa_token: None,
+    })
+}
+
+fn verify_signatures<F, G>(
+    reader: &mut BufReader<File>,
+    signatures: &[ArtifactSignature],
+    hash_algorithm: HashAlgorithm,
+    get_pair: F,
+    required: usize,
+    progress: G,
+) -> Result<(Vec<String>, String)>
+where
+    F: Fn(&str) -> Result<SigKeyPair>,
+    G: FnMut(u64, u64),
+{
+    let computed_hash = hash_payload(reader, hash_algorithm, progress)?;
+    let mut valid: Vec<String> = Vec::new();
+    // Only one signature counts for each origin, however many of its keys signed
+    let mut origins: Vec<String> = Vec::new();
+    for signature in signatures {
+        let origin = match parse_name_with_rev(&signature.key_name) {
+            Ok((origin, _)) => origin,
+            Err(e) => {
+                debug!("Signature by {} is not valid: {}", signature.key_name, e);
+                continue;
+            }
+        };
+        if origins.contains(&origin) {
+            continue;
+        }
+        match verify_signature(signature, &computed_hash, &get_pair) {
+            Ok(()) => {
+                valid.push(signature.key_name.clone());
+                origins.push(origin);
+            }
+            Err(e) => debug!("Signature by {} is not valid: {}", signature.key_name, e),
+        }
+    }
+    if valid.len() < required {
+        return Err(Error::CryptoError(format!(
+            "Habitat artifact has {} valid signature(s) but {} are required",
+            valid.len(),
+            required
+        )));
+    }
+    Ok((valid, computed_hash))
+}
+
+fn verify_signature<F>(
+    signature: &ArtifactSignature,
+    computed_hash: &str,
+    get_pair: &F,
+) -> Result<()>
+where
+    F: Fn(&str) -> Result<SigKeyPair>,
+{
+    let signed_data = open_signature(signature, get_pair)?;
+    if !secure_eq(
+        &signed_data,
+        signed_message(computed_hash, signature.timestamp),
+    ) {
+        return Err(Error::CryptoError(format!(
+            "Habitat artifact is invalid, hashes don't match (computed: {})",
+            computed_hash
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the message signed by a signature, once it is checked against the signer's key.
+fn open_signature<F>(signature: &ArtifactSignature, get_pair: &F) -> Result<Vec<u8>>
+where
+    F: Fn(&str) -> Result<SigKeyPair>,
+{
+    let pair = get_pair(&signature.key_name)?;
+    let signature_raw = base64::decode(&signature.signature_raw)
+        .map_err(|e| Error::CryptoError(format!("Can't decode signature: {}", e)))?;
+    sign::verify(signature_raw.as_slice(), pair.public()?)
+        .map_err(|_| Error::CryptoError("Verification failed".to_string()))
+}
+
+/// Returns the message which is signed for a payload hash: the hash itself, or the hash and the
+/// signature's timestamp on separate lines when it is timestamped.
+fn signed_message(hash: &str, timestamp: Option<i64>) -> String {
+    match timestamp {
+        Some(timestamp) => format!("{}\n{}", hash, timestamp),
+        None => hash.to_string(),
+    }
+}
+
+/// Returns the chunk layout of the payload which follows an artifact's header, with no chunks
+/// hashed yet. Hashing it with `hash::hash_chunks` spreads the work over several threads and
+/// can be resumed by saving the layout between runs.
+pub fn payload_chunk_layout<P: ?Sized>(src: &P, chunk_size: u64) -> Result<hash::ChunkLayout>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = get_archive_reader(&src)?;
+    let header_len = reader.seek(SeekFrom::Current(0))?;
+    let total = reader.get_ref().metadata()?.len();
+    hash::ChunkLayout::new(header_len, total - header_len, chunk_size)
+}
+
+/// Hash the payload which follows an artifact's header, reporting progress against the size of
+/// the payload.
+fn hash_payload<F>(
+    reader: &mut BufReader<File>,
+    algorithm: HashAlgorithm,
+    progress: F,
+) -> Result<String>
+where
+    F: FnMut(u64, u64),
+{
+    let header_len = reader.seek(SeekFrom::Current(0))?;
+    let total = reader
+        .get_ref()
+        .metadata()?
+        .len()
+        .saturating_sub(header_len);
+    hash::hash_reader_with(reader, algorithm, total, progress)
+}
+
+fn write_multi_signature_header<W: Write>(
+    writer: &mut W,
+    signatures: &[ArtifactSignature],
+) -> Result<()> {
+    if signatures.len() > MAX_SIGNATURES {
+        return Err(Error::CryptoError(format!(
+            "An artifact can carry at most {} signatures",
+            MAX_SIGNATURES
+        )));
+    }
+    write!(
+        writer,
+        "{}\n{}\n{}\n",
+        MULTI_SIG_HART_FORMAT_VERSION,
+        SIG_HASH_TYPE,
+        signatures.len()
+    )?;
+    for signature in signatures {
+        write!(
+            writer,
+            "{}\n{}\n",
+            signature.key_name, signature.signature_raw
+        )?;
+    }
+    write!(writer, "\n")?;
+    Ok(())
+}
+
+/// Read the header of any artifact format, leaving the reader at the start of the payload.
+fn read_signature_header<R: BufRead>(
+    reader: &mut R,
+) -> Result<(HashAlgorithm, Vec<ArtifactSignature>)> {
+    let format_version = read_header_line(reader, "format version")?;
+    if format_version == MULTI_SIG_HART_FORMAT_VERSION {
+        return Ok((HashAlgorithm::Blake2b, read_multi_signature_header(reader)?));
+    }
+    if format_version == VERSIONED_HART_FORMAT_VERSION {
+        let (hash_algorithm, _, signature) = read_versioned_header(reader)?;
+        return Ok((hash_algorithm, vec![signature]));
+    }
+    if format_version != HART_FORMAT_VERSION {
+        return Err(Error::CryptoError(format!(
+            "Unsupported format version: {}",
+            format_version
+        )));
+    }
+    let key_name = read_header_line(reader, "origin key name")?;
+    parse_name_with_rev(&key_name)?;
+    let hash_type = read_header_line(reader, "hash type")?;
+    if hash_type != SIG_HASH_TYPE {
+        return Err(Error::CryptoError(format!(
+            "Unsupported signature type: {}",
+            hash_type
+        )));
+    }
+    let signature_raw = read_header_line(reader, "signature")?;
+    read_header_line(reader, "end of header")?;
+    Ok((
+        HashAlgorithm::Blake2b,
+        vec![ArtifactSignature {
+            key_name: key_name,
+            signature_raw: signature_raw,
+            timestamp: None,
+            tsa_token: None,
+        }],
+    ))
+}
+
+/// Read the remainder of a `HART-3` header, following its format version line.
+fn read_versioned_header<R: BufRead>(
+    reader: &mut R,
+) -> Result<(HashAlgorithm, KeyAlgorithm, ArtifactSignature)> {
+    let key_name = read_header_line(reader, "origin key name")?;
+    parse_name_with_rev(&key_name)?;
+    let hash_algorithm = read_header_line(reader, "hash type")?.parse::<HashAlgorithm>()?;
+    let signature_algorithm =
+        read_header_line(reader, "signature type")?.parse::<KeyAlgorithm>()?;
+    signature_algorithm.check_key_type(KeyType::Sig)?;
+    let mut signature = ArtifactSignature {
+        key_name: key_name,
+        signature_raw: read_header_line(reader, "signature")?,
+        timestamp: None,
+        tsa_token: None,
+    };
+    // Optional attributes of the signature follow, one per line, until the end of the header
+    loop {
+        let line = read_header_line(reader, "end of header")?;
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next().map(|v| v.trim())) {
+            (Some("timestamp"), Some(value)) => {
+                signature.timestamp = Some(value.parse::<i64>().map_err(|e| {
+                    Error::CryptoError(format!("Can't parse signature timestamp: {}", e))
+                })?);
+            }
+            (Some("tsa-token"), Some(value)) => signature.tsa_token = Some(value.to_string()),
+            _ => debug!("Ignoring unknown artifact header attribute: {}", line),
+        }
+    }
+    Ok((hash_algorithm, signature_algorithm, signature))
+}
+
+fn write_versioned_header<W: Write>(
+    writer: &mut W,
+    hash_algorithm: HashAlgorithm,
+    signature_algorithm: KeyAlgorithm,
+    signature: &ArtifactSignature,
+) -> Result<()> {
+    write!(
+        writer,
+        "{}\n{}\n{}\n{}\n{}\n",
+        VERSIONED_HART_FORMAT_VERSION,
+        signature.key_name,
+        hash_algorithm,
+        signature_algorithm,
+        signature.signature_raw
+    )?;
+    if let Some(timestamp) = signature.timestamp {
+        write!(writer, "timestamp: {}\n", timestamp)?;
+    }
+    if let Some(ref tsa_token) = signature.tsa_token {
+        write!(writer, "tsa-token: {}\n", tsa_token)?;
+    }
+    write!(writer, "\n")?;
+    Ok(())
+}
+
+/// Read the remainder of a multiple signature header, following its format version line.
+fn read_multi_signature_header<R: BufRead>(reader: &mut R) -> Result<Vec<ArtifactSignature>> {
+    let hash_type = read_header_line(reader, "hash type")?;
+    if hash_type != SIG_HASH_TYPE {
+        return Err(Error::CryptoError(format!(
+            "Unsupported signature type: {}",
+            hash_type
+        )));
+    }
+    let count = read_header_line(reader, "signature count")?
+        .parse::<usize>()
+        .map_err(|e| Error::CryptoError(format!("Can't parse signature count: {}", e)))?;
+    if count == 0 {
+        return Err(Error::CryptoError(
+            "Corrupt payload, artifact has no signatures".to_string(),
+        ));
+    }
+    if count > MAX_SIGNATURES {
+        return Err(Error::CryptoError(format!(
+            "Corrupt payload, artifact claims {} signatures but at most {} are allowed",
+            count, MAX_SIGNATURES
+        )));
+    }
+    let mut signatures = Vec::new();
+    for _ in 0..count {
+        let key_name = read_header_line(reader, "origin key name")?;
+        parse_name_with_rev(&key_name)?;
+        let signature_raw = read_header_line(reader, "signature")?;
+        signatures.push(ArtifactSignature {
+            key_name: key_name,
+            signature_raw: signature_raw,
+            timestamp: None,
+            tsa_token: None,
+        });
+    }
+    read_header_line(reader, "end of header")?;
+    Ok(signatures)
+}
+
+fn read_header_line<R: BufRead>(reader: &mut R, component: &str) -> Result<String> {
+    let mut buffer = String::new();
+    if reader.read_line(&mut buffer)? == 0 {
+        return Err(Error::CryptoError(format!(
+            "Corrupt payload, can't read {}",
+            component
+        )));
+    }
+    Ok(buffer.trim().to_string())
+}
+
 pub fn artifact_signer<P: AsRef<Path>>(src: &P) -> Result<String> {
     let f = File::open(src)?;
     let mut reader = BufReader::new(f);
@@ -250,6 +1123,14 @@ pub fn artifact_signer<P: AsRef<Path>>(src: &P) -> Result<String> {
                 ))
             }
             Ok(_) => {
+                if buffer.trim() == MULTI_SIG_HART_FORMAT_VERSION {
+                    let mut signatures = read_multi_signature_header(&mut reader)?;
+                    return Ok(signatures.remove(0).key_name);
+                }
+                if buffer.trim() == VERSIONED_HART_FORMAT_VERSION {
+                    let (_, _, signature) = read_versioned_header(&mut reader)?;
+                    return Ok(signature.key_name);
+                }
                 if buffer.trim() != HART_FORMAT_VERSION {
                     let msg = format!("Unsupported format version: {}", &buffer.trim());
                     return Err(Error::CryptoError(msg));
@@ -281,7 +1162,9 @@ mod test {
 
     use super::super::keys::parse_name_with_rev;
     use super::super::test_support::*;
-    use super::super::{SigKeyPair, HART_FORMAT_VERSION, SIG_HASH_TYPE};
+    use super::super::{
+        SigKeyPair, HART_FORMAT_VERSION, MULTI_SIG_HART_FORMAT_VERSION, SIG_HASH_TYPE,
+    };
     use super::*;
 
     #[test]
@@ -524,4 +1407,380 @@ mod test {
         assert_eq!(SIG_HASH_TYPE, hart_header.hash_type);
         assert!(hart_header.signature_raw.len() > 0);
     }
+
+    #[test]
+    fn sign_with_hash_algorithm_and_verify() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+
+        sign_with(&fixture("signme.dat"), &dst, &pair, HashAlgorithm::Sha256).unwrap();
+        let header = get_artifact_header(&dst).unwrap();
+        assert_eq!(VERSIONED_HART_FORMAT_VERSION, header.format_version);
+        assert_eq!("SHA256", header.hash_type);
+        assert_eq!("ed25519", header.signature_type);
+        assert_eq!(pair.name_with_rev(), artifact_signer(&dst).unwrap());
+
+        let (signer, hash) = verify(&dst, cache.path()).unwrap();
+        assert_eq!(signer, pair.name_with_rev());
+        assert_eq!(
+            hash,
+            hash::hash_file_with(&fixture("signme.dat"), HashAlgorithm::Sha256).unwrap()
+        );
+        let mut buffer = Vec::new();
+        get_archive_reader(&dst)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, fs::read(fixture("signme.dat")).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported key algorithm: rsa")]
+    fn verify_unsupported_signature_algorithm() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        let mut f = File::create(&dst).unwrap();
+        f.write_all(
+            format!(
+                "HART-3\n{}\nBLAKE2b\nrsa\nU3VycHJpc2Uh\n\n",
+                pair.name_with_rev()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        verify(&dst, cache.path()).unwrap();
+    }
+
+    #[test]
+    fn sign_timestamped_and_verify() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+
+        sign_timestamped(
+            &fixture("signme.dat"),
+            &dst,
+            &pair,
+            HashAlgorithm::Blake2b,
+            Some(b"token"),
+        )
+        .unwrap();
+        let verification = verify_timestamped(&dst, cache.path()).unwrap();
+        assert_eq!(verification.signer, pair.name_with_rev());
+        assert_eq!(
+            verification.hash,
+            hash::hash_file(&fixture("signme.dat")).unwrap()
+        );
+        assert_eq!(verification.tsa_token, Some(base64::encode(b"token")));
+        let timestamp = verification.timestamp.unwrap();
+        assert!(verification.signed_before(timestamp + 1));
+        assert!(!verification.signed_before(timestamp));
+        // The other verification functions accept timestamped signatures as well
+        verify(&dst, cache.path()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "has been revoked")]
+    fn verify_timestamped_rejects_backdated_signature() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let key_cache = FsKeyCache::new(cache.path());
+        let mut revoked = key_cache.revocation_list().unwrap();
+        revoked
+            .revoke_at(&pair.name_with_rev(), time::get_time().sec - 60)
+            .unwrap();
+        key_cache.write_revocation_list(&revoked).unwrap();
+
+        // Whoever stole the key signs with a timestamp from before it was revoked
+        let hash = hash::hash_file_with(&fixture("signme.dat"), HashAlgorithm::Blake2b).unwrap();
+        let signature = sign_hash_at(&hash, &pair, Some(1)).unwrap();
+        let dst = cache.path().join("forged.dat");
+        {
+            let mut writer = BufWriter::new(File::create(&dst).unwrap());
+            write_versioned_header(
+                &mut writer,
+                HashAlgorithm::Blake2b,
+                pair.algorithm(),
+                &signature,
+            )
+            .unwrap();
+            io::copy(&mut File::open(fixture("signme.dat")).unwrap(), &mut writer).unwrap();
+        }
+
+        verify_timestamped(&dst, cache.path()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Habitat artifact is invalid")]
+    fn verify_tampered_timestamp() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let signed = cache.path().join("signed.dat");
+        sign_timestamped(
+            &fixture("signme.dat"),
+            &signed,
+            &pair,
+            HashAlgorithm::Blake2b,
+            None,
+        )
+        .unwrap();
+        let mut content = Vec::new();
+        File::open(&signed)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        let content = String::from_utf8_lossy(&content).replace("timestamp: ", "timestamp: 1");
+        let dst = cache.path().join("tampered.dat");
+        File::create(&dst)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+
+        verify_timestamped(&dst, cache.path()).unwrap();
+    }
+
+    #[test]
+    fn read_payload_while_verifying() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        let mut payload = open_verifying(&dst, cache.path()).unwrap();
+        let mut content = String::new();
+        payload.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "foo\n");
+        let (signer, hash) = payload.finish().unwrap();
+        assert_eq!(signer, pair.name_with_rev());
+        assert_eq!(hash, hash::hash_file(&fixture("signme.dat")).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Habitat artifact is invalid")]
+    fn read_tampered_payload_while_verifying() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let signed = cache.path().join("signed.dat");
+        sign(&fixture("signme.dat"), &signed, &pair).unwrap();
+        let mut content = Vec::new();
+        File::open(&signed)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content.push(b'!');
+        let dst = cache.path().join("tampered.dat");
+        File::create(&dst).unwrap().write_all(&content).unwrap();
+
+        // Only part of the payload is read, the rest is read by `finish`
+        let mut payload = open_verifying(&dst, cache.path()).unwrap();
+        payload.read_exact(&mut [0u8; 2]).unwrap();
+        payload.finish().unwrap();
+    }
+
+    #[test]
+    fn sign_multi_and_verify_threshold() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let builder = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        builder.to_pair_files(cache.path()).unwrap();
+        let release = SigKeyPair::generate_pair_for_origin("release").unwrap();
+        release.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+
+        sign_multi(&fixture("signme.dat"), &dst, &[&builder, &release]).unwrap();
+        let (signers, _) = verify_threshold(&dst, cache.path(), 2).unwrap();
+        assert_eq!(
+            signers,
+            vec![builder.name_with_rev(), release.name_with_rev()]
+        );
+        // A multiple signature artifact still verifies with a single valid signature
+        let (signer, _) = verify(&dst, cache.path()).unwrap();
+        assert_eq!(signer, builder.name_with_rev());
+    }
+
+    #[test]
+    #[should_panic(expected = "Habitat artifact has 2 valid signature(s) but 3 are required")]
+    fn verify_threshold_not_met() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let builder = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        builder.to_pair_files(cache.path()).unwrap();
+        let release = SigKeyPair::generate_pair_for_origin("release").unwrap();
+        release.to_pair_files(cache.path()).unwrap();
+        // Signs, but its key isn't in the cache
+        let stranger = SigKeyPair::generate_pair_for_origin("stranger").unwrap();
+        let dst = cache.path().join("signed.dat");
+
+        sign_multi(
+            &fixture("signme.dat"),
+            &dst,
+            &[&builder, &release, &stranger],
+        )
+        .unwrap();
+        verify_threshold(&dst, cache.path(), 3).unwrap();
+    }
+
+    #[test]
+    fn verify_threshold_refuses_thresholds_no_artifact_can_meet() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let builder = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        builder.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        sign_multi(&fixture("signme.dat"), &dst, &[&builder, &builder]).unwrap();
+
+        assert!(verify_threshold(&dst, cache.path(), 0).is_err());
+        // The same key signing twice counts once
+        match verify_threshold(&dst, cache.path(), 2) {
+            Err(Error::CryptoError(msg)) => assert!(msg.contains("signed by keys of 1 origin(s)")),
+            other => panic!("Expected a crypto error, got {:?}", other),
+        }
+        verify_threshold(&dst, cache.path(), 1).unwrap();
+    }
+
+    #[test]
+    fn verify_threshold_counts_revisions_of_one_origin_once() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let builder = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        builder.to_pair_files(cache.path()).unwrap();
+        let rotated = SigKeyPair::new(
+            "unicorn".to_string(),
+            "29991231235959".to_string(),
+            Some(builder.public().unwrap().clone()),
+            Some(builder.secret().unwrap().clone()),
+        );
+        rotated.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        sign_multi(&fixture("signme.dat"), &dst, &[&builder, &rotated]).unwrap();
+
+        match verify_threshold(&dst, cache.path(), 2) {
+            Err(Error::CryptoError(msg)) => assert!(msg.contains("signed by keys of 1 origin(s)")),
+            other => panic!("Expected a crypto error, got {:?}", other),
+        }
+        let (signers, _) = verify_threshold(&dst, cache.path(), 1).unwrap();
+        assert_eq!(signers.len(), 1);
+    }
+
+    #[test]
+    fn multi_signature_header_count_is_bounded() {
+        let header = format!("{}\n{}\n", SIG_HASH_TYPE, MAX_SIGNATURES + 1);
+        match read_multi_signature_header(&mut header.as_bytes()) {
+            Err(Error::CryptoError(msg)) => assert!(msg.contains("at most")),
+            other => panic!("Expected a crypto error, got {:?}", other),
+        }
+        let header = format!("{}\n{}\n", SIG_HASH_TYPE, usize::max_value());
+        assert!(read_multi_signature_header(&mut header.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn add_signature_to_signed_artifact() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let builder = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        builder.to_pair_files(cache.path()).unwrap();
+        let release = SigKeyPair::generate_pair_for_origin("release").unwrap();
+        release.to_pair_files(cache.path()).unwrap();
+        let src = cache.path().join("src.in");
+        let signed = cache.path().join("src.signed");
+        let cosigned = cache.path().join("src.cosigned");
+        let mut f = File::create(&src).unwrap();
+        f.write_all("hearty goodness".as_bytes()).unwrap();
+        sign(&src, &signed, &builder).unwrap();
+
+        add_signature(&signed, &cosigned, &release).unwrap();
+
+        let header = get_artifact_header(&cosigned).unwrap();
+        assert_eq!(MULTI_SIG_HART_FORMAT_VERSION, header.format_version);
+        assert_eq!(builder.name_with_rev(), header.key_name);
+        let signatures = artifact_signatures(&cosigned).unwrap();
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(signatures[1].key_name, release.name_with_rev());
+        verify_threshold(&cosigned, cache.path(), 2).unwrap();
+
+        let mut buffer = String::new();
+        let mut reader = get_archive_reader(&cosigned).unwrap();
+        reader.read_to_string(&mut buffer).unwrap();
+        assert_eq!(buffer.as_bytes(), "hearty goodness".as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "Habitat artifact is already signed by")]
+    fn add_signature_twice_by_same_key() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let signed = cache.path().join("signed.dat");
+        let cosigned = cache.path().join("cosigned.dat");
+        sign(&fixture("signme.dat"), &signed, &pair).unwrap();
+
+        add_signature(&signed, &cosigned, &pair).unwrap();
+    }
+
+    #[test]
+    fn sign_and_verify_detached() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let sig = cache.path().join("signme.dat.sig");
+
+        sign_detached(&fixture("signme.dat"), &sig, &pair).unwrap();
+        let (signer, hash) = verify_detached(&fixture("signme.dat"), &sig, cache.path()).unwrap();
+        assert_eq!(signer, pair.name_with_rev());
+        assert_eq!(hash, hash::hash_file(&fixture("signme.dat")).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Habitat artifact is invalid, hashes don't match")]
+    fn verify_detached_modified_file() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let src = cache.path().join("src.in");
+        let sig = cache.path().join("src.in.sig");
+        let mut f = File::create(&src).unwrap();
+        f.write_all("hearty goodness".as_bytes()).unwrap();
+        sign_detached(&src, &sig, &pair).unwrap();
+
+        let mut f = File::create(&src).unwrap();
+        f.write_all("not so hearty".as_bytes()).unwrap();
+        verify_detached(&src, &sig, cache.path()).unwrap();
+    }
+
+    #[test]
+    fn verify_with_progress_reports_payload_size() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        let mut last = (0, 0);
+        verify_with_progress(&dst, cache.path(), |processed, total| {
+            last = (processed, total)
+        })
+        .unwrap();
+
+        let size = fs::metadata(fixture("signme.dat")).unwrap().len();
+        assert_eq!(last, (size, size));
+    }
+
+    #[test]
+    fn payload_chunk_layout_covers_payload() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let dst = cache.path().join("signed.dat");
+        sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        let mut layout = payload_chunk_layout(&dst, 64).unwrap();
+        hash::hash_chunks(&dst, &mut layout, 2).unwrap();
+        let expected = hash::hash_file_chunked(&fixture("signme.dat"), 64, 1).unwrap();
+        assert_eq!(layout.chunks(), expected.chunks());
+    }
 }
@@ -0,0 +1,114 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A mode which restricts this crate to FIPS-approved cryptographic primitives.
+//!
+//! FIPS mode is turned on when the crate is built with the `fips` feature, when the
+//! `HAB_FIPS_MODE` environment variable is set to `1` or `true` at startup, or at runtime with
+//! `enable`. Once on, it can't be turned off again for the life of the process.
+//!
+//! In FIPS mode, any operation which would use an algorithm that isn't approved fails with an
+//! error naming the algorithm, rather than falling back to another one. Of the algorithms this
+//! crate supports, only `SHA256` hashes and `ed25519` signatures are approved, so artifacts must
+//! be signed and verified with `artifact::sign_with` and `HashAlgorithm::Sha256`, and box and ring
+//! keys can't be used at all.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::hash::HashAlgorithm;
+use super::keys::KeyAlgorithm;
+use env as henv;
+use error::{Error, Result};
+
+/// This environment variable turns on FIPS mode when set to `1` or `true`
+pub static FIPS_MODE_ENV_VAR: &'static str = "HAB_FIPS_MODE";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref ENABLED_BY_ENV: bool = match henv::var(FIPS_MODE_ENV_VAR) {
+        Ok(val) => val == "1" || val.to_lowercase() == "true",
+        Err(_) => false,
+    };
+}
+
+/// Turns on FIPS mode for the rest of the life of the process.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether FIPS mode is on.
+pub fn is_enabled() -> bool {
+    cfg!(feature = "fips") || *ENABLED_BY_ENV || ENABLED.load(Ordering::SeqCst)
+}
+
+/// Returns whether a hashing algorithm is FIPS-approved.
+pub fn is_approved_hash(algorithm: HashAlgorithm) -> bool {
+    match algorithm {
+        HashAlgorithm::Sha256 => true,
+        HashAlgorithm::Blake2b | HashAlgorithm::Blake3 => false,
+    }
+}
+
+/// Returns whether a key algorithm is FIPS-approved.
+pub fn is_approved_key(algorithm: KeyAlgorithm) -> bool {
+    match algorithm {
+        KeyAlgorithm::Ed25519 => true,
+        KeyAlgorithm::X25519XSalsa20Poly1305 | KeyAlgorithm::XSalsa20Poly1305 => false,
+    }
+}
+
+/// Returns an error if FIPS mode is on and the hashing algorithm isn't approved.
+pub fn check_hash_algorithm(algorithm: HashAlgorithm) -> Result<()> {
+    if is_enabled() && !is_approved_hash(algorithm) {
+        return Err(not_approved(&algorithm.to_string()));
+    }
+    Ok(())
+}
+
+/// Returns an error if FIPS mode is on and the key algorithm isn't approved.
+pub fn check_key_algorithm(algorithm: KeyAlgorithm) -> Result<()> {
+    if is_enabled() && !is_approved_key(algorithm) {
+        return Err(not_approved(&algorithm.to_string()));
+    }
+    Ok(())
+}
+
+fn not_approved(algorithm: &str) -> Error {
+    Error::CryptoError(format!(
+        "{} is not a FIPS-approved algorithm and can't be used in FIPS mode",
+        algorithm
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn approved_algorithms() {
+        assert!(is_approved_hash(HashAlgorithm::Sha256));
+        assert!(!is_approved_hash(HashAlgorithm::Blake2b));
+        assert!(!is_approved_hash(HashAlgorithm::Blake3));
+        assert!(is_approved_key(KeyAlgorithm::Ed25519));
+        assert!(!is_approved_key(KeyAlgorithm::X25519XSalsa20Poly1305));
+        assert!(!is_approved_key(KeyAlgorithm::XSalsa20Poly1305));
+    }
+
+    #[test]
+    fn approved_algorithms_are_always_allowed() {
+        check_hash_algorithm(HashAlgorithm::Sha256).unwrap();
+        check_key_algorithm(KeyAlgorithm::Ed25519).unwrap();
+    }
+}
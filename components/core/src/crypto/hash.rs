@@ -12,19 +12,82 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::cmp;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::str::FromStr;
+use std::thread;
 
+#[cfg(feature = "blake3")]
+use blake3;
 use hex;
 use libsodium_sys;
+use rust_crypto::digest::Digest;
+use rust_crypto::sha2::Sha256;
 
-use error::Result;
+use super::{fips, secure_eq};
+use error::{Error, Result};
 
 const BUF_SIZE: usize = 1024;
 
+/// The default size of the chunks hashed in parallel by `hash_file_chunked`
+pub const HASH_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// The most chunks a `ChunkLayout` can have, which is 8 TiB of `HASH_CHUNK_SIZE` chunks
+pub const MAX_HASH_CHUNKS: u64 = 1 << 20;
+pub static CHUNK_LAYOUT_FORMAT_VERSION: &'static str = "CHUNKS-1";
+
+/// The hashing algorithms which can be selected by callers. `Blake2b` is the default and is the
+/// algorithm used when signing and verifying artifacts; `Blake3` is considerably faster on large
+/// inputs and can be chosen where the hash is only ever compared locally. `Sha256` is the only
+/// one allowed in FIPS mode.
+///
+/// `Blake3` is only available when this crate is built with the `blake3` feature, as the crate
+/// which implements it needs a newer Rust than the rest of this one. Without it the algorithm is
+/// still known by name, so whatever asks for it is refused with an error rather than misread.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum HashAlgorithm {
+    Blake2b,
+    Blake3,
+    Sha256,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        HashAlgorithm::Blake2b
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            HashAlgorithm::Blake2b => "BLAKE2b",
+            HashAlgorithm::Blake3 => "BLAKE3",
+            HashAlgorithm::Sha256 => "SHA256",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "BLAKE2b" => Ok(HashAlgorithm::Blake2b),
+            "BLAKE3" => Ok(HashAlgorithm::Blake3),
+            "SHA256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(Error::CryptoError(format!(
+                "Unsupported hash algorithm: {}",
+                value
+            ))),
+        }
+    }
+}
+
 /// Calculate the BLAKE2b hash of a file, return as a hex string
 /// digest size = 32 BYTES
 /// NOTE: the hashing is keyless
@@ -37,6 +100,34 @@ where
     hash_reader(&mut reader)
 }
 
+/// Calculate the BLAKE2b hash of a file as `hash_file` does, calling `progress` with the number
+/// of bytes hashed so far and the size of the file as each chunk is hashed
+pub fn hash_file_with_progress<P, F>(filename: P, progress: F) -> Result<String>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, u64),
+{
+    let file = File::open(filename.as_ref())?;
+    let total = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    hash_reader_with_progress(&mut reader, total, progress)
+}
+
+/// Calculate the hash of a file using the given algorithm, return as a hex string
+/// digest size = 32 BYTES
+pub fn hash_file_with<P>(filename: P, algorithm: HashAlgorithm) -> Result<String>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(filename.as_ref())?;
+    let mut reader = BufReader::new(file);
+    match algorithm {
+        HashAlgorithm::Blake2b => hash_reader(&mut reader),
+        HashAlgorithm::Blake3 => blake3_reader(&mut reader, 0, |_, _| ()),
+        HashAlgorithm::Sha256 => sha256_reader(&mut reader, 0, |_, _| ()),
+    }
+}
+
 pub fn hash_string(data: &str) -> String {
     let mut out = [0u8; libsodium_sys::crypto_generichash_BYTES];
     let mut st = vec![0u8; unsafe { libsodium_sys::crypto_generichash_statebytes() }];
@@ -65,7 +156,142 @@ pub fn hash_bytes(data: &[u8]) -> String {
     hex::encode(out)
 }
 
+/// Calculate the hash of `data` using the given algorithm, return as a hex string
+///
+/// # Failures
+///
+/// * The algorithm isn't allowed in FIPS mode
+pub fn hash_bytes_with(data: &[u8], algorithm: HashAlgorithm) -> Result<String> {
+    fips::check_hash_algorithm(algorithm)?;
+    let hash = match algorithm {
+        HashAlgorithm::Blake2b => hash_bytes(data),
+        HashAlgorithm::Blake3 => blake3_bytes(data)?,
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            hasher.input(data);
+            hasher.result_str()
+        }
+    };
+    Ok(hash)
+}
+
+#[cfg(feature = "blake3")]
+fn blake3_bytes(data: &[u8]) -> Result<String> {
+    Ok(hex::encode(blake3::hash(data).as_bytes()))
+}
+
+#[cfg(not(feature = "blake3"))]
+fn blake3_bytes(_data: &[u8]) -> Result<String> {
+    Err(blake3_unavailable())
+}
+
+#[cfg(not(feature = "blake3"))]
+fn blake3_unavailable() -> Error {
+    Error::CryptoError(
+        "BLAKE3 hashes are not supported, this was built without the blake3 feature".to_string(),
+    )
+}
+
+/// An incremental hash, for when the data to hash isn't read in one place, such as when it is
+/// being consumed by something else as it is hashed.
+pub struct Hasher {
+    state: HasherState,
+}
+
+enum HasherState {
+    /// The libsodium `crypto_generichash_state`, kept as bytes as `hash_bytes` does
+    Blake2b(Vec<u8>),
+    #[cfg(feature = "blake3")]
+    Blake3(blake3::Hasher),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    /// Starts a hash with the given algorithm, failing if it isn't allowed in FIPS mode.
+    pub fn new(algorithm: HashAlgorithm) -> Result<Self> {
+        fips::check_hash_algorithm(algorithm)?;
+        let state = match algorithm {
+            HashAlgorithm::Blake2b => {
+                let mut st = vec![0u8; unsafe { libsodium_sys::crypto_generichash_statebytes() }];
+                unsafe {
+                    libsodium_sys::crypto_generichash_init(
+                        blake2b_state(&mut st),
+                        ptr::null_mut(),
+                        0,
+                        libsodium_sys::crypto_generichash_BYTES,
+                    );
+                }
+                HasherState::Blake2b(st)
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => HasherState::Blake3(blake3::Hasher::new()),
+            #[cfg(not(feature = "blake3"))]
+            HashAlgorithm::Blake3 => return Err(blake3_unavailable()),
+            HashAlgorithm::Sha256 => HasherState::Sha256(Sha256::new()),
+        };
+        Ok(Hasher { state: state })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self.state {
+            HasherState::Blake2b(ref mut st) => unsafe {
+                libsodium_sys::crypto_generichash_update(
+                    blake2b_state(st),
+                    data.as_ptr(),
+                    data.len() as u64,
+                );
+            },
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+            HasherState::Sha256(ref mut hasher) => hasher.input(data),
+        }
+    }
+
+    /// Returns the hex encoded hash of everything passed to `update`.
+    pub fn finish(self) -> String {
+        match self.state {
+            HasherState::Blake2b(mut st) => {
+                let mut out = [0u8; libsodium_sys::crypto_generichash_BYTES];
+                unsafe {
+                    libsodium_sys::crypto_generichash_final(
+                        blake2b_state(&mut st),
+                        out.as_mut_ptr(),
+                        out.len(),
+                    );
+                }
+                hex::encode(out)
+            }
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(hasher) => hex::encode(hasher.finalize().as_bytes()),
+            HasherState::Sha256(mut hasher) => hasher.result_str(),
+        }
+    }
+}
+
+fn blake2b_state(st: &mut [u8]) -> *mut libsodium_sys::crypto_generichash_state {
+    unsafe {
+        mem::transmute::<*mut u8, *mut libsodium_sys::crypto_generichash_state>(st.as_mut_ptr())
+    }
+}
+
 pub fn hash_reader(reader: &mut BufReader<File>) -> Result<String> {
+    hash_reader_with_progress(reader, 0, |_, _| ())
+}
+
+/// Calculate the BLAKE2b hash of everything left in `reader`, calling `progress` with the number
+/// of bytes hashed so far and the expected `total` as each chunk is hashed
+pub fn hash_reader_with_progress<R, F>(
+    reader: &mut R,
+    total: u64,
+    mut progress: F,
+) -> Result<String>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    fips::check_hash_algorithm(HashAlgorithm::Blake2b)?;
     let mut out = [0u8; libsodium_sys::crypto_generichash_BYTES];
     let mut st = vec![0u8; unsafe { libsodium_sys::crypto_generichash_statebytes() }];
     let pst = unsafe {
@@ -75,6 +301,7 @@ pub fn hash_reader(reader: &mut BufReader<File>) -> Result<String> {
         libsodium_sys::crypto_generichash_init(pst, ptr::null_mut(), 0, out.len());
     }
     let mut buf = [0u8; BUF_SIZE];
+    let mut processed = 0;
     loop {
         let bytes_read = reader.read(&mut buf)?;
         if bytes_read == 0 {
@@ -84,6 +311,8 @@ pub fn hash_reader(reader: &mut BufReader<File>) -> Result<String> {
         unsafe {
             libsodium_sys::crypto_generichash_update(pst, chunk.as_ptr(), chunk.len() as u64);
         }
+        processed += bytes_read as u64;
+        progress(processed, total);
     }
     unsafe {
         libsodium_sys::crypto_generichash_final(pst, out.as_mut_ptr(), out.len());
@@ -91,6 +320,483 @@ pub fn hash_reader(reader: &mut BufReader<File>) -> Result<String> {
     Ok(hex::encode(out))
 }
 
+/// Calculate the hash of everything left in `reader` using the given algorithm, calling
+/// `progress` as `hash_reader_with_progress` does
+pub fn hash_reader_with<R, F>(
+    reader: &mut R,
+    algorithm: HashAlgorithm,
+    total: u64,
+    progress: F,
+) -> Result<String>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    match algorithm {
+        HashAlgorithm::Blake2b => hash_reader_with_progress(reader, total, progress),
+        HashAlgorithm::Blake3 => blake3_reader(reader, total, progress),
+        HashAlgorithm::Sha256 => sha256_reader(reader, total, progress),
+    }
+}
+
+#[cfg(not(feature = "blake3"))]
+fn blake3_reader<R, F>(_reader: &mut R, _total: u64, _progress: F) -> Result<String>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    Err(blake3_unavailable())
+}
+
+#[cfg(feature = "blake3")]
+fn blake3_reader<R, F>(reader: &mut R, total: u64, mut progress: F) -> Result<String>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    fips::check_hash_algorithm(HashAlgorithm::Blake3)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = [0u8; BUF_SIZE];
+    let mut processed = 0;
+    loop {
+        let bytes_read = reader.read(&mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[0..bytes_read]);
+        processed += bytes_read as u64;
+        progress(processed, total);
+    }
+    Ok(hex::encode(hasher.finalize().as_bytes()))
+}
+
+fn sha256_reader<R, F>(reader: &mut R, total: u64, mut progress: F) -> Result<String>
+where
+    R: Read,
+    F: FnMut(u64, u64),
+{
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; BUF_SIZE];
+    let mut processed = 0;
+    loop {
+        let bytes_read = reader.read(&mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.input(&buf[0..bytes_read]);
+        processed += bytes_read as u64;
+        progress(processed, total);
+    }
+    Ok(hasher.result_str())
+}
+
+/// The layout of a region of a file which is hashed as fixed-size chunks, along with the BLAKE2b
+/// hash of every chunk which has been hashed so far.
+///
+/// A layout can be written out with `to_string` and parsed back, so hashing a large file can be
+/// resumed with `hash_chunks` after an interruption, re-hashing only the chunks which are missing.
+/// Its text form has the format version, the offset and length of the region and the chunk size
+/// on one line each, followed by one line per chunk holding either its hash or `-`:
+///
+/// ```text
+/// CHUNKS-1
+/// 0
+/// 20971520
+/// 8388608
+/// 1d1e0a...
+/// 8bbf51...
+/// -
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkLayout {
+    /// The offset in the file where the hashed region begins
+    pub offset: u64,
+    /// The length of the hashed region
+    pub len: u64,
+    pub chunk_size: u64,
+    chunks: Vec<Option<String>>,
+}
+
+impl ChunkLayout {
+    /// Creates a layout for `len` bytes starting at `offset`, with no chunks hashed yet.
+    ///
+    /// # Failures
+    ///
+    /// * The chunk size is zero
+    /// * The region ends past the largest offset a file can have
+    /// * The region would take more than `MAX_HASH_CHUNKS` chunks
+    pub fn new(offset: u64, len: u64, chunk_size: u64) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(Error::CryptoError(
+                "Chunk size must be greater than zero".to_string(),
+            ));
+        }
+        if offset.checked_add(len).is_none() {
+            return Err(Error::CryptoError(format!(
+                "A region of {} bytes at offset {} is too large",
+                len, offset
+            )));
+        }
+        let count = len / chunk_size + if len % chunk_size == 0 { 0 } else { 1 };
+        if count > MAX_HASH_CHUNKS {
+            return Err(Error::CryptoError(format!(
+                "A region of {} bytes takes {} chunks of {} bytes, more than the {} allowed",
+                len, count, chunk_size, MAX_HASH_CHUNKS
+            )));
+        }
+        Ok(ChunkLayout {
+            offset: offset,
+            len: len,
+            chunk_size: chunk_size,
+            chunks: vec![None; count as usize],
+        })
+    }
+
+    /// Returns the hash of every chunk, where `None` marks a chunk which hasn't been hashed yet.
+    pub fn chunks(&self) -> &[Option<String>] {
+        &self.chunks
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(|c| c.is_some())
+    }
+
+    /// Returns the BLAKE2b hash of the chunk hashes, in order, which identifies the whole region.
+    ///
+    /// # Failures
+    ///
+    /// * A chunk hasn't been hashed yet
+    /// * BLAKE2b isn't allowed in FIPS mode
+    pub fn root_hash(&self) -> Result<String> {
+        let mut combined = String::new();
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            match *chunk {
+                Some(ref hash) => combined.push_str(hash),
+                None => {
+                    return Err(Error::CryptoError(format!(
+                        "Chunk {} of {} hasn't been hashed",
+                        i,
+                        self.chunks.len()
+                    )))
+                }
+            }
+        }
+        hash_bytes_with(combined.as_bytes(), HashAlgorithm::Blake2b)
+    }
+
+    /// Returns the offset in the file and the length of a chunk, failing if the layout's fields
+    /// were changed since it was created so that the chunk is no longer within the region.
+    fn chunk_bounds(&self, index: usize) -> Result<(u64, u64)> {
+        let start = (index as u64).checked_mul(self.chunk_size);
+        let bounds = start.and_then(|start| {
+            let len = cmp::min(self.chunk_size, self.len.checked_sub(start)?);
+            Some((self.offset.checked_add(start)?, len))
+        });
+        bounds.ok_or_else(|| {
+            Error::CryptoError(format!(
+                "Chunk {} of {} is outside of the region",
+                index,
+                self.chunks.len()
+            ))
+        })
+    }
+}
+
+impl fmt::Display for ChunkLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", CHUNK_LAYOUT_FORMAT_VERSION)?;
+        writeln!(f, "{}", self.offset)?;
+        writeln!(f, "{}", self.len)?;
+        writeln!(f, "{}", self.chunk_size)?;
+        for chunk in &self.chunks {
+            match *chunk {
+                Some(ref hash) => writeln!(f, "{}", hash)?,
+                None => writeln!(f, "-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ChunkLayout {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut lines = value.lines().map(|l| l.trim());
+        match lines.next() {
+            Some(version) if version == CHUNK_LAYOUT_FORMAT_VERSION => (),
+            Some(version) => {
+                return Err(Error::CryptoError(format!(
+                    "Unsupported chunk layout version: {}",
+                    version
+                )))
+            }
+            None => {
+                return Err(Error::CryptoError(
+                    "Corrupt chunk layout, can't read format version".to_string(),
+                ))
+            }
+        }
+        let mut numbers = Vec::with_capacity(3);
+        for component in &["offset", "length", "chunk size"] {
+            let number = lines
+                .next()
+                .ok_or_else(|| {
+                    Error::CryptoError(format!("Corrupt chunk layout, can't read {}", component))
+                })?
+                .parse::<u64>()
+                .map_err(|e| {
+                    Error::CryptoError(format!("Can't parse chunk layout {}: {}", component, e))
+                })?;
+            numbers.push(number);
+        }
+        let mut layout = ChunkLayout::new(numbers[0], numbers[1], numbers[2])?;
+        let count = layout.chunks.len();
+        for (i, chunk) in layout.chunks.iter_mut().enumerate() {
+            match lines.next() {
+                Some("-") => (),
+                Some(hash) => *chunk = Some(hash.to_string()),
+                None => {
+                    return Err(Error::CryptoError(format!(
+                        "Corrupt chunk layout, can't read chunk {} of {}",
+                        i, count
+                    )))
+                }
+            }
+        }
+        Ok(layout)
+    }
+}
+
+/// Hash an entire file as fixed-size chunks on `threads` threads, returning the completed layout
+pub fn hash_file_chunked<P>(filename: P, chunk_size: u64, threads: usize) -> Result<ChunkLayout>
+where
+    P: AsRef<Path>,
+{
+    let len = File::open(filename.as_ref())?.metadata()?.len();
+    let mut layout = ChunkLayout::new(0, len, chunk_size)?;
+    hash_chunks(filename, &mut layout, threads)?;
+    Ok(layout)
+}
+
+/// Hash every chunk of `layout` which hasn't been hashed yet, spreading the chunks over `threads`
+/// threads which each read the file independently. Chunks which were hashed successfully are
+/// recorded in the layout even if hashing another chunk fails, so the work can be resumed.
+pub fn hash_chunks<P>(filename: P, layout: &mut ChunkLayout, threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let pending = layout
+        .chunks
+        .iter()
+        .enumerate()
+        .filter(|&(_, chunk)| chunk.is_none())
+        .map(|(i, _)| {
+            let (start, len) = layout.chunk_bounds(i)?;
+            Ok((i, start, len))
+        })
+        .collect::<Result<Vec<(usize, u64, u64)>>>()?;
+    let threads = if threads == 0 { 1 } else { threads };
+    let mut handles = Vec::with_capacity(threads);
+    for t in 0..threads {
+        let path = PathBuf::from(filename.as_ref());
+        let work: Vec<(usize, u64, u64)> =
+            pending.iter().skip(t).step_by(threads).cloned().collect();
+        if work.is_empty() {
+            continue;
+        }
+        handles.push(thread::spawn(move || {
+            let mut results = Vec::with_capacity(work.len());
+            for (index, start, len) in work {
+                results.push((index, hash_chunk(&path, start, len)));
+            }
+            results
+        }));
+    }
+
+    let mut first_error = None;
+    for handle in handles {
+        let results = handle
+            .join()
+            .map_err(|_| Error::CryptoError("Chunk hashing thread panicked".to_string()))?;
+        for (index, result) in results {
+            match result {
+                Ok(hash) => layout.chunks[index] = Some(hash),
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Hash a file with the geometry of `expected` and check that every chunk matches it
+pub fn verify_file_chunked<P>(filename: P, expected: &ChunkLayout, threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut computed = ChunkLayout::new(expected.offset, expected.len, expected.chunk_size)?;
+    hash_chunks(filename, &mut computed, threads)?;
+    for (i, (e, c)) in expected
+        .chunks
+        .iter()
+        .zip(computed.chunks.iter())
+        .enumerate()
+    {
+        let matches = match (e, c) {
+            (&Some(ref e), &Some(ref c)) => secure_eq(e, c),
+            (&None, &None) => true,
+            _ => false,
+        };
+        if !matches {
+            return Err(Error::CryptoError(format!(
+                "Chunk {} of {} doesn't match (expected: {}, computed: {})",
+                i,
+                expected.chunks.len(),
+                e.as_ref().map(|h| h.as_str()).unwrap_or("-"),
+                c.as_ref().map(|h| h.as_str()).unwrap_or("-")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Calculate a BLAKE2b Merkle hash of a directory tree, return as a hex string.
+///
+/// See `hash_dir_with`.
+pub fn hash_dir<P>(path: P) -> Result<String>
+where
+    P: AsRef<Path>,
+{
+    hash_dir_with(path, HashAlgorithm::Blake2b)
+}
+
+/// Calculate a Merkle hash of a directory tree using the given algorithm, return as a hex string.
+///
+/// Every directory is hashed from a sorted listing of its entries, each of which gives the kind
+/// of entry, its permissions, its name and its own hash: the content hash of a file, the hash of
+/// a symlink's target (symlinks aren't followed) or the Merkle hash of a subdirectory. FIFOs,
+/// sockets and devices are never opened; they are hashed by their kind and, for devices, their
+/// device number. The hash
+/// is the same for two trees with the same content and permissions wherever they are, and it
+/// changes when any file, link or permission below `path` does. Ownership and modification times
+/// are not covered, nor is the metadata of `path` itself.
+pub fn hash_dir_with<P>(path: P, algorithm: HashAlgorithm) -> Result<String>
+where
+    P: AsRef<Path>,
+{
+    fips::check_hash_algorithm(algorithm)?;
+    let path = path.as_ref();
+    if !fs::metadata(path)?.is_dir() {
+        return Err(Error::CryptoError(format!(
+            "Can't hash {}, it is not a directory",
+            path.display()
+        )));
+    }
+    hash_dir_node(path, algorithm)
+}
+
+fn hash_dir_node(dir: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        entries.push((dir_entry.file_name(), dir_entry.path()));
+    }
+    entries.sort();
+
+    let mut listing = String::new();
+    for (name, path) in entries {
+        let metadata = fs::symlink_metadata(&path)?;
+        let (kind, hash) = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            (
+                "link",
+                hash_bytes_with(target.to_string_lossy().as_bytes(), algorithm)?,
+            )
+        } else if metadata.is_dir() {
+            ("dir", hash_dir_node(&path, algorithm)?)
+        } else if metadata.is_file() {
+            ("file", hash_file_with(&path, algorithm)?)
+        } else {
+            let (kind, device) = special_file(&metadata);
+            (kind, hash_bytes_with(device.as_bytes(), algorithm)?)
+        };
+        // Names are prefixed with their length, so that no name can be mistaken for a separator
+        let name = name.to_string_lossy();
+        listing.push_str(&format!(
+            "{} {:o} {}:{} {}\n",
+            kind,
+            dir_mode(&metadata),
+            name.len(),
+            name,
+            hash
+        ));
+    }
+    hash_bytes_with(listing.as_bytes(), algorithm)
+}
+
+/// The kind of a file which is neither a regular file, a directory nor a symlink, along with its
+/// device number if it is a device.
+#[cfg(not(windows))]
+fn special_file(metadata: &Metadata) -> (&'static str, String) {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        ("fifo", String::new())
+    } else if file_type.is_socket() {
+        ("socket", String::new())
+    } else if file_type.is_block_device() {
+        ("block", metadata.rdev().to_string())
+    } else if file_type.is_char_device() {
+        ("char", metadata.rdev().to_string())
+    } else {
+        ("special", String::new())
+    }
+}
+
+#[cfg(windows)]
+fn special_file(_metadata: &Metadata) -> (&'static str, String) {
+    ("special", String::new())
+}
+
+#[cfg(not(windows))]
+fn dir_mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(windows)]
+fn dir_mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+fn hash_chunk(path: &Path, start: u64, len: u64) -> Result<String> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file).take(len);
+    let hash = hash_reader_with_progress(&mut reader, len, |_, _| ())?;
+    if reader.limit() > 0 {
+        return Err(Error::CryptoError(format!(
+            "Unexpected end of file in {} at offset {}",
+            path.display(),
+            start + len - reader.limit()
+        )));
+    }
+    Ok(hash)
+}
+
 #[cfg(test)]
 mod test {
     use std::env;
@@ -100,6 +806,8 @@ mod test {
     use std::io;
     use std::path::PathBuf;
 
+    use tempfile::Builder;
+
     use super::super::test_support::*;
     use super::*;
     #[cfg(feature = "functional")]
@@ -143,6 +851,230 @@ mod test {
         assert_eq!(computed, expected);
     }
 
+    #[test]
+    fn hash_file_with_default_matches_hash_file() {
+        let computed = hash_file_with(&fixture("signme.dat"), HashAlgorithm::default()).unwrap();
+        let expected = "20590a52c4f00588c500328b16d466c982a26fabaa5fa4dcc83052dd0a84f233";
+        assert_eq!(computed, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn hash_with_blake3() {
+        // The expected value is the BLAKE3 test vector for empty input
+        let expected = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+        assert_eq!(
+            hash_bytes_with(&[], HashAlgorithm::Blake3).unwrap(),
+            expected
+        );
+
+        let computed = hash_file_with(&fixture("signme.dat"), HashAlgorithm::Blake3).unwrap();
+        let data = fs::read(fixture("signme.dat")).unwrap();
+        assert_eq!(
+            computed,
+            hash_bytes_with(&data, HashAlgorithm::Blake3).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "blake3"))]
+    fn blake3_is_refused_without_the_feature() {
+        assert!(hash_bytes_with(&[], HashAlgorithm::Blake3).is_err());
+        assert!(hash_file_with(&fixture("signme.dat"), HashAlgorithm::Blake3).is_err());
+        assert!(Hasher::new(HashAlgorithm::Blake3).is_err());
+    }
+
+    #[test]
+    fn hash_with_sha256() {
+        // The expected value is the SHA-256 test vector for "abc"
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(
+            hash_bytes_with(b"abc", HashAlgorithm::Sha256).unwrap(),
+            expected
+        );
+
+        let computed = hash_file_with(&fixture("signme.dat"), HashAlgorithm::Sha256).unwrap();
+        let data = fs::read(fixture("signme.dat")).unwrap();
+        assert_eq!(
+            computed,
+            hash_bytes_with(&data, HashAlgorithm::Sha256).unwrap()
+        );
+    }
+
+    #[test]
+    fn incremental_hash_matches_hash_bytes() {
+        let data = b"one two three four";
+        let mut algorithms = vec![HashAlgorithm::Blake2b, HashAlgorithm::Sha256];
+        if cfg!(feature = "blake3") {
+            algorithms.push(HashAlgorithm::Blake3);
+        }
+        for algorithm in algorithms {
+            let mut hasher = Hasher::new(algorithm).unwrap();
+            for chunk in data.chunks(5) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), hash_bytes_with(data, algorithm).unwrap());
+        }
+    }
+
+    #[test]
+    fn hash_algorithm_from_str() {
+        for algorithm in &[
+            HashAlgorithm::Blake2b,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+        ] {
+            assert_eq!(
+                *algorithm,
+                algorithm.to_string().parse::<HashAlgorithm>().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn hash_file_with_progress_reports_every_byte() {
+        let mut updates = Vec::new();
+        let computed = hash_file_with_progress(&fixture("signme.dat"), |processed, total| {
+            updates.push((processed, total))
+        })
+        .unwrap();
+
+        assert_eq!(computed, hash_file(&fixture("signme.dat")).unwrap());
+        let size = fs::metadata(fixture("signme.dat")).unwrap().len();
+        assert_eq!(updates.last(), Some(&(size, size)));
+        assert!(updates.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn hash_dir_detects_drift() {
+        let first = Builder::new().prefix("hash_dir").tempdir().unwrap();
+        let second = Builder::new().prefix("hash_dir").tempdir().unwrap();
+        for root in &[first.path(), second.path()] {
+            fs::create_dir_all(root.join("config/empty")).unwrap();
+            fs::copy(fixture("signme.dat"), root.join("config/signme.dat")).unwrap();
+            fs::write(root.join("run"), "#!/bin/sh\n").unwrap();
+        }
+
+        let hash = hash_dir(first.path()).unwrap();
+        assert_eq!(hash, hash_dir(second.path()).unwrap());
+        assert_ne!(
+            hash,
+            hash_dir_with(first.path(), HashAlgorithm::Sha256).unwrap()
+        );
+
+        fs::write(second.path().join("config/signme.dat"), "changed").unwrap();
+        assert_ne!(hash, hash_dir(second.path()).unwrap());
+        fs::copy(
+            fixture("signme.dat"),
+            second.path().join("config/signme.dat"),
+        )
+        .unwrap();
+        assert_eq!(hash, hash_dir(second.path()).unwrap());
+
+        fs::remove_dir(second.path().join("config/empty")).unwrap();
+        assert_ne!(hash, hash_dir(second.path()).unwrap());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn hash_dir_covers_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = Builder::new().prefix("hash_dir").tempdir().unwrap();
+        fs::write(dir.path().join("run"), "#!/bin/sh\n").unwrap();
+        let hash = hash_dir(dir.path()).unwrap();
+        fs::set_permissions(dir.path().join("run"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_ne!(hash, hash_dir(dir.path()).unwrap());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn hash_dir_does_not_read_special_files() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = Builder::new().prefix("hash_dir").tempdir().unwrap();
+        let _listener = UnixListener::bind(dir.path().join("run")).unwrap();
+        let hash = hash_dir(dir.path()).unwrap();
+        assert_eq!(hash, hash_dir(dir.path()).unwrap());
+
+        let other = Builder::new().prefix("hash_dir").tempdir().unwrap();
+        fs::write(other.path().join("run"), "").unwrap();
+        assert_ne!(hash, hash_dir(other.path()).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "it is not a directory")]
+    fn hash_dir_of_a_file() {
+        hash_dir(&fixture("signme.dat")).unwrap();
+    }
+
+    #[test]
+    fn hash_file_chunked_matches_chunk_hashes() {
+        let data = fs::read(fixture("signme.dat")).unwrap();
+        let layout = hash_file_chunked(&fixture("signme.dat"), 100, 3).unwrap();
+
+        assert!(layout.is_complete());
+        assert_eq!(layout.chunks().len(), (data.len() + 99) / 100);
+        for (chunk, expected) in layout.chunks().iter().zip(data.chunks(100)) {
+            assert_eq!(chunk.as_ref().unwrap(), &hash_bytes(expected));
+        }
+        verify_file_chunked(&fixture("signme.dat"), &layout, 2).unwrap();
+    }
+
+    #[test]
+    fn hash_chunks_resumes_from_parsed_layout() {
+        let complete = hash_file_chunked(&fixture("signme.dat"), 64, 1).unwrap();
+        let mut partial = complete.clone();
+        partial.chunks[1] = None;
+        let mut resumed: ChunkLayout = partial.to_string().parse().unwrap();
+        assert!(!resumed.is_complete());
+
+        hash_chunks(&fixture("signme.dat"), &mut resumed, 4).unwrap();
+        assert_eq!(resumed, complete);
+        assert_eq!(resumed.root_hash().unwrap(), complete.root_hash().unwrap());
+    }
+
+    #[test]
+    fn chunk_layout_refuses_regions_too_large_to_lay_out() {
+        assert!(ChunkLayout::new(u64::max_value(), 1, 64).is_err());
+        assert!(ChunkLayout::new(0, u64::max_value(), 1).is_err());
+        assert!(ChunkLayout::new(0, MAX_HASH_CHUNKS + 1, 1).is_err());
+        assert_eq!(
+            ChunkLayout::new(0, u64::max_value(), u64::max_value())
+                .unwrap()
+                .chunks()
+                .len(),
+            1
+        );
+
+        let huge = format!(
+            "{}\n0\n{}\n1\n",
+            CHUNK_LAYOUT_FORMAT_VERSION,
+            u64::max_value()
+        );
+        assert!(huge.parse::<ChunkLayout>().is_err());
+        let overflowing = format!(
+            "{}\n{}\n1\n1\n-\n",
+            CHUNK_LAYOUT_FORMAT_VERSION,
+            u64::max_value()
+        );
+        assert!(overflowing.parse::<ChunkLayout>().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Chunk 0 of")]
+    fn verify_file_chunked_mismatch() {
+        let mut layout = hash_file_chunked(&fixture("signme.dat"), 64, 2).unwrap();
+        layout.chunks[0] = Some(hash_string("nope"));
+        verify_file_chunked(&fixture("signme.dat"), &layout, 2).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported hash algorithm: SHA1")]
+    fn hash_algorithm_from_str_unsupported() {
+        "SHA1".parse::<HashAlgorithm>().unwrap();
+    }
+
     #[test]
     #[cfg(feature = "functional")]
     fn hash_file_large_binary() {
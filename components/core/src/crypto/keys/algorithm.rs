@@ -0,0 +1,187 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The algorithms keys can be generated with.
+//!
+//! Every algorithm has its own key format versions, which are written on the first line of each
+//! key file (ex: `SIG-PUB-1`). A key whose version names a known kind of key but an algorithm this
+//! release doesn't support is refused with an "Unsupported key version" error, rather than having
+//! its bytes misread as a key of another algorithm.
+
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use super::super::{
+    PUBLIC_BOX_KEY_VERSION, PUBLIC_SIG_KEY_VERSION, SECRET_BOX_KEY_VERSION, SECRET_SIG_KEY_VERSION,
+    SECRET_SYM_KEY_VERSION,
+};
+use super::{KeyType, PairType};
+use error::{Error, Result};
+
+lazy_static! {
+    static ref KEY_VERSION_RE: Regex = Regex::new(r"\A(SIG|BOX|SYM)-(PUB|SEC)-\d+\z").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum KeyAlgorithm {
+    /// Ed25519 signatures, used for origin keys
+    Ed25519,
+    /// X25519 key exchange with XSalsa20-Poly1305 authenticated encryption, used for user and
+    /// service keys
+    X25519XSalsa20Poly1305,
+    /// XSalsa20-Poly1305 authenticated encryption with a shared secret, used for ring keys
+    XSalsa20Poly1305,
+}
+
+impl KeyAlgorithm {
+    /// Returns the algorithm keys of the given type are generated with unless another is chosen.
+    pub fn default_for(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::Sig => KeyAlgorithm::Ed25519,
+            KeyType::Box => KeyAlgorithm::X25519XSalsa20Poly1305,
+            KeyType::Sym => KeyAlgorithm::XSalsa20Poly1305,
+        }
+    }
+
+    /// Returns the type of key this algorithm can be used for.
+    pub fn key_type(&self) -> KeyType {
+        match *self {
+            KeyAlgorithm::Ed25519 => KeyType::Sig,
+            KeyAlgorithm::X25519XSalsa20Poly1305 => KeyType::Box,
+            KeyAlgorithm::XSalsa20Poly1305 => KeyType::Sym,
+        }
+    }
+
+    /// Returns the format version of public keys of this algorithm, if it has public keys.
+    pub fn public_key_version(&self) -> Option<&'static str> {
+        match *self {
+            KeyAlgorithm::Ed25519 => Some(PUBLIC_SIG_KEY_VERSION),
+            KeyAlgorithm::X25519XSalsa20Poly1305 => Some(PUBLIC_BOX_KEY_VERSION),
+            KeyAlgorithm::XSalsa20Poly1305 => None,
+        }
+    }
+
+    /// Returns the format version of secret keys of this algorithm.
+    pub fn secret_key_version(&self) -> &'static str {
+        match *self {
+            KeyAlgorithm::Ed25519 => SECRET_SIG_KEY_VERSION,
+            KeyAlgorithm::X25519XSalsa20Poly1305 => SECRET_BOX_KEY_VERSION,
+            KeyAlgorithm::XSalsa20Poly1305 => SECRET_SYM_KEY_VERSION,
+        }
+    }
+
+    /// Returns the algorithm and half of the pair for a key format version.
+    pub fn from_key_version(version: &str) -> Result<(Self, PairType)> {
+        match version {
+            PUBLIC_SIG_KEY_VERSION => Ok((KeyAlgorithm::Ed25519, PairType::Public)),
+            SECRET_SIG_KEY_VERSION => Ok((KeyAlgorithm::Ed25519, PairType::Secret)),
+            PUBLIC_BOX_KEY_VERSION => Ok((KeyAlgorithm::X25519XSalsa20Poly1305, PairType::Public)),
+            SECRET_BOX_KEY_VERSION => Ok((KeyAlgorithm::X25519XSalsa20Poly1305, PairType::Secret)),
+            SECRET_SYM_KEY_VERSION => Ok((KeyAlgorithm::XSalsa20Poly1305, PairType::Secret)),
+            _ => Err(Error::CryptoError(format!(
+                "Unsupported key version: {}",
+                version
+            ))),
+        }
+    }
+
+    /// Returns an error if this algorithm can't be used for keys of the given type.
+    pub fn check_key_type(&self, key_type: KeyType) -> Result<()> {
+        if self.key_type() == key_type {
+            Ok(())
+        } else {
+            Err(Error::CryptoError(format!(
+                "Key algorithm {} can't be used for {} keys",
+                self, key_type
+            )))
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::X25519XSalsa20Poly1305 => "x25519-xsalsa20poly1305",
+            KeyAlgorithm::XSalsa20Poly1305 => "xsalsa20poly1305",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = Error;
+
+    fn from_str(value: &str) -> result::Result<Self, Self::Err> {
+        match value {
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            "x25519-xsalsa20poly1305" => Ok(KeyAlgorithm::X25519XSalsa20Poly1305),
+            "xsalsa20poly1305" => Ok(KeyAlgorithm::XSalsa20Poly1305),
+            _ => Err(Error::CryptoError(format!(
+                "Unsupported key algorithm: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Refuses key contents whose first line is the format version of a kind of key this release
+/// knows about, but of an algorithm it doesn't support. Anything else is left for the key parsers
+/// to reject.
+pub fn check_key_version(version: &str) -> Result<()> {
+    if KEY_VERSION_RE.is_match(version) {
+        KeyAlgorithm::from_key_version(version)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_algorithm_from_str() {
+        for algorithm in &[
+            KeyAlgorithm::Ed25519,
+            KeyAlgorithm::X25519XSalsa20Poly1305,
+            KeyAlgorithm::XSalsa20Poly1305,
+        ] {
+            assert_eq!(
+                *algorithm,
+                algorithm.to_string().parse::<KeyAlgorithm>().unwrap()
+            );
+            let (parsed, pair_type) =
+                KeyAlgorithm::from_key_version(algorithm.secret_key_version()).unwrap();
+            assert_eq!(parsed, *algorithm);
+            assert_eq!(pair_type, PairType::Secret);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported key version: SIG-PUB-2")]
+    fn check_key_version_rejects_newer_versions() {
+        check_key_version("SIG-PUB-1").unwrap();
+        check_key_version("SOMETHING").unwrap();
+        check_key_version("SIG-PUB-2").unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Key algorithm ed25519 can\\'t be used for box keys")]
+    fn check_key_type_mismatch() {
+        KeyAlgorithm::Ed25519.check_key_type(KeyType::Box).unwrap();
+    }
+}
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str;
 
@@ -19,19 +21,29 @@ use base64;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey as BoxPublicKey;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey as BoxSecretKey;
-use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{gen_nonce, Nonce};
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{
+    gen_nonce, Nonce, MACBYTES, NONCEBYTES,
+};
 use sodiumoxide::crypto::sealedbox;
+use sodiumoxide::crypto::secretbox;
 
 use super::super::{
-    ANONYMOUS_BOX_FORMAT_VERSION, BOX_FORMAT_VERSION, PUBLIC_BOX_KEY_VERSION, PUBLIC_KEY_SUFFIX,
+    fips, ANONYMOUS_BOX_FORMAT_VERSION, ANONYMOUS_BOX_STREAM_FORMAT_VERSION, BOX_FORMAT_VERSION,
+    BOX_STREAM_FORMAT_VERSION, MULTI_BOX_FORMAT_VERSION, PUBLIC_BOX_KEY_VERSION, PUBLIC_KEY_SUFFIX,
     SECRET_BOX_KEY_SUFFIX, SECRET_BOX_KEY_VERSION,
 };
 use super::{
-    get_key_revisions, mk_key_filename, mk_revision_string, parse_name_with_rev, read_key_bytes,
-    read_key_bytes_from_str, write_keypair_files, KeyPair, KeyType,
+    get_cached_key_revisions, get_key_revisions, mk_key_file_name, mk_key_filename,
+    mk_revision_string, parse_name_with_rev, read_cached_key_bytes, read_key_bytes,
+    read_key_bytes_from_str, stream, write_keypair_files, FsKeyCache, KeyAlgorithm, KeyCache,
+    KeyPair, KeyType,
 };
 use error::{Error, Result};
 
+/// The most receivers a payload from `encrypt_to_many` may have. The count is read from the
+/// payload before anything in it is decrypted, so a payload can't claim more than this.
+const MAX_RECEIVERS: usize = 1024;
+
 #[derive(Debug)]
 pub struct BoxSecret<'a> {
     pub sender: &'a str,
@@ -42,12 +54,19 @@ pub struct BoxSecret<'a> {
 
 pub type BoxKeyPair = KeyPair<BoxPublicKey, BoxSecretKey>;
 
+impl fmt::Debug for BoxKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BoxKeyPair({})", self.name_with_rev())
+    }
+}
+
 impl BoxKeyPair {
     pub fn generate_pair_for_service<S1, S2>(org: S1, service_group: S2) -> Result<Self>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
+        fips::check_key_algorithm(KeyAlgorithm::X25519XSalsa20Poly1305)?;
         let revision = mk_revision_string()?;
         let keyname =
             Self::mk_key_name_for_service(org.as_ref(), service_group.as_ref(), &revision);
@@ -57,11 +76,36 @@ impl BoxKeyPair {
         Ok(Self::new(name, revision, Some(pk), Some(sk)))
     }
 
+    /// Generates a service key with the given algorithm, which must be a box algorithm.
+    pub fn generate_pair_for_service_with<S1, S2>(
+        org: S1,
+        service_group: S2,
+        algorithm: KeyAlgorithm,
+    ) -> Result<Self>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        algorithm.check_key_type(KeyType::Box)?;
+        Self::generate_pair_for_service(org, service_group)
+    }
+
     pub fn generate_pair_for_user(user: &str) -> Result<Self> {
         debug!("new user box key");
         Self::generate_pair_for_string(user)
     }
 
+    /// Generates a user key with the given algorithm, which must be a box algorithm.
+    pub fn generate_pair_for_user_with(user: &str, algorithm: KeyAlgorithm) -> Result<Self> {
+        algorithm.check_key_type(KeyType::Box)?;
+        Self::generate_pair_for_user(user)
+    }
+
+    /// Returns the algorithm of the key.
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::X25519XSalsa20Poly1305
+    }
+
     pub fn generate_pair_for_origin(origin: &str) -> Result<Self> {
         debug!("new origin box key");
         Self::generate_pair_for_string(origin)
@@ -140,6 +184,46 @@ impl BoxKeyPair {
         }
     }
 
+    pub fn get_pairs_from_cache<T, C>(name: T, cache: &C) -> Result<Vec<Self>>
+    where
+        T: AsRef<str>,
+        C: KeyCache + ?Sized,
+    {
+        let revisions = get_cached_key_revisions(name.as_ref(), cache, None, &KeyType::Box)?;
+        revisions
+            .iter()
+            .map(|name_with_rev| Self::get_pair_from_cache(name_with_rev, cache))
+            .collect()
+    }
+
+    pub fn get_pair_from_cache<T, C>(name_with_rev: T, cache: &C) -> Result<Self>
+    where
+        T: AsRef<str>,
+        C: KeyCache + ?Sized,
+    {
+        let name_with_rev = name_with_rev.as_ref();
+        let pk = read_cached_key_bytes(cache, name_with_rev, PUBLIC_KEY_SUFFIX)
+            .and_then(|bytes| Self::public_key_from_bytes(&bytes));
+        let sk = read_cached_key_bytes(cache, name_with_rev, SECRET_BOX_KEY_SUFFIX)
+            .and_then(|bytes| Self::secret_key_from_bytes(&bytes));
+        Self::from_loaded_keys(name_with_rev, pk, sk)
+    }
+
+    pub fn get_latest_pair_from_cache<T, C>(name: T, cache: &C) -> Result<Self>
+    where
+        T: AsRef<str>,
+        C: KeyCache + ?Sized,
+    {
+        let mut all = Self::get_pairs_from_cache(name.as_ref(), cache)?;
+        match all.len() {
+            0 => {
+                let msg = format!("No revisions found for {} box key", name.as_ref());
+                return Err(Error::CryptoError(msg));
+            }
+            _ => Ok(all.remove(0)),
+        }
+    }
+
     pub fn get_public_key_path<P: AsRef<Path> + ?Sized>(
         key_with_rev: &str,
         cache_key_path: &P,
@@ -173,12 +257,217 @@ impl BoxKeyPair {
     /// If no recipient is specified, the encrypted payload is decryptable only
     /// by the encrypting user.
     pub fn encrypt(&self, data: &[u8], receiver: Option<&Self>) -> Result<Vec<u8>> {
+        fips::check_key_algorithm(self.algorithm())?;
         match receiver {
             Some(r) => self.encrypt_box(data, r),
             None => self.encrypt_anonymous_box(data),
         }
     }
 
+    /// Encrypt data once so that any one of `receivers` can decrypt it. The data is sealed with a
+    /// random session key, and the session key is encrypted from this pair to each receiver, so
+    /// the payload grows by one line per receiver rather than by a copy of the data.
+    pub fn encrypt_to_many(&self, data: &[u8], receivers: &[&Self]) -> Result<Vec<u8>> {
+        fips::check_key_algorithm(self.algorithm())?;
+        if receivers.is_empty() {
+            return Err(Error::CryptoError(
+                "Can't encrypt a payload without receivers".to_string(),
+            ));
+        }
+        if receivers.len() > MAX_RECEIVERS {
+            return Err(Error::CryptoError(format!(
+                "Can't encrypt a payload to more than {} receivers",
+                MAX_RECEIVERS
+            )));
+        }
+        let session_key = secretbox::gen_key();
+        let nonce = secretbox::gen_nonce();
+        let ciphertext = secretbox::seal(data, &nonce, &session_key);
+
+        let mut out = format!(
+            "{}\n{}\n{}\n",
+            MULTI_BOX_FORMAT_VERSION,
+            self.name_with_rev(),
+            receivers.len()
+        );
+        for receiver in receivers {
+            let key_nonce = gen_nonce();
+            let wrapped_key = box_::seal(
+                &session_key[..],
+                &key_nonce,
+                receiver.public()?,
+                self.secret()?,
+            );
+            out.push_str(&format!(
+                "{} {} {}\n",
+                receiver.name_with_rev(),
+                base64::encode(&key_nonce[..]),
+                base64::encode(&wrapped_key)
+            ));
+        }
+        out.push_str(&format!(
+            "{}\n{}",
+            base64::encode(&nonce[..]),
+            base64::encode(&ciphertext)
+        ));
+
+        Ok(out.into_bytes())
+    }
+
+    /// Returns the key names, including revisions, of every receiver of a payload produced by
+    /// `encrypt_to_many`.
+    pub fn multi_box_receivers(payload: &[u8]) -> Result<Vec<String>> {
+        let (_, receivers, _, _) = Self::multi_box_metadata(payload)?;
+        Ok(receivers
+            .into_iter()
+            .map(|(name, _, _)| name.to_string())
+            .collect())
+    }
+
+    pub fn is_multi_box(payload: &[u8]) -> bool {
+        payload.starts_with(format!("{}\n", MULTI_BOX_FORMAT_VERSION).as_bytes())
+    }
+
+    /// Encrypt everything read from `input` into `output` as a sequence of framed ciphertexts,
+    /// so that large payloads never need to be held in memory. Key names and the base nonce are
+    /// written to a plaintext header at the start of the stream. If no recipient is specified,
+    /// the stream is decryptable only by the encrypting user.
+    ///
+    /// Returns the number of plaintext bytes which were encrypted.
+    pub fn encrypt_stream<R, W>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        receiver: Option<&Self>,
+    ) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+    {
+        fips::check_key_algorithm(self.algorithm())?;
+        let nonce = gen_nonce();
+        let key = match receiver {
+            Some(r) => {
+                let key = box_::precompute(r.public()?, self.secret()?);
+                write!(
+                    output,
+                    "{}\n{}\n{}\n{}\n\n",
+                    BOX_STREAM_FORMAT_VERSION,
+                    self.name_with_rev(),
+                    r.name_with_rev(),
+                    base64::encode(&nonce[..])
+                )?;
+                key
+            }
+            None => {
+                let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+                let key = box_::precompute(self.public()?, &ephemeral_sk);
+                write!(
+                    output,
+                    "{}\n{}\n{}\n{}\n\n",
+                    ANONYMOUS_BOX_STREAM_FORMAT_VERSION,
+                    self.name_with_rev(),
+                    base64::encode(&ephemeral_pk[..]),
+                    base64::encode(&nonce[..])
+                )?;
+                key
+            }
+        };
+
+        stream::encrypt_frames(input, output, |chunk, counter| {
+            box_::seal_precomputed(chunk, &stream_nonce(&nonce, counter), &key)
+        })
+    }
+
+    /// Decrypt a stream produced by `encrypt_stream`, writing the plaintext into `output` as each
+    /// frame is authenticated. Key names are embedded in the stream header and the keys must be
+    /// present in `cache_key_path` while decrypting.
+    ///
+    /// Returns the number of plaintext bytes which were decrypted.
+    pub fn decrypt_stream<R, W, P>(input: &mut R, output: &mut W, cache_key_path: P) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+        P: AsRef<Path>,
+    {
+        debug!(
+            "Decrypt stream key path = {}",
+            cache_key_path.as_ref().display()
+        );
+        let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+        Self::decrypt_stream_with_lookup(input, output, |name_with_rev| {
+            revoked.check(name_with_rev)?;
+            Self::get_pair_for(name_with_rev, cache_key_path.as_ref())
+        })
+    }
+
+    /// Decrypt a stream produced by `encrypt_stream` using keys held in a `KeyCache`.
+    pub fn decrypt_stream_with_cache<R, W, C>(
+        input: &mut R,
+        output: &mut W,
+        cache: &C,
+    ) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+        C: KeyCache + ?Sized,
+    {
+        let revoked = cache.revocation_list()?;
+        Self::decrypt_stream_with_lookup(input, output, |name_with_rev| {
+            revoked.check(name_with_rev)?;
+            Self::get_pair_from_cache(name_with_rev, cache)
+        })
+    }
+
+    fn decrypt_stream_with_lookup<R, W, F>(
+        input: &mut R,
+        output: &mut W,
+        get_pair: F,
+    ) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+        F: Fn(&str) -> Result<Self>,
+    {
+        fips::check_key_algorithm(KeyAlgorithm::X25519XSalsa20Poly1305)?;
+        let mut input = BufReader::new(input);
+        let version = stream::read_header_line(&mut input)?;
+        let key = if version == BOX_STREAM_FORMAT_VERSION {
+            let sender = stream::read_header_line(&mut input)?;
+            let sender = get_pair(Self::box_key_sender(Some(&sender))?)?;
+            let receiver = stream::read_header_line(&mut input)?;
+            let receiver = get_pair(Self::box_key_receiver(Some(&receiver))?)?;
+            box_::precompute(sender.public()?, receiver.secret()?)
+        } else if version == ANONYMOUS_BOX_STREAM_FORMAT_VERSION {
+            let receiver = stream::read_header_line(&mut input)?;
+            let receiver = get_pair(Self::box_key_receiver(Some(&receiver))?)?;
+            let ephemeral_pk = base64::decode(&stream::read_header_line(&mut input)?)
+                .map_err(|e| Error::CryptoError(format!("Can't decode sender key: {}", e)))?;
+            box_::precompute(
+                &Self::public_key_from_bytes(&ephemeral_pk)?,
+                receiver.secret()?,
+            )
+        } else {
+            return Err(Error::CryptoError(format!(
+                "Unsupported version: {}",
+                version
+            )));
+        };
+        let nonce = Self::box_key_nonce(Some(&stream::read_header_line(&mut input)?))?;
+        if !stream::read_header_line(&mut input)?.is_empty() {
+            return Err(Error::CryptoError(
+                "Corrupt stream, malformed header".to_string(),
+            ));
+        }
+        stream::decrypt_frames(&mut input, output, MACBYTES, |ciphertext, counter| {
+            box_::open_precomputed(ciphertext, &stream_nonce(&nonce, counter), &key).map_err(|_| {
+                Error::CryptoError(format!(
+                    "Secret key, public key, and nonce could not decrypt stream frame {}",
+                    counter
+                ))
+            })
+        })
+    }
     pub fn to_public_string(&self) -> Result<String> {
         match self.public {
             Some(pk) => Ok(format!(
@@ -214,6 +503,7 @@ impl BoxKeyPair {
     }
 
     fn generate_pair_for_string(string: &str) -> Result<Self> {
+        fips::check_key_algorithm(KeyAlgorithm::X25519XSalsa20Poly1305)?;
         let revision = mk_revision_string()?;
         let keyname = Self::mk_key_name_for_string(string, &revision);
         debug!("new sig key name = {}", &keyname);
@@ -319,6 +609,7 @@ impl BoxKeyPair {
         receiver: Option<Self>,
         nonce: Option<Nonce>,
     ) -> Result<Vec<u8>> {
+        fips::check_key_algorithm(self.algorithm())?;
         match receiver {
             Some(recv) => {
                 Self::decrypt_box(ciphertext, &nonce.unwrap(), self.public()?, recv.secret()?)
@@ -360,7 +651,18 @@ impl BoxKeyPair {
         P: AsRef<Path>,
     {
         debug!("Decrypt key path = {}", cache_key_path.as_ref().display());
+        let revoked = FsKeyCache::new(cache_key_path.as_ref()).revocation_list()?;
+        if Self::is_multi_box(payload) {
+            return Self::decrypt_multi_box(payload, |name_with_rev| {
+                revoked.check(name_with_rev)?;
+                Self::get_pair_for(name_with_rev, cache_key_path.as_ref())
+            });
+        }
         let box_secret = Self::secret_metadata(payload)?;
+        revoked.check(box_secret.sender)?;
+        if let Some(recv) = box_secret.receiver {
+            revoked.check(recv)?;
+        }
         let sender = Self::get_pair_for(box_secret.sender, cache_key_path.as_ref())?;
         let receiver = match box_secret.receiver {
             Some(recv) => Some(Self::get_pair_for(recv, cache_key_path.as_ref())?),
@@ -369,6 +671,40 @@ impl BoxKeyPair {
         sender.decrypt(&box_secret.ciphertext, receiver, box_secret.nonce)
     }
 
+    /// Decrypt data from a user that was received at a service, using keys held in a
+    /// `KeyCache`. Key names are embedded in the message payload.
+    pub fn decrypt_with_cache<C: KeyCache + ?Sized>(payload: &[u8], cache: &C) -> Result<Vec<u8>> {
+        let revoked = cache.revocation_list()?;
+        if Self::is_multi_box(payload) {
+            return Self::decrypt_multi_box(payload, |name_with_rev| {
+                revoked.check(name_with_rev)?;
+                Self::get_pair_from_cache(name_with_rev, cache)
+            });
+        }
+        let box_secret = Self::secret_metadata(payload)?;
+        revoked.check(box_secret.sender)?;
+        if let Some(recv) = box_secret.receiver {
+            revoked.check(recv)?;
+        }
+        let sender = Self::get_pair_from_cache(box_secret.sender, cache)?;
+        let receiver = match box_secret.receiver {
+            Some(recv) => Some(Self::get_pair_from_cache(recv, cache)?),
+            None => None,
+        };
+        sender.decrypt(&box_secret.ciphertext, receiver, box_secret.nonce)
+    }
+
+    pub fn to_cache<C: KeyCache + ?Sized>(&self, cache: &C) -> Result<()> {
+        cache.write_key(
+            &mk_key_file_name(&self.name_with_rev(), PUBLIC_KEY_SUFFIX),
+            &self.to_public_string()?,
+        )?;
+        cache.write_key(
+            &mk_key_file_name(&self.name_with_rev(), SECRET_BOX_KEY_SUFFIX),
+            &self.to_secret_string()?,
+        )
+    }
+
     pub fn to_pair_files<P: AsRef<Path> + ?Sized>(&self, path: &P) -> Result<()> {
         let public_keyfile = mk_key_filename(path, self.name_with_rev(), PUBLIC_KEY_SUFFIX);
         let secret_keyfile = mk_key_filename(path, self.name_with_rev(), SECRET_BOX_KEY_SUFFIX);
@@ -383,6 +719,115 @@ impl BoxKeyPair {
         )
     }
 
+    fn multi_box_metadata(
+        payload: &[u8],
+    ) -> Result<(&str, Vec<(&str, Nonce, Vec<u8>)>, secretbox::Nonce, Vec<u8>)> {
+        let mut lines = str::from_utf8(payload)?.lines();
+        match lines.next() {
+            Some(version) if version == MULTI_BOX_FORMAT_VERSION => (),
+            Some(version) => {
+                return Err(Error::CryptoError(format!(
+                    "Unsupported version: {}",
+                    version
+                )))
+            }
+            None => {
+                return Err(Error::CryptoError(
+                    "Corrupt payload, can't read version".to_string(),
+                ))
+            }
+        }
+        let sender = Self::box_key_sender(lines.next())?;
+        let count = lines
+            .next()
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or_else(|| {
+                Error::CryptoError("Corrupt payload, can't read receiver count".to_string())
+            })?;
+        if count > MAX_RECEIVERS {
+            return Err(Error::CryptoError(format!(
+                "Corrupt payload, {} receivers claimed but at most {} are allowed",
+                count, MAX_RECEIVERS
+            )));
+        }
+        let mut receivers = Vec::new();
+        for _ in 0..count {
+            let mut fields = lines.next().unwrap_or("").split_whitespace();
+            let name = Self::box_key_receiver(fields.next())?;
+            let key_nonce = Self::box_key_nonce(fields.next())?;
+            let wrapped_key = Self::box_key_ciphertext(fields.next())?;
+            receivers.push((name, key_nonce, wrapped_key));
+        }
+        let nonce = lines
+            .next()
+            .and_then(|nonce| base64::decode(nonce).ok())
+            .and_then(|nonce| secretbox::Nonce::from_slice(&nonce))
+            .ok_or_else(|| Error::CryptoError("Corrupt payload, can't read nonce".to_string()))?;
+        let ciphertext = Self::box_key_ciphertext(lines.next())?;
+        Ok((sender, receivers, nonce, ciphertext))
+    }
+
+    /// Decrypts a payload produced by `encrypt_to_many` with any of its receivers whose secret
+    /// key `get_pair` can find. Every receiver is tried in turn, so one whose key can't be loaded,
+    /// or whose entry doesn't decrypt, doesn't stop the next from being tried.
+    fn decrypt_multi_box<F>(payload: &[u8], get_pair: F) -> Result<Vec<u8>>
+    where
+        F: Fn(&str) -> Result<Self>,
+    {
+        fips::check_key_algorithm(KeyAlgorithm::X25519XSalsa20Poly1305)?;
+        let (sender, receivers, nonce, ciphertext) = Self::multi_box_metadata(payload)?;
+        let sender = get_pair(sender)?;
+        let mut last_error = None;
+        for (name, key_nonce, wrapped_key) in receivers {
+            let receiver = match get_pair(name) {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    debug!("Skipping receiver {}: {}", name, e);
+                    continue;
+                }
+            };
+            let secret = match receiver.secret() {
+                Ok(secret) => secret,
+                Err(_) => continue,
+            };
+            match Self::open_multi_box(
+                &sender,
+                secret,
+                &key_nonce,
+                &wrapped_key,
+                &nonce,
+                &ciphertext,
+            ) {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    debug!("Receiver {} could not decrypt the payload: {}", name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            Error::CryptoError("No secret key found for any receiver of the payload".to_string())
+        }))
+    }
+
+    /// Unwraps the session key of a payload produced by `encrypt_to_many` with one receiver's
+    /// secret key, and decrypts the payload with it.
+    fn open_multi_box(
+        sender: &Self,
+        secret: &BoxSecretKey,
+        key_nonce: &Nonce,
+        wrapped_key: &[u8],
+        nonce: &secretbox::Nonce,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let key_bytes = Self::decrypt_box(wrapped_key, key_nonce, sender.public()?, secret)?;
+        let key = secretbox::Key::from_slice(&key_bytes)
+            .ok_or_else(|| Error::CryptoError("Invalid size of session key".to_string()))?;
+        secretbox::open(ciphertext, nonce, &key).map_err(|_| {
+            Error::CryptoError("Session key and nonce could not decrypt ciphertext".to_string())
+        })
+    }
+
     fn decrypt_box(
         ciphertext: &[u8],
         nonce: &Nonce,
@@ -469,6 +914,13 @@ impl BoxKeyPair {
     }
 }
 
+/// Derive the nonce for a stream frame from the base nonce and the frame's position.
+fn stream_nonce(base: &Nonce, counter: u64) -> Nonce {
+    let mut bytes = [0u8; NONCEBYTES];
+    bytes.copy_from_slice(&stream::frame_nonce(&base[..], counter));
+    Nonce(bytes)
+}
+
 #[cfg(test)]
 mod test {
     use std::fs;
@@ -476,8 +928,12 @@ mod test {
 
     use tempfile::Builder;
 
+    use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::MACBYTES;
+
     use super::super::super::test_support::*;
+    use super::super::super::{BOX_STREAM_CHUNK_SIZE, MULTI_BOX_FORMAT_VERSION};
     use super::BoxKeyPair;
+    use error::Error;
 
     static VALID_KEY: &'static str = "service-key-valid.default@acme-20160509181736.box.key";
     static VALID_PUB: &'static str = "service-key-valid.default@acme-20160509181736.pub";
@@ -742,6 +1198,97 @@ mod test {
         assert_eq!(message, "Buy more rockets".as_bytes());
     }
 
+    #[test]
+    fn encrypt_and_decrypt_to_many_receivers() {
+        let sender_cache = Builder::new().prefix("sender_cache").tempdir().unwrap();
+        let receiver_cache = Builder::new().prefix("receiver_cache").tempdir().unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        let tnt = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        let anvil = BoxKeyPair::generate_pair_for_service("acme", "anvil.default").unwrap();
+        user.to_pair_files(sender_cache.path()).unwrap();
+        tnt.to_pair_files(sender_cache.path()).unwrap();
+        anvil.to_pair_files(sender_cache.path()).unwrap();
+
+        // The receiving end holds only the public sender key and one of the receivers' keys
+        fs::copy(
+            BoxKeyPair::get_public_key_path(&user.name_with_rev(), sender_cache.path()).unwrap(),
+            receiver_cache
+                .path()
+                .join(format!("{}.pub", user.name_with_rev())),
+        )
+        .unwrap();
+        anvil.to_pair_files(receiver_cache.path()).unwrap();
+
+        let ciphertext = user
+            .encrypt_to_many("Acme rockets".as_bytes(), &[&tnt, &anvil])
+            .unwrap();
+        assert_eq!(
+            BoxKeyPair::multi_box_receivers(&ciphertext).unwrap(),
+            vec![tnt.name_with_rev(), anvil.name_with_rev()]
+        );
+        for cache in &[&sender_cache, &receiver_cache] {
+            let message = BoxKeyPair::decrypt_with_path(&ciphertext, cache.path()).unwrap();
+            assert_eq!(message, "Acme rockets".as_bytes());
+        }
+    }
+
+    #[test]
+    fn decrypt_to_many_tries_every_receiver() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        let tnt = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        let anvil = BoxKeyPair::generate_pair_for_service("acme", "anvil.default").unwrap();
+        user.to_pair_files(cache.path()).unwrap();
+        tnt.to_pair_files(cache.path()).unwrap();
+        anvil.to_pair_files(cache.path()).unwrap();
+
+        let ciphertext = user
+            .encrypt_to_many("Acme rockets".as_bytes(), &[&tnt, &anvil])
+            .unwrap();
+        // Swap the first receiver's wrapped key for the second's, so it can't be unwrapped
+        let text = String::from_utf8(ciphertext).unwrap();
+        let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let anvil_key = lines[4].split_whitespace().nth(2).unwrap().to_string();
+        let tnt_fields: Vec<String> = lines[3].split_whitespace().map(|f| f.to_string()).collect();
+        lines[3] = format!("{} {} {}", tnt_fields[0], tnt_fields[1], anvil_key);
+        let tampered = lines.join("\n");
+
+        let message = BoxKeyPair::decrypt_with_path(tampered.as_bytes(), cache.path()).unwrap();
+        assert_eq!(message, "Acme rockets".as_bytes());
+    }
+
+    #[test]
+    fn decrypt_to_many_refuses_too_many_receivers() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        user.to_pair_files(cache.path()).unwrap();
+        let payload = format!(
+            "{}\n{}\n{}\n",
+            MULTI_BOX_FORMAT_VERSION,
+            user.name_with_rev(),
+            usize::max_value()
+        );
+
+        match BoxKeyPair::decrypt_with_path(payload.as_bytes(), cache.path()) {
+            Err(Error::CryptoError(msg)) => assert!(msg.contains("at most")),
+            other => panic!("Expected a crypto error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "No secret key found for any receiver of the payload")]
+    fn decrypt_to_many_without_receiver_key() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        let tnt = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        user.to_pair_files(cache.path()).unwrap();
+
+        let ciphertext = user
+            .encrypt_to_many("Acme rockets".as_bytes(), &[&tnt])
+            .unwrap();
+        BoxKeyPair::decrypt_with_path(&ciphertext, cache.path()).unwrap();
+    }
+
     #[test]
     fn encrypt_to_self_with_only_public_key() {
         let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
@@ -1028,4 +1575,100 @@ mod test {
 
         BoxKeyPair::decrypt_with_path(botched.as_bytes(), cache.path()).unwrap();
     }
+
+    #[test]
+    fn encrypt_and_decrypt_stream_from_user_to_service() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let service = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        service.to_pair_files(cache.path()).unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        user.to_pair_files(cache.path()).unwrap();
+
+        let data: Vec<u8> = (0..BOX_STREAM_CHUNK_SIZE * 2 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut ciphertext = Vec::new();
+        let written = user
+            .encrypt_stream(&mut data.as_slice(), &mut ciphertext, Some(&service))
+            .unwrap();
+        assert_eq!(written, data.len() as u64);
+
+        let mut message = Vec::new();
+        let read =
+            BoxKeyPair::decrypt_stream(&mut ciphertext.as_slice(), &mut message, cache.path())
+                .unwrap();
+        assert_eq!(read, data.len() as u64);
+        assert_eq!(message, data);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_stream_of_whole_chunks() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let service = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        service.to_pair_files(cache.path()).unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        user.to_pair_files(cache.path()).unwrap();
+
+        let data = vec![7u8; BOX_STREAM_CHUNK_SIZE];
+        let mut ciphertext = Vec::new();
+        user.encrypt_stream(&mut data.as_slice(), &mut ciphertext, Some(&service))
+            .unwrap();
+        let mut message = Vec::new();
+        BoxKeyPair::decrypt_stream(&mut ciphertext.as_slice(), &mut message, cache.path()).unwrap();
+        assert_eq!(message, data);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_stream_to_self() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let sender = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        sender.to_pair_files(cache.path()).unwrap();
+
+        let mut ciphertext = Vec::new();
+        sender
+            .encrypt_stream(&mut "Buy more rockets".as_bytes(), &mut ciphertext, None)
+            .unwrap();
+        let mut message = Vec::new();
+        BoxKeyPair::decrypt_stream(&mut ciphertext.as_slice(), &mut message, cache.path()).unwrap();
+        assert_eq!(message, "Buy more rockets".as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupt stream, missing final frame")]
+    fn decrypt_stream_truncated() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let sender = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        sender.to_pair_files(cache.path()).unwrap();
+
+        let data = vec![7u8; BOX_STREAM_CHUNK_SIZE + 1];
+        let mut ciphertext = Vec::new();
+        sender
+            .encrypt_stream(&mut data.as_slice(), &mut ciphertext, None)
+            .unwrap();
+        // Drop the final frame, which holds a single plaintext byte plus its tag
+        let final_frame_len = 4 + 2 + MACBYTES;
+        let truncated_len = ciphertext.len() - final_frame_len;
+        ciphertext.truncate(truncated_len);
+
+        let mut message = Vec::new();
+        BoxKeyPair::decrypt_stream(&mut ciphertext.as_slice(), &mut message, cache.path()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "could not decrypt stream frame 0")]
+    fn decrypt_stream_tampered_frame() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let sender = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        sender.to_pair_files(cache.path()).unwrap();
+
+        let mut ciphertext = Vec::new();
+        sender
+            .encrypt_stream(&mut "problems ahead".as_bytes(), &mut ciphertext, None)
+            .unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 0xff;
+
+        let mut message = Vec::new();
+        BoxKeyPair::decrypt_stream(&mut ciphertext.as_slice(), &mut message, cache.path()).unwrap();
+    }
 }
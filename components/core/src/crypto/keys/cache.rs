@@ -0,0 +1,445 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of keys, decoupled from the on-disk key cache.
+//!
+//! A `KeyCache` holds key files by their file name (ex: `habitat-20160405144945.pub`) and content,
+//! in exactly the format they have on disk. `FsKeyCache` is backed by a key cache directory and
+//! behaves like the path based functions elsewhere in this module, while `MemoryKeyCache` keeps
+//! keys in memory so they can be loaded from environment variables, mounted secrets, or a remote
+//! store--and so tests don't need to touch the filesystem.
+//!
+//! A key cache also holds the `RevocationList` of key revisions which must no longer be trusted.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use super::super::PUBLIC_KEY_SUFFIX;
+use super::lock::KeyCacheLock;
+use super::revocation::{RevocationList, REVOCATION_LIST_FILE_NAME};
+use super::write_keypair_files;
+use error::{Error, Result};
+
+pub trait KeyCache {
+    /// Returns the file names of every key held in the cache.
+    fn key_file_names(&self) -> Result<Vec<String>>;
+
+    /// Returns the content of the key with the given file name.
+    fn read_key(&self, file_name: &str) -> Result<String>;
+
+    /// Stores a key under the given file name. A key which already exists is never replaced.
+    fn write_key(&self, file_name: &str, content: &str) -> Result<()>;
+
+    /// Replaces the revocation list held in the cache.
+    fn write_revocation_list(&self, list: &RevocationList) -> Result<()>;
+
+    /// Returns the revocation list held in the cache, which is empty if none has been written.
+    fn revocation_list(&self) -> Result<RevocationList> {
+        if self
+            .key_file_names()?
+            .iter()
+            .any(|name| name == REVOCATION_LIST_FILE_NAME)
+        {
+            self.read_key(REVOCATION_LIST_FILE_NAME)?.parse()
+        } else {
+            Ok(RevocationList::new())
+        }
+    }
+
+    /// Returns whether the key with the given name with revision has been revoked.
+    fn is_revoked(&self, key_id: &str) -> Result<bool> {
+        Ok(self.revocation_list()?.is_revoked(key_id))
+    }
+
+    /// Adds the key with the given name with revision to the cache's revocation list.
+    fn revoke(&self, key_id: &str) -> Result<()> {
+        let mut list = self.revocation_list()?;
+        if list.revoke(key_id)? {
+            self.write_revocation_list(&list)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `KeyCache` backed by a key cache directory, ex: `/hab/cache/keys`.
+#[derive(Clone, Debug)]
+pub struct FsKeyCache {
+    path: PathBuf,
+}
+
+impl FsKeyCache {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FsKeyCache { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_revocation_list_locked(&self, list: &RevocationList) -> Result<()> {
+        let path = self.path.join(REVOCATION_LIST_FILE_NAME);
+        let tmp_path = self.path.join(format!("{}.tmp", REVOCATION_LIST_FILE_NAME));
+        // Write the whole list before renaming it into place so readers never see a partial list
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(list.to_string().as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+impl KeyCache for FsKeyCache {
+    fn key_file_names(&self) -> Result<Vec<String>> {
+        let dir_entries = fs::read_dir(&self.path).map_err(|e| {
+            Error::CryptoError(format!(
+                "Error reading key directory {}: {}",
+                self.path.display(),
+                e
+            ))
+        })?;
+        let mut names = Vec::new();
+        for result in dir_entries {
+            let dir_entry =
+                result.map_err(|e| Error::CryptoError(format!("Error reading key path {}", e)))?;
+            // NB: this metadata() call traverses symlinks, which is
+            // exactly what we want.
+            match dir_entry.path().metadata() {
+                Ok(ref md) if md.is_file() => (),
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!("Error checking file metadata {}", e);
+                    continue;
+                }
+            }
+            match dir_entry.file_name().into_string() {
+                Ok(name) => names.push(name),
+                Err(e) => debug!("Invalid filename {:?}", e),
+            }
+        }
+        Ok(names)
+    }
+
+    fn read_key(&self, file_name: &str) -> Result<String> {
+        let path = self.path.join(file_name);
+        let mut file = File::open(&path)
+            .map_err(|e| Error::CryptoError(format!("Can't read key {}: {}", path.display(), e)))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    fn write_key(&self, file_name: &str, content: &str) -> Result<()> {
+        let path = self.path.join(file_name);
+        if file_name.ends_with(&format!(".{}", PUBLIC_KEY_SUFFIX)) {
+            write_keypair_files(Some(&path), Some(content.to_string()), None, None)
+        } else {
+            write_keypair_files(None, None, Some(&path), Some(content.to_string()))
+        }
+    }
+
+    fn write_revocation_list(&self, list: &RevocationList) -> Result<()> {
+        let _lock = KeyCacheLock::acquire(&self.path)?;
+        self.write_revocation_list_locked(list)
+    }
+
+    fn revocation_list(&self) -> Result<RevocationList> {
+        if self.path.join(REVOCATION_LIST_FILE_NAME).is_file() {
+            self.read_key(REVOCATION_LIST_FILE_NAME)?.parse()
+        } else {
+            Ok(RevocationList::new())
+        }
+    }
+
+    fn revoke(&self, key_id: &str) -> Result<()> {
+        // Hold the lock from reading the list to writing it, so no other revocation is lost
+        let _lock = KeyCacheLock::acquire(&self.path)?;
+        let mut list = self.revocation_list()?;
+        if list.revoke(key_id)? {
+            self.write_revocation_list_locked(&list)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `KeyCache` which only ever holds keys in memory.
+#[derive(Default)]
+pub struct MemoryKeyCache {
+    keys: RwLock<HashMap<String, String>>,
+}
+
+impl MemoryKeyCache {
+    pub fn new() -> Self {
+        MemoryKeyCache::default()
+    }
+}
+
+impl KeyCache for MemoryKeyCache {
+    fn key_file_names(&self) -> Result<Vec<String>> {
+        let keys = self.keys.read().expect("Key cache lock is poisoned");
+        let mut names: Vec<String> = keys.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn read_key(&self, file_name: &str) -> Result<String> {
+        let keys = self.keys.read().expect("Key cache lock is poisoned");
+        match keys.get(file_name) {
+            Some(content) => Ok(content.clone()),
+            None => Err(Error::CryptoError(format!(
+                "Can't read key {}: not present in cache",
+                file_name
+            ))),
+        }
+    }
+
+    fn write_key(&self, file_name: &str, content: &str) -> Result<()> {
+        let mut keys = self.keys.write().expect("Key cache lock is poisoned");
+        if keys.contains_key(file_name) {
+            return Err(Error::CryptoError(format!(
+                "Key {} already exists in cache",
+                file_name
+            )));
+        }
+        keys.insert(file_name.to_string(), content.to_string());
+        Ok(())
+    }
+
+    fn write_revocation_list(&self, list: &RevocationList) -> Result<()> {
+        let mut keys = self.keys.write().expect("Key cache lock is poisoned");
+        keys.insert(REVOCATION_LIST_FILE_NAME.to_string(), list.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use tempfile::Builder;
+
+    use super::super::super::artifact;
+    use super::super::super::test_support::*;
+    use super::super::box_key_pair::BoxKeyPair;
+    use super::super::sig_key_pair::SigKeyPair;
+    use super::super::sym_key::SymKey;
+    use super::*;
+
+    #[test]
+    fn memory_cache_write_and_read() {
+        let cache = MemoryKeyCache::new();
+        cache
+            .write_key("unicorn-20160517220007.pub", "content")
+            .unwrap();
+
+        assert_eq!(
+            cache.key_file_names().unwrap(),
+            vec!["unicorn-20160517220007.pub".to_string()]
+        );
+        assert_eq!(
+            cache.read_key("unicorn-20160517220007.pub").unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Key unicorn-20160517220007.pub already exists in cache")]
+    fn memory_cache_never_replaces_keys() {
+        let cache = MemoryKeyCache::new();
+        cache
+            .write_key("unicorn-20160517220007.pub", "content")
+            .unwrap();
+        cache
+            .write_key("unicorn-20160517220007.pub", "other")
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "not present in cache")]
+    fn memory_cache_read_missing_key() {
+        MemoryKeyCache::new()
+            .read_key("unicorn-20160517220007.pub")
+            .unwrap();
+    }
+
+    #[test]
+    fn fs_cache_reads_keys_written_to_disk() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(dir.path()).unwrap();
+
+        let cache = FsKeyCache::new(dir.path());
+        let loaded = SigKeyPair::get_latest_pair_from_cache("unicorn", &cache, None).unwrap();
+        assert_eq!(loaded.name_with_rev(), pair.name_with_rev());
+        assert!(loaded.public().is_ok());
+        assert!(loaded.secret().is_ok());
+    }
+
+    #[test]
+    fn sig_pair_round_trip_through_memory_cache() {
+        let cache = MemoryKeyCache::new();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_cache(&cache).unwrap();
+
+        let loaded = SigKeyPair::get_pair_from_cache(&pair.name_with_rev(), &cache).unwrap();
+        assert_eq!(loaded.public().unwrap(), pair.public().unwrap());
+        assert_eq!(loaded.secret().unwrap(), pair.secret().unwrap());
+    }
+
+    #[test]
+    fn sym_key_round_trip_through_memory_cache() {
+        let cache = MemoryKeyCache::new();
+        let pair = SymKey::generate_pair_for_ring("beyonce").unwrap();
+        pair.to_cache(&cache).unwrap();
+
+        let loaded = SymKey::get_latest_pair_from_cache("beyonce", &cache).unwrap();
+        let (nonce, ciphertext) = pair.encrypt("Ringonit".as_bytes()).unwrap();
+        let message = loaded.decrypt(&nonce, &ciphertext).unwrap();
+        assert_eq!(message, "Ringonit".as_bytes());
+    }
+
+    #[test]
+    fn verify_artifact_with_memory_cache() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let cache = MemoryKeyCache::new();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_cache(&cache).unwrap();
+        let dst = dir.path().join("signed.dat");
+        artifact::sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        let (name_with_rev, _) = artifact::verify_with_cache(&dst, &cache).unwrap();
+        assert_eq!(name_with_rev, pair.name_with_rev());
+    }
+
+    #[test]
+    #[should_panic(expected = "No public or secret keys found for name_with_rev")]
+    fn verify_artifact_with_memory_cache_missing_key() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        let dst = dir.path().join("signed.dat");
+        artifact::sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        artifact::verify_with_cache(&dst, &MemoryKeyCache::new()).unwrap();
+    }
+
+    #[test]
+    fn decrypt_with_memory_cache() {
+        let cache = MemoryKeyCache::new();
+        let service = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        service.to_cache(&cache).unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        user.to_cache(&cache).unwrap();
+
+        let ciphertext = user
+            .encrypt("I wish to buy more rockets".as_bytes(), Some(&service))
+            .unwrap();
+        let message = BoxKeyPair::decrypt_with_cache(&ciphertext, &cache).unwrap();
+        assert_eq!(message, "I wish to buy more rockets".as_bytes());
+    }
+
+    #[test]
+    fn cached_revisions_ignore_other_key_types() {
+        let cache = MemoryKeyCache::new();
+        SymKey::generate_pair_for_ring("unicorn")
+            .unwrap()
+            .to_cache(&cache)
+            .unwrap();
+
+        assert!(SigKeyPair::get_pairs_from_cache("unicorn", &cache, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn revoke_key_in_memory_cache() {
+        let cache = MemoryKeyCache::new();
+        assert!(!cache.is_revoked("unicorn-20160517220007").unwrap());
+
+        cache.revoke("unicorn-20160517220007").unwrap();
+        cache.revoke("unicorn-20160517220007").unwrap();
+        assert!(cache.is_revoked("unicorn-20160517220007").unwrap());
+        assert_eq!(cache.revocation_list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn revoke_key_in_fs_cache() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let cache = FsKeyCache::new(dir.path());
+        cache.revoke("unicorn-20160517220007").unwrap();
+        cache.revoke("unicorn-20170101000000").unwrap();
+
+        let reloaded = FsKeyCache::new(dir.path());
+        assert!(reloaded.is_revoked("unicorn-20160517220007").unwrap());
+        assert!(reloaded.is_revoked("unicorn-20170101000000").unwrap());
+        assert!(!reloaded.is_revoked("unicorn-20180101000000").unwrap());
+    }
+
+    #[test]
+    fn concurrent_revocations_in_fs_cache() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let cache = FsKeyCache::new(dir.path());
+                thread::spawn(move || {
+                    cache
+                        .revoke(&format!("unicorn-2016051722000{}", i))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            FsKeyCache::new(dir.path()).revocation_list().unwrap().len(),
+            8
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "has been revoked")]
+    fn verify_artifact_signed_by_revoked_key() {
+        let dir = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(dir.path()).unwrap();
+        let dst = dir.path().join("signed.dat");
+        artifact::sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        FsKeyCache::new(dir.path())
+            .revoke(&pair.name_with_rev())
+            .unwrap();
+        artifact::verify(&dst, dir.path()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "has been revoked")]
+    fn decrypt_with_revoked_key() {
+        let cache = MemoryKeyCache::new();
+        let service = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        service.to_cache(&cache).unwrap();
+        let user = BoxKeyPair::generate_pair_for_user("wecoyote").unwrap();
+        user.to_cache(&cache).unwrap();
+        let ciphertext = user
+            .encrypt("I wish to buy more rockets".as_bytes(), Some(&service))
+            .unwrap();
+
+        cache.revoke(&user.name_with_rev()).unwrap();
+        BoxKeyPair::decrypt_with_cache(&ciphertext, &cache).unwrap();
+    }
+}
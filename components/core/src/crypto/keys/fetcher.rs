@@ -0,0 +1,324 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fetching of origin public keys which are missing from a key cache.
+//!
+//! A `KeyFetcher` downloads public origin keys from a Builder and writes them into a key cache,
+//! so that an artifact can be verified without its origin key having been installed beforehand.
+//! This crate doesn't carry an HTTP client, so requests are made through a `KeyTransport`, which
+//! callers implement with the client they already use to talk to Builder.
+//!
+//! Key revisions never change, so a revision which is already cached is never requested again.
+//! The latest revision of an origin's key can change at any time; the ETag of its last response
+//! is kept in the key cache, in a file named `<origin>.latest.etag`, and sent with the next
+//! request so that an unchanged key isn't downloaded twice.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::super::artifact;
+use super::super::PUBLIC_KEY_SUFFIX;
+use super::sig_key_pair::SigKeyPair;
+use super::{mk_key_filename, parse_key_str, parse_name_with_rev, PairType};
+use error::{Error, Result};
+
+/// The suffix on the end of a file holding the ETag of the latest revision of an origin's key
+pub static LATEST_ETAG_SUFFIX: &'static str = "latest.etag";
+
+/// The outcome of a request made through a `KeyTransport`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FetchResponse {
+    /// The content is unchanged since the response with the ETag sent in the request
+    NotModified,
+    /// The body of the response, with its ETag if the server sent one
+    Found { body: String, etag: Option<String> },
+}
+
+pub trait KeyTransport {
+    /// Performs a `GET` of `url`, sending `etag` in an `If-None-Match` header when one is given.
+    /// A response for a key which doesn't exist is returned as an error.
+    fn get(&self, url: &str, etag: Option<&str>) -> Result<FetchResponse>;
+}
+
+pub struct KeyFetcher<T> {
+    bldr_url: String,
+    cache_key_path: PathBuf,
+    transport: T,
+}
+
+impl<T: KeyTransport> KeyFetcher<T> {
+    /// Returns a fetcher which downloads keys from the Builder at `bldr_url` into
+    /// `cache_key_path`.
+    pub fn new<P>(bldr_url: &str, cache_key_path: P, transport: T) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        KeyFetcher {
+            bldr_url: bldr_url.trim_right_matches('/').to_string(),
+            cache_key_path: cache_key_path.into(),
+            transport: transport,
+        }
+    }
+
+    pub fn cache_key_path(&self) -> &Path {
+        &self.cache_key_path
+    }
+
+    /// Returns the origin key with the given name and revision, downloading its public key into
+    /// the key cache first if it isn't there.
+    pub fn public_key_for(&self, name_with_rev: &str) -> Result<SigKeyPair> {
+        let (origin, rev) = parse_name_with_rev(name_with_rev)?;
+        if !mk_key_filename(&self.cache_key_path, name_with_rev, PUBLIC_KEY_SUFFIX).is_file() {
+            let url = format!("{}/v1/depot/origins/{}/keys/{}", self.bldr_url, origin, rev);
+            match self.transport.get(&url, None)? {
+                FetchResponse::Found { body, .. } => {
+                    if self.check_public_key(&body)? != name_with_rev {
+                        return Err(Error::CryptoError(format!(
+                            "Fetched key is not the key {}",
+                            name_with_rev
+                        )));
+                    }
+                    self.write_public_key(&body)?;
+                }
+                FetchResponse::NotModified => {
+                    return Err(Error::CryptoError(format!(
+                        "Unexpected response fetching key {} from {}",
+                        name_with_rev, url
+                    )))
+                }
+            }
+        }
+        SigKeyPair::get_pair_for(name_with_rev, &self.cache_key_path)
+    }
+
+    /// Returns the latest revision of an origin's key, as known to Builder. The key is only
+    /// downloaded when it has changed since it was last fetched.
+    pub fn latest_public_key_for(&self, origin: &str) -> Result<SigKeyPair> {
+        let url = format!("{}/v1/depot/origins/{}/keys/latest", self.bldr_url, origin);
+        let etag_path = mk_key_filename(&self.cache_key_path, origin, LATEST_ETAG_SUFFIX);
+        let cached = self.read_etag(&etag_path)?;
+        let response = {
+            let etag = cached.as_ref().map(|&(ref etag, _)| etag.as_str());
+            self.transport.get(&url, etag)?
+        };
+
+        match response {
+            FetchResponse::NotModified => match cached {
+                Some((_, name_with_rev)) => {
+                    SigKeyPair::get_pair_for(&name_with_rev, &self.cache_key_path)
+                }
+                None => Err(Error::CryptoError(format!(
+                    "Unexpected response fetching the latest key for {} from {}",
+                    origin, url
+                ))),
+            },
+            FetchResponse::Found { body, etag } => {
+                let name_with_rev = self.check_public_key(&body)?;
+                if parse_name_with_rev(&name_with_rev)?.0 != origin {
+                    return Err(Error::CryptoError(format!(
+                        "Fetched key {} is not a key for origin {}",
+                        name_with_rev, origin
+                    )));
+                }
+                let pair = self.write_public_key(&body)?;
+                match etag {
+                    Some(etag) => self.write_etag(&etag_path, &etag, &pair.name_with_rev())?,
+                    None => {
+                        if etag_path.is_file() {
+                            fs::remove_file(&etag_path)?;
+                        }
+                    }
+                }
+                SigKeyPair::get_pair_for(&pair.name_with_rev(), &self.cache_key_path)
+            }
+        }
+    }
+
+    /// Verifies a .hart file as `artifact::verify` does, fetching the public key of the origin
+    /// key which signed it first if it isn't in the key cache.
+    pub fn verify_artifact<P: ?Sized>(&self, src: &P) -> Result<(String, String)>
+    where
+        P: AsRef<Path>,
+    {
+        let signer = artifact::artifact_signer(&src.as_ref())?;
+        self.public_key_for(&signer)?;
+        artifact::verify(src, &self.cache_key_path)
+    }
+
+    /// Returns the name with revision of a fetched key, refusing anything but a public key.
+    fn check_public_key(&self, content: &str) -> Result<String> {
+        let (pair_type, name_with_rev, _) = parse_key_str(content)?;
+        if pair_type != PairType::Public {
+            return Err(Error::CryptoError(format!(
+                "Fetched key {} is not a public key",
+                name_with_rev
+            )));
+        }
+        Ok(name_with_rev)
+    }
+
+    fn write_public_key(&self, content: &str) -> Result<SigKeyPair> {
+        let (pair, _) = SigKeyPair::write_file_from_str(content, &self.cache_key_path)?;
+        debug!(
+            "Fetched public key {} into {}",
+            pair.name_with_rev(),
+            self.cache_key_path.display()
+        );
+        Ok(pair)
+    }
+
+    fn read_etag(&self, path: &Path) -> Result<Option<(String, String)>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        let mut lines = content.lines();
+        match (lines.next(), lines.next()) {
+            (Some(etag), Some(name_with_rev))
+                if mk_key_filename(&self.cache_key_path, name_with_rev, PUBLIC_KEY_SUFFIX)
+                    .is_file() =>
+            {
+                Ok(Some((etag.to_string(), name_with_rev.to_string())))
+            }
+            // Without the key it refers to, an ETag is of no use
+            _ => Ok(None),
+        }
+    }
+
+    fn write_etag(&self, path: &Path, etag: &str, name_with_rev: &str) -> Result<()> {
+        let tmp_path = path.with_extension("etag.tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            write!(file, "{}\n{}\n", etag, name_with_rev)?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use tempfile::Builder;
+
+    use super::super::super::test_support::*;
+    use super::*;
+
+    /// Serves keys from memory, recording every request made
+    struct MemoryTransport {
+        keys: HashMap<String, (String, String)>,
+        requests: RefCell<Vec<(String, Option<String>)>>,
+    }
+
+    impl MemoryTransport {
+        fn new() -> Self {
+            MemoryTransport {
+                keys: HashMap::new(),
+                requests: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl KeyTransport for MemoryTransport {
+        fn get(&self, url: &str, etag: Option<&str>) -> Result<FetchResponse> {
+            self.requests
+                .borrow_mut()
+                .push((url.to_string(), etag.map(|e| e.to_string())));
+            match self.keys.get(url) {
+                Some(&(_, ref current)) if Some(current.as_str()) == etag => {
+                    Ok(FetchResponse::NotModified)
+                }
+                Some(&(ref body, ref current)) => Ok(FetchResponse::Found {
+                    body: body.clone(),
+                    etag: Some(current.clone()),
+                }),
+                None => Err(Error::CryptoError(format!("Not found: {}", url))),
+            }
+        }
+    }
+
+    #[test]
+    fn fetch_missing_key_and_verify() {
+        let signing_cache = Builder::new().prefix("signing_cache").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        pair.to_pair_files(signing_cache.path()).unwrap();
+        let dst = signing_cache.path().join("signed.dat");
+        artifact::sign(&fixture("signme.dat"), &dst, &pair).unwrap();
+
+        let mut transport = MemoryTransport::new();
+        transport.keys.insert(
+            format!(
+                "https://bldr.example.com/v1/depot/origins/unicorn/keys/{}",
+                pair.rev
+            ),
+            (pair.to_public_string().unwrap(), "abc".to_string()),
+        );
+        let fetcher = KeyFetcher::new("https://bldr.example.com/", cache.path(), transport);
+
+        let (signer, _) = fetcher.verify_artifact(&dst).unwrap();
+        assert_eq!(signer, pair.name_with_rev());
+        // The key is cached now, so verifying again makes no request
+        fetcher.verify_artifact(&dst).unwrap();
+        assert_eq!(fetcher.transport.requests.borrow().len(), 1);
+        assert!(
+            SigKeyPair::get_pair_for(&pair.name_with_rev(), cache.path())
+                .unwrap()
+                .secret
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn fetch_latest_key_with_etag() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        let url = "https://bldr.example.com/v1/depot/origins/unicorn/keys/latest";
+        let mut transport = MemoryTransport::new();
+        transport.keys.insert(
+            url.to_string(),
+            (pair.to_public_string().unwrap(), "abc".to_string()),
+        );
+        let fetcher = KeyFetcher::new("https://bldr.example.com", cache.path(), transport);
+
+        let fetched = fetcher.latest_public_key_for("unicorn").unwrap();
+        assert_eq!(fetched.name_with_rev(), pair.name_with_rev());
+        let fetched = fetcher.latest_public_key_for("unicorn").unwrap();
+        assert_eq!(fetched.name_with_rev(), pair.name_with_rev());
+        assert_eq!(
+            *fetcher.transport.requests.borrow(),
+            vec![
+                (url.to_string(), None),
+                (url.to_string(), Some("abc".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not found")]
+    fn fetch_unknown_key() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fetcher = KeyFetcher::new(
+            "https://bldr.example.com",
+            cache.path(),
+            MemoryTransport::new(),
+        );
+
+        fetcher.public_key_for("unicorn-20160517220007").unwrap();
+    }
+}
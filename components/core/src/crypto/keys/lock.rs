@@ -0,0 +1,119 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Advisory locking of a key cache directory.
+//!
+//! Processes which write into the same key cache take a `KeyCacheLock` first, so that a pair of
+//! key files, or a read-modify-write of the revocation list, is never interleaved with another
+//! process' writes. The lock is an `fs::FileLock` on a file named `.keys.lock` in the key cache,
+//! which is left in place when the lock is dropped. It only excludes other writers which take
+//! the lock; readers never need it, since key files are moved into place whole.
+//!
+//! The operating system releases the lock when the process holding it dies, so a lock can't be
+//! left behind and there is nothing stale to take over.
+
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::{Error, Result};
+use fs::FileLock;
+
+/// The name of the lock file in a key cache directory
+pub static KEY_CACHE_LOCK_FILE_NAME: &'static str = ".keys.lock";
+/// How long to wait for another process to release the lock before giving up
+pub const KEY_CACHE_LOCK_TIMEOUT_SECS: u64 = 30;
+
+const RETRY_INTERVAL_MS: u64 = 50;
+
+/// An exclusive hold on a key cache directory, released when dropped.
+#[derive(Debug)]
+pub struct KeyCacheLock {
+    lock: FileLock,
+}
+
+impl KeyCacheLock {
+    /// Takes the lock on the key cache at `cache_key_path`, waiting for up to
+    /// `KEY_CACHE_LOCK_TIMEOUT_SECS` if another process holds it.
+    pub fn acquire<P: AsRef<Path>>(cache_key_path: P) -> Result<Self> {
+        Self::acquire_with_timeout(
+            cache_key_path,
+            Duration::from_secs(KEY_CACHE_LOCK_TIMEOUT_SECS),
+        )
+    }
+
+    /// Takes the lock on the key cache at `cache_key_path`, waiting for up to `timeout` if
+    /// another process holds it.
+    pub fn acquire_with_timeout<P: AsRef<Path>>(
+        cache_key_path: P,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let path = cache_key_path.as_ref().join(KEY_CACHE_LOCK_FILE_NAME);
+        let started = Instant::now();
+        loop {
+            if let Some(lock) = FileLock::try_exclusive(&path)? {
+                debug!("Acquired key cache lock {}", path.display());
+                return Ok(KeyCacheLock { lock: lock });
+            }
+            if started.elapsed() >= timeout {
+                return Err(Error::CryptoError(format!(
+                    "Timed out waiting for the key cache lock {}",
+                    path.display()
+                )));
+            }
+            thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.lock.path()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::time::Duration;
+
+    use tempfile::Builder;
+
+    use super::*;
+
+    #[test]
+    fn lock_is_released_on_drop() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        {
+            let lock = KeyCacheLock::acquire(cache.path()).unwrap();
+            assert!(lock.path().is_file());
+        }
+        KeyCacheLock::acquire_with_timeout(cache.path(), Duration::from_millis(0)).unwrap();
+    }
+
+    #[test]
+    fn leftover_lock_file_is_not_held() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        File::create(cache.path().join(KEY_CACHE_LOCK_FILE_NAME)).unwrap();
+
+        KeyCacheLock::acquire_with_timeout(cache.path(), Duration::from_millis(0)).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Timed out waiting for the key cache lock")]
+    fn lock_is_exclusive() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let _lock = KeyCacheLock::acquire(cache.path()).unwrap();
+
+        KeyCacheLock::acquire_with_timeout(cache.path(), Duration::from_millis(200)).unwrap();
+    }
+}
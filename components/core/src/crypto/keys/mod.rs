@@ -20,10 +20,12 @@ use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 use std::result;
-use std::str::FromStr;
+use std::str::{self, FromStr};
 
 use base64;
+use hex;
 use regex::Regex;
+use sodiumoxide::randombytes::randombytes;
 use time;
 
 use error::{Error, Result};
@@ -40,13 +42,37 @@ lazy_static! {
         Regex::new(r"\A(?P<name>.+)-(?P<rev>\d{14})\.(?P<suffix>[a-z]+(\.[a-z]+)?)\z").unwrap();
 }
 
+pub mod algorithm;
 pub mod box_key_pair;
+pub mod cache;
+pub mod fetcher;
+pub mod interop;
+pub mod lock;
+pub mod revocation;
+pub mod rotation;
+pub mod secret;
 pub mod sig_key_pair;
+mod stream;
 pub mod sym_key;
 
-enum KeyType {
+pub use self::algorithm::KeyAlgorithm;
+pub use self::cache::{FsKeyCache, KeyCache, MemoryKeyCache};
+pub use self::fetcher::{FetchResponse, KeyFetcher, KeyTransport};
+pub use self::lock::KeyCacheLock;
+pub use self::revocation::RevocationList;
+pub use self::rotation::{
+    rotate_origin_key, rotate_ring_key, rotate_service_key, rotate_user_key, RotationReport,
+};
+pub use self::secret::SecretBytes;
+
+/// The kinds of keys, by what they are used for.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum KeyType {
+    /// Signing keys, used for origins
     Sig,
+    /// Box keys, used for users and services
     Box,
+    /// Symmetric keys, used for rings
     Sym,
 }
 
@@ -138,6 +164,39 @@ impl<P, S> KeyPair<P, S> {
         format!("{}-{}", self.name, self.rev)
     }
 
+    /// Builds a pair from the outcome of loading each of its halves. A missing half is not an
+    /// error unless the other one is missing too.
+    fn from_loaded_keys(name_with_rev: &str, public: Result<P>, secret: Result<S>) -> Result<Self> {
+        let (name, rev) = parse_name_with_rev(name_with_rev)?;
+        let pk = match public {
+            Ok(k) => Some(k),
+            Err(e) => {
+                debug!(
+                    "Can't find public key for name_with_rev {}: {}",
+                    name_with_rev, e
+                );
+                None
+            }
+        };
+        let sk = match secret {
+            Ok(k) => Some(k),
+            Err(e) => {
+                debug!(
+                    "Can't find secret key for name_with_rev {}: {}",
+                    name_with_rev, e
+                );
+                None
+            }
+        };
+        if pk.is_none() && sk.is_none() {
+            return Err(Error::CryptoError(format!(
+                "No public or secret keys found for name_with_rev {}",
+                name_with_rev
+            )));
+        }
+        Ok(Self::new(name, rev, pk, sk))
+    }
+
     pub fn public(&self) -> Result<&P> {
         match self.public.as_ref() {
             Some(s) => Ok(s),
@@ -333,6 +392,51 @@ where
     Ok(candidate_vec)
 }
 
+/// Take a key name (ex "habitat"), and find all revisions of that
+/// keyname held in a `KeyCache`.
+fn get_cached_key_revisions<C>(
+    keyname: &str,
+    cache: &C,
+    pair_type: Option<&PairType>,
+    key_type: &KeyType,
+) -> Result<Vec<String>>
+where
+    C: KeyCache + ?Sized,
+{
+    let mut candidates = HashSet::new();
+    for filename in cache.key_file_names()? {
+        let content = cache.read_key(&filename)?;
+        if !content.starts_with(&key_type.to_string().to_uppercase()) {
+            debug!("Invalid key content in {} for type {}", filename, &key_type);
+            continue;
+        }
+        debug!("checking cached key: {}", &filename);
+        check_filename(keyname, filename, &mut candidates, pair_type);
+    }
+
+    let mut candidate_vec: Vec<String> = candidates.into_iter().collect();
+    candidate_vec.sort();
+    // newest key first
+    candidate_vec.reverse();
+    Ok(candidate_vec)
+}
+
+fn read_cached_key_bytes<C>(cache: &C, keyname: &str, suffix: &str) -> Result<SecretBytes>
+where
+    C: KeyCache + ?Sized,
+{
+    let content = SecretBytes::from(
+        cache
+            .read_key(&mk_key_file_name(keyname, suffix))?
+            .into_bytes(),
+    );
+    read_key_bytes_from_str(str::from_utf8(&content)?)
+}
+
+fn mk_key_file_name(keyname: &str, suffix: &str) -> String {
+    format!("{}.{}", keyname, suffix)
+}
+
 fn mk_key_filename<P, S1, S2>(path: P, keyname: S1, suffix: S2) -> PathBuf
 where
     P: AsRef<Path>,
@@ -489,83 +593,99 @@ pub fn parse_key_str(content: &str) -> Result<(PairType, String, String)> {
     }
 }
 
-fn read_key_bytes(keyfile: &Path) -> Result<Vec<u8>> {
+fn read_key_bytes(keyfile: &Path) -> Result<SecretBytes> {
     let mut f = File::open(keyfile)?;
-    let mut s = String::new();
-    if f.read_to_string(&mut s)? <= 0 {
+    // Size the buffer up front so that reading never reallocates, leaving copies of the key behind
+    let mut content = SecretBytes::new(Vec::with_capacity(f.metadata()?.len() as usize + 1));
+    if f.read_to_end(&mut content)? <= 0 {
         return Err(Error::CryptoError("Can't read key bytes".to_string()));
     }
-    read_key_bytes_from_str(&s)
+    read_key_bytes_from_str(str::from_utf8(&content)?)
 }
 
-fn read_key_bytes_from_str(key: &str) -> Result<Vec<u8>> {
+fn read_key_bytes_from_str(key: &str) -> Result<SecretBytes> {
+    if let Some(version) = key.lines().next() {
+        algorithm::check_key_version(version.trim())?;
+    }
     match key.lines().nth(3) {
         Some(encoded) => {
             let v = base64::decode(encoded)
                 .map_err(|e| Error::CryptoError(format!("Can't read raw key {}", e)))?;
-            Ok(v)
+            Ok(SecretBytes::from(v))
         }
         None => Err(Error::CryptoError(format!("Malformed key contents"))),
     }
 }
 
+/// Writes the given key files, failing if any of them already exists. The files are written while
+/// holding the lock on the key cache they are written into, and each is written to a temporary
+/// file first and moved into place once complete, so that neither another process nor a reader
+/// ever sees a partial key or an incomplete pair.
 fn write_keypair_files(
     public_keyfile: Option<&Path>,
     public_content: Option<String>,
     secret_keyfile: Option<&Path>,
     secret_content: Option<String>,
 ) -> Result<()> {
+    let mut keyfiles = Vec::new();
     if let Some(public_keyfile) = public_keyfile {
         let public_content = match public_content {
             Some(c) => c,
             None => panic!("Invalid calling of this function"),
         };
-
-        if let Some(pk_dir) = public_keyfile.parent() {
-            fs::create_dir_all(pk_dir)?;
-        } else {
-            return Err(Error::BadKeyPath(
-                public_keyfile.to_string_lossy().into_owned(),
-            ));
-        }
-        if public_keyfile.exists() {
-            return Err(Error::CryptoError(format!(
-                "Public keyfile or a directory already \
-                 exists {}",
-                public_keyfile.display()
-            )));
-        }
-        let public_file = File::create(public_keyfile)?;
-        let mut public_writer = BufWriter::new(&public_file);
-        public_writer.write_all(public_content.as_bytes())?;
-        set_permissions(public_keyfile)?;
+        keyfiles.push(("Public", public_keyfile, public_content));
     }
-
     if let Some(secret_keyfile) = secret_keyfile {
         let secret_content = match secret_content {
             Some(c) => c,
             None => panic!("Invalid calling of this function"),
         };
+        keyfiles.push(("Secret", secret_keyfile, secret_content));
+    }
 
-        if let Some(sk_dir) = secret_keyfile.parent() {
-            fs::create_dir_all(sk_dir)?;
+    let mut lock = None;
+    for (kind, keyfile, content) in keyfiles {
+        if let Some(dir) = keyfile.parent() {
+            fs::create_dir_all(dir)?;
+            if lock.is_none() {
+                lock = Some(KeyCacheLock::acquire(dir)?);
+            }
         } else {
-            return Err(Error::BadKeyPath(
-                secret_keyfile.to_string_lossy().into_owned(),
-            ));
+            return Err(Error::BadKeyPath(keyfile.to_string_lossy().into_owned()));
         }
-        if secret_keyfile.exists() {
+        if keyfile.exists() {
             return Err(Error::CryptoError(format!(
-                "Secret keyfile or a directory already \
+                "{} keyfile or a directory already \
                  exists {}",
-                secret_keyfile.display()
+                kind,
+                keyfile.display()
             )));
         }
-        let secret_file = File::create(secret_keyfile)?;
-        let mut secret_writer = BufWriter::new(&secret_file);
-        secret_writer.write_all(secret_content.as_bytes())?;
-        set_permissions(secret_keyfile)?;
+        write_keyfile(keyfile, &content)?;
+    }
+    Ok(())
+}
+
+fn write_keyfile(keyfile: &Path, content: &str) -> Result<()> {
+    let tmpfile = TmpKeyfile {
+        path: keyfile.with_file_name(format!(
+            "{}.{}.tmp",
+            keyfile
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            hex::encode(randombytes(6).as_slice())
+        )),
+    };
+    {
+        let file = File::create(&tmpfile.path)?;
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(content.as_bytes())?;
+        writer.flush()?;
+        file.sync_all()?;
     }
+    set_permissions(&tmpfile.path)?;
+    fs::rename(&tmpfile.path, keyfile)?;
     Ok(())
 }
 
@@ -0,0 +1,211 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Revocation of compromised key revisions.
+//!
+//! A revocation list is kept in a key cache alongside the keys themselves, in a file named
+//! `REVOKED`. The first line holds the format version and every following line holds the name
+//! with revision of a revoked key, optionally followed by the time it was revoked in seconds since
+//! the Unix epoch. Blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! REVOKED-1
+//! # leaked from a build worker
+//! habitat-20160405144945 1526601600
+//! tnt.default@acme-20160405150012
+//! ```
+//!
+//! Revoked keys are refused when verifying artifacts and when decrypting payloads, even though
+//! their files may still be present in the key cache. The time a key was revoked is kept for the
+//! record only: a signature's timestamp is written by whoever holds the key, so it can't show
+//! that a signature was made before the key was compromised.
+
+use std::collections::btree_map::{self, BTreeMap};
+use std::fmt;
+use std::str::FromStr;
+
+use super::parse_name_with_rev;
+use error::{Error, Result};
+
+/// The file name of the revocation list in a key cache
+pub static REVOCATION_LIST_FILE_NAME: &'static str = "REVOKED";
+pub static REVOCATION_LIST_FORMAT_VERSION: &'static str = "REVOKED-1";
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RevocationList {
+    revoked: BTreeMap<String, Option<i64>>,
+}
+
+impl RevocationList {
+    pub fn new() -> Self {
+        RevocationList::default()
+    }
+
+    /// Adds a key revision to the list, returning `false` if it was already revoked.
+    pub fn revoke(&mut self, name_with_rev: &str) -> Result<bool> {
+        parse_name_with_rev(name_with_rev)?;
+        if self.revoked.contains_key(name_with_rev) {
+            return Ok(false);
+        }
+        self.revoked.insert(name_with_rev.to_string(), None);
+        Ok(true)
+    }
+
+    /// Adds a key revision to the list as revoked at the given time, in seconds since the Unix
+    /// epoch, returning `false` if it was already revoked at that time.
+    pub fn revoke_at(&mut self, name_with_rev: &str, revoked_at: i64) -> Result<bool> {
+        parse_name_with_rev(name_with_rev)?;
+        let previous = self
+            .revoked
+            .insert(name_with_rev.to_string(), Some(revoked_at));
+        Ok(previous != Some(Some(revoked_at)))
+    }
+
+    pub fn is_revoked(&self, name_with_rev: &str) -> bool {
+        self.revoked.contains_key(name_with_rev)
+    }
+
+    /// Returns the time a key revision was revoked, if it is revoked and the time is known.
+    pub fn revoked_at(&self, name_with_rev: &str) -> Option<i64> {
+        self.revoked.get(name_with_rev).and_then(|t| *t)
+    }
+
+    /// Returns an error if the given key revision has been revoked.
+    pub fn check(&self, name_with_rev: &str) -> Result<()> {
+        if self.is_revoked(name_with_rev) {
+            return Err(Error::CryptoError(format!(
+                "Key {} has been revoked",
+                name_with_rev
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> btree_map::Keys<String, Option<i64>> {
+        self.revoked.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.revoked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revoked.is_empty()
+    }
+}
+
+impl FromStr for RevocationList {
+    type Err = Error;
+
+    fn from_str(content: &str) -> Result<Self> {
+        let mut lines = content.lines();
+        match lines.next() {
+            Some(version) if version.trim() == REVOCATION_LIST_FORMAT_VERSION => (),
+            Some(version) => {
+                return Err(Error::CryptoError(format!(
+                    "Unsupported revocation list version: {}",
+                    version.trim()
+                )))
+            }
+            None => {
+                return Err(Error::CryptoError(
+                    "Corrupt revocation list, can't read format version".to_string(),
+                ))
+            }
+        }
+        let mut list = RevocationList::new();
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let name_with_rev = fields.next().unwrap_or(line);
+            match fields.next() {
+                Some(revoked_at) => {
+                    let revoked_at = revoked_at.parse::<i64>().map_err(|e| {
+                        Error::CryptoError(format!(
+                            "Can't parse revocation time for {}: {}",
+                            name_with_rev, e
+                        ))
+                    })?;
+                    list.revoke_at(name_with_rev, revoked_at)?;
+                }
+                None => {
+                    list.revoke(name_with_rev)?;
+                }
+            }
+        }
+        Ok(list)
+    }
+}
+
+impl fmt::Display for RevocationList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", REVOCATION_LIST_FORMAT_VERSION)?;
+        for (name_with_rev, revoked_at) in &self.revoked {
+            match *revoked_at {
+                Some(revoked_at) => writeln!(f, "{} {}", name_with_rev, revoked_at)?,
+                None => writeln!(f, "{}", name_with_rev)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_revocation_list() {
+        let list: RevocationList = "REVOKED-1\n# leaked\nhabitat-20160405144945\n\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(list.len(), 1);
+        assert!(list.is_revoked("habitat-20160405144945"));
+        assert!(!list.is_revoked("habitat-20160405150012"));
+    }
+
+    #[test]
+    fn revocation_list_round_trip() {
+        let mut list = RevocationList::new();
+        assert!(list.revoke("habitat-20160405144945").unwrap());
+        assert!(!list.revoke("habitat-20160405144945").unwrap());
+        list.revoke("core-20170101000000").unwrap();
+        assert!(list.revoke_at("core-20180101000000", 1526601600).unwrap());
+
+        let parsed: RevocationList = list.to_string().parse().unwrap();
+        assert_eq!(parsed, list);
+        assert_eq!(parsed.revoked_at("core-20180101000000"), Some(1526601600));
+        assert_eq!(parsed.revoked_at("core-20170101000000"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported revocation list version: REVOKED-9")]
+    fn parse_revocation_list_unsupported_version() {
+        "REVOKED-9\nhabitat-20160405144945"
+            .parse::<RevocationList>()
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Key habitat-20160405144945 has been revoked")]
+    fn check_revoked_key() {
+        let mut list = RevocationList::new();
+        list.revoke("habitat-20160405144945").unwrap();
+        list.check("habitat-20160405144945").unwrap();
+    }
+}
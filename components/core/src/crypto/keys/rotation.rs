@@ -0,0 +1,233 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rotation of origin, service, user, and ring keys.
+//!
+//! Rotating a key generates a new revision of it in the key cache. Existing revisions are never
+//! removed, so artifacts signed and payloads encrypted with them can still be verified and
+//! decrypted; every lookup of the "latest" key will pick up the new revision from then on.
+
+use std::path::{Path, PathBuf};
+
+use super::super::{
+    PUBLIC_KEY_SUFFIX, SECRET_BOX_KEY_SUFFIX, SECRET_SIG_KEY_SUFFIX, SECRET_SYM_KEY_SUFFIX,
+};
+use super::box_key_pair::BoxKeyPair;
+use super::sig_key_pair::SigKeyPair;
+use super::sym_key::SymKey;
+use super::{get_key_revisions, mk_key_filename, KeyType};
+use error::Result;
+
+/// The outcome of rotating a key to a new revision.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RotationReport {
+    /// The name of the rotated key, ex: "habitat"
+    pub name: String,
+    /// The name with revision of the newly generated key
+    pub new_key: String,
+    /// The names with revision of the keys which existed before the rotation, newest first. These
+    /// are left in place for verification and decryption.
+    pub retained_keys: Vec<String>,
+    /// The key files which were written for the new revision
+    pub written_files: Vec<PathBuf>,
+}
+
+impl RotationReport {
+    /// Returns the name with revision of the key which was current before the rotation, if any.
+    pub fn previous_key(&self) -> Option<&str> {
+        self.retained_keys.first().map(|k| k.as_str())
+    }
+}
+
+/// Generates a new revision of an origin's signing key in `cache_key_path`.
+pub fn rotate_origin_key<P: AsRef<Path> + ?Sized>(
+    origin: &str,
+    cache_key_path: &P,
+) -> Result<RotationReport> {
+    let retained_keys = existing_revisions(origin, cache_key_path.as_ref(), &KeyType::Sig)?;
+    let pair = SigKeyPair::generate_pair_for_origin(origin)?;
+    pair.to_pair_files(cache_key_path)?;
+    debug!("rotated origin key {} to {}", origin, pair.name_with_rev());
+    Ok(report(
+        pair.name.clone(),
+        pair.name_with_rev(),
+        retained_keys,
+        cache_key_path.as_ref(),
+        &[PUBLIC_KEY_SUFFIX, SECRET_SIG_KEY_SUFFIX],
+    ))
+}
+
+/// Generates a new revision of a service group's box key in `cache_key_path`.
+pub fn rotate_service_key<P: AsRef<Path> + ?Sized>(
+    org: &str,
+    service_group: &str,
+    cache_key_path: &P,
+) -> Result<RotationReport> {
+    let pair = BoxKeyPair::generate_pair_for_service(org, service_group)?;
+    rotate_box_key(pair, cache_key_path.as_ref())
+}
+
+/// Generates a new revision of a user's box key in `cache_key_path`.
+pub fn rotate_user_key<P: AsRef<Path> + ?Sized>(
+    user: &str,
+    cache_key_path: &P,
+) -> Result<RotationReport> {
+    let pair = BoxKeyPair::generate_pair_for_user(user)?;
+    rotate_box_key(pair, cache_key_path.as_ref())
+}
+
+/// Generates a new revision of a ring's symmetric key in `cache_key_path`.
+pub fn rotate_ring_key<P: AsRef<Path> + ?Sized>(
+    ring: &str,
+    cache_key_path: &P,
+) -> Result<RotationReport> {
+    let retained_keys = existing_revisions(ring, cache_key_path.as_ref(), &KeyType::Sym)?;
+    let pair = SymKey::generate_pair_for_ring(ring)?;
+    pair.to_pair_files(cache_key_path)?;
+    debug!("rotated ring key {} to {}", ring, pair.name_with_rev());
+    Ok(report(
+        pair.name.clone(),
+        pair.name_with_rev(),
+        retained_keys,
+        cache_key_path.as_ref(),
+        &[SECRET_SYM_KEY_SUFFIX],
+    ))
+}
+
+fn rotate_box_key(pair: BoxKeyPair, cache_key_path: &Path) -> Result<RotationReport> {
+    let retained_keys = existing_revisions(&pair.name, cache_key_path, &KeyType::Box)?;
+    pair.to_pair_files(cache_key_path)?;
+    debug!("rotated box key {} to {}", pair.name, pair.name_with_rev());
+    Ok(report(
+        pair.name.clone(),
+        pair.name_with_rev(),
+        retained_keys,
+        cache_key_path,
+        &[PUBLIC_KEY_SUFFIX, SECRET_BOX_KEY_SUFFIX],
+    ))
+}
+
+/// A key cache which doesn't exist yet simply has no revisions; it is created when the first key
+/// is written.
+fn existing_revisions(
+    name: &str,
+    cache_key_path: &Path,
+    key_type: &KeyType,
+) -> Result<Vec<String>> {
+    if cache_key_path.is_dir() {
+        get_key_revisions(name, cache_key_path, None, key_type)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+fn report(
+    name: String,
+    new_key: String,
+    retained_keys: Vec<String>,
+    cache_key_path: &Path,
+    suffixes: &[&str],
+) -> RotationReport {
+    let written_files = suffixes
+        .iter()
+        .map(|suffix| mk_key_filename(cache_key_path, &new_key, suffix))
+        .collect();
+    RotationReport {
+        name: name,
+        new_key: new_key,
+        retained_keys: retained_keys,
+        written_files: written_files,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::Builder;
+
+    use super::super::super::test_support::*;
+    use super::super::box_key_pair::BoxKeyPair;
+    use super::super::sig_key_pair::SigKeyPair;
+    use super::super::sym_key::SymKey;
+    use super::*;
+
+    #[test]
+    fn rotate_origin_key_without_existing_keys() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let report = rotate_origin_key("unicorn", cache.path()).unwrap();
+
+        assert_eq!(report.name, "unicorn");
+        assert!(report.retained_keys.is_empty());
+        assert_eq!(report.previous_key(), None);
+        assert_eq!(report.written_files.len(), 2);
+        for file in &report.written_files {
+            assert!(file.is_file());
+        }
+    }
+
+    #[test]
+    fn rotate_origin_key_retains_old_revisions() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let original = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        original.to_pair_files(cache.path()).unwrap();
+
+        let report = match wait_until_ok(|| rotate_origin_key("unicorn", cache.path())) {
+            Some(report) => report,
+            None => panic!("Failed to rotate the origin key after waiting"),
+        };
+
+        assert_eq!(
+            report.previous_key(),
+            Some(original.name_with_rev().as_str())
+        );
+        let latest = SigKeyPair::get_latest_pair_for("unicorn", cache.path(), None).unwrap();
+        assert_eq!(latest.name_with_rev(), report.new_key);
+        // The original revision is still usable for verification
+        SigKeyPair::get_pair_for(&original.name_with_rev(), cache.path()).unwrap();
+    }
+
+    #[test]
+    fn rotate_service_key_retains_old_revisions() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let original = BoxKeyPair::generate_pair_for_service("acme", "tnt.default").unwrap();
+        original.to_pair_files(cache.path()).unwrap();
+
+        let report = match wait_until_ok(|| rotate_service_key("acme", "tnt.default", cache.path()))
+        {
+            Some(report) => report,
+            None => panic!("Failed to rotate the service key after waiting"),
+        };
+
+        assert_eq!(report.name, "tnt.default@acme");
+        assert_eq!(report.retained_keys, vec![original.name_with_rev()]);
+        let latest = BoxKeyPair::get_latest_pair_for("tnt.default@acme", cache.path()).unwrap();
+        assert_eq!(latest.name_with_rev(), report.new_key);
+    }
+
+    #[test]
+    fn rotate_ring_key_retains_old_revisions() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let original = SymKey::generate_pair_for_ring("beyonce").unwrap();
+        original.to_pair_files(cache.path()).unwrap();
+
+        let report = match wait_until_ok(|| rotate_ring_key("beyonce", cache.path())) {
+            Some(report) => report,
+            None => panic!("Failed to rotate the ring key after waiting"),
+        };
+
+        assert_eq!(report.retained_keys, vec![original.name_with_rev()]);
+        assert_eq!(report.written_files.len(), 1);
+        let latest = SymKey::get_latest_pair_for("beyonce", cache.path()).unwrap();
+        assert_eq!(latest.name_with_rev(), report.new_key);
+    }
+}
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -26,13 +27,21 @@ use super::super::{
     hash, PUBLIC_KEY_SUFFIX, PUBLIC_SIG_KEY_VERSION, SECRET_SIG_KEY_SUFFIX, SECRET_SIG_KEY_VERSION,
 };
 use super::{
-    get_key_revisions, mk_key_filename, mk_revision_string, parse_name_with_rev, read_key_bytes,
-    write_keypair_files, KeyPair, KeyType, PairType, TmpKeyfile,
+    get_cached_key_revisions, get_key_revisions, mk_key_file_name, mk_key_filename,
+    mk_revision_string, parse_name_with_rev, read_cached_key_bytes, read_key_bytes,
+    write_keypair_files, KeyAlgorithm, KeyCache, KeyCacheLock, KeyPair, KeyType, PairType,
+    TmpKeyfile,
 };
 use error::{Error, Result};
 
 pub type SigKeyPair = KeyPair<SigPublicKey, SigSecretKey>;
 
+impl fmt::Debug for SigKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigKeyPair({})", self.name_with_rev())
+    }
+}
+
 impl SigKeyPair {
     pub fn generate_pair_for_origin(name: &str) -> Result<Self> {
         let revision = mk_revision_string()?;
@@ -40,6 +49,17 @@ impl SigKeyPair {
         Ok(Self::new(name.to_string(), revision, Some(pk), Some(sk)))
     }
 
+    /// Generates an origin key with the given algorithm, which must be a signing algorithm.
+    pub fn generate_pair_for_origin_with(name: &str, algorithm: KeyAlgorithm) -> Result<Self> {
+        algorithm.check_key_type(KeyType::Sig)?;
+        Self::generate_pair_for_origin(name)
+    }
+
+    /// Returns the algorithm of the key.
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::Ed25519
+    }
+
     /// Return a Vec of origin keys with a given name.
     /// The newest key is listed first in the Vec.
     pub fn get_pairs_for<P: AsRef<Path> + ?Sized>(
@@ -113,6 +133,54 @@ impl SigKeyPair {
         }
     }
 
+    /// Return a Vec of origin keys with a given name held in a `KeyCache`.
+    /// The newest key is listed first in the Vec.
+    pub fn get_pairs_from_cache<C: KeyCache + ?Sized>(
+        name: &str,
+        cache: &C,
+        pair_type: Option<&PairType>,
+    ) -> Result<Vec<Self>> {
+        let revisions = get_cached_key_revisions(name, cache, pair_type, &KeyType::Sig)?;
+        debug!("revisions = {:?}", &revisions);
+        revisions
+            .iter()
+            .map(|name_with_rev| Self::get_pair_from_cache(name_with_rev, cache))
+            .collect()
+    }
+
+    pub fn get_pair_from_cache<C: KeyCache + ?Sized>(
+        name_with_rev: &str,
+        cache: &C,
+    ) -> Result<Self> {
+        let pk = read_cached_key_bytes(cache, name_with_rev, PUBLIC_KEY_SUFFIX).and_then(|bytes| {
+            SigPublicKey::from_slice(&bytes).ok_or_else(|| {
+                Error::CryptoError(format!("Can't read sig public key for {}", name_with_rev))
+            })
+        });
+        let sk =
+            read_cached_key_bytes(cache, name_with_rev, SECRET_SIG_KEY_SUFFIX).and_then(|bytes| {
+                SigSecretKey::from_slice(&bytes).ok_or_else(|| {
+                    Error::CryptoError(format!("Can't read sig secret key for {}", name_with_rev))
+                })
+            });
+        Self::from_loaded_keys(name_with_rev, pk, sk)
+    }
+
+    pub fn get_latest_pair_from_cache<C: KeyCache + ?Sized>(
+        name: &str,
+        cache: &C,
+        pair_type: Option<&PairType>,
+    ) -> Result<Self> {
+        let mut all = Self::get_pairs_from_cache(name, cache, pair_type)?;
+        match all.len() {
+            0 => {
+                let msg = format!("No revisions found for {} sig key", name);
+                return Err(Error::CryptoError(msg));
+            }
+            _ => Ok(all.remove(0)),
+        }
+    }
+
     pub fn get_public_key_path<P: AsRef<Path> + ?Sized>(
         key_with_rev: &str,
         cache_key_path: &P,
@@ -248,6 +316,7 @@ impl SigKeyPair {
             }
         }
 
+        let _lock = KeyCacheLock::acquire(cache_key_path.as_ref())?;
         if Path::new(&keyfile).is_file() {
             let existing_hash = hash::hash_file(&keyfile)?;
             let new_hash = hash::hash_file(&tmpfile.path)?;
@@ -329,6 +398,17 @@ impl SigKeyPair {
         )
     }
 
+    pub fn to_cache<C: KeyCache + ?Sized>(&self, cache: &C) -> Result<()> {
+        cache.write_key(
+            &mk_key_file_name(&self.name_with_rev(), PUBLIC_KEY_SUFFIX),
+            &self.to_public_string()?,
+        )?;
+        cache.write_key(
+            &mk_key_file_name(&self.name_with_rev(), SECRET_SIG_KEY_SUFFIX),
+            &self.to_secret_string()?,
+        )
+    }
+
     fn get_public_key(key_with_rev: &str, cache_key_path: &Path) -> Result<SigPublicKey> {
         let public_keyfile = mk_key_filename(cache_key_path, key_with_rev, PUBLIC_KEY_SUFFIX);
         let bytes = read_key_bytes(&public_keyfile)?;
@@ -361,12 +441,12 @@ impl SigKeyPair {
 #[cfg(test)]
 mod test {
     use std::fs::{self, File};
-    use std::io::Read;
+    use std::io::{Read, Write};
 
     use tempfile::Builder;
 
     use super::super::super::test_support::*;
-    use super::super::PairType;
+    use super::super::{KeyAlgorithm, PairType};
     use super::SigKeyPair;
 
     static VALID_KEY: &'static str = "origin-key-valid-20160509190508.sig.key";
@@ -779,4 +859,29 @@ mod test {
             cache.path(),
         ).unwrap();
     }
+
+    #[test]
+    fn generate_pair_for_origin_with_algorithm() {
+        let pair =
+            SigKeyPair::generate_pair_for_origin_with("unicorn", KeyAlgorithm::Ed25519).unwrap();
+        assert_eq!(pair.algorithm(), KeyAlgorithm::Ed25519);
+    }
+
+    #[test]
+    #[should_panic(expected = "Key algorithm x25519-xsalsa20poly1305 can\\'t be used for sig keys")]
+    fn generate_pair_for_origin_with_box_algorithm() {
+        SigKeyPair::generate_pair_for_origin_with("unicorn", KeyAlgorithm::X25519XSalsa20Poly1305)
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported key version: SIG-PUB-2")]
+    fn get_public_key_from_newer_format() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let mut f = File::create(cache.path().join("unicorn-20160517220007.pub")).unwrap();
+        f.write_all("SIG-PUB-2\nunicorn-20160517220007\n\nc29tZXRoaW5n".as_bytes())
+            .unwrap();
+
+        SigKeyPair::get_public_key("unicorn-20160517220007", cache.path()).unwrap();
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The framing shared by box and ring encrypted streams.
+//!
+//! The plaintext is cut into chunks of `BOX_STREAM_CHUNK_SIZE` bytes, each prefixed with a tag
+//! byte marking whether more chunks follow, and each sealed on its own. A frame is the 4 byte
+//! big-endian length of a sealed chunk followed by the sealed chunk. The key and the sealing
+//! itself are left to the callers.
+
+use std::io::{self, BufRead, Read, Write};
+
+use super::super::BOX_STREAM_CHUNK_SIZE;
+use error::{Error, Result};
+
+/// Leading plaintext byte of a stream frame which is followed by more frames
+const STREAM_TAG_MESSAGE: u8 = 0;
+/// Leading plaintext byte of the last frame in a stream
+const STREAM_TAG_FINAL: u8 = 1;
+
+/// Seal everything read from `input` into frames written to `output`. `seal` is called with the
+/// tagged plaintext of each frame and the frame's position in the stream.
+///
+/// Returns the number of plaintext bytes which were sealed.
+pub fn encrypt_frames<R, W, F>(input: &mut R, output: &mut W, mut seal: F) -> Result<u64>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&[u8], u64) -> Vec<u8>,
+{
+    let mut chunk = vec![0u8; BOX_STREAM_CHUNK_SIZE + 1];
+    let mut total = 0;
+    let mut counter = 0;
+    loop {
+        let len = read_chunk(input, &mut chunk[1..])?;
+        let last = len < BOX_STREAM_CHUNK_SIZE;
+        chunk[0] = if last {
+            STREAM_TAG_FINAL
+        } else {
+            STREAM_TAG_MESSAGE
+        };
+        let ciphertext = seal(&chunk[..len + 1], counter);
+        output.write_all(&frame_len_to_bytes(ciphertext.len()))?;
+        output.write_all(&ciphertext)?;
+        total += len as u64;
+        counter += 1;
+        if last {
+            break;
+        }
+    }
+    output.flush()?;
+    Ok(total)
+}
+
+/// Open the frames read from `input`, writing the plaintext into `output` as each frame is
+/// authenticated. `open` is called with the sealed chunk of each frame and the frame's position
+/// in the stream, and no frame may be larger than `overhead` bytes over a whole chunk.
+///
+/// Returns the number of plaintext bytes which were opened.
+pub fn decrypt_frames<R, W, F>(
+    input: &mut R,
+    output: &mut W,
+    overhead: usize,
+    mut open: F,
+) -> Result<u64>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&[u8], u64) -> Result<Vec<u8>>,
+{
+    let max_frame_len = BOX_STREAM_CHUNK_SIZE + 1 + overhead;
+    let mut total = 0;
+    let mut counter = 0;
+    loop {
+        let mut len_bytes = [0u8; 4];
+        input
+            .read_exact(&mut len_bytes)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    Error::CryptoError("Corrupt stream, missing final frame".to_string())
+                }
+                _ => Error::IO(e),
+            })?;
+        let len = frame_len_from_bytes(&len_bytes);
+        if len > max_frame_len {
+            return Err(Error::CryptoError(format!(
+                "Corrupt stream, frame of {} bytes exceeds maximum of {}",
+                len, max_frame_len
+            )));
+        }
+        let mut ciphertext = vec![0u8; len];
+        input
+            .read_exact(&mut ciphertext)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    Error::CryptoError("Corrupt stream, truncated frame".to_string())
+                }
+                _ => Error::IO(e),
+            })?;
+        let plaintext = open(&ciphertext, counter)?;
+        let (tag, data) = match plaintext.split_first() {
+            Some((tag, data)) => (*tag, data),
+            None => {
+                return Err(Error::CryptoError(
+                    "Corrupt stream, empty frame".to_string(),
+                ))
+            }
+        };
+        output.write_all(data)?;
+        total += data.len() as u64;
+        counter += 1;
+        match tag {
+            STREAM_TAG_MESSAGE => continue,
+            STREAM_TAG_FINAL => break,
+            _ => {
+                return Err(Error::CryptoError(format!(
+                    "Corrupt stream, unknown frame tag {}",
+                    tag
+                )))
+            }
+        }
+    }
+    if input.read(&mut [0u8; 1])? != 0 {
+        return Err(Error::CryptoError(
+            "Corrupt stream, data found after final frame".to_string(),
+        ));
+    }
+    output.flush()?;
+    Ok(total)
+}
+
+/// Mix a frame counter into the trailing bytes of a copy of the stream's base nonce, so no two
+/// frames of a stream are sealed with the same nonce.
+pub fn frame_nonce(base: &[u8], counter: u64) -> Vec<u8> {
+    let mut bytes = base.to_vec();
+    let len = bytes.len();
+    for (i, b) in bytes[len - 8..].iter_mut().enumerate() {
+        *b ^= (counter >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+pub fn read_header_line<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(Error::CryptoError(
+            "Corrupt stream, can't read header".to_string(),
+        ));
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn frame_len_to_bytes(len: usize) -> [u8; 4] {
+    [
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ]
+}
+
+fn frame_len_from_bytes(bytes: &[u8; 4]) -> usize {
+    bytes.iter().fold(0, |len, b| (len << 8) | *b as usize)
+}
+
+/// Fill `buf` from `input`, returning fewer bytes than its length only once `input` is exhausted.
+fn read_chunk<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::IO(e)),
+        }
+    }
+    Ok(filled)
+}
@@ -14,6 +14,7 @@
 
 use std::fmt;
 use std::fs;
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 
 use base64;
@@ -22,10 +23,15 @@ use sodiumoxide::crypto::secretbox;
 use sodiumoxide::crypto::secretbox::Key as SymSecretKey;
 use sodiumoxide::randombytes::randombytes;
 
-use super::super::{hash, SECRET_SYM_KEY_SUFFIX, SECRET_SYM_KEY_VERSION};
+use super::super::{
+    fips, hash, RING_MESSAGE_FORMAT_VERSION, RING_STREAM_FORMAT_VERSION, SECRET_SYM_KEY_SUFFIX,
+    SECRET_SYM_KEY_VERSION,
+};
 use super::{
-    get_key_revisions, mk_key_filename, mk_revision_string, parse_name_with_rev, read_key_bytes,
-    write_keypair_files, KeyPair, KeyType, PairType, TmpKeyfile,
+    get_cached_key_revisions, get_key_revisions, mk_key_file_name, mk_key_filename,
+    mk_revision_string, parse_name_with_rev, read_cached_key_bytes, read_key_bytes, stream,
+    write_keypair_files, KeyAlgorithm, KeyCache, KeyCacheLock, KeyPair, KeyType, PairType,
+    TmpKeyfile,
 };
 use error::{Error, Result};
 
@@ -39,6 +45,7 @@ impl fmt::Debug for SymKey {
 
 impl SymKey {
     pub fn generate_pair_for_ring<S: ToString>(name: S) -> Result<Self> {
+        fips::check_key_algorithm(KeyAlgorithm::XSalsa20Poly1305)?;
         let revision = mk_revision_string()?;
         let secret_key = secretbox::gen_key();
         Ok(SymKey::new(
@@ -49,6 +56,20 @@ impl SymKey {
         ))
     }
 
+    /// Generates a ring key with the given algorithm, which must be a symmetric algorithm.
+    pub fn generate_pair_for_ring_with<S: ToString>(
+        name: S,
+        algorithm: KeyAlgorithm,
+    ) -> Result<Self> {
+        algorithm.check_key_type(KeyType::Sym)?;
+        Self::generate_pair_for_ring(name)
+    }
+
+    /// Returns the algorithm of the key.
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::XSalsa20Poly1305
+    }
+
     pub fn get_pairs_for<P: AsRef<Path> + ?Sized>(
         name: &str,
         cache_key_path: &P,
@@ -117,6 +138,44 @@ impl SymKey {
         }
     }
 
+    pub fn get_pairs_from_cache<C: KeyCache + ?Sized>(name: &str, cache: &C) -> Result<Vec<Self>> {
+        let revisions = get_cached_key_revisions(name, cache, None, &KeyType::Sym)?;
+        revisions
+            .iter()
+            .map(|name_with_rev| Self::get_pair_from_cache(name_with_rev, cache))
+            .collect()
+    }
+
+    pub fn get_pair_from_cache<C: KeyCache + ?Sized>(
+        name_with_rev: &str,
+        cache: &C,
+    ) -> Result<Self> {
+        let sk =
+            read_cached_key_bytes(cache, name_with_rev, SECRET_SYM_KEY_SUFFIX).and_then(|bytes| {
+                SymSecretKey::from_slice(&bytes).ok_or_else(|| {
+                    Error::CryptoError(format!("Can't read sym secret key for {}", name_with_rev))
+                })
+            });
+        Self::from_loaded_keys(
+            name_with_rev,
+            Err(Error::CryptoError(
+                "SymKey never contains a public key".to_string(),
+            )),
+            sk,
+        )
+    }
+
+    pub fn get_latest_pair_from_cache<C: KeyCache + ?Sized>(name: &str, cache: &C) -> Result<Self> {
+        let mut all = Self::get_pairs_from_cache(name, cache)?;
+        match all.len() {
+            0 => {
+                let msg = format!("No revisions found for {} sym key", name);
+                return Err(Error::CryptoError(msg));
+            }
+            _ => Ok(all.remove(0)),
+        }
+    }
+
     pub fn get_public_key_path<P: AsRef<Path> + ?Sized>(
         _key_with_rev: &str,
         _cache_key_path: &P,
@@ -195,6 +254,7 @@ impl SymKey {
     ///
     /// * If the secret key component of the `SymKey` is not present
     pub fn encrypt(&self, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        fips::check_key_algorithm(self.algorithm())?;
         let key = self.secret()?;
         let nonce = secretbox::gen_nonce();
         Ok((nonce.as_ref().to_vec(), secretbox::seal(data, &nonce, &key)))
@@ -231,6 +291,7 @@ impl SymKey {
     /// * If the size of the provided nonce data is not the required size
     /// * If the ciphertext was not decryptable given the nonce and symmetric key
     pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        fips::check_key_algorithm(self.algorithm())?;
         let key = self.secret()?;
         let nonce = match secretbox::Nonce::from_slice(&nonce) {
             Some(n) => n,
@@ -244,6 +305,156 @@ impl SymKey {
         }
     }
 
+    /// Encrypts a byte slice of data into a single versioned payload holding the nonce and the
+    /// ciphertext, in the current ring message format.
+    ///
+    /// # Errors
+    ///
+    /// * If the secret key component of the `SymKey` is not present
+    pub fn encrypt_message(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = self.encrypt(data)?;
+        Self::message_from_parts(&nonce, &ciphertext)
+    }
+
+    /// Decrypts a payload produced by `encrypt_message`.
+    ///
+    /// # Errors
+    ///
+    /// * If the secret key component of the `SymKey` is not present
+    /// * If the payload's format version isn't supported
+    /// * If the ciphertext was not decryptable given the nonce and symmetric key
+    pub fn decrypt_message(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = Self::message_into_parts(payload)?;
+        self.decrypt(nonce, ciphertext)
+    }
+
+    /// Returns the format version of a ring message payload.
+    pub fn message_version(payload: &[u8]) -> Result<u8> {
+        match payload.first() {
+            Some(&RING_MESSAGE_FORMAT_VERSION) => Ok(RING_MESSAGE_FORMAT_VERSION),
+            Some(version) => Err(Error::CryptoError(format!(
+                "Unsupported ring message version: {}",
+                version
+            ))),
+            None => Err(Error::CryptoError(
+                "Corrupt payload, can't read version".to_string(),
+            )),
+        }
+    }
+
+    /// Builds a payload in the current ring message format from a nonce and ciphertext returned
+    /// by `encrypt`, as sent by peers which predate versioned messages.
+    pub fn message_from_parts(nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if nonce.len() != secretbox::NONCEBYTES {
+            return Err(Error::CryptoError("Invalid size of nonce".to_string()));
+        }
+        let mut payload = Vec::with_capacity(1 + nonce.len() + ciphertext.len());
+        payload.push(RING_MESSAGE_FORMAT_VERSION);
+        payload.extend_from_slice(nonce);
+        payload.extend_from_slice(ciphertext);
+        Ok(payload)
+    }
+
+    /// Splits a ring message payload into its nonce and ciphertext, which can be passed to
+    /// `decrypt` or sent to peers which predate versioned messages.
+    pub fn message_into_parts(payload: &[u8]) -> Result<(&[u8], &[u8])> {
+        Self::message_version(payload)?;
+        if payload.len() < 1 + secretbox::NONCEBYTES {
+            return Err(Error::CryptoError(
+                "Corrupt payload, can't read nonce".to_string(),
+            ));
+        }
+        Ok(payload[1..].split_at(secretbox::NONCEBYTES))
+    }
+
+    /// Encrypts everything read from `input` into `output` as a sequence of framed ciphertexts,
+    /// as `BoxKeyPair::encrypt_stream` does, so that data can be piped through ring encryption
+    /// without being held in memory. The ring key's name and the base nonce are written to a
+    /// plaintext header at the start of the stream.
+    ///
+    /// Returns the number of plaintext bytes which were encrypted.
+    ///
+    /// # Errors
+    ///
+    /// * If the secret key component of the `SymKey` is not present
+    /// * If `input` can't be read or `output` can't be written
+    pub fn encrypt_stream<R, W>(&self, input: &mut R, output: &mut W) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+    {
+        fips::check_key_algorithm(self.algorithm())?;
+        let key = self.secret()?;
+        let nonce = secretbox::gen_nonce();
+        write!(
+            output,
+            "{}\n{}\n{}\n\n",
+            RING_STREAM_FORMAT_VERSION,
+            self.name_with_rev(),
+            base64::encode(&nonce[..])
+        )?;
+        stream::encrypt_frames(input, output, |chunk, counter| {
+            secretbox::seal(chunk, &stream_nonce(&nonce, counter), key)
+        })
+    }
+
+    /// Decrypts a stream produced by `encrypt_stream`, writing the plaintext into `output` as
+    /// each frame is authenticated.
+    ///
+    /// Returns the number of plaintext bytes which were decrypted.
+    ///
+    /// # Errors
+    ///
+    /// * If the secret key component of the `SymKey` is not present
+    /// * If the stream was encrypted with another ring key
+    /// * If the stream is truncated or any frame was not decryptable with the symmetric key
+    pub fn decrypt_stream<R, W>(&self, input: &mut R, output: &mut W) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+    {
+        fips::check_key_algorithm(self.algorithm())?;
+        let key = self.secret()?;
+        let mut input = BufReader::new(input);
+        let version = stream::read_header_line(&mut input)?;
+        if version != RING_STREAM_FORMAT_VERSION {
+            return Err(Error::CryptoError(format!(
+                "Unsupported version: {}",
+                version
+            )));
+        }
+        let key_name = stream::read_header_line(&mut input)?;
+        if key_name != self.name_with_rev() {
+            return Err(Error::CryptoError(format!(
+                "Stream was encrypted with ring key {}, not {}",
+                key_name,
+                self.name_with_rev()
+            )));
+        }
+        let nonce = base64::decode(&stream::read_header_line(&mut input)?)
+            .ok()
+            .and_then(|bytes| secretbox::Nonce::from_slice(&bytes))
+            .ok_or_else(|| Error::CryptoError("Invalid size of nonce".to_string()))?;
+        if !stream::read_header_line(&mut input)?.is_empty() {
+            return Err(Error::CryptoError(
+                "Corrupt stream, malformed header".to_string(),
+            ));
+        }
+        stream::decrypt_frames(
+            &mut input,
+            output,
+            secretbox::MACBYTES,
+            |ciphertext, counter| {
+                secretbox::open(ciphertext, &stream_nonce(&nonce, counter), key).map_err(|_| {
+                    Error::CryptoError(format!(
+                        "Secret key and nonce could not decrypt stream frame {}",
+                        counter
+                    ))
+                })
+            },
+        )
+    }
+
     pub fn to_secret_string(&self) -> Result<String> {
         match self.secret {
             Some(ref sk) => Ok(format!(
@@ -273,6 +484,13 @@ impl SymKey {
         )
     }
 
+    pub fn to_cache<C: KeyCache + ?Sized>(&self, cache: &C) -> Result<()> {
+        cache.write_key(
+            &mk_key_file_name(&self.name_with_rev(), SECRET_SYM_KEY_SUFFIX),
+            &self.to_secret_string()?,
+        )
+    }
+
     fn get_public_key(_key_with_rev: &str, _cache_key_path: &Path) -> Result<()> {
         Err(Error::CryptoError(
             "SymKey never contains a public key".to_string(),
@@ -390,6 +608,7 @@ impl SymKey {
         debug!("Writing temp key file {}", tmpfile.path.display());
         write_keypair_files(None, None, Some(&tmpfile.path), Some(content.to_string()))?;
 
+        let _lock = KeyCacheLock::acquire(cache_key_path.as_ref())?;
         if Path::new(&secret_keyfile).is_file() {
             let existing_hash = hash::hash_file(&secret_keyfile)?;
             let new_hash = hash::hash_file(&tmpfile.path)?;
@@ -431,6 +650,13 @@ impl SymKey {
     }
 }
 
+/// Derive the nonce for a stream frame from the base nonce and the frame's position.
+fn stream_nonce(base: &secretbox::Nonce, counter: u64) -> secretbox::Nonce {
+    let mut bytes = [0u8; secretbox::NONCEBYTES];
+    bytes.copy_from_slice(&stream::frame_nonce(&base[..], counter));
+    secretbox::Nonce(bytes)
+}
+
 #[cfg(test)]
 mod test {
     use std::fs::{self, File};
@@ -439,6 +665,7 @@ mod test {
     use tempfile::Builder;
 
     use super::super::super::test_support::*;
+    use super::super::super::BOX_STREAM_CHUNK_SIZE;
     use super::super::PairType;
     use super::SymKey;
 
@@ -626,6 +853,74 @@ mod test {
         assert_eq!(message, "Ringonit".to_string().into_bytes());
     }
 
+    #[test]
+    fn encrypt_and_decrypt_message() {
+        let pair = SymKey::generate_pair_for_ring("beyonce").unwrap();
+
+        let payload = pair.encrypt_message("Ringonit".as_bytes()).unwrap();
+        assert_eq!(SymKey::message_version(&payload).unwrap(), 1);
+        assert_eq!(
+            pair.decrypt_message(&payload).unwrap(),
+            "Ringonit".to_string().into_bytes()
+        );
+
+        // Messages from and to peers which send the nonce and ciphertext separately
+        let (nonce, ciphertext) = SymKey::message_into_parts(&payload).unwrap();
+        assert_eq!(
+            pair.decrypt(nonce, ciphertext).unwrap(),
+            "Ringonit".to_string().into_bytes()
+        );
+        let (nonce, ciphertext) = pair.encrypt("Halo".as_bytes()).unwrap();
+        let payload = SymKey::message_from_parts(&nonce, &ciphertext).unwrap();
+        assert_eq!(
+            pair.decrypt_message(&payload).unwrap(),
+            "Halo".to_string().into_bytes()
+        );
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_stream() {
+        let pair = SymKey::generate_pair_for_ring("beyonce").unwrap();
+        let data: Vec<u8> = (0..BOX_STREAM_CHUNK_SIZE * 2 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let mut ciphertext = Vec::new();
+        assert_eq!(
+            pair.encrypt_stream(&mut data.as_slice(), &mut ciphertext)
+                .unwrap(),
+            data.len() as u64
+        );
+        let mut message = Vec::new();
+        pair.decrypt_stream(&mut ciphertext.as_slice(), &mut message)
+            .unwrap();
+        assert_eq!(message, data);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stream was encrypted with ring key")]
+    fn decrypt_stream_with_other_key() {
+        let pair = SymKey::generate_pair_for_ring("beyonce").unwrap();
+        let other = SymKey::generate_pair_for_ring("jayz").unwrap();
+        let mut ciphertext = Vec::new();
+        pair.encrypt_stream(&mut "Ringonit".as_bytes(), &mut ciphertext)
+            .unwrap();
+
+        other
+            .decrypt_stream(&mut ciphertext.as_slice(), &mut Vec::new())
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported ring message version: 2")]
+    fn decrypt_message_unsupported_version() {
+        let pair = SymKey::generate_pair_for_ring("beyonce").unwrap();
+        let mut payload = pair.encrypt_message("Ringonit".as_bytes()).unwrap();
+        payload[0] = 2;
+
+        pair.decrypt_message(&payload).unwrap();
+    }
+
     #[test]
     #[should_panic(expected = "Secret key is required but not present for")]
     fn encrypt_missing_secret_key() {
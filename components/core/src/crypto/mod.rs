@@ -189,6 +189,91 @@
 //! is **not** a supported workflow for working with Habitat artifacts--they are signed for very
 //! important reasons.
 //!
+//! ## Artifacts with multiple signatures
+//!
+//! An artifact can carry signatures from more than one origin key, for example the key of a build
+//! service plus the key of a release manager. These artifacts use a variable length header:
+//!
+//! 1. The artifact format version, `HART-2`
+//! 1. The hashing algorithm used, which at present is only `BLAKE2b`
+//! 1. The number of signatures which follow
+//! 1. For each signature, the name with revision of the origin key on one line followed by the
+//!    Base64 *signed* value of the binary blob's file hash on the next
+//! 1. The last line is left empty, as in the single signature format
+//!
+//! ```text
+//! HART-2
+//! BLAKE2b
+//! 2
+//! builder-20160405144945
+//! signed BLAKE2b signature
+//! release-20160405150012
+//! signed BLAKE2b signature
+//!
+//! <binary-blob>
+//! ```
+//!
+//! ## Artifacts with explicit algorithms
+//!
+//! The `HART-3` format names both the hashing algorithm and the signature algorithm, so that new
+//! algorithms can be introduced without changing the format. Its header has 6 plaintext lines:
+//!
+//! 1. The artifact format version, `HART-3`
+//! 1. The name with revision of the origin key which was used to sign the artifact
+//! 1. The hashing algorithm used, one of `BLAKE2b`, `BLAKE3` or `SHA256`
+//! 1. The signature algorithm used, which at present is only `ed25519`
+//! 1. A Base64 *signed* value of the binary blob's file hash
+//! 1. The last line is left empty, as in the other formats
+//!
+//! ```text
+//! HART-3
+//! habitat-20160405144945
+//! BLAKE3
+//! ed25519
+//! signed BLAKE3 signature
+//!
+//! <binary-blob>
+//! ```
+//!
+//! `HART-1` and `HART-2` artifacts are read as using `BLAKE2b` and `ed25519`. Artifacts are still
+//! signed as `HART-1` unless an algorithm is chosen, so that they remain readable by releases
+//! which don't know about `HART-3`.
+//!
+//! A `HART-3` signature can be timestamped. Optional `name: value` attribute lines then come
+//! between the signature and the empty line: `timestamp`, the time of signing in seconds since
+//! the Unix epoch, and `tsa-token`, a Base64 token from an external timestamp authority. A
+//! timestamped signature signs the hash and the timestamp, separated by a newline, rather than
+//! the hash alone; the timestamp authority token is not covered. Attributes which aren't known
+//! are ignored.
+//!
+//! ```text
+//! HART-3
+//! habitat-20160405144945
+//! BLAKE2b
+//! ed25519
+//! signed BLAKE2b signature and timestamp
+//! timestamp: 1539648000
+//!
+//! <binary-blob>
+//! ```
+//!
+//! A signature by a key which has been revoked is refused whatever its timestamp says, as the
+//! signer writes the timestamp and nothing validates the timestamp authority token.
+//!
+//! ## Detached signatures
+//!
+//! Artifacts kept somewhere they can't be modified, such as an artifact proxy or an object store,
+//! can be signed with a standalone signature file instead. The signature covers the hash of the
+//! whole file, as stored, and has the same header lines as a signed artifact without the binary
+//! blob:
+//!
+//! ```text
+//! HART-SIG-1
+//! habitat-20160405144945
+//! BLAKE2b
+//! signed BLAKE2b signature
+//! ```
+//!
 //! ## Encrypted payloads
 //!
 //! The first 4 lines of an encrypted payload are as follows:
@@ -208,6 +293,69 @@
 //! <ciphertext_base64>
 //! ```
 //!
+//! ## Payloads encrypted to multiple recipients
+//!
+//! A payload can be encrypted once for several recipients. The message is encrypted with a random
+//! session key, and that session key is encrypted from the sender to each recipient in turn, so
+//! any one of the recipients can decrypt the message:
+//!
+//! 1. The encrypted format version
+//! 1. The key name, including revision of the source user
+//! 1. The number of recipients
+//! 1. One line per recipient: its key name including revision, a nonce in Base64 format and the
+//!    encrypted session key in Base64 format, separated by spaces
+//! 1. A nonce for the message, in Base64 format
+//! 1. The encrypted message in Base64 format
+//!
+//! ```text
+//! MULTI-BOX-1
+//! signing key name
+//! 2
+//! recipient key name nonce_base64 session_key_base64
+//! recipient key name nonce_base64 session_key_base64
+//! nonce_base64
+//! <ciphertext_base64>
+//! ```
+//!
+//! ## Encrypted streams
+//!
+//! Large payloads can be encrypted without buffering them fully in memory. A stream starts with a
+//! plaintext header of 4 lines followed by an empty line:
+//!
+//! 1. The encrypted stream format version
+//! 1. The key name, including revision of the source user (or, for an anonymous stream, of the
+//!    recipient)
+//! 1. The key name, including revision of the recipient service (or, for an anonymous stream,
+//!    the Base64 public half of a one-time sender key)
+//! 1. A base nonce, in Base64 format
+//!
+//! ```text
+//! BOX-STREAM-1
+//! signing key name
+//! recipient key name
+//! nonce_base64
+//!
+//! <frames>
+//! ```
+//!
+//! The remainder of the stream is a sequence of binary frames. Each frame is a 4 byte big-endian
+//! length followed by that many bytes of box ciphertext. The first plaintext byte of every frame
+//! is a tag marking whether more frames follow, so a truncated stream is detected on decryption.
+//! Every frame is sealed with its own nonce derived from the base nonce and the frame's position.
+//!
+//! A stream can also be encrypted with a ring key, so that data can be piped through ring
+//! encryption. Its header has the `RING-STREAM-1` format version, the ring key name including
+//! revision and the base nonce, followed by an empty line, and its frames are sealed with the
+//! ring key in the same way:
+//!
+//! ```text
+//! RING-STREAM-1
+//! ring key name
+//! nonce_base64
+//!
+//! <frames>
+//! ```
+//!
 //! ## Ring keys
 //!
 //! There are 3 lines, that is 3 parts that are separated by a newline character `\n`. They are as
@@ -223,6 +371,19 @@
 //!
 //! <symkey_base64>
 //! ```
+//!
+//! ## Ring encrypted messages
+//!
+//! Messages encrypted with a ring key are exchanged between peers as a single binary payload:
+//!
+//! 1. One byte holding the message format version, at present `1`
+//! 1. The nonce, whose length and scheme depend on the version. Version `1` uses a random 24 byte
+//!    XSalsa20 nonce, which is large enough that random nonces never collide in practice
+//! 1. The ciphertext, which is the rest of the payload
+//!
+//! Older releases send the nonce and ciphertext as separate values with no version. A payload in
+//! the current format can be built from such a pair, and split back into one for older peers, so
+//! a fleet can be upgraded a member at a time.
 
 use rust_crypto;
 use std::path::{Path, PathBuf};
@@ -231,8 +392,14 @@ use env as henv;
 pub use sodiumoxide::init;
 
 pub use self::keys::box_key_pair::BoxKeyPair;
+pub use self::keys::cache::{FsKeyCache, KeyCache, MemoryKeyCache};
+pub use self::keys::fetcher::{FetchResponse, KeyFetcher, KeyTransport};
+pub use self::keys::lock::KeyCacheLock;
+pub use self::keys::revocation::RevocationList;
+pub use self::keys::secret::SecretBytes;
 pub use self::keys::sig_key_pair::SigKeyPair;
 pub use self::keys::sym_key::SymKey;
+pub use self::provider::{CryptoProvider, FakeCryptoProvider, SodiumCryptoProvider};
 use fs::cache_key_path;
 
 /// The suffix on the end of a public sig/box file
@@ -250,8 +417,19 @@ pub static SIG_HASH_TYPE: &'static str = "BLAKE2b";
 /// at runtime. This is useful for testing.
 pub static CACHE_KEY_PATH_ENV_VAR: &'static str = "HAB_CACHE_KEY_PATH";
 pub static HART_FORMAT_VERSION: &'static str = "HART-1";
+pub static MULTI_SIG_HART_FORMAT_VERSION: &'static str = "HART-2";
+pub static VERSIONED_HART_FORMAT_VERSION: &'static str = "HART-3";
+pub static DETACHED_SIG_FORMAT_VERSION: &'static str = "HART-SIG-1";
 pub static BOX_FORMAT_VERSION: &'static str = "BOX-1";
 pub static ANONYMOUS_BOX_FORMAT_VERSION: &'static str = "ANONYMOUS-BOX-1";
+pub static MULTI_BOX_FORMAT_VERSION: &'static str = "MULTI-BOX-1";
+pub static BOX_STREAM_FORMAT_VERSION: &'static str = "BOX-STREAM-1";
+pub static ANONYMOUS_BOX_STREAM_FORMAT_VERSION: &'static str = "ANONYMOUS-BOX-STREAM-1";
+pub static RING_STREAM_FORMAT_VERSION: &'static str = "RING-STREAM-1";
+/// The version of the format of messages encrypted with a ring key
+pub const RING_MESSAGE_FORMAT_VERSION: u8 = 1;
+/// The size of the plaintext chunks sealed into each frame of an encrypted stream
+pub const BOX_STREAM_CHUNK_SIZE: usize = 64 * 1024;
 /// Create secret key files with these permissions
 #[cfg(not(windows))]
 static KEY_PERMISSIONS: u32 = 0o400;
@@ -265,8 +443,11 @@ pub const SECRET_SYM_KEY_VERSION: &'static str = "SYM-SEC-1";
 pub mod artifact;
 #[cfg(windows)]
 pub mod dpapi;
+pub mod fips;
 pub mod hash;
 pub mod keys;
+pub mod provider;
+pub mod secrets;
 
 pub fn default_cache_key_path(fs_root_path: Option<&Path>) -> PathBuf {
     match henv::var(CACHE_KEY_PATH_ENV_VAR) {
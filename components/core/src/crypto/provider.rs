@@ -0,0 +1,188 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pluggable signing and hashing.
+//!
+//! Code which hashes or signs through a `CryptoProvider` can be exercised in unit tests with
+//! `FakeCryptoProvider`, which is deterministic, needs no key material, and never touches
+//! libsodium. `SodiumCryptoProvider` is the real implementation and is what the rest of this crate
+//! uses by default.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use hex;
+use sodiumoxide::crypto::sign;
+
+use super::{hash, SigKeyPair};
+use error::{Error, Result};
+
+pub trait CryptoProvider {
+    /// Returns the hex encoded hash of a file's content.
+    fn hash_file(&self, path: &Path) -> Result<String>;
+
+    /// Returns the hex encoded hash of some bytes, failing if the hash algorithm isn't allowed in
+    /// FIPS mode.
+    fn hash_bytes(&self, data: &[u8]) -> Result<String>;
+
+    /// Signs a message with the secret key of `pair`, returning the signed message.
+    fn sign(&self, message: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>>;
+
+    /// Verifies a signed message with the public key of `pair`, returning the original message.
+    fn verify(&self, signed: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>>;
+}
+
+/// The `CryptoProvider` backed by libsodium: BLAKE2b hashes and Ed25519 signatures.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SodiumCryptoProvider;
+
+impl CryptoProvider for SodiumCryptoProvider {
+    fn hash_file(&self, path: &Path) -> Result<String> {
+        hash::hash_file(path)
+    }
+
+    fn hash_bytes(&self, data: &[u8]) -> Result<String> {
+        hash::hash_bytes_with(data, hash::HashAlgorithm::Blake2b)
+    }
+
+    fn sign(&self, message: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>> {
+        Ok(sign::sign(message, pair.secret()?))
+    }
+
+    fn verify(&self, signed: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>> {
+        sign::verify(signed, pair.public()?)
+            .map_err(|_| Error::CryptoError("Verification failed".to_string()))
+    }
+}
+
+const FAKE_SIGNATURE_PREFIX: &'static str = "FAKE-SIG";
+
+/// A deterministic `CryptoProvider` for tests. Hashes are a cheap FNV-1a digest, which is not
+/// collision resistant, and a "signature" is the message prefixed with the name of the signing
+/// key, so key pairs need neither a public nor a secret key.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FakeCryptoProvider;
+
+impl FakeCryptoProvider {
+    fn digest(data: &[u8]) -> String {
+        let mut state: u64 = 0xcbf29ce484222325;
+        for byte in data {
+            state ^= *byte as u64;
+            state = state.wrapping_mul(0x100000001b3);
+        }
+        let mut out = Vec::with_capacity(32);
+        for i in 0..4 {
+            let word = state.rotate_left(i * 16);
+            for shift in 0..8 {
+                out.push((word >> (shift * 8)) as u8);
+            }
+        }
+        hex::encode(out)
+    }
+}
+
+impl CryptoProvider for FakeCryptoProvider {
+    fn hash_file(&self, path: &Path) -> Result<String> {
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+        Ok(Self::digest(&data))
+    }
+
+    fn hash_bytes(&self, data: &[u8]) -> Result<String> {
+        Ok(Self::digest(data))
+    }
+
+    fn sign(&self, message: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>> {
+        let mut signed =
+            format!("{}:{}\n", FAKE_SIGNATURE_PREFIX, pair.name_with_rev()).into_bytes();
+        signed.extend_from_slice(message);
+        Ok(signed)
+    }
+
+    fn verify(&self, signed: &[u8], pair: &SigKeyPair) -> Result<Vec<u8>> {
+        let header = format!("{}:{}\n", FAKE_SIGNATURE_PREFIX, pair.name_with_rev()).into_bytes();
+        if signed.starts_with(&header) {
+            Ok(signed[header.len()..].to_vec())
+        } else {
+            Err(Error::CryptoError("Verification failed".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_support::*;
+    use super::*;
+
+    #[test]
+    fn sodium_provider_matches_hash_module() {
+        let provider = SodiumCryptoProvider;
+        assert_eq!(
+            provider.hash_file(&fixture("signme.dat")).unwrap(),
+            hash::hash_file(&fixture("signme.dat")).unwrap()
+        );
+        assert_eq!(
+            provider.hash_bytes(b"hello").unwrap(),
+            hash::hash_bytes(b"hello")
+        );
+    }
+
+    #[test]
+    fn sodium_provider_sign_and_verify() {
+        let provider = SodiumCryptoProvider;
+        let pair = SigKeyPair::generate_pair_for_origin("unicorn").unwrap();
+        let signed = provider.sign(b"hello", &pair).unwrap();
+        assert_eq!(provider.verify(&signed, &pair).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn fake_provider_is_deterministic() {
+        let provider = FakeCryptoProvider;
+        let hash = provider.hash_bytes(b"hello").unwrap();
+        assert_eq!(provider.hash_bytes(b"hello").unwrap(), hash);
+        assert_ne!(provider.hash_bytes(b"hellp").unwrap(), hash);
+        assert_eq!(hash.len(), 64);
+
+        let pair = SigKeyPair::new(
+            "unicorn".to_string(),
+            "20160517220007".to_string(),
+            None,
+            None,
+        );
+        let signed = provider.sign(b"hello", &pair).unwrap();
+        assert_eq!(provider.sign(b"hello", &pair).unwrap(), signed);
+        assert_eq!(provider.verify(&signed, &pair).unwrap(), b"hello");
+    }
+
+    #[test]
+    #[should_panic(expected = "Verification failed")]
+    fn fake_provider_rejects_other_signers() {
+        let provider = FakeCryptoProvider;
+        let signer = SigKeyPair::new(
+            "unicorn".to_string(),
+            "20160517220007".to_string(),
+            None,
+            None,
+        );
+        let other = SigKeyPair::new(
+            "unicorn".to_string(),
+            "20170101000000".to_string(),
+            None,
+            None,
+        );
+        let signed = provider.sign(b"hello", &signer).unwrap();
+        provider.verify(&signed, &other).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Named secrets for a service, stored encrypted under the service's data path.
+//!
+//! Each secret is sealed to the service group's box key (ex: `redis.default@acme`) as an
+//! anonymous box and written to its own file, `<name>.secret`, in the store's directory. Only
+//! the holder of the service group's secret key can read a secret back, so hooks and templates
+//! can use a `SecretStore` instead of leaving plaintext files on disk. A secret which was written
+//! before the service key was rotated is still readable as long as the older key revision is in
+//! the key cache.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::BoxKeyPair;
+use error::{Error, Result};
+use fs as hfs;
+
+/// The suffix on the end of a file holding an encrypted secret
+pub static SECRET_SUFFIX: &'static str = "secret";
+/// Create secret files with these permissions
+#[cfg(not(windows))]
+const SECRET_PERMISSIONS: u32 = 0o600;
+
+#[derive(Clone, Debug)]
+pub struct SecretStore {
+    path: PathBuf,
+    key_name: String,
+    cache_key_path: PathBuf,
+}
+
+impl SecretStore {
+    /// Returns a store of secrets kept in `path`, encrypted to the latest revision of the box key
+    /// named `key_name` found in `cache_key_path`.
+    pub fn new<P1, P2>(path: P1, key_name: &str, cache_key_path: P2) -> Self
+    where
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        SecretStore {
+            path: path.into(),
+            key_name: key_name.to_string(),
+            cache_key_path: cache_key_path.into(),
+        }
+    }
+
+    /// Returns the store of secrets for a service, or one instance of it, kept under the data
+    /// path of the service and encrypted to its service group key, ex: `redis.default@acme`.
+    ///
+    /// # Failures
+    ///
+    /// * The service name or the instance can't name a service directory
+    pub fn for_service<P, T>(
+        service_name: &str,
+        instance: Option<&str>,
+        service_key_name: &str,
+        cache_key_path: P,
+        fs_root_path: Option<T>,
+    ) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+        T: AsRef<Path>,
+    {
+        Ok(Self::new(
+            hfs::svc_data_path(service_name, instance, fs_root_path)?.join("secrets"),
+            service_key_name,
+            cache_key_path,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Encrypts `value` and stores it under `name`, replacing any existing secret of that name.
+    pub fn put(&self, name: &str, value: &[u8]) -> Result<()> {
+        validate_name(name)?;
+        let pair = BoxKeyPair::get_latest_pair_for(&self.key_name, &self.cache_key_path)?;
+        let ciphertext = pair.encrypt(value, None)?;
+
+        fs::create_dir_all(&self.path)?;
+        // Write the whole secret before renaming it into place so readers never see a partial one
+        let tmp_path = self.path.join(format!(".{}.tmp", name));
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&ciphertext)?;
+            file.sync_all()?;
+        }
+        set_permissions(&tmp_path)?;
+        fs::rename(&tmp_path, self.secret_path(name))?;
+        debug!("Stored secret {} in {}", name, self.path.display());
+        Ok(())
+    }
+
+    /// Reads and decrypts the secret stored under `name`.
+    pub fn get(&self, name: &str) -> Result<Vec<u8>> {
+        validate_name(name)?;
+        let path = self.secret_path(name);
+        let mut file = File::open(&path).map_err(|e| {
+            Error::CryptoError(format!(
+                "Can't read secret {} from {}: {}",
+                name,
+                path.display(),
+                e
+            ))
+        })?;
+        let mut payload = Vec::new();
+        file.read_to_end(&mut payload)?;
+        BoxKeyPair::decrypt_with_path(&payload, &self.cache_key_path)
+    }
+
+    /// Returns the names of every secret in the store, sorted by name.
+    pub fn list(&self) -> Result<Vec<String>> {
+        if !self.path.is_dir() {
+            return Ok(Vec::new());
+        }
+        let suffix = format!(".{}", SECRET_SUFFIX);
+        let mut names = Vec::new();
+        for dir_entry in fs::read_dir(&self.path)? {
+            let file_name = dir_entry?.file_name();
+            match file_name.to_str() {
+                Some(file_name) if file_name.ends_with(&suffix) => {
+                    names.push(file_name[..file_name.len() - suffix.len()].to_string())
+                }
+                Some(_) => continue,
+                None => debug!("Invalid filename {:?}", file_name),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn secret_path(&self, name: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", name, SECRET_SUFFIX))
+    }
+}
+
+/// Secret names become file names, so they are limited to characters which are safe in a path.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::CryptoError(format!("Invalid secret name: {}", name)))
+    }
+}
+
+#[cfg(not(windows))]
+fn set_permissions<T: AsRef<Path>>(path: T) -> Result<()> {
+    use util::posix_perm;
+
+    posix_perm::set_permissions(path.as_ref(), SECRET_PERMISSIONS)
+}
+
+#[cfg(windows)]
+fn set_permissions<T: AsRef<Path>>(path: T) -> Result<()> {
+    use util::win_perm;
+
+    win_perm::harden_path(path.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::Builder;
+
+    use super::super::keys::rotation::rotate_service_key;
+    use super::super::test_support::*;
+    use super::*;
+
+    #[test]
+    fn put_get_and_list_secrets() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        BoxKeyPair::generate_pair_for_service("acme", "redis.default")
+            .unwrap()
+            .to_pair_files(cache.path())
+            .unwrap();
+        let store = SecretStore::for_service(
+            "redis",
+            None,
+            "redis.default@acme",
+            cache.path(),
+            Some(fs_root.path()),
+        )
+        .unwrap();
+        assert!(store.list().unwrap().is_empty());
+
+        store.put("db_password", b"hunter2").unwrap();
+        store.put("api.token", b"abc123").unwrap();
+        store.put("db_password", b"correct horse").unwrap();
+
+        assert_eq!(store.get("db_password").unwrap(), b"correct horse");
+        assert_eq!(store.get("api.token").unwrap(), b"abc123");
+        assert_eq!(
+            store.list().unwrap(),
+            vec!["api.token".to_string(), "db_password".to_string()]
+        );
+        let raw = fs::read(store.path().join("db_password.secret")).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("correct horse"));
+    }
+
+    #[test]
+    fn secrets_survive_key_rotation() {
+        let dir = Builder::new().prefix("secrets").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        BoxKeyPair::generate_pair_for_service("acme", "redis.default")
+            .unwrap()
+            .to_pair_files(cache.path())
+            .unwrap();
+        let store = SecretStore::new(dir.path(), "redis.default@acme", cache.path());
+        store.put("db_password", b"hunter2").unwrap();
+
+        if wait_until_ok(|| rotate_service_key("acme", "redis.default", cache.path())).is_none() {
+            panic!("Failed to rotate the service key after waiting");
+        }
+        assert_eq!(store.get("db_password").unwrap(), b"hunter2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid secret name: ../escape")]
+    fn put_invalid_secret_name() {
+        let dir = Builder::new().prefix("secrets").tempdir().unwrap();
+        let store = SecretStore::new(dir.path(), "redis.default@acme", dir.path());
+        store.put("../escape", b"nope").unwrap();
+    }
+}
@@ -35,7 +35,12 @@ pub type Result<T> = result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     /// Occurs when a `habitat_core::package::PackageArchive` is being read.
+    /// Occurs when the payload of a package archive is compressed in a format which can't be
+    /// read, or in no format known at all.
+    ArchiveCompressionUnsupported(String),
     ArchiveError(libarchive::error::ArchiveError),
+    /// Occurs when a `habitat_core::package::PackageArchive` is being written.
+    ArchiveWriteFailed(String),
     BadBindingMode(String),
     /// An invalid path to a keyfile was given.
     BadKeyPath(String),
@@ -88,12 +93,18 @@ pub enum Error {
     CryptProtectDataFailed(String),
     /// Occurs when a call to CryptUnprotectData fails
     CryptUnprotectDataFailed(String),
+    /// Occurs when the dependencies of a set of packages form a cycle.
+    DependencyCycle(Vec<package::PackageIdent>),
     /// Occurs when a file that should exist does not or could not be read.
     FileNotFound(String),
     /// Occurs when a fully-qualified package identifier is required,
     /// but a non-qualified identifier (e.g. "foo/bar" or
     /// "foo/bar/1.0.0") was given instead.
     FullyQualifiedPackageIdentRequired(String),
+    /// Occurs when a package's install hook can't be run or fails.
+    InstallHookFailed(String),
+    /// Occurs when a filesystem hasn't the free space or inodes an operation needs.
+    InsufficientSpace(String),
     /// Occurs when an application environment string cannot be successfully parsed.
     InvalidApplicationEnvironment(String),
     /// Occurs when a package identifier string cannot be successfully parsed.
@@ -104,10 +115,14 @@ pub enum Error {
     InvalidPackageType(String),
     /// Occurs when a service group string cannot be successfully parsed.
     InvalidServiceGroup(String),
+    /// Occurs when a service name or instance can't name a directory of its own under `SVC_ROOT`.
+    InvalidServiceName(String),
     /// Occurs when an origin is in an invalid format
     InvalidOrigin(String),
     /// Occurs when an OsString path cannot be converted to a String
     InvalidPathString(ffi::OsString),
+    /// Occurs when a package version constraint string cannot be successfully parsed.
+    InvalidVersionConstraint(String),
     /// Occurs when making lower level IO calls.
     IO(io::Error),
     /// Errors when joining paths :)
@@ -149,6 +164,9 @@ pub enum Error {
     TargetMatchError(String),
     /// Occurs when a `uname` libc call returns an error.
     UnameFailed(String),
+    /// When a path from an untrusted source would lead outside of the directory it is joined
+    /// onto.
+    UnsafePath(String),
     /// Occurs when a `waitpid` libc call returns an error.
     WaitpidFailed(String),
     /// Occurs when a `kill` libc call returns an error.
@@ -161,6 +179,14 @@ pub enum Error {
     WaitForSingleObjectFailed(String),
     /// Occurs when a `TerminateProcess` win32 call returns an error.
     TerminateProcessFailed(String),
+    /// Occurs when a Job Object can't be created, limited, or given a process.
+    JobObjectFailed(String),
+    /// Occurs when a cgroup can't be created, limited, joined, or removed.
+    CgroupFailed(String),
+    /// Occurs when capabilities can't be named or dropped.
+    CapabilitiesFailed(String),
+    /// Occurs when a user or group can't be created.
+    UserProvisioningFailed(String),
     /// When an error occurs attempting to interpret a sequence of u8 as a string.
     Utf8Error(str::Utf8Error),
     /// When a `PackageTaget` for a package does not match the active `PackageTarget` for this
@@ -171,7 +197,12 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg = match *self {
+            Error::ArchiveCompressionUnsupported(ref format) => format!(
+                "Package archive payload is compressed with {}, which is not supported",
+                format
+            ),
             Error::ArchiveError(ref err) => format!("{}", err),
+            Error::ArchiveWriteFailed(ref e) => format!("Failed to write package archive: {}", e),
             Error::BadBindingMode(ref value) => format!("Unknown binding mode '{}'", value),
             Error::BadKeyPath(ref e) => format!(
                 "Invalid keypath: {}. Specify an absolute path to a file on disk.",
@@ -257,11 +288,17 @@ impl fmt::Display for Error {
             Error::CryptoError(ref e) => format!("Crypto error: {}", e),
             Error::CryptProtectDataFailed(ref e) => format!("{}", e),
             Error::CryptUnprotectDataFailed(ref e) => format!("{}", e),
+            Error::DependencyCycle(ref idents) => {
+                let idents: Vec<String> = idents.iter().map(|i| i.to_string()).collect();
+                format!("Dependency cycle between packages: {}", idents.join(" -> "))
+            }
             Error::FileNotFound(ref e) => format!("File not found at: {}", e),
             Error::FullyQualifiedPackageIdentRequired(ref ident) => format!(
                 "Fully-qualified package identifier was expected, but found: {:?}",
                 ident
             ),
+            Error::InstallHookFailed(ref e) => format!("Install hook failed: {}", e),
+            Error::InsufficientSpace(ref e) => format!("Not enough free space: {}", e),
             Error::InvalidApplicationEnvironment(ref e) => format!(
                 "Invalid application environment: {}. A valid application environment string \
                  is in the form application.environment (example: twitter.prod)",
@@ -283,6 +320,11 @@ impl fmt::Display for Error {
                  service.group (example: redis.production)",
                 e
             ),
+            Error::InvalidServiceName(ref e) => format!(
+                "Invalid service name or instance: {}. Allowed characters include a - z, \
+                 A - Z, 0 - 9, _, and -",
+                e
+            ),
             Error::InvalidOrigin(ref origin) => format!(
                 "Invalid origin: {}. Origins must begin with a lowercase letter or number. \
                  Allowed characters include lowercase letters, numbers, -, and _. \
@@ -292,6 +334,12 @@ impl fmt::Display for Error {
             Error::InvalidPathString(ref s) => {
                 format!("Could not generate String from path: {:?}", s)
             }
+            Error::InvalidVersionConstraint(ref e) => format!(
+                "Invalid version constraint: {:?}. A valid constraint is a comma-separated \
+                 list of versions with an optional =, >, >=, <, <=, or ~ operator \
+                 (example: >=1.2, <2.0)",
+                e
+            ),
             Error::IO(ref err) => format!("{}", err),
             Error::JoinPathsError(ref err) => format!("{}", err),
             Error::LogonTypeNotGranted => format!(
@@ -329,6 +377,12 @@ impl fmt::Display for Error {
             Error::StringFromUtf8Error(ref e) => format!("{}", e),
             Error::TargetMatchError(ref e) => format!("{}", e),
             Error::UnameFailed(ref e) => format!("{}", e),
+            Error::UnsafePath(ref e) => {
+                format!(
+                    "Refusing the path {}, which leads outside of its directory",
+                    e
+                )
+            }
             Error::WaitpidFailed(ref e) => format!("{}", e),
             Error::SignalFailed(ref r, ref e) => {
                 format!("Failed to send a signal to the child process: {}, {}", r, e)
@@ -337,6 +391,10 @@ impl fmt::Display for Error {
             Error::CreateToolhelp32SnapshotFailed(ref e) => format!("{}", e),
             Error::WaitForSingleObjectFailed(ref e) => format!("{}", e),
             Error::TerminateProcessFailed(ref e) => format!("{}", e),
+            Error::JobObjectFailed(ref e) => format!("{}", e),
+            Error::CgroupFailed(ref e) => format!("{}", e),
+            Error::CapabilitiesFailed(ref e) => format!("{}", e),
+            Error::UserProvisioningFailed(ref e) => format!("{}", e),
             Error::Utf8Error(ref e) => format!("{}", e),
             Error::WrongActivePackageTarget(ref active, ref wrong) => format!(
                 "Package target '{}' is not supported as this system has a different \
@@ -351,7 +409,11 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::ArchiveCompressionUnsupported(_) => {
+                "Package archive payload compression is not supported"
+            }
             Error::ArchiveError(ref err) => err.description(),
+            Error::ArchiveWriteFailed(_) => "Failed to write package archive",
             Error::BadBindingMode(_) => "Unknown binding mode",
             Error::BadKeyPath(_) => "An absolute path to a file on disk is required",
             Error::CompositePackageExpected(_) => "A composite package was expected",
@@ -418,10 +480,13 @@ impl error::Error for Error {
             Error::CryptoError(_) => "Crypto error",
             Error::CryptProtectDataFailed(_) => "CryptProtectData failed",
             Error::CryptUnprotectDataFailed(_) => "CryptUnprotectData failed",
+            Error::DependencyCycle(_) => "The dependencies of a set of packages form a cycle",
             Error::FileNotFound(_) => "File not found",
             Error::FullyQualifiedPackageIdentRequired(_) => {
                 "A fully-qualified package identifier was expected"
             }
+            Error::InstallHookFailed(_) => "Install hook failed",
+            Error::InsufficientSpace(_) => "Not enough free space on a filesystem",
             Error::InvalidApplicationEnvironment(_) => {
                 "Application environment strings must be in \
                  application.environment format (example: twitter.prod)"
@@ -436,11 +501,18 @@ impl error::Error for Error {
             Error::InvalidServiceGroup(_) => {
                 "Service group strings must be in service.group[@organization] format (example: redis.production or foo.default@bazcorp)"
             }
+            Error::InvalidServiceName(_) => {
+                "Service names and instances may only contain a - z, A - Z, 0 - 9, _, and -"
+            }
             Error::InvalidOrigin(_) => {
                 "Origins must begin with a lowercase letter or number.  \
                  Allowed characters include a - z, 0 - 9, _, and -. No more than 255 characters."
             }
             Error::InvalidPathString(_) => "Failed to convert an OsString Path to a String",
+            Error::InvalidVersionConstraint(_) => {
+                "Version constraints must be comma-separated versions with optional operators \
+                 (example: >=1.2, <2.0)"
+            }
             Error::IO(ref err) => err.description(),
             Error::JoinPathsError(ref err) => err.description(),
             Error::LogonTypeNotGranted => {
@@ -465,12 +537,17 @@ impl error::Error for Error {
             Error::StringFromUtf8Error(_) => "Failed to convert a string from a Vec<u8> as UTF-8",
             Error::TargetMatchError(_) => "System target does not match package target",
             Error::UnameFailed(_) => "uname failed",
+            Error::UnsafePath(_) => "Path leads outside of its directory",
             Error::SignalFailed(_, _) => "Failed to send a signal to the child process",
             Error::CreateToolhelp32SnapshotFailed(_) => "CreateToolhelp32Snapshot failed",
             Error::WaitpidFailed(_) => "waitpid failed",
             Error::GetExitCodeProcessFailed(_) => "GetExitCodeProcess failed",
             Error::WaitForSingleObjectFailed(_) => "WaitForSingleObjectFailed failed",
             Error::TerminateProcessFailed(_) => "Failed to call TerminateProcess",
+            Error::JobObjectFailed(_) => "Failed to set up a Job Object",
+            Error::CgroupFailed(_) => "Failed to set up a cgroup",
+            Error::CapabilitiesFailed(_) => "Failed to drop capabilities",
+            Error::UserProvisioningFailed(_) => "Failed to create a user or group",
             Error::Utf8Error(_) => "Failed to interpret a sequence of bytes as a string",
             Error::WrongActivePackageTarget(_, _) => {
                 "Package target is not supported as this system has a different \
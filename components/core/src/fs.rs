@@ -13,16 +13,30 @@
 // limitations under the License.
 
 use dirs;
+use std::collections::HashMap;
 use std::env;
+#[cfg(not(windows))]
+use std::ffi::{CStr, CString};
+use std::fs::{self as stdfs, File};
 use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
+use tempfile::{Builder, NamedTempFile, TempDir};
 use users;
 
 use env as henv;
-use error::Result;
-use package::{Identifiable, PackageIdent, PackageInstall};
+use error::{Error, Result};
+#[cfg(not(windows))]
+use libc;
+use package::{FullyQualifiedPackageIdent, PackageIdent, PackageInstall};
+
+pub use os::watch::{watch, watch_with_delay, WatchEvent, Watcher};
 
 /// The default root path of the Habitat filesystem
 pub const ROOT_PATH: &'static str = "hab";
@@ -34,8 +48,16 @@ pub const CACHE_ARTIFACT_PATH: &'static str = "hab/cache/artifacts";
 pub const CACHE_KEY_PATH: &'static str = "hab/cache/keys";
 /// The default path where source artifacts are downloaded, extracted, & compiled
 pub const CACHE_SRC_PATH: &'static str = "hab/cache/src";
+/// The default path where temporary files and directories are made in the cache
+pub const CACHE_TMP_PATH: &'static str = "hab/cache/tmp";
+/// The prefix of the temporary files and directories made in the cache
+pub const CACHE_TMP_PREFIX: &'static str = ".hab-tmp";
 /// The default path where SSL-related artifacts are placed
 pub const CACHE_SSL_PATH: &'static str = "hab/cache/ssl";
+/// The content-addressed store which installed packages share file content through
+pub const CONTENT_STORE_PATH: &'static str = "hab/store";
+/// The file listing the package releases which are held
+pub const HOLDS_PATH: &'static str = "hab/holds";
 /// The root path for the launcher runtime
 pub const LAUNCHER_ROOT_PATH: &'static str = "hab/launcher";
 /// The root path containing all locally installed packages
@@ -45,6 +67,8 @@ pub const LAUNCHER_ROOT_PATH: &'static str = "hab/launcher";
 pub const PKG_PATH: &'static str = "hab/pkgs";
 #[cfg(target_os = "windows")]
 pub const PKG_PATH: &'static str = "hab\\pkgs";
+/// The root path containing all runtime service directories and files
+pub const SVC_ROOT: &'static str = "hab/svc";
 /// The environment variable pointing to the filesystem root. This exists for internal
 /// Habitat team usage and is not intended to be used by Habitat consumers.
 /// Using this variable could lead to broken Supervisor services and it should
@@ -96,134 +120,2013 @@ lazy_static! {
         }
     };
 
-    static ref MY_CACHE_SRC_PATH: PathBuf = {
-        if am_i_root() {
-            PathBuf::from(CACHE_SRC_PATH)
-        } else {
-            match dirs::home_dir() {
-                Some(home) => home.join(format!(".{}", CACHE_SRC_PATH)),
-                None => PathBuf::from(CACHE_SRC_PATH),
+    static ref MY_CACHE_SRC_PATH: PathBuf = {
+        if am_i_root() {
+            PathBuf::from(CACHE_SRC_PATH)
+        } else {
+            match dirs::home_dir() {
+                Some(home) => home.join(format!(".{}", CACHE_SRC_PATH)),
+                None => PathBuf::from(CACHE_SRC_PATH),
+            }
+        }
+    };
+
+    static ref MY_CACHE_SSL_PATH: PathBuf = {
+        if am_i_root() {
+            PathBuf::from(CACHE_SSL_PATH)
+        } else {
+            match dirs::home_dir() {
+                Some(home) => home.join(format!(".{}", CACHE_SSL_PATH)),
+                None => PathBuf::from(CACHE_SSL_PATH),
+            }
+        }
+    };
+
+    static ref MY_CACHE_TMP_PATH: PathBuf = {
+        if am_i_root() {
+            PathBuf::from(CACHE_TMP_PATH)
+        } else {
+            match dirs::home_dir() {
+                Some(home) => home.join(format!(".{}", CACHE_TMP_PATH)),
+                None => PathBuf::from(CACHE_TMP_PATH),
+            }
+        }
+    };
+}
+
+/// A Habitat filesystem root. Paths under a root are worked out from the handle rather than from
+/// the process-wide `FS_ROOT_PATH`, so several roots can be worked with side by side in one
+/// process, such as by an exporter assembling a root for a container. A handle can also be given
+/// wherever a path function takes an optional filesystem root.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FsRoot {
+    path: PathBuf,
+}
+
+impl FsRoot {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FsRoot { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn cache_artifact_path(&self) -> PathBuf {
+        cache_artifact_path(Some(self))
+    }
+
+    pub fn cache_key_path(&self) -> PathBuf {
+        cache_key_path(Some(self))
+    }
+
+    pub fn cache_tmp_path(&self) -> PathBuf {
+        cache_tmp_path(Some(self))
+    }
+
+    pub fn content_store_path(&self) -> PathBuf {
+        content_store_path(Some(self))
+    }
+
+    pub fn holds_path(&self) -> PathBuf {
+        holds_path(Some(self))
+    }
+
+    pub fn launcher_root_path(&self) -> PathBuf {
+        launcher_root_path(Some(self))
+    }
+
+    pub fn pkg_root_path(&self) -> PathBuf {
+        pkg_root_path(Some(self))
+    }
+
+    pub fn pkg_install_path(&self, ident: &FullyQualifiedPackageIdent) -> PkgInstallDir {
+        pkg_install_path(ident, Some(self))
+    }
+
+    pub fn svc_path(&self, service_name: &str, instance: Option<&str>) -> Result<SvcDir> {
+        svc_path(service_name, instance, Some(self))
+    }
+
+    pub fn svc_config_path(
+        &self,
+        service_name: &str,
+        instance: Option<&str>,
+    ) -> Result<SvcConfigDir> {
+        svc_config_path(service_name, instance, Some(self))
+    }
+
+    pub fn svc_data_path(&self, service_name: &str, instance: Option<&str>) -> Result<PathBuf> {
+        svc_data_path(service_name, instance, Some(self))
+    }
+
+    pub fn svc_hooks_path(
+        &self,
+        service_name: &str,
+        instance: Option<&str>,
+    ) -> Result<SvcHooksDir> {
+        svc_hooks_path(service_name, instance, Some(self))
+    }
+
+    pub fn svc_logs_path(&self, service_name: &str, instance: Option<&str>) -> Result<PathBuf> {
+        svc_logs_path(service_name, instance, Some(self))
+    }
+
+    pub fn svc_var_path(&self, service_name: &str, instance: Option<&str>) -> Result<PathBuf> {
+        svc_var_path(service_name, instance, Some(self))
+    }
+}
+
+/// The root of the process, which is `FS_ROOT_PATH`.
+impl Default for FsRoot {
+    fn default() -> Self {
+        FsRoot::new(&*FS_ROOT_PATH)
+    }
+}
+
+impl AsRef<Path> for FsRoot {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Declares a newtype over `PathBuf` for one kind of well-known location. Only the path functions
+/// of this module make them, so an API which takes one can be sure of what kind of directory it
+/// was given, while it still reads as a `Path` everywhere else.
+macro_rules! dir_newtype {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        pub struct $name(PathBuf);
+
+        impl $name {
+            pub fn as_path(&self) -> &Path {
+                &self.0
+            }
+
+            pub fn into_path_buf(self) -> PathBuf {
+                self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Path;
+
+            fn deref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl AsRef<Path> for $name {
+            fn as_ref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl From<$name> for PathBuf {
+            fn from(dir: $name) -> PathBuf {
+                dir.0
+            }
+        }
+
+        impl PartialEq<Path> for $name {
+            fn eq(&self, other: &Path) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl<'a> PartialEq<&'a Path> for $name {
+            fn eq(&self, other: &&'a Path) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialEq<PathBuf> for $name {
+            fn eq(&self, other: &PathBuf) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+dir_newtype!(
+    /// A service's directory under `SVC_ROOT`, as returned by `svc_path`.
+    SvcDir
+);
+
+dir_newtype!(
+    /// A service's rendered configuration directory, as returned by `svc_config_path`.
+    SvcConfigDir
+);
+
+dir_newtype!(
+    /// A service's rendered hooks directory, as returned by `svc_hooks_path`.
+    SvcHooksDir
+);
+
+dir_newtype!(
+    /// An installed package's directory, as returned by `pkg_install_path`.
+    PkgInstallDir
+);
+
+impl SvcDir {
+    /// Returns the service's rendered configuration directory.
+    pub fn config_dir(&self) -> SvcConfigDir {
+        SvcConfigDir(self.0.join("config"))
+    }
+
+    /// Returns the service's rendered hooks directory.
+    pub fn hooks_dir(&self) -> SvcHooksDir {
+        SvcHooksDir(self.0.join("hooks"))
+    }
+}
+
+impl SvcConfigDir {
+    /// Returns the directory of the service the configuration is for.
+    pub fn svc_dir(&self) -> SvcDir {
+        SvcDir(
+            self.0
+                .parent()
+                .expect("Config dirs are in a svc dir")
+                .to_path_buf(),
+        )
+    }
+}
+
+impl SvcHooksDir {
+    /// Returns the directory of the service the hooks are for.
+    pub fn svc_dir(&self) -> SvcDir {
+        SvcDir(
+            self.0
+                .parent()
+                .expect("Hooks dirs are in a svc dir")
+                .to_path_buf(),
+        )
+    }
+}
+
+/// Returns the path to the analytics cache, optionally taking a custom filesystem root.
+pub fn cache_analytics_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_ANALYTICS_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_ANALYTICS_PATH),
+    }
+}
+
+/// Returns the path to the artifacts cache, optionally taking a custom filesystem root.
+pub fn cache_artifact_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_ARTIFACT_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_ARTIFACT_PATH),
+    }
+}
+
+/// Returns the path to the keys cache, optionally taking a custom filesystem root.
+pub fn cache_key_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_KEY_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_KEY_PATH),
+    }
+}
+
+/// Returns the path to the src cache, optionally taking a custom filesystem root.
+pub fn cache_src_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_SRC_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_SRC_PATH),
+    }
+}
+
+/// Returns the path where temporary files and directories are made in the cache, optionally
+/// taking a custom filesystem root.
+pub fn cache_tmp_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_TMP_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_TMP_PATH),
+    }
+}
+
+/// Returns the path to the SSL cache, optionally taking a custom filesystem root.
+pub fn cache_ssl_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_SSL_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_SSL_PATH),
+    }
+}
+
+pub fn pkg_root_path<T>(fs_root: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    let mut buf = fs_root.map_or(PathBuf::from("/"), |p| p.as_ref().into());
+    buf.push(PKG_PATH);
+    buf
+}
+
+pub fn pkg_install_path<T>(ident: &FullyQualifiedPackageIdent, fs_root: Option<T>) -> PkgInstallDir
+where
+    T: AsRef<Path>,
+{
+    let mut pkg_path = pkg_root_path(fs_root);
+    pkg_path.push(ident.origin());
+    pkg_path.push(ident.name());
+    pkg_path.push(ident.version());
+    pkg_path.push(ident.release());
+    PkgInstallDir(pkg_path)
+}
+
+/// Given a linux style absolute path (prepended with '/') and a fs_root,
+/// this will "re-root" the path just under the fs_root. Otherwise returns
+/// the given path unchanged. Non-Windows platforms will always return the
+/// unchanged path.
+pub fn fs_rooted_path<T>(path: &PathBuf, fs_root: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root {
+        Some(ref root) if path.starts_with("/") && cfg!(windows) => {
+            Path::new(root.as_ref()).join(path.strip_prefix("/").unwrap())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Returns the path to the content store, optionally taking a custom filesystem root.
+pub fn content_store_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(CONTENT_STORE_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(CONTENT_STORE_PATH),
+    }
+}
+
+/// Returns the path to the file listing held package releases, optionally taking a custom
+/// filesystem root.
+pub fn holds_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(HOLDS_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(HOLDS_PATH),
+    }
+}
+
+/// Return the path to the root of the launcher runtime directory
+pub fn launcher_root_path<T>(fs_root_path: Option<T>) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(LAUNCHER_ROOT_PATH),
+        None => Path::new(&*FS_ROOT_PATH).join(LAUNCHER_ROOT_PATH),
+    }
+}
+
+/// Returns the name of a service's directory under `SVC_ROOT`. It is the service's name, or for
+/// one of several instances of the same service, the name and the instance, ex: `redis.cache`.
+///
+/// # Failures
+///
+/// * The service name or the instance is empty, or has a character other than a letter, a digit,
+///   `_` or `-`, as it could then lead outside of `SVC_ROOT` or name another service's directory
+pub fn svc_dir_name(service_name: &str, instance: Option<&str>) -> Result<String> {
+    fn check(name: &str) -> Result<()> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error::InvalidServiceName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    check(service_name)?;
+    match instance {
+        Some(instance) => {
+            check(instance)?;
+            Ok(format!("{}.{}", service_name, instance))
+        }
+        None => Ok(service_name.to_string()),
+    }
+}
+
+/// Returns the root path for a given service's configuration, files, and data, optionally taking
+/// an instance of the service, so that several instances of one package each get their own
+/// hooks, logs, and data, and a custom filesystem root.
+///
+/// # Failures
+///
+/// * The service name or the instance isn't valid, as described for `svc_dir_name`
+pub fn svc_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<SvcDir>
+where
+    T: AsRef<Path>,
+{
+    let dir_name = svc_dir_name(service_name, instance)?;
+    Ok(SvcDir(match fs_root_path {
+        Some(fs_root_path) => fs_root_path.as_ref().join(SVC_ROOT).join(dir_name),
+        None => Path::new(&*FS_ROOT_PATH).join(SVC_ROOT).join(dir_name),
+    }))
+}
+
+/// Returns the path to a given service's rendered configuration.
+pub fn svc_config_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<SvcConfigDir>
+where
+    T: AsRef<Path>,
+{
+    Ok(svc_path(service_name, instance, fs_root_path)?.config_dir())
+}
+
+/// Returns the path to a given service's data.
+pub fn svc_data_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<PathBuf>
+where
+    T: AsRef<Path>,
+{
+    Ok(svc_path(service_name, instance, fs_root_path)?.join("data"))
+}
+
+/// Returns the path to a given service's rendered hooks.
+pub fn svc_hooks_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<SvcHooksDir>
+where
+    T: AsRef<Path>,
+{
+    Ok(svc_path(service_name, instance, fs_root_path)?.hooks_dir())
+}
+
+/// Returns the path to a given service's logs.
+pub fn svc_logs_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<PathBuf>
+where
+    T: AsRef<Path>,
+{
+    Ok(svc_path(service_name, instance, fs_root_path)?.join("logs"))
+}
+
+/// Returns the path to a given service's variable state.
+pub fn svc_var_path<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<PathBuf>
+where
+    T: AsRef<Path>,
+{
+    Ok(svc_path(service_name, instance, fs_root_path)?.join("var"))
+}
+
+/// Creates the data and var directories of a service if need be, and hands them and everything
+/// in them over to the user and group the service runs as. On Windows, where files have no
+/// group, the user is given full control instead.
+pub fn set_svc_dirs_owner<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    user: &str,
+    group: &str,
+    fs_root_path: Option<T>,
+) -> Result<()>
+where
+    T: AsRef<Path>,
+{
+    let svc_path = svc_path(service_name, instance, fs_root_path)?;
+    for dir in &[svc_path.join("data"), svc_path.join("var")] {
+        stdfs::create_dir_all(dir)?;
+        chown_r(dir, user, group, SymlinkPolicy::Link)?;
+    }
+    Ok(())
+}
+
+/// Writes a file so that it is never seen partly written. The content is written to a temporary
+/// file beside it and synced to disk, and the temporary file is then renamed over the file, so
+/// readers see either the old content or the new. The file gets the permission bits `mode`, which
+/// are ignored on Windows.
+///
+/// # Failures
+///
+/// * The temporary file can't be written, such as when the file's directory doesn't exist
+/// * The temporary file can't be renamed over the file
+pub fn atomic_write<P: AsRef<Path>>(path: P, bytes: &[u8], mode: u32) -> Result<()> {
+    static TMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let path = long_path(path);
+    let path = path.as_path();
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't write to {}, it has no file name", path.display()),
+            )
+            .into())
+        }
+    };
+    let tmp = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        file_name,
+        process::id(),
+        TMP_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let written = write_synced(&tmp, bytes, mode).and_then(|()| replace_file(&tmp, path));
+    if let Err(e) = written {
+        let _ = stdfs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    sync_parent(path);
+    Ok(())
+}
+
+fn write_synced(path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    set_file_mode(&file, mode)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+#[cfg(not(windows))]
+fn set_file_mode(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(stdfs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+fn set_file_mode(_file: &File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn replace_file(src: &Path, dst: &Path) -> io::Result<()> {
+    stdfs::rename(src, dst)
+}
+
+/// Renames `src` over `dst`, falling back to `ReplaceFileW` when the rename is refused, as it is
+/// when `dst` is open elsewhere without delete sharing.
+#[cfg(windows)]
+fn replace_file(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::um::winbase::ReplaceFileW;
+
+    match stdfs::rename(src, dst) {
+        Ok(()) => Ok(()),
+        Err(ref e) if dst.is_file() => {
+            debug!("Replacing {} after rename failed: {}", dst.display(), e);
+            let wide =
+                |p: &Path| -> Vec<u16> { p.as_os_str().encode_wide().chain(Some(0)).collect() };
+            let (dst_w, src_w) = (wide(dst), wide(src));
+            let replaced = unsafe {
+                ReplaceFileW(
+                    dst_w.as_ptr(),
+                    src_w.as_ptr(),
+                    ptr::null(),
+                    0,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            if replaced == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Syncs the directory of a file which was renamed into place, so the rename itself survives a
+/// crash. This is best effort, and does nothing on Windows, where directories can't be opened.
+#[cfg(not(windows))]
+fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(windows)]
+fn sync_parent(_path: &Path) {}
+
+/// How much of a directory of logs `prune_logs` keeps. A log which any limit rules out is
+/// removed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LogRetention {
+    /// Logs last written longer ago than this are removed
+    pub max_age: Option<Duration>,
+    /// The most room the logs may take up, in bytes. The least recently written logs are
+    /// removed until the rest fit.
+    pub max_total_bytes: Option<u64>,
+    /// How many of the most recently written logs are kept whatever the limits, such as the one
+    /// which is still being written to
+    pub keep_latest: usize,
+}
+
+/// What `prune_logs` removed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PruneReport {
+    pub removed: Vec<PathBuf>,
+    /// The room the removed logs took up, in bytes
+    pub freed: u64,
+}
+
+/// Removes the logs in `dir`, such as a service's `svc_logs_path`, which `retention` doesn't
+/// keep. Only the files directly in `dir` are logs; directories in it are left alone. A directory
+/// which doesn't exist has nothing to prune.
+///
+/// # Failures
+///
+/// * The directory can't be read, or a log in it can't be removed
+pub fn prune_logs<P: AsRef<Path>>(dir: P, retention: &LogRetention) -> Result<PruneReport> {
+    let dir = dir.as_ref();
+    let mut report = PruneReport::default();
+    if !dir.is_dir() {
+        return Ok(report);
+    }
+    let mut logs = Vec::new();
+    for entry in stdfs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::now());
+            logs.push((modified, entry.path(), metadata.len()));
+        }
+    }
+    // Most recently written first
+    logs.sort_by(|a, b| b.cmp(a));
+
+    let now = SystemTime::now();
+    let mut kept_bytes = 0;
+    for (i, (modified, path, len)) in logs.into_iter().enumerate() {
+        let too_old = match retention.max_age {
+            Some(max_age) => now.duration_since(modified).unwrap_or_default() > max_age,
+            None => false,
+        };
+        let too_big = match retention.max_total_bytes {
+            Some(max_total_bytes) => kept_bytes + len > max_total_bytes,
+            None => false,
+        };
+        if i < retention.keep_latest || !(too_old || too_big) {
+            kept_bytes += len;
+            continue;
+        }
+        stdfs::remove_file(&path)?;
+        debug!("Pruned log {}", path.display());
+        report.freed += len;
+        report.removed.push(path);
+    }
+    Ok(report)
+}
+
+/// A temporary file in the cache, which is on the same filesystem as the artifacts and keys kept
+/// beside it, so it can be renamed into place among them whole. It's removed when dropped unless
+/// it's persisted. The name of the file carries the pid of the process which made it, so one
+/// left behind by a process which crashed can be removed with `clean_cache_tmp`.
+#[derive(Debug)]
+pub struct TempFileInCache {
+    file: NamedTempFile,
+}
+
+impl TempFileInCache {
+    /// Makes a temporary file in the cache under `fs_root_path`, or `FS_ROOT_PATH` if it isn't
+    /// given, creating the cache's temporary directory if need be.
+    pub fn new<T: AsRef<Path>>(fs_root_path: Option<T>) -> Result<Self> {
+        let file =
+            cache_tmp_builder(&cache_tmp_prefix()).tempfile_in(ensure_cache_tmp(fs_root_path)?)?;
+        Ok(TempFileInCache { file: file })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        self.file.as_file_mut()
+    }
+
+    /// Renames the file to `path`, replacing whatever is there, and keeps it.
+    ///
+    /// # Failures
+    ///
+    /// * The file can't be renamed, such as when `path` is on another filesystem
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<File> {
+        Ok(self.file.persist(path).map_err(|e| e.error)?)
+    }
+}
+
+impl Write for TempFileInCache {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A temporary directory in the cache, which, like a `TempFileInCache`, can be renamed into place
+/// whole and is removed when dropped unless it's persisted.
+#[derive(Debug)]
+pub struct TempDirInCache {
+    dir: TempDir,
+}
+
+impl TempDirInCache {
+    /// Makes a temporary directory in the cache under `fs_root_path`, or `FS_ROOT_PATH` if it
+    /// isn't given, creating the cache's temporary directory if need be.
+    pub fn new<T: AsRef<Path>>(fs_root_path: Option<T>) -> Result<Self> {
+        let dir =
+            cache_tmp_builder(&cache_tmp_prefix()).tempdir_in(ensure_cache_tmp(fs_root_path)?)?;
+        Ok(TempDirInCache { dir: dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Renames the directory to `path`, which must not exist or be an empty directory, and keeps
+    /// it.
+    ///
+    /// # Failures
+    ///
+    /// * The directory can't be renamed, such as when `path` is on another filesystem
+    pub fn persist<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        stdfs::rename(self.dir.path(), path)?;
+        // The directory is gone from the cache now that it has been moved into place
+        let _ = self.dir.into_path();
+        Ok(())
+    }
+}
+
+/// Removes the temporary files and directories in the cache under `fs_root_path`, or
+/// `FS_ROOT_PATH` if it isn't given, which were left behind by processes which are no longer
+/// running, such as ones which crashed. It's meant to be called as a process starts, and returns
+/// how many were removed.
+///
+/// # Failures
+///
+/// * The cache's temporary directory can't be read
+pub fn clean_cache_tmp<T: AsRef<Path>>(fs_root_path: Option<T>) -> Result<usize> {
+    use os::process::{current_pid, is_alive, Pid};
+
+    let tmp_path = cache_tmp_path(fs_root_path);
+    if !tmp_path.is_dir() {
+        return Ok(0);
+    }
+    let own_pid = current_pid();
+    let mut removed = 0;
+    for entry in stdfs::read_dir(&tmp_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(CACHE_TMP_PREFIX) {
+            continue;
+        }
+        let pid = match name[CACHE_TMP_PREFIX.len()..]
+            .split('-')
+            .nth(1)
+            .and_then(|pid| pid.parse::<Pid>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == own_pid || is_alive(pid, None) {
+            continue;
+        }
+        let path = entry.path();
+        let result = if entry.file_type()?.is_dir() {
+            stdfs::remove_dir_all(&path)
+        } else {
+            stdfs::remove_file(&path)
+        };
+        match result {
+            Ok(()) => {
+                debug!("Removed stale temporary {}", path.display());
+                removed += 1;
+            }
+            Err(e) => debug!("Error removing stale temporary {}: {}", path.display(), e),
+        }
+    }
+    Ok(removed)
+}
+
+/// The prefix of the temporary files and directories this process makes in the cache, ex:
+/// `.hab-tmp-4242-`.
+fn cache_tmp_prefix() -> String {
+    format!("{}-{}-", CACHE_TMP_PREFIX, ::os::process::current_pid())
+}
+
+fn cache_tmp_builder(prefix: &str) -> Builder {
+    let mut builder = Builder::new();
+    builder.prefix(prefix);
+    builder
+}
+
+fn ensure_cache_tmp<T: AsRef<Path>>(fs_root_path: Option<T>) -> Result<PathBuf> {
+    let tmp_path = cache_tmp_path(fs_root_path);
+    stdfs::create_dir_all(&tmp_path)?;
+    Ok(tmp_path)
+}
+
+/// What a recursive change of ownership or permissions does with the symbolic links it comes
+/// across.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Leave links, and whatever they point to, alone
+    Skip,
+    /// Change the link itself but not what it points to. Links have no permissions of their own,
+    /// so `chmod_r` leaves them alone.
+    Link,
+    /// Change whatever the link points to, without descending into a directory it points to,
+    /// which may be anywhere on the filesystem
+    Follow,
+}
+
+/// What a recursive walk comes across: a directory, a file, or a link it was asked to change
+/// rather than follow.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Walked {
+    Dir,
+    File,
+    Link,
+}
+
+/// Changes the owner of `path`, and of everything under it, to `user` and `group`, doing with
+/// symbolic links as `symlinks` says. On Windows, where files have no group, `user` is given full
+/// control alongside Administrators and SYSTEM instead.
+///
+/// # Failures
+///
+/// * The user or group doesn't exist
+/// * The owner of something under `path` can't be changed
+pub fn chown_r<P: AsRef<Path>>(
+    path: P,
+    user: &str,
+    group: &str,
+    symlinks: SymlinkPolicy,
+) -> Result<()> {
+    let mut owner = Owner::lookup(user, group)?;
+    walk_r(path.as_ref(), symlinks, &mut |entry, walked| {
+        owner.apply(entry, walked)
+    })
+}
+
+/// Sets the permission bits of every directory under `path`, and `path` itself if it's a
+/// directory, to `dir_mode`, and of every file to `file_mode`, doing with symbolic links as
+/// `symlinks` says. On Windows only the read-only flag can be set, which files get when
+/// `file_mode` has no write bits.
+///
+/// # Failures
+///
+/// * The permissions of something under `path` can't be set
+pub fn chmod_r<P: AsRef<Path>>(
+    path: P,
+    dir_mode: u32,
+    file_mode: u32,
+    symlinks: SymlinkPolicy,
+) -> Result<()> {
+    walk_r(path.as_ref(), symlinks, &mut |entry, walked| match walked {
+        Walked::Dir => set_mode(entry, dir_mode),
+        Walked::File => set_mode(entry, file_mode),
+        Walked::Link => Ok(()),
+    })
+}
+
+/// Something a walk comes across. On Unix it's changed through the open directory it's in rather
+/// than by its path, so that it can't be swapped for a symbolic link to somewhere else between
+/// being looked at and being changed.
+#[cfg(not(windows))]
+struct WalkEntry<'a> {
+    /// The directory the entry is in, or `AT_FDCWD` for the path the walk starts at
+    dirfd: libc::c_int,
+    name: &'a CStr,
+    path: &'a Path,
+    /// The entry itself, opened without following links, when it's a directory
+    fd: Option<libc::c_int>,
+    /// Whether the entry is a symbolic link whose target is to be changed
+    follow: bool,
+}
+
+#[cfg(windows)]
+struct WalkEntry<'a> {
+    path: &'a Path,
+}
+
+/// A file descriptor, closed when it is dropped.
+#[cfg(not(windows))]
+struct Fd(libc::c_int);
+
+#[cfg(not(windows))]
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Calls `apply` with `path`, and then with everything under it if it's a directory.
+#[cfg(not(windows))]
+fn walk_r<F>(path: &Path, symlinks: SymlinkPolicy, apply: &mut F) -> Result<()>
+where
+    F: FnMut(&WalkEntry, Walked) -> Result<()>,
+{
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::PermissionFailed(format!("Invalid path {}", path.display())))?;
+    walk_at(libc::AT_FDCWD, &name, path, symlinks, apply)
+}
+
+/// Calls `apply` with the entry `name` of the directory `dirfd`, and then with everything under
+/// it if it's a directory, which is opened without following links and walked through its
+/// descriptor.
+#[cfg(not(windows))]
+fn walk_at<F>(
+    dirfd: libc::c_int,
+    name: &CStr,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+    apply: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&WalkEntry, Walked) -> Result<()>,
+{
+    use std::ffi::OsStr;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut entry = WalkEntry {
+        dirfd: dirfd,
+        name: name,
+        path: path,
+        fd: None,
+        follow: false,
+    };
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatat(dirfd, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        let err = io::Error::last_os_error();
+        // Whatever is removed from under the walk while it's under way is left alone
+        if dirfd != libc::AT_FDCWD && err.raw_os_error() == Some(libc::ENOENT) {
+            return Ok(());
+        }
+        return Err(err.into());
+    }
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFLNK => match symlinks {
+            SymlinkPolicy::Skip => Ok(()),
+            SymlinkPolicy::Link => apply(&entry, Walked::Link),
+            SymlinkPolicy::Follow => {
+                if unsafe { libc::fstatat(dirfd, name.as_ptr(), &mut stat, 0) } != 0 {
+                    // Nothing to change at the end of a dangling link
+                    return Ok(());
+                }
+                entry.follow = true;
+                if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                    apply(&entry, Walked::Dir)
+                } else {
+                    apply(&entry, Walked::File)
+                }
+            }
+        },
+        libc::S_IFDIR => {
+            let fd = unsafe {
+                libc::openat(
+                    dirfd,
+                    name.as_ptr(),
+                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                let err = io::Error::last_os_error();
+                // It has been swapped for a link since it was looked at, which is left alone
+                if err.raw_os_error() == Some(libc::ELOOP)
+                    || err.raw_os_error() == Some(libc::ENOTDIR)
+                {
+                    return Ok(());
+                }
+                return Err(err.into());
+            }
+            let dir = Fd(fd);
+            entry.fd = Some(dir.0);
+            apply(&entry, Walked::Dir)?;
+            for child in dir_entries(dir.0)? {
+                let child_path = path.join(OsStr::from_bytes(child.as_bytes()));
+                walk_at(dir.0, &child, &child_path, symlinks, apply)?;
+            }
+            Ok(())
+        }
+        _ => apply(&entry, Walked::File),
+    }
+}
+
+/// Returns the names of everything in the open directory `fd` besides `.` and `..`.
+#[cfg(not(windows))]
+fn dir_entries(fd: libc::c_int) -> Result<Vec<CString>> {
+    // The directory stream takes the descriptor it's given over, and closes it
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let dir = unsafe { libc::fdopendir(dup) };
+    if dir.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(dup);
+        }
+        return Err(err.into());
+    }
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(dir) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe {
+        libc::closedir(dir);
+    }
+    Ok(names)
+}
+
+/// Calls `apply` with `path`, and then with everything under it if it's a directory.
+#[cfg(windows)]
+fn walk_r<F>(path: &Path, symlinks: SymlinkPolicy, apply: &mut F) -> Result<()>
+where
+    F: FnMut(&WalkEntry, Walked) -> Result<()>,
+{
+    let entry = WalkEntry { path: path };
+    let metadata = stdfs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return match symlinks {
+            SymlinkPolicy::Skip => Ok(()),
+            SymlinkPolicy::Link => apply(&entry, Walked::Link),
+            SymlinkPolicy::Follow => match stdfs::metadata(path) {
+                Ok(ref target) if target.is_dir() => apply(&entry, Walked::Dir),
+                Ok(_) => apply(&entry, Walked::File),
+                // Nothing to change at the end of a dangling link
+                Err(_) => Ok(()),
+            },
+        };
+    }
+    if metadata.is_dir() {
+        apply(&entry, Walked::Dir)?;
+        for child in stdfs::read_dir(path)? {
+            walk_r(&child?.path(), symlinks, apply)?;
+        }
+        Ok(())
+    } else {
+        apply(&entry, Walked::File)
+    }
+}
+
+#[cfg(not(windows))]
+struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+#[cfg(not(windows))]
+impl Owner {
+    fn lookup(user: &str, group: &str) -> Result<Self> {
+        let uid = users::get_uid_by_name(user)
+            .ok_or_else(|| Error::PermissionFailed(format!("No such user {}", user)))?;
+        let gid = users::get_gid_by_name(group)
+            .ok_or_else(|| Error::PermissionFailed(format!("No such group {}", group)))?;
+        Ok(Owner { uid: uid, gid: gid })
+    }
+
+    fn apply(&mut self, entry: &WalkEntry, _walked: Walked) -> Result<()> {
+        let res = unsafe {
+            match entry.fd {
+                Some(fd) => libc::fchown(fd, self.uid, self.gid),
+                None => {
+                    let flags = if entry.follow {
+                        0
+                    } else {
+                        libc::AT_SYMLINK_NOFOLLOW
+                    };
+                    libc::fchownat(entry.dirfd, entry.name.as_ptr(), self.uid, self.gid, flags)
+                }
+            }
+        };
+        if res != 0 {
+            return Err(Error::PermissionFailed(format!(
+                "Can't change owner of {} to {}:{}: {}",
+                entry.path.display(),
+                self.uid,
+                self.gid,
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+struct Owner {
+    entries: Vec<::util::win_perm::PermissionEntry>,
+}
+
+#[cfg(windows)]
+impl Owner {
+    fn lookup(user: &str, _group: &str) -> Result<Self> {
+        use habitat_win_users::account::Account;
+        use util::win_perm::PermissionEntry;
+        use winapi::um::winnt::FILE_ALL_ACCESS;
+
+        let mut entries = Vec::new();
+        for name in &[user, "Administrators", "SYSTEM"] {
+            let account = Account::from_name(name)
+                .ok_or_else(|| Error::PermissionFailed(format!("No such account {}", name)))?;
+            entries.push(PermissionEntry {
+                account: account,
+                access_mask: FILE_ALL_ACCESS,
+            });
+        }
+        Ok(Owner { entries: entries })
+    }
+
+    fn apply(&mut self, entry: &WalkEntry, _walked: Walked) -> Result<()> {
+        ::util::win_perm::set_permissions(entry.path, &self.entries)
+    }
+}
+
+#[cfg(not(windows))]
+fn set_mode(entry: &WalkEntry, mode: u32) -> Result<()> {
+    let mode = mode as libc::mode_t;
+    let res = unsafe {
+        match entry.fd {
+            Some(fd) => libc::fchmod(fd, mode),
+            None if entry.follow => libc::fchmodat(entry.dirfd, entry.name.as_ptr(), mode, 0),
+            None => libc::fchmodat(
+                entry.dirfd,
+                entry.name.as_ptr(),
+                mode,
+                libc::AT_SYMLINK_NOFOLLOW,
+            ),
+        }
+    };
+    if res != 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(code) if code == libc::ENOTSUP || code == libc::EOPNOTSUPP => {
+                return set_mode_through_fd(entry, mode)
+            }
+            _ => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Sets the mode of a file through a descriptor opened without following links, for systems
+/// which can't do so by name.
+#[cfg(not(windows))]
+fn set_mode_through_fd(entry: &WalkEntry, mode: libc::mode_t) -> Result<()> {
+    let fd = unsafe {
+        libc::openat(
+            entry.dirfd,
+            entry.name.as_ptr(),
+            libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // Swapped for a link since it was looked at, and links have no mode of their own, or
+            // a socket, which can't be opened
+            Some(libc::ELOOP) | Some(libc::ENXIO) => return Ok(()),
+            _ => return Err(err.into()),
+        }
+    }
+    let file = Fd(fd);
+    if unsafe { libc::fchmod(file.0, mode) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_mode(entry: &WalkEntry, mode: u32) -> Result<()> {
+    let path = entry.path;
+    let mut permissions = stdfs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    stdfs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// An advisory lock on a file, released when it is dropped. The lock only keeps out other
+/// processes which take it too, using `flock` on Unix and `LockFileEx` on Windows, and the
+/// operating system releases it if the process holding it dies.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Takes an exclusive lock on the file at `path`, creating it and its parent directories if
+    /// need be, and waiting for any other holder of a lock on it to release theirs.
+    pub fn exclusive<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::acquire(path.as_ref(), true, true)?.expect("waited for the lock"))
+    }
+
+    /// Takes a shared lock on the file at `path`, which can be held alongside other shared locks
+    /// but not alongside an exclusive one, waiting for an exclusive lock to be released.
+    pub fn shared<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::acquire(path.as_ref(), false, true)?.expect("waited for the lock"))
+    }
+
+    /// Takes an exclusive lock on the file at `path` if nothing else holds a lock on it, and
+    /// returns `None` straight away if something does.
+    pub fn try_exclusive<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        Self::acquire(path.as_ref(), true, false)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn acquire(path: &Path, exclusive: bool, wait: bool) -> Result<Option<Self>> {
+        if let Some(parent) = path.parent() {
+            stdfs::create_dir_all(parent)?;
+        }
+        let file = stdfs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        if !lock_file(&file, exclusive, wait)? {
+            return Ok(None);
+        }
+        debug!("Acquired lock {}", path.display());
+        Ok(Some(FileLock {
+            file: file,
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock too, so this only releases it a little sooner
+        if let Err(e) = unlock_file(&self.file) {
+            debug!("Error releasing lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Locks the data of a service against other processes which lock it too, for as long as the
+/// returned lock is held. The lock file is kept beside the data rather than in it.
+pub fn lock_svc_data<T>(
+    service_name: &str,
+    instance: Option<&str>,
+    fs_root_path: Option<T>,
+) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(svc_path(service_name, instance, fs_root_path)?.join(".data.lock"))
+}
+
+/// Locks the artifact cache against other processes which lock it too, for as long as the
+/// returned lock is held.
+pub fn lock_cache_artifacts<T>(fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(cache_artifact_path(fs_root_path).join(".artifacts.lock"))
+}
+
+/// Locks the installed packages against other processes which lock them too, for as long as
+/// the returned lock is held, such as while packages are removed. The lock file is kept beside
+/// the packages rather than among them.
+pub fn lock_packages<T>(fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(pkg_root_path(fs_root_path).with_extension("lock"))
+}
+
+/// Takes the lock `lock_packages` takes, but shared, for work such as installing packages which
+/// can go on alongside other work like it but not while packages are removed.
+pub fn lock_packages_shared<T>(fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::shared(pkg_root_path(fs_root_path).with_extension("lock"))
+}
+
+/// Locks the file of held releases against other processes which lock it too, for as long as the
+/// returned lock is held, so that holds changed at the same time aren't lost. Reading the file
+/// needs no lock, as it's only ever replaced whole.
+pub fn lock_holds<T>(fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(holds_path(fs_root_path).with_extension("lock"))
+}
+
+/// Locks the content store against other processes which lock it too, for as long as the
+/// returned lock is held. The lock file is kept beside the store rather than in it.
+pub fn lock_content_store<T>(fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(content_store_path(fs_root_path).with_extension("lock"))
+}
+
+/// Locks the artifact called `file_name` in the artifact cache against other processes which
+/// lock it too, for as long as the returned lock is held. Unlike `lock_cache_artifacts`, it
+/// doesn't hold up anything working on other artifacts.
+pub fn lock_cache_artifact<T>(file_name: &str, fs_root_path: Option<T>) -> Result<FileLock>
+where
+    T: AsRef<Path>,
+{
+    FileLock::exclusive(
+        cache_artifact_path(fs_root_path)
+            .join(".locks")
+            .join(format!("{}.lock", file_name)),
+    )
+}
+
+/// Locks `file`, returning whether it was locked, which is only ever not the case when `wait` is
+/// false and something else holds a conflicting lock.
+#[cfg(not(windows))]
+fn lock_file(file: &File, exclusive: bool, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    let mut operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            _ => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn unlock_file(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Locks `file`, returning whether it was locked, which is only ever not the case when `wait` is
+/// false and something else holds a conflicting lock.
+#[cfg(windows)]
+fn lock_file(file: &File, exclusive: bool, wait: bool) -> io::Result<bool> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let mut flags = 0;
+    if exclusive {
+        flags |= LOCKFILE_EXCLUSIVE_LOCK;
+    }
+    if !wait {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+    let locked =
+        unsafe { LockFileEx(file.as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped) };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(windows)]
+fn unlock_file(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::um::fileapi::UnlockFile;
+
+    if unsafe { UnlockFile(file.as_raw_handle() as _, 0, 0, !0, !0) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The room left on the filesystem which holds a path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AvailableSpace {
+    /// Bytes which can be written by an unprivileged user
+    pub bytes: u64,
+    /// Inodes which can be used by an unprivileged user. Windows doesn't count them, so it's
+    /// `None` there.
+    pub inodes: Option<u64>,
+}
+
+/// The room an operation, such as unpacking a package, needs on a filesystem.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SpaceNeeded {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// Returns the room left on the filesystem which holds `path`. A path which doesn't exist yet
+/// gets the room on the filesystem of its closest existing parent, where it would be created.
+///
+/// # Failures
+///
+/// * Neither the path nor any of its parents exist
+/// * The filesystem can't be queried
+pub fn available_space<P: AsRef<Path>>(path: P) -> Result<AvailableSpace> {
+    let mut existing = path.as_ref();
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => existing = parent,
+            _ => break,
+        }
+    }
+    Ok(filesystem_space(existing)?)
+}
+
+/// Fails unless the filesystem which holds `path` has the room `needed` for an operation, such
+/// as unpacking a package, so the operation can be refused before it starts rather than leave
+/// something half written when the filesystem fills up.
+///
+/// # Failures
+///
+/// * The filesystem has fewer bytes or inodes free than needed
+/// * The filesystem can't be queried
+pub fn ensure_space<P: AsRef<Path>>(path: P, needed: &SpaceNeeded) -> Result<()> {
+    let path = path.as_ref();
+    let available = available_space(path)?;
+    if available.bytes < needed.bytes {
+        return Err(Error::InsufficientSpace(format!(
+            "{} needs {}, only {} free",
+            path.display(),
+            human_size(needed.bytes),
+            human_size(available.bytes)
+        )));
+    }
+    match available.inodes {
+        Some(inodes) if inodes < needed.inodes => Err(Error::InsufficientSpace(format!(
+            "{} needs {} inodes, only {} free",
+            path.display(),
+            needed.inodes,
+            inodes
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(windows))]
+fn filesystem_space(path: &Path) -> io::Result<AvailableSpace> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    use libc;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(AvailableSpace {
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes: Some(stat.f_favail as u64),
+    })
+}
+
+#[cfg(windows)]
+fn filesystem_space(path: &Path) -> io::Result<AvailableSpace> {
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::um::fileapi::GetDiskFreeSpaceExW;
+    use winapi::um::winnt::ULARGE_INTEGER;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free: ULARGE_INTEGER = unsafe { mem::zeroed() };
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut free, ptr::null_mut(), ptr::null_mut()) }
+        == 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(AvailableSpace {
+        bytes: unsafe { *free.QuadPart() },
+        inodes: None,
+    })
+}
+
+/// Formats a number of bytes for people to read, such as `350MB`.
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit > 0 && size < 10.0 {
+        format!("{:.1}{}", size, units[unit])
+    } else {
+        format!("{:.0}{}", size, units[unit])
+    }
+}
+
+/// Copies the file `src` to `dst`, replacing whatever is there, as `std::fs::copy` does, but has
+/// the filesystem share the content of the copy with the original when it can: with a reflink
+/// on Linux filesystems which support them, such as Btrfs and XFS, or with `clonefile` on APFS.
+/// On Linux the copy is otherwise left to the kernel with `copy_file_range`. Anything else gets
+/// an ordinary copy. Returns the number of bytes copied.
+///
+/// # Failures
+///
+/// * `src` can't be read, or `dst` can't be written
+pub fn copy_cow<P, Q>(src: P, dst: Q) -> Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    Ok(copy_cow_imp(src.as_ref(), dst.as_ref())?)
+}
+
+#[cfg(target_os = "linux")]
+fn copy_cow_imp(src: &Path, dst: &Path) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use libc;
+
+    const FICLONE: u64 = 0x4004_9409;
+
+    let mut reader = File::open(src)?;
+    let metadata = reader.metadata()?;
+    let mut writer = File::create(dst)?;
+    writer.set_permissions(metadata.permissions())?;
+    let len = metadata.len();
+    if unsafe { libc::ioctl(writer.as_raw_fd(), FICLONE as _, reader.as_raw_fd()) } == 0 {
+        return Ok(len);
+    }
+    let mut copied = 0;
+    while copied < len {
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                reader.as_raw_fd(),
+                ptr::null_mut::<libc::loff_t>(),
+                writer.as_raw_fd(),
+                ptr::null_mut::<libc::loff_t>(),
+                (len - copied) as libc::size_t,
+                0 as libc::c_uint,
+            )
+        };
+        if n == 0 {
+            // The file got shorter since it was opened
+            return Ok(copied);
+        }
+        if n > 0 {
+            copied += n as u64;
+            continue;
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // Not supported by the kernel or between these filesystems, so nothing was copied
+            // and the ordinary copy below starts from the beginning of both files
+            Some(libc::ENOSYS)
+            | Some(libc::EXDEV)
+            | Some(libc::EINVAL)
+            | Some(libc::EOPNOTSUPP)
+                if copied == 0 =>
+            {
+                break
             }
+            _ => return Err(e),
         }
-    };
+    }
+    if copied == len {
+        return Ok(copied);
+    }
+    io::copy(&mut reader, &mut writer)
+}
 
-    static ref MY_CACHE_SSL_PATH: PathBuf = {
-        if am_i_root() {
-            PathBuf::from(CACHE_SSL_PATH)
-        } else {
-            match dirs::home_dir() {
-                Some(home) => home.join(format!(".{}", CACHE_SSL_PATH)),
-                None => PathBuf::from(CACHE_SSL_PATH),
-            }
+#[cfg(target_os = "macos")]
+fn copy_cow_imp(src: &Path, dst: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    use libc::{c_char, c_int};
+
+    extern "C" {
+        fn clonefile(src: *const c_char, dst: *const c_char, flags: u32) -> c_int;
+    }
+
+    // A clone can't replace a file, so one which is already there gets an ordinary copy
+    if !dst.exists() {
+        let c_path = |p: &Path| {
+            CString::new(p.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let (c_src, c_dst) = (c_path(src)?, c_path(dst)?);
+        if unsafe { clonefile(c_src.as_ptr(), c_dst.as_ptr(), 0) } == 0 {
+            return Ok(stdfs::metadata(dst)?.len());
         }
-    };
+    }
+    stdfs::copy(src, dst)
 }
 
-/// Returns the path to the analytics cache, optionally taking a custom filesystem root.
-pub fn cache_analytics_path<T>(fs_root_path: Option<T>) -> PathBuf
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn copy_cow_imp(src: &Path, dst: &Path) -> io::Result<u64> {
+    stdfs::copy(src, dst)
+}
+
+/// Copies the file `src` to `dst`, along with its permissions, leaving the holes of a sparse
+/// `src` as holes in `dst` rather than writing them out as zeros. The space the rest of `dst`
+/// needs is reserved before it's written, so the filesystem can lay it out in as few pieces as it
+/// can manage. Returns the number of bytes in the file.
+///
+/// Linux finds the holes with `SEEK_DATA` and `SEEK_HOLE`, and Windows copies the holes of files
+/// marked sparse. Elsewhere, where holes can't be found, any block of zeros is left as a hole.
+///
+/// # Failures
+///
+/// * `src` can't be read, or `dst` can't be written
+/// * There isn't room for `dst`
+pub fn copy_sparse<P, Q>(src: P, dst: Q) -> Result<u64>
 where
-    T: AsRef<Path>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
 {
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_ANALYTICS_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_ANALYTICS_PATH),
+    let mut reader = File::open(src.as_ref())?;
+    let metadata = reader.metadata()?;
+    let mut writer = File::create(dst.as_ref())?;
+    writer.set_permissions(metadata.permissions())?;
+    copy_sparse_imp(&mut reader, &metadata, &mut writer)?;
+    // Makes room for a hole at the end, which nothing was written after
+    writer.set_len(metadata.len())?;
+    Ok(metadata.len())
+}
+
+/// Reserves space on disk for the first `len` bytes of `file` without changing its length, so
+/// writing them can't run out of space part way, and the file isn't scattered across the disk as
+/// it grows. Filesystems and platforms which can't reserve space are left to allocate it as the
+/// file is written.
+///
+/// # Failures
+///
+/// * There isn't room for `len` bytes
+pub fn preallocate(file: &File, len: u64) -> Result<()> {
+    Ok(preallocate_imp(file, 0, len)?)
+}
+
+#[cfg(target_os = "linux")]
+fn copy_sparse_imp(
+    reader: &mut File,
+    metadata: &stdfs::Metadata,
+    writer: &mut File,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    let len = metadata.len();
+    let mut offset = 0;
+    while offset < len {
+        let start =
+            unsafe { libc::lseek(reader.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                // The rest of the file is a hole
+                Some(libc::ENXIO) => Ok(()),
+                // The filesystem can't tell where its holes are, so the rest is copied whole
+                Some(libc::EINVAL) => copy_extent(reader, writer, offset, len - offset),
+                _ => Err(e),
+            };
+        }
+        let end = unsafe { libc::lseek(reader.as_raw_fd(), start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The file may have grown since its length was read
+        let end = if end as u64 > len { len } else { end as u64 };
+        copy_extent(reader, writer, start as u64, end - start as u64)?;
+        offset = end;
     }
+    Ok(())
 }
 
-/// Returns the path to the artifacts cache, optionally taking a custom filesystem root.
-pub fn cache_artifact_path<T>(fs_root_path: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_ARTIFACT_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_ARTIFACT_PATH),
+#[cfg(target_os = "linux")]
+fn copy_extent(reader: &mut File, writer: &mut File, offset: u64, len: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    preallocate_imp(writer, offset, len)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    writer.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut Read::by_ref(reader).take(len), writer)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn copy_sparse_imp(
+    reader: &mut File,
+    metadata: &stdfs::Metadata,
+    writer: &mut File,
+) -> io::Result<()> {
+    use std::os::windows::fs::MetadataExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+
+    use winapi::um::ioapiset::DeviceIoControl;
+    use winapi::um::winioctl::FSCTL_SET_SPARSE;
+    use winapi::um::winnt::FILE_ATTRIBUTE_SPARSE_FILE;
+
+    if metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0 {
+        preallocate_imp(writer, 0, metadata.len())?;
+        io::copy(reader, writer)?;
+        return Ok(());
+    }
+    let mut returned = 0;
+    let set_sparse = unsafe {
+        DeviceIoControl(
+            writer.as_raw_handle() as _,
+            FSCTL_SET_SPARSE,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if set_sparse == 0 {
+        return Err(io::Error::last_os_error());
     }
+    copy_skipping_zeros(reader, writer)
 }
 
-/// Returns the path to the keys cache, optionally taking a custom filesystem root.
-pub fn cache_key_path<T>(fs_root_path: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_KEY_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_KEY_PATH),
+#[cfg(not(any(target_os = "linux", windows)))]
+fn copy_sparse_imp(
+    reader: &mut File,
+    _metadata: &stdfs::Metadata,
+    writer: &mut File,
+) -> io::Result<()> {
+    copy_skipping_zeros(reader, writer)
+}
+
+/// Copies `reader` to `writer`, seeking past each block of zeros rather than writing it, which
+/// leaves a hole in a file which can have them.
+#[cfg(not(target_os = "linux"))]
+fn copy_skipping_zeros(reader: &mut File, writer: &mut File) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf[..n].iter().all(|b| *b == 0) {
+            writer.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            writer.write_all(&buf[..n])?;
+        }
     }
 }
 
-/// Returns the path to the src cache, optionally taking a custom filesystem root.
-pub fn cache_src_path<T>(fs_root_path: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_SRC_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_SRC_PATH),
+#[cfg(target_os = "linux")]
+fn preallocate_imp(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    if len == 0 {
+        return Ok(());
+    }
+    loop {
+        let reserved = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if reserved == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => return Ok(()),
+            _ => return Err(e),
+        }
     }
 }
 
-/// Returns the path to the SSL cache, optionally taking a custom filesystem root.
-pub fn cache_ssl_path<T>(fs_root_path: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(&*MY_CACHE_SSL_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(&*MY_CACHE_SSL_PATH),
+#[cfg(windows)]
+fn preallocate_imp(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::um::minwinbase::FileAllocationInfo;
+    use winapi::um::winbase::{SetFileInformationByHandle, FILE_ALLOCATION_INFO};
+
+    let mut info: FILE_ALLOCATION_INFO = unsafe { mem::zeroed() };
+    unsafe { *info.AllocationSize.QuadPart_mut() = (offset + len) as i64 };
+    let reserved = unsafe {
+        SetFileInformationByHandle(
+            file.as_raw_handle() as _,
+            FileAllocationInfo,
+            &mut info as *mut _ as *mut _,
+            mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+        )
+    };
+    if reserved == 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
 }
 
-pub fn pkg_root_path<T>(fs_root: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    let mut buf = fs_root.map_or(PathBuf::from("/"), |p| p.as_ref().into());
-    buf.push(PKG_PATH);
-    buf
+#[cfg(not(any(target_os = "linux", windows)))]
+fn preallocate_imp(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
 }
 
-pub fn pkg_install_path<T>(ident: &PackageIdent, fs_root: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    assert!(
-        ident.fully_qualified(),
-        "Cannot determine install path without fully qualified ident"
-    );
-    let mut pkg_path = pkg_root_path(fs_root);
-    pkg_path.push(&ident.origin);
-    pkg_path.push(&ident.name);
-    pkg_path.push(ident.version.as_ref().unwrap());
-    pkg_path.push(ident.release.as_ref().unwrap());
-    pkg_path
+/// Returns `path` in a form the platform can open however long it is. On Windows, a path too long
+/// for `MAX_PATH` is made absolute and returned in its extended-length `\\?\` form. Windows takes
+/// such paths literally, so their `.` and `..` components are resolved first. Shorter paths, and
+/// paths on other platforms, are returned as they are.
+///
+/// Package install paths, `pkgs/origin/name/version/release` and whatever the package has under
+/// them, are often too long for `MAX_PATH`.
+pub fn long_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    long_path_imp(path.as_ref())
 }
 
-/// Given a linux style absolute path (prepended with '/') and a fs_root,
-/// this will "re-root" the path just under the fs_root. Otherwise returns
-/// the given path unchanged. Non-Windows platforms will always return the
-/// unchanged path.
-pub fn fs_rooted_path<T>(path: &PathBuf, fs_root: Option<T>) -> PathBuf
-where
-    T: AsRef<Path>,
-{
-    match fs_root {
-        Some(ref root) if path.starts_with("/") && cfg!(windows) => {
-            Path::new(root.as_ref()).join(path.strip_prefix("/").unwrap())
+/// The longest a path can be on Windows, unless it's in its extended-length form. A directory
+/// must leave room for an 8.3 file name in it, so its limit is 12 shorter.
+#[cfg(windows)]
+const MAX_PATH: usize = 260 - 12;
+
+#[cfg(windows)]
+fn long_path_imp(path: &Path) -> PathBuf {
+    use std::path::Prefix;
+
+    if path.as_os_str().len() < MAX_PATH {
+        return path.to_path_buf();
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => return path.to_path_buf(),
+        }
+    };
+    let mut long = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(_) => {
+                    long.push(format!(r"\\?\{}\", prefix.as_os_str().to_string_lossy()))
+                }
+                Prefix::UNC(server, share) => long.push(format!(
+                    r"\\?\UNC\{}\{}\",
+                    server.to_string_lossy(),
+                    share.to_string_lossy()
+                )),
+                // Already extended-length, or a device
+                _ => return path.to_path_buf(),
+            },
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                long.pop();
+            }
+            Component::Normal(part) => long.push(part),
         }
-        _ => path.to_path_buf(),
     }
+    long
 }
 
-/// Return the path to the root of the launcher runtime directory
-pub fn launcher_root_path<T>(fs_root_path: Option<T>) -> PathBuf
+#[cfg(not(windows))]
+fn long_path_imp(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Joins `untrusted`, a relative path which came from a package or another source which can't be
+/// trusted, onto `base`, refusing any path which would lead outside of `base`.
+///
+/// # Failures
+///
+/// * `untrusted` is empty, absolute, or has a `..` component
+/// * A symbolic link which already exists along the joined path points outside of `base`, or
+///   can't be resolved
+pub fn join_safe<B, U>(base: B, untrusted: U) -> Result<PathBuf>
 where
-    T: AsRef<Path>,
+    B: AsRef<Path>,
+    U: AsRef<Path>,
 {
-    match fs_root_path {
-        Some(fs_root_path) => fs_root_path.as_ref().join(LAUNCHER_ROOT_PATH),
-        None => Path::new(&*FS_ROOT_PATH).join(LAUNCHER_ROOT_PATH),
+    let (base, untrusted) = (base.as_ref(), untrusted.as_ref());
+    let unsafe_path = || Error::UnsafePath(untrusted.display().to_string());
+    let mut joined = base.to_path_buf();
+    for component in untrusted.components() {
+        match component {
+            Component::Normal(part) => joined.push(part),
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(unsafe_path())
+            }
+        }
+        let is_symlink = stdfs::symlink_metadata(&joined)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink {
+            let resolved = joined.canonicalize().map_err(|_| unsafe_path())?;
+            if !resolved.starts_with(base.canonicalize()?) {
+                return Err(unsafe_path());
+            }
+        }
+    }
+    if joined == base {
+        return Err(unsafe_path());
     }
+    Ok(joined)
 }
 
 /// Returns the absolute path for a given command, if it exists, by searching the `PATH`
@@ -302,7 +2205,18 @@ where
     }
 }
 
-/// Returns the absolute path to the given command from a given package installation.
+lazy_static! {
+    /// The runtime path entries of each package `find_command_in_pkg` has searched, by installed
+    /// path. An installed package never changes, and neither do the entries it composes from
+    /// its dependencies.
+    static ref PKG_SEARCH_PATHS: Mutex<HashMap<PathBuf, Vec<PathBuf>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns the absolute path to the given command from a given package installation, searching
+/// the package's runtime `PATH`, which is composed of its own path entries followed by those of
+/// its dependencies, as its hooks and programs see it. On Windows a command without an extension
+/// is also looked for with each of the extensions in `PATHEXT`.
 ///
 /// If the command is not found, then `None` is returned.
 ///
@@ -318,24 +2232,41 @@ where
     T: AsRef<Path>,
     U: AsRef<Path>,
 {
-    for path in pkg_install.paths()? {
+    for path in pkg_search_paths(pkg_install)? {
         let stripped = path.strip_prefix("/").expect(&format!(
             "Package path missing / prefix {}",
             path.to_string_lossy()
         ));
-        let candidate = fs_root_path.as_ref().join(stripped).join(command.as_ref());
+        let candidate = long_path(fs_root_path.as_ref().join(stripped).join(command.as_ref()));
         if candidate.is_file() {
             return Ok(Some(path.join(command.as_ref())));
-        } else {
-            match find_command_with_pathext(&candidate) {
-                Some(result) => return Ok(Some(result)),
-                None => {}
-            }
+        }
+        if let Some(found) = find_command_with_pathext(&candidate) {
+            // The path the package knows the command by, with the extension it was found with
+            let file_name = found.file_name().expect("Commands have file names");
+            return Ok(Some(path.join(file_name)));
         }
     }
     Ok(None)
 }
 
+fn pkg_search_paths(pkg_install: &PackageInstall) -> Result<Vec<PathBuf>> {
+    let installed_path = pkg_install.installed_path();
+    if let Some(paths) = PKG_SEARCH_PATHS
+        .lock()
+        .expect("Package search paths lock is poisoned")
+        .get(installed_path)
+    {
+        return Ok(paths.clone());
+    }
+    let paths = pkg_install.runtime_paths()?;
+    PKG_SEARCH_PATHS
+        .lock()
+        .expect("Package search paths lock is poisoned")
+        .insert(installed_path.to_path_buf(), paths.clone());
+    Ok(paths)
+}
+
 /// Resolves the absolute path to a program in the given package identifier string.
 ///
 /// Note: this function is designed to be callable in `lazy_static!` blocks, meaning that if it
@@ -462,6 +2393,439 @@ fn fs_root_path() -> PathBuf {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use tempfile::Builder;
+
+    use super::*;
+
+    #[test]
+    fn roots_are_independent_of_each_other() {
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/redis/4.0.10/20180608202239").unwrap();
+        let first = FsRoot::new("/tmp/first");
+        let second = FsRoot::new("/tmp/second");
+
+        assert_eq!(
+            first.pkg_install_path(&ident),
+            Path::new("/tmp/first/hab/pkgs/core/redis/4.0.10/20180608202239")
+        );
+        assert_eq!(
+            second.svc_logs_path("redis", None).unwrap(),
+            Path::new("/tmp/second/hab/svc/redis/logs")
+        );
+        assert_eq!(
+            svc_data_path("redis", None, Some(&first)).unwrap(),
+            first.svc_data_path("redis", None).unwrap()
+        );
+        assert_eq!(FsRoot::default().path(), &*FS_ROOT_PATH);
+    }
+
+    #[test]
+    fn instances_of_a_service_get_their_own_paths() {
+        let root = FsRoot::new("/tmp/root");
+
+        assert_eq!(
+            root.svc_path("redis", None).unwrap(),
+            Path::new("/tmp/root/hab/svc/redis")
+        );
+        assert_eq!(
+            root.svc_hooks_path("redis", Some("cache")).unwrap(),
+            Path::new("/tmp/root/hab/svc/redis.cache/hooks")
+        );
+        assert_eq!(
+            root.svc_data_path("redis", Some("sessions")).unwrap(),
+            Path::new("/tmp/root/hab/svc/redis.sessions/data")
+        );
+        assert_ne!(
+            root.svc_logs_path("redis", Some("cache")).unwrap(),
+            root.svc_logs_path("redis", Some("sessions")).unwrap()
+        );
+    }
+
+    #[test]
+    fn service_names_and_instances_cannot_leave_their_directory() {
+        let root = FsRoot::new("/tmp/root");
+
+        for &(name, instance) in &[
+            ("redis", Some("x/../../..")),
+            ("redis", Some("..")),
+            ("redis", Some("")),
+            ("../redis", None),
+            ("redis.cache", None),
+            ("redis", Some("cache.1")),
+            (r"redis\cache", None),
+        ] {
+            match root.svc_path(name, instance) {
+                Err(Error::InvalidServiceName(_)) => (),
+                other => panic!(
+                    "Expected {}/{:?} to be refused, got {:?}",
+                    name, instance, other
+                ),
+            }
+            match set_svc_dirs_owner(name, instance, "hab", "hab", Some(root.path())) {
+                Err(Error::InvalidServiceName(_)) => (),
+                other => panic!(
+                    "Expected {}/{:?} to be refused, got {:?}",
+                    name, instance, other
+                ),
+            }
+        }
+        assert_eq!(svc_dir_name("redis", Some("cache")).unwrap(), "redis.cache");
+    }
+
+    #[test]
+    fn svc_dirs_convert_between_kinds() {
+        let root = FsRoot::new("/tmp/root");
+        let svc_dir = root.svc_path("redis", Some("cache")).unwrap();
+
+        assert_eq!(
+            svc_dir.config_dir(),
+            root.svc_config_path("redis", Some("cache")).unwrap()
+        );
+        assert_eq!(svc_dir.hooks_dir().svc_dir(), svc_dir);
+        assert_eq!(
+            svc_dir.config_dir().join("redis.conf"),
+            Path::new("/tmp/root/hab/svc/redis.cache/config/redis.conf")
+        );
+        assert_eq!(
+            PathBuf::from(svc_dir.hooks_dir()),
+            Path::new("/tmp/root/hab/svc/redis.cache/hooks")
+        );
+    }
+
+    #[test]
+    fn atomic_write_replaces_whole_files() {
+        let dir = Builder::new().prefix("atomic-write").tempdir().unwrap();
+        let path = dir.path().join("status");
+
+        atomic_write(&path, b"first", 0o600).unwrap();
+        atomic_write(&path, b"second", 0o644).unwrap();
+
+        assert_eq!(stdfs::read(&path).unwrap(), b"second");
+        assert_eq!(stdfs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(atomic_write(dir.path().join("missing/status"), b"", 0o644).is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn atomic_write_sets_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = Builder::new().prefix("atomic-write").tempdir().unwrap();
+        let path = dir.path().join("run");
+        atomic_write(&path, b"#!/bin/sh\n", 0o755).unwrap();
+
+        let mode = stdfs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn chmod_r_sets_modes_by_symlink_policy() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let outside = Builder::new().prefix("outside").tempdir().unwrap();
+        let target = outside.path().join("target");
+        File::create(&target).unwrap();
+        stdfs::set_permissions(&target, stdfs::Permissions::from_mode(0o600)).unwrap();
+        let dir = Builder::new().prefix("svc-data").tempdir().unwrap();
+        stdfs::create_dir_all(dir.path().join("db/tables")).unwrap();
+        File::create(dir.path().join("db/tables/users")).unwrap();
+        symlink(&target, dir.path().join("db/link")).unwrap();
+        let mode = |p: &Path| stdfs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        chmod_r(dir.path(), 0o750, 0o640, SymlinkPolicy::Link).unwrap();
+        assert_eq!(mode(&dir.path().join("db/tables")), 0o750);
+        assert_eq!(mode(&dir.path().join("db/tables/users")), 0o640);
+        assert_eq!(mode(&target), 0o600);
+
+        chmod_r(dir.path(), 0o750, 0o644, SymlinkPolicy::Follow).unwrap();
+        assert_eq!(mode(&target), 0o644);
+
+        let user = users::get_current_username().unwrap();
+        let group = users::get_current_groupname().unwrap();
+        chown_r(dir.path(), &user, &group, SymlinkPolicy::Link).unwrap();
+        match chown_r(dir.path(), "no-such-hab-user", "hab", SymlinkPolicy::Skip) {
+            Err(Error::PermissionFailed(_)) => (),
+            other => panic!("Expected a permission error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn walks_stay_inside_the_tree_past_links_to_directories() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let outside = Builder::new().prefix("outside").tempdir().unwrap();
+        let secret = outside.path().join("secret");
+        File::create(&secret).unwrap();
+        stdfs::set_permissions(&secret, stdfs::Permissions::from_mode(0o600)).unwrap();
+        stdfs::set_permissions(outside.path(), stdfs::Permissions::from_mode(0o700)).unwrap();
+        let dir = Builder::new().prefix("svc-var").tempdir().unwrap();
+        symlink(outside.path(), dir.path().join("elsewhere")).unwrap();
+        let mode = |p: &Path| stdfs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        chmod_r(dir.path(), 0o755, 0o644, SymlinkPolicy::Link).unwrap();
+        assert_eq!(mode(outside.path()), 0o700);
+        assert_eq!(mode(&secret), 0o600);
+
+        chmod_r(dir.path(), 0o750, 0o644, SymlinkPolicy::Follow).unwrap();
+        assert_eq!(mode(outside.path()), 0o750);
+        assert_eq!(mode(&secret), 0o600);
+    }
+
+    #[test]
+    fn temporaries_in_the_cache_are_kept_or_cleaned_up() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let mut file = TempFileInCache::new(Some(fs_root.path())).unwrap();
+        assert!(file
+            .path()
+            .starts_with(cache_tmp_path(Some(fs_root.path()))));
+        file.write_all(b"artifact").unwrap();
+        let artifact = cache_artifact_path(Some(fs_root.path())).join("core-redis.hart");
+        stdfs::create_dir_all(artifact.parent().unwrap()).unwrap();
+        file.persist(&artifact).unwrap();
+        assert_eq!(stdfs::read(&artifact).unwrap(), b"artifact");
+
+        let dir = TempDirInCache::new(Some(fs_root.path())).unwrap();
+        let dir_path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!dir_path.exists());
+
+        // Left behind by a process which is long gone, and by one which is still running
+        let tmp_path = cache_tmp_path(Some(fs_root.path()));
+        let stale = tmp_path.join(format!("{}-999999999-abc123", CACHE_TMP_PREFIX));
+        stdfs::create_dir_all(stale.join("nested")).unwrap();
+        let live = TempFileInCache::new(Some(fs_root.path())).unwrap();
+
+        assert_eq!(clean_cache_tmp(Some(fs_root.path())).unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(live.path().exists());
+    }
+
+    #[test]
+    fn prune_logs_keeps_what_the_retention_allows() {
+        use std::thread;
+
+        let dir = Builder::new().prefix("logs").tempdir().unwrap();
+        for name in &["hook.1.log", "hook.2.log", "hook.3.log"] {
+            stdfs::write(dir.path().join(name), vec![b'x'; 100]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        stdfs::create_dir(dir.path().join("archive")).unwrap();
+
+        let by_size = LogRetention {
+            max_total_bytes: Some(250),
+            ..LogRetention::default()
+        };
+        let report = prune_logs(dir.path(), &by_size).unwrap();
+        assert_eq!(report.removed, vec![dir.path().join("hook.1.log")]);
+        assert_eq!(report.freed, 100);
+
+        let by_age = LogRetention {
+            max_age: Some(Duration::from_secs(0)),
+            keep_latest: 1,
+            ..LogRetention::default()
+        };
+        let report = prune_logs(dir.path(), &by_age).unwrap();
+        assert_eq!(report.removed, vec![dir.path().join("hook.2.log")]);
+        assert!(dir.path().join("hook.3.log").is_file());
+        assert!(dir.path().join("archive").is_dir());
+        assert_eq!(
+            prune_logs(dir.path().join("missing"), &by_age).unwrap(),
+            PruneReport::default()
+        );
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn find_command_in_pkg_searches_runtime_path() {
+        use package::test_support::testing_package_install;
+
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let redis = testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+        let busybox = testing_package_install("core/busybox/1.0.0/20180608202239", fs_root.path());
+        let exact = |install: &PackageInstall| {
+            FullyQualifiedPackageIdent::try_from(install.ident().clone()).unwrap()
+        };
+        let redis_bin = pkg_install_path(&exact(&redis), None::<&Path>).join("bin");
+        let busybox_bin = pkg_install_path(&exact(&busybox), None::<&Path>).join("bin");
+        stdfs::write(
+            redis.installed_path().join("RUNTIME_PATH"),
+            format!("{}:{}", redis_bin.display(), busybox_bin.display()),
+        )
+        .unwrap();
+        for (install, command) in &[(&redis, "redis-server"), (&busybox, "ls")] {
+            let bin = install.installed_path().join("bin");
+            stdfs::create_dir_all(&bin).unwrap();
+            File::create(bin.join(command)).unwrap();
+        }
+
+        assert_eq!(
+            find_command_in_pkg("redis-server", &redis, fs_root.path()).unwrap(),
+            Some(redis_bin.join("redis-server"))
+        );
+        assert_eq!(
+            find_command_in_pkg("ls", &redis, fs_root.path()).unwrap(),
+            Some(busybox_bin.join("ls"))
+        );
+        assert_eq!(
+            find_command_in_pkg("missing", &redis, fs_root.path()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn long_path_extends_paths_too_long_for_max_path() {
+        let deep = format!(
+            r"C:\hab\pkgs\core\{}\1.0.0\20180608202239\..\bin",
+            "x".repeat(250)
+        );
+        assert_eq!(
+            long_path(&deep),
+            PathBuf::from(format!(
+                r"\\?\C:\hab\pkgs\core\{}\1.0.0\bin",
+                "x".repeat(250)
+            ))
+        );
+        assert_eq!(
+            long_path(r"C:\hab\pkgs\core\redis"),
+            PathBuf::from(r"C:\hab\pkgs\core\redis")
+        );
+        let verbatim = format!(r"\\?\C:\hab\{}", "x".repeat(250));
+        assert_eq!(long_path(&verbatim), PathBuf::from(verbatim));
+    }
+
+    #[test]
+    fn file_locks_exclude_each_other() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        {
+            let lock = lock_svc_data("redis", None, Some(fs_root.path())).unwrap();
+            assert!(lock
+                .path()
+                .starts_with(svc_path("redis", None, Some(fs_root.path())).unwrap()));
+            assert!(FileLock::try_exclusive(lock.path()).unwrap().is_none());
+        }
+        let path = cache_artifact_path(Some(fs_root.path())).join(".artifacts.lock");
+        assert!(FileLock::try_exclusive(&path).unwrap().is_some());
+
+        let first = FileLock::shared(&path).unwrap();
+        let second = FileLock::shared(&path).unwrap();
+        assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+        drop(first);
+        drop(second);
+        let _lock = lock_cache_artifacts(Some(fs_root.path())).unwrap();
+        assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn ensure_space_compares_with_available_space() {
+        let dir = Builder::new().prefix("space").tempdir().unwrap();
+        let available = available_space(dir.path()).unwrap();
+        assert!(available.bytes > 0);
+        assert!(available_space(dir.path().join("not/yet")).unwrap().bytes > 0);
+
+        assert!(ensure_space(dir.path(), &SpaceNeeded::default()).is_ok());
+        let too_much = SpaceNeeded {
+            bytes: u64::max_value(),
+            inodes: 1,
+        };
+        match ensure_space(dir.path(), &too_much) {
+            Err(Error::InsufficientSpace(ref msg)) => assert!(msg.contains("only")),
+            other => panic!("Expected an insufficient space error, got {:?}", other),
+        }
+        assert_eq!(human_size(350 * 1024 * 1024), "350MB");
+        assert_eq!(human_size(1536), "1.5KB");
+        assert_eq!(human_size(90), "90B");
+    }
+
+    #[test]
+    fn copy_cow_copies_content_and_permissions() {
+        let dir = Builder::new().prefix("copy-cow").tempdir().unwrap();
+        let src = dir.path().join("core-redis.hart");
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        stdfs::write(&src, &content).unwrap();
+        let mut permissions = stdfs::metadata(&src).unwrap().permissions();
+        permissions.set_readonly(true);
+        stdfs::set_permissions(&src, permissions).unwrap();
+        let dst = dir.path().join("copy.hart");
+        stdfs::write(&dst, vec![0; 300_000]).unwrap();
+
+        assert_eq!(copy_cow(&src, &dst).unwrap(), content.len() as u64);
+        assert_eq!(stdfs::read(&dst).unwrap(), content);
+        assert!(stdfs::metadata(&dst).unwrap().permissions().readonly());
+        assert!(copy_cow(dir.path().join("missing"), &dst).is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn copy_sparse_keeps_holes() {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = Builder::new().prefix("copy-sparse").tempdir().unwrap();
+        let src = dir.path().join("dump.rdb");
+        let len = 8 * 1024 * 1024;
+        {
+            let mut file = File::create(&src).unwrap();
+            file.set_len(len).unwrap();
+            file.write_all(b"REDIS0009").unwrap();
+            file.seek(SeekFrom::Start(len / 2)).unwrap();
+            file.write_all(b"middle").unwrap();
+        }
+        let dst = dir.path().join("copy.rdb");
+
+        assert_eq!(copy_sparse(&src, &dst).unwrap(), len);
+        assert_eq!(stdfs::read(&dst).unwrap(), stdfs::read(&src).unwrap());
+        let copied = stdfs::metadata(&dst).unwrap();
+        assert_eq!(copied.len(), len);
+        // Only the blocks which were written take up space
+        assert!(copied.blocks() * 512 < len / 2);
+    }
+
+    #[test]
+    fn join_safe_refuses_paths_outside_of_base() {
+        let base = Path::new("/hab/svc/redis");
+
+        assert_eq!(
+            join_safe(base, "hooks/./run").unwrap(),
+            Path::new("/hab/svc/redis/hooks/run")
+        );
+        for untrusted in &[
+            "",
+            ".",
+            "../redis-evil/run",
+            "hooks/../../run",
+            "/etc/passwd",
+        ] {
+            match join_safe(base, untrusted) {
+                Err(Error::UnsafePath(_)) => (),
+                other => panic!("Expected {} to be refused, got {:?}", untrusted, other),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn join_safe_refuses_symlinks_outside_of_base() {
+        use std::os::unix::fs::symlink;
+
+        let outside = Builder::new().prefix("outside").tempdir().unwrap();
+        let base = Builder::new().prefix("svc").tempdir().unwrap();
+        stdfs::create_dir(base.path().join("config")).unwrap();
+        symlink(base.path().join("config"), base.path().join("inside")).unwrap();
+        symlink(outside.path(), base.path().join("escape")).unwrap();
+        symlink(base.path().join("missing"), base.path().join("dangling")).unwrap();
+
+        assert!(join_safe(base.path(), "inside/redis.conf").is_ok());
+        assert!(join_safe(base.path(), "escape/redis.conf").is_err());
+        assert!(join_safe(base.path(), "escape").is_err());
+        assert!(join_safe(base.path(), "dangling").is_err());
+    }
+}
+
 #[cfg(test)]
 mod test_find_command {
 
@@ -17,6 +17,8 @@
 
 extern crate ansi_term;
 extern crate base64;
+#[cfg(feature = "blake3")]
+extern crate blake3;
 extern crate crypto as rust_crypto;
 #[cfg(windows)]
 extern crate ctrlc;
@@ -28,6 +30,7 @@ extern crate hyper;
 #[macro_use]
 extern crate lazy_static;
 extern crate libarchive;
+extern crate libarchive3_sys;
 extern crate libc;
 extern crate libsodium_sys;
 #[macro_use]
@@ -0,0 +1,307 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dropping the Linux capabilities a process started by root doesn't need, so a hook which only
+//! has to bind a low port, say, doesn't run with everything root can do.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+
+use libc;
+
+use error::{Error, Result};
+use os::users::Credentials;
+
+/// The capabilities the kernel knows of, by their number.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Capability {
+    Chown = 0,
+    DacOverride = 1,
+    DacReadSearch = 2,
+    Fowner = 3,
+    Fsetid = 4,
+    Kill = 5,
+    Setgid = 6,
+    Setuid = 7,
+    Setpcap = 8,
+    LinuxImmutable = 9,
+    NetBindService = 10,
+    NetBroadcast = 11,
+    NetAdmin = 12,
+    NetRaw = 13,
+    IpcLock = 14,
+    IpcOwner = 15,
+    SysModule = 16,
+    SysRawio = 17,
+    SysChroot = 18,
+    SysPtrace = 19,
+    SysPacct = 20,
+    SysAdmin = 21,
+    SysBoot = 22,
+    SysNice = 23,
+    SysResource = 24,
+    SysTime = 25,
+    SysTtyConfig = 26,
+    Mknod = 27,
+    Lease = 28,
+    AuditWrite = 29,
+    AuditControl = 30,
+    Setfcap = 31,
+    MacOverride = 32,
+    MacAdmin = 33,
+    Syslog = 34,
+    WakeAlarm = 35,
+    BlockSuspend = 36,
+    AuditRead = 37,
+}
+
+const CAPABILITIES: [(Capability, &'static str); 38] = [
+    (Capability::Chown, "CAP_CHOWN"),
+    (Capability::DacOverride, "CAP_DAC_OVERRIDE"),
+    (Capability::DacReadSearch, "CAP_DAC_READ_SEARCH"),
+    (Capability::Fowner, "CAP_FOWNER"),
+    (Capability::Fsetid, "CAP_FSETID"),
+    (Capability::Kill, "CAP_KILL"),
+    (Capability::Setgid, "CAP_SETGID"),
+    (Capability::Setuid, "CAP_SETUID"),
+    (Capability::Setpcap, "CAP_SETPCAP"),
+    (Capability::LinuxImmutable, "CAP_LINUX_IMMUTABLE"),
+    (Capability::NetBindService, "CAP_NET_BIND_SERVICE"),
+    (Capability::NetBroadcast, "CAP_NET_BROADCAST"),
+    (Capability::NetAdmin, "CAP_NET_ADMIN"),
+    (Capability::NetRaw, "CAP_NET_RAW"),
+    (Capability::IpcLock, "CAP_IPC_LOCK"),
+    (Capability::IpcOwner, "CAP_IPC_OWNER"),
+    (Capability::SysModule, "CAP_SYS_MODULE"),
+    (Capability::SysRawio, "CAP_SYS_RAWIO"),
+    (Capability::SysChroot, "CAP_SYS_CHROOT"),
+    (Capability::SysPtrace, "CAP_SYS_PTRACE"),
+    (Capability::SysPacct, "CAP_SYS_PACCT"),
+    (Capability::SysAdmin, "CAP_SYS_ADMIN"),
+    (Capability::SysBoot, "CAP_SYS_BOOT"),
+    (Capability::SysNice, "CAP_SYS_NICE"),
+    (Capability::SysResource, "CAP_SYS_RESOURCE"),
+    (Capability::SysTime, "CAP_SYS_TIME"),
+    (Capability::SysTtyConfig, "CAP_SYS_TTY_CONFIG"),
+    (Capability::Mknod, "CAP_MKNOD"),
+    (Capability::Lease, "CAP_LEASE"),
+    (Capability::AuditWrite, "CAP_AUDIT_WRITE"),
+    (Capability::AuditControl, "CAP_AUDIT_CONTROL"),
+    (Capability::Setfcap, "CAP_SETFCAP"),
+    (Capability::MacOverride, "CAP_MAC_OVERRIDE"),
+    (Capability::MacAdmin, "CAP_MAC_ADMIN"),
+    (Capability::Syslog, "CAP_SYSLOG"),
+    (Capability::WakeAlarm, "CAP_WAKE_ALARM"),
+    (Capability::BlockSuspend, "CAP_BLOCK_SUSPEND"),
+    (Capability::AuditRead, "CAP_AUDIT_READ"),
+];
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", CAPABILITIES[*self as usize].1)
+    }
+}
+
+/// Parses a capability by its name, with or without the `CAP_` prefix and in any case, so
+/// `CAP_NET_BIND_SERVICE` and `net_bind_service` are the same capability.
+impl FromStr for Capability {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let name = value.to_uppercase();
+        let name = if name.starts_with("CAP_") {
+            name
+        } else {
+            format!("CAP_{}", name)
+        };
+        match CAPABILITIES.iter().find(|&&(_, known)| known == name) {
+            Some(&(capability, _)) => Ok(capability),
+            None => Err(Error::CapabilitiesFailed(format!(
+                "Unknown capability {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// `_LINUX_CAPABILITY_VERSION_3`, which has 64 bits of each set, in two halves
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// What the process `command` starts has to do to keep only some capabilities, worked out
+/// before it's started, as only async-signal-safe calls can be made between the fork and the
+/// exec.
+struct CapabilityDrop {
+    /// The capabilities to drop from the bounding set, which the process can never get back
+    bounding: Vec<libc::c_ulong>,
+    /// The capabilities to keep, which are raised as ambient capabilities too, so they survive
+    /// the exec of a program without file capabilities by a user other than root
+    keep: Vec<libc::c_ulong>,
+    mask: u64,
+    /// The credentials the process switches to, keeping its capabilities as it does
+    credentials: Option<Credentials>,
+}
+
+impl CapabilityDrop {
+    fn new(
+        keep: &[Capability],
+        last_cap: u32,
+        credentials: Option<&Credentials>,
+    ) -> CapabilityDrop {
+        let keep: Vec<libc::c_ulong> = keep.iter().map(|c| *c as libc::c_ulong).collect();
+        CapabilityDrop {
+            bounding: (0..last_cap as libc::c_ulong + 1)
+                .filter(|c| !keep.contains(c))
+                .collect(),
+            mask: keep.iter().fold(0, |mask, c| mask | 1 << c),
+            keep: keep,
+            credentials: credentials.cloned(),
+        }
+    }
+
+    fn apply(&self) -> io::Result<()> {
+        prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0)?;
+        for cap in &self.bounding {
+            match prctl(libc::PR_CAPBSET_DROP, *cap, 0) {
+                Ok(()) => (),
+                // A process without CAP_SETPCAP, which is to say one not run by root, has
+                // nothing in its bounding set worth dropping
+                Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        // Switching to a user other than root empties the permitted set unless it's asked to be
+        // kept, and only then can what's kept be made effective, and ambient, again
+        if let Some(ref credentials) = self.credentials {
+            prctl(libc::PR_SET_KEEPCAPS, 1, 0)?;
+            credentials.switch()?;
+        }
+        let header = CapUserHeader {
+            version: CAPABILITY_VERSION,
+            pid: 0,
+        };
+        let half = |mask: u64| CapUserData {
+            effective: mask as u32,
+            permitted: mask as u32,
+            inheritable: mask as u32,
+        };
+        let data = [half(self.mask), half(self.mask >> 32)];
+        if unsafe {
+            libc::syscall(
+                libc::SYS_capset,
+                &header as *const CapUserHeader,
+                data.as_ptr(),
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        for cap in &self.keep {
+            match prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                *cap,
+            ) {
+                Ok(()) => (),
+                // Kernels older than 4.3 have no ambient capabilities
+                Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> io::Result<()> {
+    if unsafe { libc::prctl(option, arg2, arg3, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Has the process `command` starts drop every capability but those in `keep` before it runs
+/// anything, and set `no_new_privs`, so neither it nor anything it starts can gain any back, such
+/// as by running a setuid program. The process switches to `credentials`, if they're given, once
+/// the rest are dropped, and keeps `keep` as it does, so a process run as a user other than root
+/// has them too. The credentials are applied here rather than with `Credentials::apply`, which
+/// would leave the process with no capabilities at all.
+///
+/// # Failures
+///
+/// * The highest capability the kernel knows of can't be read
+pub fn keep_only(
+    command: &mut Command,
+    keep: &[Capability],
+    credentials: Option<&Credentials>,
+) -> Result<()> {
+    let last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .map_err(|e| e.to_string())
+        .and_then(|body| body.trim().parse::<u32>().map_err(|e| e.to_string()))
+        .map_err(|e| {
+            Error::CapabilitiesFailed(format!("Failed to read the last capability: {}", e))
+        })?;
+    let drop = CapabilityDrop::new(keep, last_cap, credentials);
+    command.before_exec(move || drop.apply());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities_parse_by_name() {
+        assert_eq!(
+            "CAP_NET_BIND_SERVICE".parse::<Capability>().unwrap(),
+            Capability::NetBindService
+        );
+        assert_eq!(
+            "sys_nice".parse::<Capability>().unwrap(),
+            Capability::SysNice
+        );
+        assert_eq!(Capability::AuditRead.to_string(), "CAP_AUDIT_READ");
+        assert!("CAP_EVERYTHING".parse::<Capability>().is_err());
+    }
+
+    #[test]
+    fn everything_but_what_is_kept_is_dropped() {
+        let drop = CapabilityDrop::new(
+            &[Capability::NetBindService, Capability::AuditRead],
+            40,
+            None,
+        );
+
+        assert_eq!(drop.mask, 1u64 << 10 | 1u64 << 37);
+        assert_eq!(drop.bounding.len(), 39);
+        assert!(!drop.bounding.contains(&10));
+        assert!(drop.bounding.contains(&40));
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limiting the CPU, memory, and processes hooks and services use with Linux cgroups (version 2,
+//! the unified hierarchy). A process put in a cgroup takes every process it starts along with it,
+//! so the limits are for the whole tree.
+
+use std::fs::{self as stdfs, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use libc;
+
+use error::{Error, Result};
+use fs;
+use os::process::Pid;
+
+/// Where the unified cgroup hierarchy is mounted
+pub const CGROUP_ROOT: &'static str = "/sys/fs/cgroup";
+
+/// The period `cpu.max` limits are over, in microseconds
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// Limits for the processes in a `Cgroup`. Limits which are `None` are left unlimited.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResourceLimits {
+    /// The CPU time the processes may use, in thousandths of a CPU, so `1500` is one and a half
+    /// CPUs
+    pub cpu_millis: Option<u64>,
+    /// The memory the processes may use between them, in bytes
+    pub memory_max: Option<u64>,
+    /// How many processes there may be
+    pub pids_max: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Returns the controllers the limits need, and what to write to each controller's file.
+    fn files(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut files = Vec::new();
+        if let Some(cpu_millis) = self.cpu_millis {
+            // The kernel won't take a quota under a millisecond
+            let quota = ::std::cmp::max(cpu_millis * CPU_PERIOD_USEC / 1000, 1000);
+            files.push(("cpu", "cpu.max", format!("{} {}", quota, CPU_PERIOD_USEC)));
+        }
+        if let Some(memory_max) = self.memory_max {
+            files.push(("memory", "memory.max", memory_max.to_string()));
+        }
+        if let Some(pids_max) = self.pids_max {
+            files.push(("pids", "pids.max", pids_max.to_string()));
+        }
+        files
+    }
+}
+
+/// A cgroup which hooks and services can be put in.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Creates the cgroup `name` under the root of the hierarchy with the given limits, or sets
+    /// the limits of one which is already there.
+    ///
+    /// # Failures
+    ///
+    /// * See `create_in`
+    pub fn create<N: AsRef<Path>>(name: N, limits: &ResourceLimits) -> Result<Cgroup> {
+        Self::create_in(CGROUP_ROOT, name, limits)
+    }
+
+    /// Creates the cgroup `name` under the cgroup `parent`, such as one systemd delegated to the
+    /// process, with the given limits, or sets the limits of one which is already there. The
+    /// controllers the limits need are enabled in `parent` first.
+    ///
+    /// # Failures
+    ///
+    /// * `name` would lead outside of `parent`
+    /// * The cgroup can't be created, such as when the process isn't allowed to
+    /// * A controller the limits need can't be enabled, or a limit can't be set
+    pub fn create_in<P, N>(parent: P, name: N, limits: &ResourceLimits) -> Result<Cgroup>
+    where
+        P: AsRef<Path>,
+        N: AsRef<Path>,
+    {
+        let parent = parent.as_ref();
+        let path = fs::join_safe(parent, name)?;
+        let files = limits.files();
+        for &(controller, _, _) in &files {
+            write_file(
+                &parent.join("cgroup.subtree_control"),
+                &format!("+{}", controller),
+            )?;
+        }
+        if let Err(e) = stdfs::create_dir(&path) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(cgroup_failed(&path, "create", e));
+            }
+        }
+        for (_, file, value) in files {
+            write_file(&path.join(file), &value)?;
+        }
+        Ok(Cgroup { path: path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the running process `pid` into the cgroup. Any processes it already started stay
+    /// where they are, so a process which is about to be started is better put in with
+    /// `attach_command`.
+    ///
+    /// # Failures
+    ///
+    /// * The process can't be moved, such as when it belongs to another user
+    pub fn attach(&self, pid: Pid) -> Result<()> {
+        write_file(&self.path.join("cgroup.procs"), &pid.to_string())
+    }
+
+    /// Has the process `command` starts move itself into the cgroup before it runs anything, so
+    /// nothing it starts escapes the cgroup.
+    ///
+    /// # Failures
+    ///
+    /// * The cgroup can't be opened to be joined
+    pub fn attach_command(&self, command: &mut Command) -> Result<()> {
+        let procs = self.path.join("cgroup.procs");
+        // Opened here, as only async-signal-safe calls can be made between the fork and the
+        // exec. The standard library opens files close-on-exec.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&procs)
+            .map_err(|e| cgroup_failed(&procs, "open", e))?;
+        command.before_exec(move || {
+            // `0` is the process which writes it
+            if unsafe { libc::write(file.as_raw_fd(), b"0".as_ptr() as *const _, 1) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        Ok(())
+    }
+
+    /// Removes the cgroup, which has to be empty of processes by then.
+    ///
+    /// # Failures
+    ///
+    /// * A process is still in the cgroup
+    pub fn remove(self) -> Result<()> {
+        stdfs::remove_dir(&self.path).map_err(|e| cgroup_failed(&self.path, "remove", e))
+    }
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|mut file: File| file.write_all(value.as_bytes()))
+        .map_err(|e| cgroup_failed(path, &format!("write {} to", value), e))
+}
+
+fn cgroup_failed(path: &Path, action: &str, e: io::Error) -> Error {
+    Error::CgroupFailed(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_are_written_to_their_controllers_files() {
+        let limits = ResourceLimits {
+            cpu_millis: Some(1500),
+            memory_max: Some(512 * 1024 * 1024),
+            pids_max: None,
+        };
+
+        assert_eq!(
+            limits.files(),
+            vec![
+                ("cpu", "cpu.max", "150000 100000".to_string()),
+                ("memory", "memory.max", "536870912".to_string()),
+            ]
+        );
+        assert!(ResourceLimits::default().files().is_empty());
+        assert_eq!(
+            ResourceLimits {
+                cpu_millis: Some(1),
+                ..Default::default()
+            }
+            .files(),
+            vec![("cpu", "cpu.max", "1000 100000".to_string())]
+        );
+    }
+
+    #[test]
+    fn names_outside_of_the_parent_are_refused() {
+        let limits = ResourceLimits::default();
+
+        assert!(Cgroup::create_in("/sys/fs/cgroup/hab", "../escaped", &limits).is_err());
+    }
+}
@@ -12,10 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_os = "linux")]
+pub mod capabilities;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 pub mod ffi;
 pub mod filesystem;
 pub mod net;
 pub mod process;
 pub mod signals;
 pub mod system;
+pub mod systemd;
 pub mod users;
+pub mod watch;
@@ -22,3 +22,47 @@ mod imp;
 mod imp;
 
 pub use self::imp::*;
+
+use std::net::IpAddr;
+
+/// A network interface, as `interfaces` finds it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Interface {
+    /// The interface's name, ex: `eth0`, or on Windows its friendly name, ex: `Ethernet`
+    pub name: String,
+    pub index: u32,
+    pub addresses: Vec<InterfaceAddress>,
+    pub flags: InterfaceFlags,
+    /// The largest packet the interface sends, or `None` where the platform doesn't say
+    pub mtu: Option<u32>,
+}
+
+impl Interface {
+    pub fn ipv4_addresses(&self) -> impl Iterator<Item = &InterfaceAddress> {
+        self.addresses.iter().filter(|address| address.ip.is_ipv4())
+    }
+
+    pub fn ipv6_addresses(&self) -> impl Iterator<Item = &InterfaceAddress> {
+        self.addresses.iter().filter(|address| address.ip.is_ipv6())
+    }
+}
+
+/// An address assigned to an interface, along with the length of its network's prefix, ex: 24
+/// for a /24, where the platform says.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct InterfaceAddress {
+    pub ip: IpAddr,
+    pub prefix_len: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct InterfaceFlags {
+    /// The interface has been brought up
+    pub up: bool,
+    /// The interface is up and connected, such as to a cable or a wireless network
+    pub running: bool,
+    pub loopback: bool,
+    pub point_to_point: bool,
+    pub broadcast: bool,
+    pub multicast: bool,
+}
@@ -14,9 +14,13 @@
 
 use std::ffi::CStr;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
 
 use libc;
 
+use super::{Interface, InterfaceAddress, InterfaceFlags};
+
 pub fn hostname() -> io::Result<String> {
     let len = 255;
     let mut buf = Vec::<u8>::with_capacity(len);
@@ -30,6 +34,145 @@ pub fn hostname() -> io::Result<String> {
     }
 }
 
+/// Returns each network interface, in the order the system lists them, with their addresses.
+pub fn interfaces() -> io::Result<Vec<Interface>> {
+    let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut interfaces: Vec<Interface> = Vec::new();
+    // There's an entry for each of an interface's addresses, and one for its link besides
+    let mut next = addrs;
+    while !next.is_null() {
+        let entry = unsafe { &*next };
+        next = entry.ifa_next;
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        let position = match interfaces
+            .iter()
+            .position(|i| i.name.as_bytes() == name.to_bytes())
+        {
+            Some(position) => position,
+            None => {
+                interfaces.push(Interface {
+                    name: name.to_string_lossy().into_owned(),
+                    index: unsafe { libc::if_nametoindex(entry.ifa_name) },
+                    addresses: Vec::new(),
+                    flags: flags(entry.ifa_flags),
+                    mtu: None,
+                });
+                interfaces.len() - 1
+            }
+        };
+        let interface = &mut interfaces[position];
+        if let Some(ip) = unsafe { ip_from_sockaddr(entry.ifa_addr) } {
+            interface.addresses.push(InterfaceAddress {
+                ip: ip,
+                prefix_len: unsafe { ip_from_sockaddr(entry.ifa_netmask) }.map(prefix_len),
+            });
+        }
+        if interface.mtu.is_none() {
+            interface.mtu = unsafe { link_mtu(entry) };
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    Ok(interfaces)
+}
+
+fn flags(ifa_flags: libc::c_uint) -> InterfaceFlags {
+    let is_set = |flag: libc::c_int| ifa_flags & flag as libc::c_uint != 0;
+    InterfaceFlags {
+        up: is_set(libc::IFF_UP),
+        running: is_set(libc::IFF_RUNNING),
+        loopback: is_set(libc::IFF_LOOPBACK),
+        point_to_point: is_set(libc::IFF_POINTOPOINT),
+        broadcast: is_set(libc::IFF_BROADCAST),
+        multicast: is_set(libc::IFF_MULTICAST),
+    }
+}
+
+/// Returns the address in `addr`, if it's an IPv4 or IPv6 one, which leaves out the link layer
+/// addresses some entries have.
+unsafe fn ip_from_sockaddr(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match i32::from((*addr).sa_family) {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the length of the prefix of the network whose mask is `netmask`.
+fn prefix_len(netmask: IpAddr) -> u8 {
+    match netmask {
+        IpAddr::V4(mask) => mask.octets().iter().map(|b| b.count_ones() as u8).sum(),
+        IpAddr::V6(mask) => mask.octets().iter().map(|b| b.count_ones() as u8).sum(),
+    }
+}
+
+/// Returns the MTU of the interface `entry` is for, which Linux keeps in sysfs.
+#[cfg(target_os = "linux")]
+unsafe fn link_mtu(entry: &libc::ifaddrs) -> Option<u32> {
+    use std::fs;
+
+    let name = CStr::from_ptr(entry.ifa_name).to_string_lossy();
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse().ok())
+}
+
+/// Returns the MTU of the interface `entry` is for, which macOS keeps with the interface's link
+/// entry.
+#[cfg(target_os = "macos")]
+unsafe fn link_mtu(entry: &libc::ifaddrs) -> Option<u32> {
+    if entry.ifa_addr.is_null()
+        || i32::from((*entry.ifa_addr).sa_family) != libc::AF_LINK
+        || entry.ifa_data.is_null()
+    {
+        return None;
+    }
+    Some((*(entry.ifa_data as *const libc::if_data)).ifi_mtu as u32)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+unsafe fn link_mtu(_entry: &libc::ifaddrs) -> Option<u32> {
+    None
+}
+
 extern "C" {
     pub fn gethostname(name: *mut libc::c_char, size: libc::size_t) -> libc::c_int;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix_len_counts_mask_bits() {
+        assert_eq!(prefix_len("255.255.255.0".parse().unwrap()), 24);
+        assert_eq!(prefix_len("ffff:ffff:ffff:ffff::".parse().unwrap()), 64);
+    }
+
+    #[test]
+    fn loopback_is_listed() {
+        let interfaces = interfaces().unwrap();
+        let loopback = interfaces
+            .iter()
+            .find(|i| i.flags.loopback)
+            .expect("no loopback interface");
+
+        assert!(loopback.index > 0);
+        assert!(loopback
+            .ipv4_addresses()
+            .any(|address| address.ip == IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))));
+    }
+}
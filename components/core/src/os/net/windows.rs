@@ -12,13 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::ffi::OsString;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::windows::ffi::OsStringExt;
+use std::ptr;
+use std::slice;
 
+use winapi::shared::ifdef::IfOperStatusUp;
+use winapi::shared::ipifcons::{IF_TYPE_PPP, IF_TYPE_SOFTWARE_LOOPBACK, IF_TYPE_TUNNEL};
+use winapi::shared::minwindef::ULONG;
+use winapi::shared::winerror::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
+use winapi::shared::ws2def::{AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR, SOCKADDR_IN};
+use winapi::shared::ws2ipdef::SOCKADDR_IN6_LH;
+use winapi::um::iphlpapi::GetAdaptersAddresses;
+use winapi::um::iptypes::{
+    GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST, IP_ADAPTER_ADDRESSES,
+    IP_ADAPTER_NO_MULTICAST,
+};
 use winapi::um::winbase;
 use winapi::um::winnt::CHAR;
 
+use super::{Interface, InterfaceAddress, InterfaceFlags};
+
 const MAX_LEN: usize = 15;
 
+/// How much room `GetAdaptersAddresses` is given at first, which is what it recommends
+const ADAPTERS_BUF_LEN: usize = 15 * 1024;
+
 pub fn hostname() -> io::Result<String> {
     let mut buf = [0 as CHAR; MAX_LEN + 1];
     let mut len = buf.len() as u32;
@@ -33,3 +54,102 @@ pub fn hostname() -> io::Result<String> {
         .collect::<Vec<u8>>();
     Ok(String::from_utf8_lossy(&bytes).into_owned())
 }
+
+/// Returns each network adapter, in the order Windows lists them, with their unicast addresses.
+pub fn interfaces() -> io::Result<Vec<Interface>> {
+    // The adapters are 8 byte aligned, so the buffer is made of u64s
+    let mut buf: Vec<u64> = vec![0; ADAPTERS_BUF_LEN / 8];
+    loop {
+        let mut len = (buf.len() * 8) as ULONG;
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_UNSPEC as ULONG,
+                GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER,
+                ptr::null_mut(),
+                buf.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES,
+                &mut len,
+            )
+        };
+        match result {
+            ERROR_SUCCESS => break,
+            // The adapters changed between calls, or there are more of them than room for, and
+            // `len` is how much room they need
+            ERROR_BUFFER_OVERFLOW => buf.resize(len as usize / 8 + 1, 0),
+            e => return Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+
+    let mut interfaces = Vec::new();
+    let mut next = buf.as_ptr() as *const IP_ADAPTER_ADDRESSES;
+    while !next.is_null() {
+        let adapter = unsafe { &*next };
+        next = adapter.Next;
+        let mut addresses = Vec::new();
+        let mut unicast = adapter.FirstUnicastAddress;
+        while !unicast.is_null() {
+            let address = unsafe { &*unicast };
+            unicast = address.Next;
+            if let Some(ip) = unsafe { ip_from_sockaddr(address.Address.lpSockaddr) } {
+                addresses.push(InterfaceAddress {
+                    ip: ip,
+                    prefix_len: Some(address.OnLinkPrefixLength),
+                });
+            }
+        }
+        let up = adapter.OperStatus == IfOperStatusUp;
+        let point_to_point = adapter.IfType == IF_TYPE_PPP || adapter.IfType == IF_TYPE_TUNNEL;
+        let index = unsafe { adapter.u.s().IfIndex };
+        interfaces.push(Interface {
+            name: unsafe { from_wide_ptr(adapter.FriendlyName) },
+            index: if index != 0 {
+                index
+            } else {
+                adapter.Ipv6IfIndex
+            },
+            addresses: addresses,
+            // Windows only says whether an adapter is up, and it's running if it is
+            flags: InterfaceFlags {
+                up: up,
+                running: up,
+                loopback: adapter.IfType == IF_TYPE_SOFTWARE_LOOPBACK,
+                point_to_point: point_to_point,
+                broadcast: !point_to_point && adapter.IfType != IF_TYPE_SOFTWARE_LOOPBACK,
+                multicast: adapter.Flags & IP_ADAPTER_NO_MULTICAST == 0,
+            },
+            mtu: Some(adapter.Mtu),
+        });
+    }
+    Ok(interfaces)
+}
+
+unsafe fn ip_from_sockaddr(addr: *const SOCKADDR) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match i32::from((*addr).sa_family) {
+        AF_INET => {
+            let addr = &*(addr as *const SOCKADDR_IN);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                *addr.sin_addr.S_un.S_addr(),
+            ))))
+        }
+        AF_INET6 => {
+            let addr = &*(addr as *const SOCKADDR_IN6_LH);
+            Some(IpAddr::V6(Ipv6Addr::from(*addr.sin6_addr.u.Byte())))
+        }
+        _ => None,
+    }
+}
+
+unsafe fn from_wide_ptr(ptr: *const u16) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    let mut len = 0;
+    while *ptr.offset(len) != 0 {
+        len += 1;
+    }
+    OsString::from_wide(slice::from_raw_parts(ptr, len as usize))
+        .to_string_lossy()
+        .into_owned()
+}
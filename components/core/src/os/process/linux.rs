@@ -13,18 +13,22 @@
 // limitations under the License.
 
 use std::ffi::OsString;
+use std::fs;
 use std::io;
+use std::mem;
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use libc::{self, pid_t};
 
-use super::{OsSignal, Signal};
+use super::{OsSignal, ProcessGroup, Signal};
 use error::{Error, Result};
 
 pub type Pid = libc::pid_t;
 pub type SignalCode = libc::c_int;
+/// When a process started, in clock ticks since the system booted
+pub type StartTime = u64;
 
 impl OsSignal for Signal {
     fn from_signal_code(code: SignalCode) -> Option<Signal> {
@@ -70,8 +74,9 @@ pub fn current_pid() -> Pid {
     unsafe { libc::getpid() as pid_t }
 }
 
-/// Determines if a process is running with the given process identifier.
-pub fn is_alive(pid: Pid) -> bool {
+/// Determines if a process is running with the given process identifier, whichever process that
+/// is now.
+pub fn is_running(pid: Pid) -> bool {
     match unsafe { libc::kill(pid as pid_t, 0) } {
         0 => true,
         _ => match io::Error::last_os_error().raw_os_error() {
@@ -82,6 +87,87 @@ pub fn is_alive(pid: Pid) -> bool {
     }
 }
 
+/// Returns whether the process has exited. Unlike `is_running`, a child of this process which has
+/// exited but not yet been waited on counts as exited, and it's left to be waited on.
+pub fn has_exited(pid: Pid) -> bool {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let waited = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    // Nothing is filled in for a child which is still running
+    if waited == 0 && info.si_signo == libc::SIGCHLD {
+        return true;
+    }
+    !is_running(pid)
+}
+
+/// Returns when the process started, in clock ticks since the system booted, or `None` if it
+/// isn't running or there's no procfs to read it from.
+pub fn start_time(pid: Pid) -> Option<StartTime> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    start_time_from_stat(&stat)
+}
+
+/// Returns each running process, along with its parent, as `(pid, parent)`. They're read from
+/// procfs where there is one, and otherwise from `ps`.
+pub fn process_parents() -> Result<Vec<(Pid, Pid)>> {
+    if !Path::new("/proc/self/stat").is_file() {
+        return ps_parents();
+    }
+    let mut parents = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_string_lossy().parse::<Pid>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        // The process may exit while the others are read
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        if let Some(parent) = parent_from_stat(&stat) {
+            parents.push((pid, parent));
+        }
+    }
+    Ok(parents)
+}
+
+/// Returns the parent from the contents of a procfs `stat` file, which are the pid, the command
+/// in parentheses, the state, and then the parent's pid. The command may itself have spaces and
+/// parentheses in it, so the fields are counted from the last `)`.
+fn parent_from_stat(stat: &str) -> Option<Pid> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Returns the start time from the contents of a procfs `stat` file, which is its 22nd field.
+fn start_time_from_stat(stat: &str) -> Option<StartTime> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn ps_parents() -> Result<Vec<(Pid, Pid)>> {
+    let output = Command::new("ps")
+        .args(&["-A", "-o", "pid=", "-o", "ppid="])
+        .output()?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().map(|f| f.parse::<Pid>());
+            match (fields.next(), fields.next()) {
+                (Some(Ok(pid)), Some(Ok(parent))) => Some((pid, parent)),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
 pub fn signal(pid: Pid, signal: Signal) -> Result<()> {
     unsafe {
         match libc::kill(pid as pid_t, signal.os_signal()) {
@@ -91,6 +177,28 @@ pub fn signal(pid: Pid, signal: Signal) -> Result<()> {
     }
 }
 
+/// Sends `signal` to every process in the process group `pgid`.
+pub fn signal_group(pgid: Pid, signal: Signal) -> Result<()> {
+    match unsafe { libc::killpg(pgid, signal.os_signal()) } {
+        0 => Ok(()),
+        e => Err(Error::SignalFailed(e, io::Error::last_os_error())),
+    }
+}
+
+/// Has `command` start its child in `group`, with `setpgid(2)` or `setsid(2)`.
+pub fn set_process_group(command: &mut Command, group: ProcessGroup) {
+    command.before_exec(move || {
+        let set = match group {
+            ProcessGroup::New => unsafe { libc::setpgid(0, 0) },
+            ProcessGroup::NewSession => unsafe { libc::setsid() },
+        };
+        if set < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    });
+}
+
 /// Makes an `execvp(3)` system call to become a new program.
 ///
 /// Note that if successful, this function will not return.
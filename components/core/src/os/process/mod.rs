@@ -26,6 +26,17 @@ mod imp;
 
 pub use self::imp::*;
 
+use std::collections::HashSet;
+use std::io;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::Result;
+
+/// How often `terminate` checks whether the process has stopped
+const TERMINATE_CHECK_MS: u64 = 50;
+
 pub trait OsSignal {
     fn os_signal(&self) -> SignalCode;
     fn from_signal_code(SignalCode) -> Option<Signal>;
@@ -49,6 +60,19 @@ pub enum Signal {
     CHLD,
 }
 
+/// The process group a child is started in, rather than its parent's. A child in a group of its
+/// own isn't sent the signals meant for its parent, such as the `INT` from a Ctrl-C at the
+/// parent's terminal, and the group as a whole can be signalled with `signal_group`, whose id is
+/// the child's pid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProcessGroup {
+    /// The child leads a new process group
+    New,
+    /// The child leads a new session, and a new process group in it, with no controlling
+    /// terminal. On Windows this is the same as `New`.
+    NewSession,
+}
+
 impl From<i32> for Signal {
     fn from(val: i32) -> Signal {
         match val {
@@ -89,3 +113,213 @@ impl From<Signal> for i32 {
         }
     }
 }
+
+/// Returns whether the process `pid` is running. Given the `start_time` it was recorded with,
+/// such as alongside it in a pid file, it must also have started then, so a process which was
+/// given the pid after the recorded one exited isn't taken for it. Where the start time can't be
+/// read, such as without procfs, only the pid is checked.
+pub fn is_alive(pid: Pid, start_time: Option<StartTime>) -> bool {
+    if !is_running(pid) {
+        return false;
+    }
+    match (start_time, self::start_time(pid)) {
+        (Some(recorded), Some(started)) => recorded == started,
+        _ => true,
+    }
+}
+
+/// Spawns `command`, returning the child along with its start time, to be recorded with its pid
+/// and checked with `is_alive`. The start time can't be missed, as a child which has exited
+/// keeps it until it's waited on.
+///
+/// # Failures
+///
+/// * The child can't be spawned
+pub fn spawn_with_start_time(command: &mut Command) -> io::Result<(Child, Option<StartTime>)> {
+    let child = command.spawn()?;
+    let start_time = start_time(child.id() as Pid);
+    Ok((child, start_time))
+}
+
+/// Stops the process `pid`, asking it to stop with `Signal::TERM` and killing it with
+/// `Signal::KILL` if it's still running once `grace` has passed, or right away if it can't be
+/// asked. On Windows a process can only be asked to stop if it was started in a process group of
+/// its own. Returns whether the process had to be killed.
+///
+/// # Failures
+///
+/// * The process can't be killed, such as when it belongs to another user
+pub fn terminate(pid: Pid, grace: Duration) -> Result<bool> {
+    if has_exited(pid) {
+        return Ok(false);
+    }
+    match signal(pid, Signal::TERM) {
+        Ok(()) => {
+            let asked = Instant::now();
+            while asked.elapsed() < grace {
+                if has_exited(pid) {
+                    return Ok(false);
+                }
+                thread::sleep(Duration::from_millis(TERMINATE_CHECK_MS));
+            }
+        }
+        Err(e) => debug!("Killing {}, asking it to stop failed: {}", pid, e),
+    }
+    if has_exited(pid) {
+        return Ok(false);
+    }
+    signal(pid, Signal::KILL)?;
+    Ok(true)
+}
+
+/// Kills the process `pid` and every process descended from it, such as the helpers a service's
+/// `run` hook started, which would otherwise go on running after the service stops. The whole
+/// tree is found before anything is killed, as a killed process's children are given another
+/// parent, and then each process is killed before its children, so it can't start any more. A
+/// process started after the tree was found is missed.
+///
+/// # Failures
+///
+/// * The running processes can't be listed
+/// * A process in the tree which is still running can't be killed
+pub fn kill_tree(pid: Pid) -> Result<()> {
+    let parents = process_parents()?;
+    let mut tree = vec![pid];
+    let mut found: HashSet<Pid> = tree.iter().cloned().collect();
+    let mut next = 0;
+    while next < tree.len() {
+        let parent = tree[next];
+        for &(child, _) in parents.iter().filter(|&&(_, p)| p == parent) {
+            if found.insert(child) {
+                tree.push(child);
+            }
+        }
+        next += 1;
+    }
+
+    let mut failed = None;
+    for pid in tree {
+        if let Err(e) = signal(pid, Signal::KILL) {
+            if !has_exited(pid) && failed.is_none() {
+                failed = Some(e);
+            }
+        }
+    }
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn terminate_asks_before_killing() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as Pid;
+
+        assert!(!terminate(pid, Duration::from_secs(10)).unwrap());
+        assert_eq!(child.wait().unwrap().signal(), Some(15));
+        assert!(!terminate(pid, Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn start_time_tells_reused_pids_apart() {
+        let (mut child, start_time) =
+            spawn_with_start_time(Command::new("sleep").arg("30")).unwrap();
+        let pid = child.id() as Pid;
+        let start_time = start_time.unwrap();
+
+        assert!(is_alive(pid, None));
+        assert!(is_alive(pid, Some(start_time)));
+        assert!(!is_alive(pid, Some(start_time + 1)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!is_alive(pid, Some(start_time)));
+    }
+
+    #[test]
+    fn children_can_lead_their_own_group() {
+        let mut leader = Command::new("sleep");
+        leader.arg("30");
+        set_process_group(&mut leader, ProcessGroup::New);
+        let mut leader = leader.spawn().unwrap();
+        let mut session = Command::new("sleep");
+        session.arg("30");
+        set_process_group(&mut session, ProcessGroup::NewSession);
+        let mut session = session.spawn().unwrap();
+        let leader_pid = leader.id() as Pid;
+        let session_pid = session.id() as Pid;
+
+        assert_eq!(unsafe { ::libc::getpgid(leader_pid) }, leader_pid);
+        assert_ne!(unsafe { ::libc::getsid(leader_pid) }, leader_pid);
+        assert_eq!(unsafe { ::libc::getpgid(session_pid) }, session_pid);
+        assert_eq!(unsafe { ::libc::getsid(session_pid) }, session_pid);
+
+        signal_group(leader_pid, Signal::KILL).unwrap();
+        signal_group(session_pid, Signal::KILL).unwrap();
+        assert_eq!(leader.wait().unwrap().signal(), Some(9));
+        assert_eq!(session.wait().unwrap().signal(), Some(9));
+    }
+
+    #[test]
+    fn kill_tree_kills_descendants() {
+        let mut child = Command::new("sh")
+            .args(&["-c", "sh -c 'sleep 30; true' & sleep 30 & wait"])
+            .spawn()
+            .unwrap();
+        let pid = child.id() as Pid;
+        let descendants = |parents: &[(Pid, Pid)], pid: Pid| -> Vec<Pid> {
+            parents
+                .iter()
+                .filter(|&&(_, p)| p == pid)
+                .map(|&(c, _)| c)
+                .collect()
+        };
+        let started = Instant::now();
+        let tree = loop {
+            let parents = process_parents().unwrap();
+            let children = descendants(&parents, pid);
+            let grandchildren: Vec<Pid> = children
+                .iter()
+                .flat_map(|&c| descendants(&parents, c))
+                .collect();
+            if children.len() == 2 && grandchildren.len() == 1 {
+                break children
+                    .into_iter()
+                    .chain(grandchildren)
+                    .collect::<Vec<_>>();
+            }
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        kill_tree(pid).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(9));
+        for pid in tree {
+            // Whoever they were handed to may not have waited on them yet
+            let started = Instant::now();
+            while !has_exited(pid) && !is_zombie(pid) {
+                assert!(started.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    fn is_zombie(pid: Pid) -> bool {
+        use std::fs;
+
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat[stat.rfind(')').unwrap() + 1..]
+                .trim_left()
+                .starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+}
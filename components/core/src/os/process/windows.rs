@@ -12,24 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::io;
+use std::mem;
+use std::os::windows::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{self, Command};
 use std::ptr;
 
-use winapi::shared::minwindef::{DWORD, FALSE, LPDWORD};
-use winapi::um::handleapi;
+use winapi::shared::minwindef::{DWORD, FALSE, FILETIME, LPDWORD};
+use winapi::um::handleapi::{self, INVALID_HANDLE_VALUE};
 use winapi::um::processthreadsapi;
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+use winapi::um::wincon::{self, CTRL_BREAK_EVENT};
+use winapi::um::winbase::CREATE_NEW_PROCESS_GROUP;
 use winapi::um::winnt::{HANDLE, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE};
 
-use super::{OsSignal, Signal};
+use super::{OsSignal, ProcessGroup, Signal};
 use error::{Error, Result};
 
 const STILL_ACTIVE: u32 = 259;
 
 pub type Pid = DWORD;
 pub type SignalCode = DWORD;
+/// When a process started, in 100ns intervals since 1601
+pub type StartTime = u64;
 
 impl OsSignal for Signal {
     fn from_signal_code(code: SignalCode) -> Option<Signal> {
@@ -68,8 +78,9 @@ pub fn handle_from_pid(pid: Pid) -> Option<HANDLE> {
     }
 }
 
-/// Determines if a process is running with the given process identifier.
-pub fn is_alive(pid: Pid) -> bool {
+/// Determines if a process is running with the given process identifier, whichever process that
+/// is now.
+pub fn is_running(pid: Pid) -> bool {
     match handle_from_pid(pid) {
         Some(handle) => {
             let exit_status = exit_status(handle).expect("Failed to get exit status");
@@ -82,13 +93,134 @@ pub fn is_alive(pid: Pid) -> bool {
     }
 }
 
+/// Returns each running process, along with its parent, as `(pid, parent)`. Windows keeps the
+/// pid of a process's parent after the parent exits, and may give it to a new process, so a
+/// parent which started after the process is taken to be another process and left out.
+pub fn process_parents() -> Result<Vec<(Pid, Pid)>> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(Error::CreateToolhelp32SnapshotFailed(format!(
+            "Failed to list processes: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    let mut entries = Vec::new();
+    let mut entry: PROCESSENTRY32W = unsafe { mem::zeroed() };
+    entry.dwSize = mem::size_of::<PROCESSENTRY32W>() as DWORD;
+    let mut more = unsafe { Process32FirstW(snapshot, &mut entry) };
+    while more != FALSE {
+        entries.push((entry.th32ProcessID, entry.th32ParentProcessID));
+        more = unsafe { Process32NextW(snapshot, &mut entry) };
+    }
+    unsafe {
+        let _ = handleapi::CloseHandle(snapshot);
+    }
+
+    let started: HashMap<Pid, StartTime> = entries
+        .iter()
+        .filter_map(|&(pid, _)| start_time(pid).map(|at| (pid, at)))
+        .collect();
+    Ok(entries
+        .into_iter()
+        .filter(
+            |&(pid, parent)| match (started.get(&pid), started.get(&parent)) {
+                (Some(pid_at), Some(parent_at)) => parent_at <= pid_at,
+                _ => false,
+            },
+        )
+        .collect())
+}
+
+/// Returns when the process started, or `None` if it isn't running.
+pub fn start_time(pid: Pid) -> Option<StartTime> {
+    let handle = handle_from_pid(pid)?;
+    let mut times: [FILETIME; 4] = unsafe { mem::zeroed() };
+    let got = unsafe {
+        let (creation, rest) = times.split_at_mut(1);
+        processthreadsapi::GetProcessTimes(
+            handle,
+            &mut creation[0],
+            &mut rest[0],
+            &mut rest[1],
+            &mut rest[2],
+        )
+    };
+    unsafe {
+        let _ = handleapi::CloseHandle(handle);
+    }
+    if got == FALSE {
+        return None;
+    }
+    Some((u64::from(times[0].dwHighDateTime) << 32) | u64::from(times[0].dwLowDateTime))
+}
+
+/// Returns whether the process has exited.
+pub fn has_exited(pid: Pid) -> bool {
+    !is_running(pid)
+}
+
+/// Windows has no signals, so the ones which ask a process to stop are sent as a console
+/// Ctrl+Break, which only reaches a process started in a process group of its own, and
+/// `Signal::KILL` terminates the process. The rest aren't sent at all.
 pub fn signal(pid: Pid, signal: Signal) -> Result<()> {
-    debug!(
-        "sending no-op(windows) signal {} to pid {}",
-        signal.os_signal(),
-        pid
-    );
-    Ok(())
+    match signal {
+        Signal::INT | Signal::TERM | Signal::QUIT | Signal::HUP => {
+            if unsafe { wincon::GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) } == FALSE {
+                let e = io::Error::last_os_error();
+                return Err(Error::SignalFailed(e.raw_os_error().unwrap_or(0), e));
+            }
+            Ok(())
+        }
+        Signal::KILL => {
+            let handle = match handle_from_pid(pid) {
+                Some(handle) => handle,
+                None => {
+                    return Err(Error::TerminateProcessFailed(format!(
+                        "Failed to open process {}: {}",
+                        pid,
+                        io::Error::last_os_error()
+                    )))
+                }
+            };
+            let terminated = unsafe { processthreadsapi::TerminateProcess(handle, 1) };
+            let result = if terminated == FALSE {
+                Err(Error::TerminateProcessFailed(format!(
+                    "Failed to terminate process {}: {}",
+                    pid,
+                    io::Error::last_os_error()
+                )))
+            } else {
+                Ok(())
+            };
+            unsafe {
+                let _ = handleapi::CloseHandle(handle);
+            }
+            result
+        }
+        _ => {
+            debug!(
+                "sending no-op(windows) signal {} to pid {}",
+                signal.os_signal(),
+                pid
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Sends `signal` to every process in the process group `pgid`. Ctrl-Break reaches the whole
+/// group on its own, and `Signal::KILL` kills the group's leader and every process descended
+/// from it.
+pub fn signal_group(pgid: Pid, signal: Signal) -> Result<()> {
+    match signal {
+        Signal::KILL => super::kill_tree(pgid),
+        _ => self::signal(pgid, signal),
+    }
+}
+
+/// Has `command` start its child in `group`, with `CREATE_NEW_PROCESS_GROUP`.
+pub fn set_process_group(command: &mut Command, group: ProcessGroup) {
+    command.creation_flags(CREATE_NEW_PROCESS_GROUP);
 }
 
 /// Executes a command as a child process and exits with the child's exit code.
@@ -44,6 +44,7 @@ use winapi::um::fileapi::{
 };
 use winapi::um::handleapi::{self, INVALID_HANDLE_VALUE};
 use winapi::um::ioapiset;
+use winapi::um::jobapi2;
 use winapi::um::minwinbase::{LPSECURITY_ATTRIBUTES, OVERLAPPED, SECURITY_ATTRIBUTES};
 use winapi::um::namedpipeapi;
 use winapi::um::processthreadsapi::{
@@ -52,16 +53,20 @@ use winapi::um::processthreadsapi::{
 use winapi::um::synchapi;
 use winapi::um::userenv;
 use winapi::um::winbase::{
-    CREATE_NEW_PROCESS_GROUP, CREATE_UNICODE_ENVIRONMENT, FILE_FLAG_FIRST_PIPE_INSTANCE,
-    FILE_FLAG_OPEN_REPARSE_POINT, FILE_FLAG_OVERLAPPED, INFINITE, PIPE_ACCESS_INBOUND,
-    PIPE_ACCESS_OUTBOUND, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
-    PIPE_WAIT, SECURITY_SQOS_PRESENT, STARTF_USESTDHANDLES, STD_ERROR_HANDLE, STD_INPUT_HANDLE,
-    STD_OUTPUT_HANDLE, WAIT_OBJECT_0,
+    CREATE_NEW_PROCESS_GROUP, CREATE_SUSPENDED, CREATE_UNICODE_ENVIRONMENT,
+    FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OPEN_REPARSE_POINT, FILE_FLAG_OVERLAPPED, INFINITE,
+    PIPE_ACCESS_INBOUND, PIPE_ACCESS_OUTBOUND, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+    PIPE_TYPE_BYTE, PIPE_WAIT, SECURITY_SQOS_PRESENT, STARTF_USESTDHANDLES, STD_ERROR_HANDLE,
+    STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, WAIT_OBJECT_0,
 };
 use winapi::um::winnt::{
-    ACCESS_MASK, FILE_GENERIC_WRITE, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
-    FILE_WRITE_DATA, GENERIC_READ, GENERIC_WRITE, HANDLE, LPCWSTR, LPWSTR, MAXDWORD, PHANDLE,
-    READ_CONTROL, WRITE_DAC,
+    JobObjectCpuRateControlInformation, JobObjectExtendedLimitInformation, ACCESS_MASK,
+    FILE_GENERIC_WRITE, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, FILE_WRITE_DATA,
+    GENERIC_READ, GENERIC_WRITE, HANDLE, JOBOBJECTINFOCLASS,
+    JOBOBJECT_CPU_RATE_CONTROL_INFORMATION, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_CPU_RATE_CONTROL_ENABLE, JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+    JOB_OBJECT_LIMIT_JOB_MEMORY, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, LPCWSTR, LPWSTR, MAXDWORD,
+    PHANDLE, READ_CONTROL, WRITE_DAC,
 };
 
 use error::{Error, Result};
@@ -180,6 +185,46 @@ impl Child {
         svc_user: U,
         svc_encrypted_password: Option<P>,
     ) -> Result<Child>
+    where
+        U: ToString,
+        P: ToString,
+    {
+        Self::spawn_imp(program, args, env, svc_user, svc_encrypted_password, None)
+    }
+
+    /// Spawns the child as `spawn` does, but in `job`, so that it and whatever it starts are
+    /// limited and stopped together. The child is started suspended and only let run once it's
+    /// in the job, so nothing it starts can escape the job.
+    pub fn spawn_in_job<U, P>(
+        program: &str,
+        args: Vec<&str>,
+        env: &HashMap<String, String>,
+        svc_user: U,
+        svc_encrypted_password: Option<P>,
+        job: &Job,
+    ) -> Result<Child>
+    where
+        U: ToString,
+        P: ToString,
+    {
+        Self::spawn_imp(
+            program,
+            args,
+            env,
+            svc_user,
+            svc_encrypted_password,
+            Some(job),
+        )
+    }
+
+    fn spawn_imp<U, P>(
+        program: &str,
+        args: Vec<&str>,
+        env: &HashMap<String, String>,
+        svc_user: U,
+        svc_encrypted_password: Option<P>,
+        job: Option<&Job>,
+    ) -> Result<Child>
     where
         U: ToString,
         P: ToString,
@@ -246,7 +291,10 @@ impl Child {
         si.hStdInput = stdin.raw();
         si.hStdOutput = stdout.raw();
         si.hStdError = stderr.raw();
-        let flags = CREATE_UNICODE_ENVIRONMENT | CREATE_NEW_PROCESS_GROUP;
+        let mut flags = CREATE_UNICODE_ENVIRONMENT | CREATE_NEW_PROCESS_GROUP;
+        if job.is_some() {
+            flags |= CREATE_SUSPENDED;
+        }
 
         let cred = ServiceCredential::new(svc_user, svc_encrypted_password)?;
         if cred.is_current_user() {
@@ -255,6 +303,26 @@ impl Child {
             create_process_as_user(cred, cmd_str.as_mut_ptr(), flags, env, &mut si, &mut pi)?;
         }
 
+        if let Some(job) = job {
+            let started = job.assign_raw(pi.hProcess).and_then(|()| {
+                if unsafe { processthreadsapi::ResumeThread(pi.hThread) } == DWORD::max_value() {
+                    return Err(Error::JobObjectFailed(format!(
+                        "Failed to resume the child once it was in its job: {}",
+                        io::Error::last_os_error()
+                    )));
+                }
+                Ok(())
+            });
+            if let Err(e) = started {
+                unsafe {
+                    processthreadsapi::TerminateProcess(pi.hProcess, 1);
+                    handleapi::CloseHandle(pi.hThread);
+                    handleapi::CloseHandle(pi.hProcess);
+                }
+                return Err(e);
+            }
+        }
+
         // We close the thread handle because we don't care about keeping
         // the thread id valid, and we aren't keeping the thread handle
         // around to be able to close it later.
@@ -294,6 +362,105 @@ impl Child {
     }
 }
 
+/// Limits for the processes in a `Job`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JobLimits {
+    /// Whether the processes are terminated once the last handle to the job is closed, as when
+    /// the `Job` is dropped, much as a Unix process group is killed together
+    pub kill_on_close: bool,
+    /// The most memory, in bytes, the processes may commit between them
+    pub memory_limit: Option<usize>,
+    /// The most of the machine's CPU time, in percent from 1 to 100, the processes may use
+    /// between them
+    pub cpu_rate: Option<u32>,
+}
+
+/// A Windows Job Object, which groups a child with the processes it starts, so that they can be
+/// limited and stopped together.
+pub struct Job {
+    handle: Handle,
+}
+
+impl Job {
+    /// Creates a job with the given limits.
+    ///
+    /// # Failures
+    ///
+    /// * The job can't be created, or its limits can't be set, such as a CPU rate on a version
+    ///   of Windows which can't limit one
+    pub fn new(limits: &JobLimits) -> Result<Job> {
+        let raw = unsafe { jobapi2::CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if raw.is_null() {
+            return Err(Error::JobObjectFailed(format!(
+                "Failed to create a job: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        let job = Job {
+            handle: Handle::new(raw),
+        };
+
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+        if limits.kill_on_close {
+            info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        }
+        if let Some(memory_limit) = limits.memory_limit {
+            info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_JOB_MEMORY;
+            info.JobMemoryLimit = memory_limit;
+        }
+        job.set_information(JobObjectExtendedLimitInformation, &mut info, "limits")?;
+
+        if let Some(cpu_rate) = limits.cpu_rate {
+            let mut info: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION = unsafe { mem::zeroed() };
+            info.ControlFlags =
+                JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+            // In hundredths of a percent
+            unsafe { *info.u.CpuRate_mut() = cmp::min(cmp::max(cpu_rate, 1), 100) * 100 };
+            job.set_information(JobObjectCpuRateControlInformation, &mut info, "CPU rate")?;
+        }
+        Ok(job)
+    }
+
+    /// Puts a child which is already running in the job. Whatever the child started before it
+    /// was put in the job stays out of it, which `Child::spawn_in_job` avoids.
+    pub fn assign(&self, child: &Child) -> Result<()> {
+        self.assign_raw(child.handle.raw())
+    }
+
+    /// Terminates every process in the job, with the given exit code.
+    pub fn terminate(&self, exit_code: u32) -> Result<()> {
+        cvt(unsafe { jobapi2::TerminateJobObject(self.handle.raw(), exit_code) }).map_err(|e| {
+            Error::JobObjectFailed(format!("Failed to terminate the job's processes: {}", e))
+        })?;
+        Ok(())
+    }
+
+    fn assign_raw(&self, process: HANDLE) -> Result<()> {
+        cvt(unsafe { jobapi2::AssignProcessToJobObject(self.handle.raw(), process) }).map_err(
+            |e| Error::JobObjectFailed(format!("Failed to put a process in the job: {}", e)),
+        )?;
+        Ok(())
+    }
+
+    fn set_information<T>(
+        &self,
+        class: JOBOBJECTINFOCLASS,
+        info: &mut T,
+        what: &str,
+    ) -> Result<()> {
+        cvt(unsafe {
+            jobapi2::SetInformationJobObject(
+                self.handle.raw(),
+                class,
+                info as *mut T as LPVOID,
+                mem::size_of::<T>() as DWORD,
+            )
+        })
+        .map_err(|e| Error::JobObjectFailed(format!("Failed to set the job's {}: {}", what, e)))?;
+        Ok(())
+    }
+}
+
 pub trait AsInner<Inner: ?Sized> {
     fn as_inner(&self) -> &Inner;
 }
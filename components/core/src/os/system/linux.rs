@@ -12,14 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::ffi::CStr;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
 use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
 
 use libc;
 
 use errno::errno;
 use error::{Error, Result};
-use os::system::Uname;
+use os::system::{DiskUsage, LoadAverage, MemoryStats, SystemStats, Uname};
 
 pub fn uname() -> Result<Uname> {
     unsafe { uname_libc() }
@@ -54,3 +59,178 @@ unsafe fn uname_libc() -> Result<Uname> {
             .into_owned(),
     })
 }
+
+/// Returns the system's CPUs, load, memory, and the usage of each mounted filesystem, which are
+/// read from procfs where there is one.
+///
+/// # Failures
+///
+/// * procfs can't be read
+pub fn stats() -> Result<SystemStats> {
+    Ok(SystemStats {
+        cpu_count: cpu_count(),
+        load: load_average(),
+        memory: memory()?,
+        disks: disks()?,
+    })
+}
+
+fn cpu_count() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => n as usize,
+        _ => 1,
+    }
+}
+
+fn load_average() -> Option<LoadAverage> {
+    let mut load = [0f64; 3];
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 3) } != 3 {
+        return None;
+    }
+    Some(LoadAverage {
+        one: load[0],
+        five: load[1],
+        fifteen: load[2],
+    })
+}
+
+fn memory() -> Result<MemoryStats> {
+    if Path::new("/proc/meminfo").is_file() {
+        return Ok(memory_from_meminfo(&fs::read_to_string("/proc/meminfo")?));
+    }
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(MemoryStats {
+        total: if pages > 0 && page_size > 0 {
+            pages as u64 * page_size as u64
+        } else {
+            0
+        },
+        available: None,
+    })
+}
+
+/// Returns the memory from the contents of `/proc/meminfo`, whose sizes are in KiB. Kernels
+/// older than 3.14 don't say how much memory is available.
+fn memory_from_meminfo(meminfo: &str) -> MemoryStats {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name) && line[name.len()..].starts_with(':'))
+            .and_then(|line| line[name.len() + 1..].split_whitespace().next())
+            .and_then(|kib| kib.parse::<u64>().ok())
+            .map(|kib| kib * 1024)
+    };
+    MemoryStats {
+        total: field("MemTotal").unwrap_or(0),
+        available: field("MemAvailable"),
+    }
+}
+
+/// Returns the usage of each mounted filesystem which has any space, which leaves out the likes
+/// of procfs and sysfs. A filesystem mounted in more than one place is listed where it was first
+/// mounted.
+fn disks() -> Result<Vec<DiskUsage>> {
+    let mount_points = if Path::new("/proc/self/mounts").is_file() {
+        mount_points_from_mounts(&fs::read_to_string("/proc/self/mounts")?)
+    } else {
+        vec![PathBuf::from("/")]
+    };
+    let mut seen = HashSet::new();
+    let mut disks = Vec::new();
+    for mount_point in mount_points {
+        let c_path = match CString::new(mount_point.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(_) => continue,
+        };
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        // A mount which can't be read, such as one the user has no access to, is left out
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 || stat.f_blocks == 0 {
+            continue;
+        }
+        if !seen.insert(stat.f_fsid) {
+            continue;
+        }
+        let block_size = stat.f_frsize as u64;
+        disks.push(DiskUsage {
+            mount_point: mount_point,
+            total: stat.f_blocks as u64 * block_size,
+            available: stat.f_bavail as u64 * block_size,
+        });
+    }
+    Ok(disks)
+}
+
+/// Returns the mount points from the contents of `/proc/self/mounts`, which escapes spaces, tabs,
+/// newlines and backslashes in them as octal, ex: `\040` for a space.
+fn mount_points_from_mounts(mounts: &str) -> Vec<PathBuf> {
+    mounts
+        .lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(unescape_mount_point)
+        .collect()
+}
+
+fn unescape_mount_point(escaped: &str) -> PathBuf {
+    let escaped = escaped.as_bytes();
+    let mut path = Vec::with_capacity(escaped.len());
+    let mut i = 0;
+    while i < escaped.len() {
+        let octal = if escaped[i] == b'\\' {
+            escaped
+                .get(i + 1..i + 4)
+                .and_then(|digits| str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        } else {
+            None
+        };
+        match octal {
+            Some(byte) => {
+                path.push(byte);
+                i += 4;
+            }
+            None => {
+                path.push(escaped[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsStr::from_bytes(&path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn meminfo_is_read_in_bytes() {
+        let meminfo = "MemTotal:        8048296 kB\nMemFree:          512000 kB\n\
+                       MemAvailable:    4024148 kB\n";
+        assert_eq!(
+            memory_from_meminfo(meminfo),
+            MemoryStats {
+                total: 8048296 * 1024,
+                available: Some(4024148 * 1024),
+            }
+        );
+        assert_eq!(memory_from_meminfo("MemTotal: 1024 kB\n").available, None);
+    }
+
+    #[test]
+    fn mount_points_are_unescaped() {
+        let mounts = "proc /proc proc rw,relatime 0 0\n\
+                      /dev/sdb1 /mnt/my\\040disk ext4 rw 0 0\n";
+        assert_eq!(
+            mount_points_from_mounts(mounts),
+            vec![PathBuf::from("/proc"), PathBuf::from("/mnt/my disk")]
+        );
+    }
+
+    #[test]
+    fn stats_are_found() {
+        let stats = stats().unwrap();
+        assert!(stats.cpu_count >= 1);
+        assert!(stats.memory.total > 0);
+        assert!(stats.disks.iter().all(|disk| disk.available <= disk.total));
+    }
+}
@@ -15,12 +15,14 @@
 #[cfg(windows)]
 mod windows;
 #[cfg(windows)]
-pub use self::windows::uname;
+pub use self::windows::{stats, uname};
 
 #[cfg(not(windows))]
 pub mod linux;
 #[cfg(not(windows))]
-pub use self::linux::uname;
+pub use self::linux::{stats, uname};
+
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct Uname {
@@ -30,3 +32,42 @@ pub struct Uname {
     pub version: String,
     pub machine: String,
 }
+
+/// The resources of the system, and how much of them is in use, as `stats` finds them.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SystemStats {
+    /// How many CPUs are online
+    pub cpu_count: usize,
+    /// The load averages, which Windows doesn't keep
+    pub load: Option<LoadAverage>,
+    pub memory: MemoryStats,
+    /// The usage of each mounted filesystem, or on Windows each fixed drive
+    pub disks: Vec<DiskUsage>,
+}
+
+/// How many processes were running or waiting to run, on average, over the last 1, 5 and 15
+/// minutes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+/// The system's memory, in bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct MemoryStats {
+    pub total: u64,
+    /// How much can be used without swapping, or `None` where the platform doesn't say, such as
+    /// a Unix without procfs
+    pub available: Option<u64>,
+}
+
+/// The space on a mounted filesystem, in bytes.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub mount_point: PathBuf,
+    pub total: u64,
+    /// How much can be used by an unprivileged user, which leaves out what's reserved for root
+    pub available: u64,
+}
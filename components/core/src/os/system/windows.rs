@@ -12,8 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::ffi::OsString;
+use std::io;
+use std::mem;
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::ptr;
+
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::um::fileapi::{GetDiskFreeSpaceExW, GetDriveTypeW, GetLogicalDriveStringsW};
+use winapi::um::sysinfoapi::{GetSystemInfo, GlobalMemoryStatusEx, MEMORYSTATUSEX, SYSTEM_INFO};
+use winapi::um::winbase::DRIVE_FIXED;
+use winapi::um::winnt::ULARGE_INTEGER;
+
 use error::Result;
-use os::system::Uname;
+use os::system::{DiskUsage, MemoryStats, SystemStats, Uname};
 
 pub fn uname() -> Result<Uname> {
     Ok(Uname {
@@ -24,3 +37,83 @@ pub fn uname() -> Result<Uname> {
         machine: String::from("x86_64"),
     })
 }
+
+/// Returns the system's CPUs, memory, and the usage of each fixed drive. Windows keeps no load
+/// averages.
+///
+/// # Failures
+///
+/// * The memory or the drives can't be read
+pub fn stats() -> Result<SystemStats> {
+    Ok(SystemStats {
+        cpu_count: cpu_count(),
+        load: None,
+        memory: memory()?,
+        disks: disks()?,
+    })
+}
+
+fn cpu_count() -> usize {
+    let mut info: SYSTEM_INFO = unsafe { mem::zeroed() };
+    unsafe { GetSystemInfo(&mut info) };
+    if info.dwNumberOfProcessors > 0 {
+        info.dwNumberOfProcessors as usize
+    } else {
+        1
+    }
+}
+
+fn memory() -> Result<MemoryStats> {
+    let mut status: MEMORYSTATUSEX = unsafe { mem::zeroed() };
+    status.dwLength = mem::size_of::<MEMORYSTATUSEX>() as DWORD;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == FALSE {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(MemoryStats {
+        total: status.ullTotalPhys,
+        available: Some(status.ullAvailPhys),
+    })
+}
+
+/// Returns the usage of each fixed drive, leaving out removable, network and optical drives,
+/// which may be slow to answer or have nothing in them.
+fn disks() -> Result<Vec<DiskUsage>> {
+    let mut buf: Vec<u16> = vec![0; 256];
+    loop {
+        let len = unsafe { GetLogicalDriveStringsW(buf.len() as DWORD, buf.as_mut_ptr()) };
+        if len == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // The buffer was too small, and `len` is how big it has to be
+        if len as usize > buf.len() {
+            buf.resize(len as usize, 0);
+            continue;
+        }
+        buf.truncate(len as usize);
+        break;
+    }
+
+    let mut disks = Vec::new();
+    // The drives are each null terminated, ex: `C:\` `\0` `D:\` `\0`
+    for drive in buf.split(|c| *c == 0).filter(|drive| !drive.is_empty()) {
+        let wide: Vec<u16> = drive.iter().cloned().chain(Some(0)).collect();
+        if unsafe { GetDriveTypeW(wide.as_ptr()) } != DRIVE_FIXED {
+            continue;
+        }
+        let mut available: ULARGE_INTEGER = unsafe { mem::zeroed() };
+        let mut total: ULARGE_INTEGER = unsafe { mem::zeroed() };
+        let got = unsafe {
+            GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, &mut total, ptr::null_mut())
+        };
+        // A drive which can't be read, such as a locked BitLocker volume, is left out
+        if got == FALSE {
+            continue;
+        }
+        disks.push(DiskUsage {
+            mount_point: PathBuf::from(OsString::from_wide(drive)),
+            total: unsafe { *total.QuadPart() },
+            available: unsafe { *available.QuadPart() },
+        });
+    }
+    Ok(disks)
+}
@@ -0,0 +1,235 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reporting to systemd, when it runs this process as a service, through its `sd_notify`
+//! protocol: that the process is ready, what it's doing, and that it's still alive for the
+//! service's watchdog. When the process isn't run by systemd, or its service doesn't take
+//! notifications, none of these do anything.
+
+use std::ffi::OsStr;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use env as henv;
+use error::Result;
+use os::process::{self, Pid};
+
+/// The socket systemd takes notifications on
+pub const NOTIFY_SOCKET_ENVVAR: &'static str = "NOTIFY_SOCKET";
+/// How often, in microseconds, systemd's watchdog expects to hear from the service
+pub const WATCHDOG_USEC_ENVVAR: &'static str = "WATCHDOG_USEC";
+/// The process systemd's watchdog expects to hear from
+pub const WATCHDOG_PID_ENVVAR: &'static str = "WATCHDOG_PID";
+
+/// Sends `state`, one or more newline separated `KEY=VALUE` assignments of the `sd_notify`
+/// protocol, to systemd. Returns whether it was sent, which it isn't when the process isn't
+/// run by systemd with notifications.
+///
+/// # Failures
+///
+/// * systemd's socket can't be written to
+pub fn notify(state: &str) -> Result<bool> {
+    match henv::var_os(NOTIFY_SOCKET_ENVVAR) {
+        Some(socket) => Ok(send(&socket, state)?),
+        None => Ok(false),
+    }
+}
+
+/// Tells systemd the process has started up and is ready, such as once its init hook and first
+/// health check have succeeded.
+pub fn notify_ready() -> Result<bool> {
+    notify("READY=1")
+}
+
+/// Tells systemd what the process is doing, which `systemctl status` shows, such as the result
+/// of its last health check.
+pub fn notify_status(status: &str) -> Result<bool> {
+    notify(&format!("STATUS={}", status.replace('\n', " ")))
+}
+
+/// Tells systemd the process is shutting down.
+pub fn notify_stopping() -> Result<bool> {
+    notify("STOPPING=1")
+}
+
+/// Tells systemd's watchdog the process is still alive.
+pub fn notify_watchdog() -> Result<bool> {
+    notify("WATCHDOG=1")
+}
+
+/// Returns how often systemd's watchdog expects to hear from the process, or `None` if the
+/// watchdog isn't watching it.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        henv::var(WATCHDOG_USEC_ENVVAR).ok(),
+        henv::var(WATCHDOG_PID_ENVVAR).ok(),
+        process::current_pid(),
+    )
+}
+
+fn watchdog_interval_from(
+    usec: Option<String>,
+    pid: Option<String>,
+    own_pid: Pid,
+) -> Option<Duration> {
+    // A child which inherited the service's environment isn't the one being watched
+    if let Some(pid) = pid {
+        if pid.parse::<Pid>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Tells systemd's watchdog the process is alive from a thread of its own until it's dropped.
+pub struct WatchdogKeepalive {
+    _stop: Sender<()>,
+}
+
+/// Starts telling systemd's watchdog the process is alive, twice as often as it expects to hear
+/// from it. Returns `None` if the watchdog isn't watching the process.
+///
+/// The keepalive says the process is alive however it's doing, so a process which can tell
+/// whether it's healthy is better off calling `notify_watchdog` itself whenever it is.
+///
+/// # Failures
+///
+/// * The thread can't be started
+pub fn watchdog_keepalive() -> Result<Option<WatchdogKeepalive>> {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return Ok(None),
+    };
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    thread::Builder::new()
+        .name("systemd-watchdog".to_string())
+        .spawn(move || loop {
+            if let Err(e) = notify_watchdog() {
+                debug!("Failed to notify systemd's watchdog: {}", e);
+            }
+            match stop_rx.recv_timeout(interval / 2) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => return,
+            }
+        })?;
+    Ok(Some(WatchdogKeepalive { _stop: stop_tx }))
+}
+
+#[cfg(unix)]
+fn send(socket: &OsStr, state: &str) -> io::Result<bool> {
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    use libc;
+
+    let path = socket.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not a socket systemd can be notified on: {:?}", socket),
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    let path_offset = addr.sun_path.as_ptr() as usize - &addr as *const _ as usize;
+    // An abstract socket, which is named with an `@` in the variable, is named with a nul in its
+    // address, and its address is no longer than its name
+    let addr_len = if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+        path_offset + path.len()
+    } else {
+        mem::size_of::<libc::sockaddr_un>()
+    };
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            0,
+            &addr as *const _ as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    let result = if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(true)
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &OsStr, _state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_is_for_the_watched_process() {
+        let usec = Some("30000000".to_string());
+
+        assert_eq!(
+            watchdog_interval_from(usec.clone(), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval_from(usec.clone(), Some("42".to_string()), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval_from(usec, Some("7".to_string()), 42),
+            None
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("0".to_string()), None, 42),
+            None
+        );
+        assert_eq!(watchdog_interval_from(None, None, 42), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn notifications_are_sent_to_the_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        use tempfile::Builder;
+
+        let dir = Builder::new().prefix("systemd").tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        assert!(send(path.as_os_str(), "READY=1\nSTATUS=Running").unwrap());
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Running");
+        assert!(send(dir.path().join("missing").as_os_str(), "READY=1").is_err());
+    }
+}
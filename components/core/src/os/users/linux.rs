@@ -12,11 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_os = "linux")]
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
+use std::process::Command;
 
+#[cfg(target_os = "linux")]
+use libc::{self, c_int};
 use linux_users;
 use linux_users::os::unix::{GroupExt, UserExt};
 
+use super::UserOptions;
+use error::{Error, Result};
+
 pub fn get_uid_by_name(owner: &str) -> Option<u32> {
     linux_users::get_user_by_name(owner).map(|u| u.uid())
 }
@@ -67,3 +80,243 @@ pub fn get_home_for_user(username: &str) -> Option<PathBuf> {
 pub fn root_level_account() -> String {
     "root".to_string()
 }
+
+/// Creates the system group `name` unless it already exists. Returns whether it was created.
+///
+/// The group is created with `groupadd`, or with busybox's `addgroup` where there's no
+/// `groupadd`, such as on Alpine.
+///
+/// # Failures
+///
+/// * Neither `groupadd` nor `addgroup` is installed
+/// * The group can't be created, such as when not running as root
+pub fn ensure_group(name: &str) -> Result<bool> {
+    if get_gid_by_name(name).is_some() {
+        return Ok(false);
+    }
+    let mut groupadd = Command::new("groupadd");
+    groupadd.arg("--system").arg(name);
+    let mut addgroup = Command::new("addgroup");
+    addgroup.arg("-S").arg(name);
+    debug!("Creating group {}", name);
+    match provision(groupadd, addgroup) {
+        Ok(()) => Ok(true),
+        // Someone else created it first
+        Err(_) if get_gid_by_name(name).is_some() => Ok(false),
+        Err(e) => Err(Error::UserProvisioningFailed(format!(
+            "Can't create group {}: {}",
+            name, e
+        ))),
+    }
+}
+
+/// Creates the user `name` unless it already exists, creating its group first if that's missing
+/// too. Returns whether the user was created.
+///
+/// The user is created with `useradd`, or with busybox's `adduser` where there's no `useradd`,
+/// such as on Alpine.
+///
+/// # Failures
+///
+/// * Neither `useradd` nor `adduser` is installed
+/// * The user or its group can't be created, such as when not running as root
+pub fn ensure_user(name: &str, opts: &UserOptions) -> Result<bool> {
+    if let Some(ref group) = opts.group {
+        ensure_group(group)?;
+    }
+    if get_uid_by_name(name).is_some() {
+        return Ok(false);
+    }
+    let mut useradd = Command::new("useradd");
+    // busybox's adduser asks for a password unless told not to
+    let mut adduser = Command::new("adduser");
+    adduser.arg("-D");
+    if opts.system {
+        useradd.arg("--system");
+        adduser.arg("-S");
+    }
+    if let Some(ref group) = opts.group {
+        useradd.arg("-g").arg(group);
+        adduser.arg("-G").arg(group);
+    }
+    match opts.home {
+        Some(ref home) => {
+            useradd.arg("-m").arg("-d").arg(home);
+            adduser.arg("-h").arg(home);
+        }
+        None => {
+            useradd.arg("-M");
+            adduser.arg("-H");
+        }
+    }
+    if let Some(ref shell) = opts.shell {
+        useradd.arg("-s").arg(shell);
+        adduser.arg("-s").arg(shell);
+    }
+    if let Some(ref comment) = opts.comment {
+        useradd.arg("-c").arg(comment);
+        adduser.arg("-g").arg(comment);
+    }
+    useradd.arg(name);
+    adduser.arg(name);
+    debug!("Creating user {}", name);
+    match provision(useradd, adduser) {
+        Ok(()) => Ok(true),
+        // Someone else created it first
+        Err(_) if get_uid_by_name(name).is_some() => Ok(false),
+        Err(e) => Err(Error::UserProvisioningFailed(format!(
+            "Can't create user {}: {}",
+            name, e
+        ))),
+    }
+}
+
+/// Who a child process runs as: its user, group and supplementary groups, looked up ahead of
+/// time so the child doesn't have to read the user and group databases between fork and exec.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl Credentials {
+    /// Looks up the credentials `user` logs in with, as `initgroups` sets them: its primary
+    /// group, or `group` if one is given, and every group it's a member of, along with
+    /// `extra_groups`, such as those which are granted access to a device or socket.
+    ///
+    /// # Failures
+    ///
+    /// * The user, or one of the groups, doesn't exist
+    pub fn for_user(user: &str, group: Option<&str>, extra_groups: &[&str]) -> Result<Credentials> {
+        let account = linux_users::get_user_by_name(user).ok_or_else(|| {
+            Error::PermissionFailed(format!("Can't run as {}, error getting user.", user))
+        })?;
+        let gid = match group {
+            Some(group) => gid_for(user, group)?,
+            None => account.primary_group_id(),
+        };
+        let mut groups = group_list(user, gid)?;
+        for group in extra_groups {
+            let gid = gid_for(user, group)?;
+            if !groups.contains(&gid) {
+                groups.push(gid);
+            }
+        }
+        Ok(Credentials {
+            uid: account.uid(),
+            gid: gid,
+            groups: groups,
+        })
+    }
+
+    /// Has `command` run with these credentials. This replaces the `CommandExt::uid` and `gid` of
+    /// `command`, which would leave it with no supplementary groups at all, so they shouldn't be
+    /// set as well.
+    pub fn apply(&self, command: &mut Command) {
+        let credentials = self.clone();
+        command.before_exec(move || credentials.switch());
+    }
+
+    /// Switches the calling process to these credentials. The supplementary groups are set
+    /// first, then the group, then the user, as only root may set the groups. It makes only
+    /// async-signal-safe calls, so it can be made between the fork and the exec of a command.
+    pub fn switch(&self) -> io::Result<()> {
+        if unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) } < 0
+            || unsafe { libc::setgid(self.gid) } < 0
+            || unsafe { libc::setuid(self.uid) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn gid_for(user: &str, group: &str) -> Result<u32> {
+    get_gid_by_name(group).ok_or_else(|| {
+        Error::PermissionFailed(format!(
+            "Can't run as {} in group {}, error getting group.",
+            user, group
+        ))
+    })
+}
+
+/// Returns the groups `user` is a member of, along with `gid`.
+#[cfg(target_os = "linux")]
+fn group_list(user: &str, gid: u32) -> Result<Vec<u32>> {
+    let c_user = CString::new(user).map_err(|_| {
+        Error::PermissionFailed(format!("Can't run as {}, error getting user.", user))
+    })?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as c_int;
+        let found =
+            unsafe { libc::getgrouplist(c_user.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // There were more groups than room for them, and `count` is now how many there are
+        let len = cmp::max(count as usize, groups.len() * 2);
+        groups.resize(len, 0);
+    }
+}
+
+/// Runs `command`, or `fallback` if `command` isn't installed, returning why it failed if it
+/// did.
+fn provision(mut command: Command, mut fallback: Command) -> ::std::result::Result<(), String> {
+    let output = match command.output() {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => fallback.output(),
+        result => result,
+    };
+    match output {
+        Ok(ref output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!(
+            "{}, {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn existing_accounts_are_left_alone() {
+        let user = get_effective_username().unwrap();
+        let group = get_effective_groupname().unwrap();
+        let opts = UserOptions {
+            group: Some(group.clone()),
+            ..Default::default()
+        };
+
+        assert!(!ensure_group(&group).unwrap());
+        assert!(!ensure_user(&user, &opts).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn credentials_include_extra_groups() {
+        let user = get_effective_username().unwrap();
+        let group = get_effective_groupname().unwrap();
+        let credentials = Credentials::for_user(&user, Some(&group), &[&group]).unwrap();
+
+        assert_eq!(credentials.uid, get_effective_uid());
+        assert_eq!(credentials.gid, get_effective_gid());
+        assert_eq!(
+            credentials
+                .groups
+                .iter()
+                .filter(|gid| **gid == credentials.gid)
+                .count(),
+            1
+        );
+        assert!(Credentials::for_user(&user, None, &["no-such-group-here"]).is_err());
+    }
+}
@@ -12,22 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::path::PathBuf;
+
 #[allow(unused_variables)]
 #[cfg(windows)]
 mod windows;
 
 #[cfg(windows)]
 pub use self::windows::{
-    get_current_groupname, get_current_username, get_effective_uid, get_gid_by_name,
-    get_home_for_user, get_uid_by_name, root_level_account,
+    ensure_group, ensure_user, get_current_groupname, get_current_username, get_effective_uid,
+    get_gid_by_name, get_home_for_user, get_uid_by_name, root_level_account,
 };
 
 #[cfg(not(windows))]
 pub mod linux;
 
+#[cfg(target_os = "linux")]
+pub use self::linux::Credentials;
+
 #[cfg(not(windows))]
 pub use self::linux::{
-    get_current_groupname, get_current_username, get_effective_gid, get_effective_groupname,
-    get_effective_uid, get_effective_username, get_gid_by_name, get_home_for_user, get_uid_by_name,
-    root_level_account,
+    ensure_group, ensure_user, get_current_groupname, get_current_username, get_effective_gid,
+    get_effective_groupname, get_effective_uid, get_effective_username, get_gid_by_name,
+    get_home_for_user, get_uid_by_name, root_level_account,
 };
+
+/// How `ensure_user` creates a user who doesn't exist yet.
+#[derive(Clone, Debug, Default)]
+pub struct UserOptions {
+    /// The user's primary group, which is created first if it's missing. On Windows the user is
+    /// added to it as a local group instead.
+    pub group: Option<String>,
+    /// The user's home directory. On Linux it's created along with the user, and without one the
+    /// user gets no home directory.
+    pub home: Option<PathBuf>,
+    /// The user's login shell, if not the platform's default. Ignored on Windows.
+    pub shell: Option<PathBuf>,
+    /// Whether the user is a system account, as service users usually are. Ignored on Windows.
+    pub system: bool,
+    /// A description of the user, such as which service it's for.
+    pub comment: Option<String>,
+    /// The user's password. Only used on Windows, where a local account may need one to satisfy
+    /// the password policy.
+    pub password: Option<String>,
+}
@@ -13,9 +13,26 @@
 // limitations under the License.
 
 use std::env;
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
 use std::path::PathBuf;
+use std::ptr;
 
 use habitat_win_users::account::Account;
+use winapi::shared::lmcons::NET_API_STATUS;
+use winapi::shared::minwindef::LPBYTE;
+use winapi::shared::winerror::{ERROR_ALIAS_EXISTS, ERROR_MEMBER_IN_ALIAS};
+use winapi::um::lmaccess::{
+    NetLocalGroupAdd, NetLocalGroupAddMembers, NetUserAdd, LOCALGROUP_INFO_1,
+    LOCALGROUP_MEMBERS_INFO_3, UF_DONT_EXPIRE_PASSWD, UF_SCRIPT, USER_INFO_1, USER_PRIV_USER,
+};
+
+use super::UserOptions;
+use error::{Error, Result};
+
+const NERR_SUCCESS: NET_API_STATUS = 0;
+const NERR_GROUP_EXISTS: NET_API_STATUS = 2223;
+const NERR_USER_EXISTS: NET_API_STATUS = 2224;
 
 extern "C" {
     pub fn GetUserTokenStatus() -> u32;
@@ -64,6 +81,120 @@ pub fn root_level_account() -> String {
     env::var("COMPUTERNAME").unwrap().to_uppercase() + "$"
 }
 
+/// Creates the local group `name` unless it already exists. Returns whether it was created.
+///
+/// # Failures
+///
+/// * The group can't be created, such as when not running as an administrator
+pub fn ensure_group(name: &str) -> Result<bool> {
+    if Account::from_name(name).is_some() {
+        return Ok(false);
+    }
+    let mut group_name = wide(name);
+    let mut info = LOCALGROUP_INFO_1 {
+        lgrpi1_name: group_name.as_mut_ptr(),
+        lgrpi1_comment: ptr::null_mut(),
+    };
+    debug!("Creating group {}", name);
+    let status = unsafe {
+        NetLocalGroupAdd(
+            ptr::null(),
+            1,
+            &mut info as *mut _ as LPBYTE,
+            ptr::null_mut(),
+        )
+    };
+    match status {
+        NERR_SUCCESS => Ok(true),
+        // Someone else created it first
+        NERR_GROUP_EXISTS | ERROR_ALIAS_EXISTS => Ok(false),
+        e => Err(Error::UserProvisioningFailed(format!(
+            "Can't create group {}: NetLocalGroupAdd failed with {}",
+            name, e
+        ))),
+    }
+}
+
+/// Creates the local user `name` unless it already exists, adding it to its group, which is
+/// created first if it's missing too. Returns whether the user was created.
+///
+/// # Failures
+///
+/// * The user or its group can't be created, such as when not running as an administrator
+/// * The password doesn't satisfy the password policy
+pub fn ensure_user(name: &str, opts: &UserOptions) -> Result<bool> {
+    if let Some(ref group) = opts.group {
+        ensure_group(group)?;
+    }
+    if Account::from_name(name).is_some() {
+        return Ok(false);
+    }
+    let mut user_name = wide(name);
+    let mut password = opts.password.as_ref().map(|p| wide(p));
+    let mut home = opts.home.as_ref().map(|h| wide(h));
+    let mut comment = opts.comment.as_ref().map(|c| wide(c));
+    let mut info = USER_INFO_1 {
+        usri1_name: user_name.as_mut_ptr(),
+        usri1_password: wide_ptr(&mut password),
+        usri1_password_age: 0,
+        usri1_priv: USER_PRIV_USER,
+        usri1_home_dir: wide_ptr(&mut home),
+        usri1_comment: wide_ptr(&mut comment),
+        usri1_flags: UF_SCRIPT | UF_DONT_EXPIRE_PASSWD,
+        usri1_script_path: ptr::null_mut(),
+    };
+    debug!("Creating user {}", name);
+    let status = unsafe {
+        NetUserAdd(
+            ptr::null(),
+            1,
+            &mut info as *mut _ as LPBYTE,
+            ptr::null_mut(),
+        )
+    };
+    match status {
+        NERR_SUCCESS => (),
+        // Someone else created it first
+        NERR_USER_EXISTS => return Ok(false),
+        e => {
+            return Err(Error::UserProvisioningFailed(format!(
+                "Can't create user {}: NetUserAdd failed with {}",
+                name, e
+            )))
+        }
+    }
+
+    if let Some(ref group) = opts.group {
+        let mut member = LOCALGROUP_MEMBERS_INFO_3 {
+            lgrmi3_domainandname: user_name.as_mut_ptr(),
+        };
+        let status = unsafe {
+            NetLocalGroupAddMembers(
+                ptr::null(),
+                wide(group).as_ptr(),
+                3,
+                &mut member as *mut _ as LPBYTE,
+                1,
+            )
+        };
+        if status != NERR_SUCCESS && status != ERROR_MEMBER_IN_ALIAS {
+            return Err(Error::UserProvisioningFailed(format!(
+                "Can't add user {} to group {}: NetLocalGroupAddMembers failed with {}",
+                name, group, status
+            )));
+        }
+    }
+    Ok(true)
+}
+
+fn wide<S: AsRef<OsStr>>(s: S) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+fn wide_ptr(s: &mut Option<Vec<u16>>) -> *mut u16 {
+    s.as_mut().map_or(ptr::null_mut(), |s| s.as_mut_ptr())
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
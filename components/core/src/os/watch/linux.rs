@@ -0,0 +1,154 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+use libc::{self, c_int, c_void};
+
+use super::{Change, STOP_CHECK_MS};
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// Watches `root` and every directory under it with inotify, sending their changes to `changes`
+/// from a thread of its own until `stop` is set.
+pub fn spawn(
+    root: &Path,
+    changes: Sender<(PathBuf, Change)>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut inotify = Inotify {
+        fd: fd,
+        dirs: HashMap::new(),
+    };
+    inotify.add_tree(root)?;
+    thread::Builder::new()
+        .name("watch-inotify".to_string())
+        .spawn(move || inotify.run(changes, stop))?;
+    Ok(())
+}
+
+struct Inotify {
+    fd: c_int,
+    /// The directory each watch descriptor is for
+    dirs: HashMap<c_int, PathBuf>,
+}
+
+impl Inotify {
+    fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_dir.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self, changes: Sender<(PathBuf, Change)>, stop: Arc<AtomicBool>) {
+        let mut buf = [0u8; 4096];
+        let header_len = mem::size_of::<libc::inotify_event>();
+        while !stop.load(Ordering::SeqCst) {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, STOP_CHECK_MS as c_int) } <= 0 {
+                continue;
+            }
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                    _ => {
+                        debug!("Stopped watching, reading inotify events failed: {}", e);
+                        return;
+                    }
+                }
+            }
+
+            let mut offset = 0;
+            while offset + header_len <= len as usize {
+                let event: libc::inotify_event =
+                    unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+                let name_start = offset + header_len;
+                offset = name_start + event.len as usize;
+                let name = buf[name_start..offset]
+                    .split(|b| *b == 0)
+                    .next()
+                    .unwrap_or(&[]);
+
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                let path = match self.dirs.get(&event.wd) {
+                    Some(dir) if name.is_empty() => dir.clone(),
+                    Some(dir) => dir.join(OsStr::from_bytes(name)),
+                    None => continue,
+                };
+                let change = if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    if event.mask & libc::IN_ISDIR != 0 {
+                        if let Err(e) = self.add_tree(&path) {
+                            debug!("Not watching {}: {}", path.display(), e);
+                        }
+                    }
+                    Change::Created
+                } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    Change::Removed
+                } else {
+                    Change::Modified
+                };
+                if changes.send((path, change)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
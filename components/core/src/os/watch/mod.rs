@@ -0,0 +1,260 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching a directory for changes to the files under it.
+//!
+//! Each platform reports changes its own way: inotify on Linux, `ReadDirectoryChangesW` on
+//! Windows, and elsewhere, such as on macOS, by scanning the directory every so often. Whichever
+//! it is, a `Watcher` reports each changed path once it has been left alone for a moment, so a
+//! file which is written in several pieces, or replaced by a rename, is reported once.
+
+#[cfg(target_os = "linux")]
+#[path = "linux.rs"]
+mod imp;
+
+#[cfg(windows)]
+#[path = "windows.rs"]
+mod imp;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+#[path = "poll.rs"]
+mod imp;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::Result;
+
+/// How long a path must be left alone before its changes are reported, by default
+pub const DEFAULT_WATCH_DELAY_MS: u64 = 200;
+
+/// How often the backends look for a request to stop
+const STOP_CHECK_MS: u64 = 100;
+
+/// A change to a path under a watched directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+}
+
+impl WatchEvent {
+    pub fn path(&self) -> &Path {
+        match *self {
+            WatchEvent::Created(ref path)
+            | WatchEvent::Modified(ref path)
+            | WatchEvent::Removed(ref path) => path,
+        }
+    }
+}
+
+/// A change as a platform reports it, before changes to the same path are merged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Change {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// A watch on a directory, which stops when dropped.
+pub struct Watcher {
+    events: Receiver<WatchEvent>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    /// Waits for the next change. Returns `None` if the watch stopped, such as when the watched
+    /// directory is removed.
+    pub fn recv(&self) -> Option<WatchEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Waits for up to `timeout` for the next change.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Returns the next change if there is one already, without waiting.
+    pub fn try_recv(&self) -> Option<WatchEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Watches the directory `path` and everything under it, reporting each changed path once it
+/// has been left alone for `DEFAULT_WATCH_DELAY_MS`.
+///
+/// # Failures
+///
+/// * `path` isn't a directory
+/// * The platform won't watch it, such as when the user's limit of inotify watches is reached
+pub fn watch<P: AsRef<Path>>(path: P) -> Result<Watcher> {
+    watch_with_delay(path, Duration::from_millis(DEFAULT_WATCH_DELAY_MS))
+}
+
+/// Watches the directory `path` and everything under it, reporting each changed path once it
+/// has been left alone for `delay`.
+///
+/// # Failures
+///
+/// * `path` isn't a directory
+/// * The platform won't watch it, such as when the user's limit of inotify watches is reached
+pub fn watch_with_delay<P: AsRef<Path>>(path: P, delay: Duration) -> Result<Watcher> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Can't watch {}, it isn't a directory", path.display()),
+        )
+        .into());
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let (changes_tx, changes_rx) = mpsc::channel();
+    imp::spawn(path, changes_tx, stop.clone())?;
+    let (events_tx, events_rx) = mpsc::channel();
+    let debounce_stop = stop.clone();
+    thread::Builder::new()
+        .name("watch-debounce".to_string())
+        .spawn(move || debounce(changes_rx, events_tx, delay, debounce_stop))?;
+    Ok(Watcher {
+        events: events_rx,
+        stop: stop,
+    })
+}
+
+/// Merges the changes reported for each path, and passes the merged change on once the path has
+/// gone unchanged for `delay`, in the order the paths settled.
+fn debounce(
+    changes: Receiver<(PathBuf, Change)>,
+    events: Sender<WatchEvent>,
+    delay: Duration,
+    stop: Arc<AtomicBool>,
+) {
+    let tick = cmp::min(delay, Duration::from_millis(STOP_CHECK_MS));
+    let mut pending: HashMap<PathBuf, (Option<Change>, Instant)> = HashMap::new();
+    while !stop.load(Ordering::SeqCst) {
+        match changes.recv_timeout(tick) {
+            Ok((path, change)) => {
+                let entry = pending.entry(path).or_insert((None, Instant::now()));
+                entry.0 = merge(entry.0, change);
+                entry.1 = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                if pending.is_empty() {
+                    return;
+                }
+                thread::sleep(tick);
+            }
+        }
+
+        let mut settled: Vec<(Instant, PathBuf)> = pending
+            .iter()
+            .filter(|&(_, &(_, at))| at.elapsed() >= delay)
+            .map(|(path, &(_, at))| (at, path.clone()))
+            .collect();
+        settled.sort();
+        for (_, path) in settled {
+            let event = match pending.remove(&path) {
+                Some((Some(Change::Created), _)) => WatchEvent::Created(path),
+                Some((Some(Change::Modified), _)) => WatchEvent::Modified(path),
+                Some((Some(Change::Removed), _)) => WatchEvent::Removed(path),
+                // Created and removed again before it settled
+                _ => continue,
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns what a path which had the change `pending` amounts to after the change `next`, or
+/// `None` if it amounts to nothing.
+fn merge(pending: Option<Change>, next: Change) -> Option<Change> {
+    match (pending, next) {
+        (None, next) => Some(next),
+        (Some(Change::Created), Change::Removed) => None,
+        (Some(Change::Created), _) => Some(Change::Created),
+        (Some(_), Change::Removed) => Some(Change::Removed),
+        // Modified, or removed and then replaced
+        (Some(_), _) => Some(Change::Modified),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use tempfile::Builder;
+
+    use super::*;
+
+    #[test]
+    fn changes_to_a_path_are_merged() {
+        assert_eq!(merge(None, Change::Removed), Some(Change::Removed));
+        assert_eq!(
+            merge(Some(Change::Created), Change::Modified),
+            Some(Change::Created)
+        );
+        assert_eq!(merge(Some(Change::Created), Change::Removed), None);
+        assert_eq!(
+            merge(Some(Change::Removed), Change::Created),
+            Some(Change::Modified)
+        );
+        assert_eq!(
+            merge(Some(Change::Modified), Change::Removed),
+            Some(Change::Removed)
+        );
+    }
+
+    #[test]
+    fn changes_are_reported_once_settled() {
+        let dir = Builder::new().prefix("watch").tempdir().unwrap();
+        let watcher = watch_with_delay(dir.path(), Duration::from_millis(50)).unwrap();
+        let path = dir.path().join("user.toml");
+        {
+            let mut file = File::create(&path).unwrap();
+            for _ in 0..10 {
+                file.write_all(b"port = 6379\n").unwrap();
+            }
+        }
+        let timeout = Duration::from_secs(10);
+
+        assert_eq!(
+            watcher.recv_timeout(timeout),
+            Some(WatchEvent::Created(path.clone()))
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            watcher.recv_timeout(timeout),
+            Some(WatchEvent::Removed(path))
+        );
+        assert!(watch(dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::{Change, STOP_CHECK_MS};
+
+/// What a scan knows of a path: when it was last modified, and its size.
+type Snapshot = HashMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Watches `root` and everything under it by scanning it every `STOP_CHECK_MS`, sending what
+/// changed between scans to `changes` from a thread of its own until `stop` is set.
+pub fn spawn(
+    root: &Path,
+    changes: Sender<(PathBuf, Change)>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let root = root.to_path_buf();
+    let mut last = Snapshot::new();
+    scan(&root, &mut last)?;
+    thread::Builder::new()
+        .name("watch-poll".to_string())
+        .spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(STOP_CHECK_MS));
+                let mut next = Snapshot::new();
+                if let Err(e) = scan(&root, &mut next) {
+                    debug!("Stopped watching {}: {}", root.display(), e);
+                    return;
+                }
+                for (path, state) in &next {
+                    let change = match last.get(path) {
+                        None => Change::Created,
+                        Some(last_state) if last_state != state => Change::Modified,
+                        Some(_) => continue,
+                    };
+                    if changes.send((path.clone(), change)).is_err() {
+                        return;
+                    }
+                }
+                for path in last.keys().filter(|path| !next.contains_key(*path)) {
+                    if changes.send((path.clone(), Change::Removed)).is_err() {
+                        return;
+                    }
+                }
+                last = next;
+            }
+        })?;
+    Ok(())
+}
+
+fn scan(dir: &Path, snapshot: &mut Snapshot) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Whatever is removed while it's being scanned is picked up as removed by the next scan
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            let _ = scan(&path, snapshot);
+        }
+        snapshot.insert(path, (metadata.modified().ok(), metadata.len()));
+    }
+    Ok(())
+}
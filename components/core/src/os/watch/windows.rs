@@ -0,0 +1,150 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::OsString;
+use std::io;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::winbase::{ReadDirectoryChangesW, FILE_FLAG_BACKUP_SEMANTICS};
+use winapi::um::winnt::{
+    FILE_ACTION_ADDED, FILE_ACTION_REMOVED, FILE_ACTION_RENAMED_NEW_NAME,
+    FILE_ACTION_RENAMED_OLD_NAME, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_ATTRIBUTES,
+    FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE,
+    FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION, FILE_SHARE_DELETE, FILE_SHARE_READ,
+    FILE_SHARE_WRITE, HANDLE,
+};
+
+use super::Change;
+
+const NOTIFY_FILTER: DWORD = FILE_NOTIFY_CHANGE_FILE_NAME
+    | FILE_NOTIFY_CHANGE_DIR_NAME
+    | FILE_NOTIFY_CHANGE_ATTRIBUTES
+    | FILE_NOTIFY_CHANGE_SIZE
+    | FILE_NOTIFY_CHANGE_LAST_WRITE;
+
+/// Watches `root` and everything under it with `ReadDirectoryChangesW`, sending its changes to
+/// `changes` from a thread of its own until `stop` is set.
+pub fn spawn(
+    root: &Path,
+    changes: Sender<(PathBuf, Change)>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let wide: Vec<u16> = root.as_os_str().encode_wide().chain(Some(0)).collect();
+    let handle = unsafe {
+        CreateFileW(
+            wide.as_ptr(),
+            FILE_LIST_DIRECTORY,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_BACKUP_SEMANTICS,
+            ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    let dir = Directory {
+        handle: handle,
+        root: root.to_path_buf(),
+    };
+    thread::Builder::new()
+        .name("watch-directory".to_string())
+        .spawn(move || dir.run(changes, stop))?;
+    Ok(())
+}
+
+struct Directory {
+    handle: HANDLE,
+    root: PathBuf,
+}
+
+// The handle is only ever used by the thread which owns the directory
+unsafe impl Send for Directory {}
+
+impl Directory {
+    fn run(self, changes: Sender<(PathBuf, Change)>, stop: Arc<AtomicBool>) {
+        // Notifications are DWORD aligned, so the buffer is made of DWORDs
+        let mut buf: Vec<DWORD> = vec![0; 16 * 1024];
+        // The read blocks until something changes, so a stopped watch only notices when the next
+        // change comes in
+        while !stop.load(Ordering::SeqCst) {
+            let mut returned: DWORD = 0;
+            let read = unsafe {
+                ReadDirectoryChangesW(
+                    self.handle,
+                    buf.as_mut_ptr() as *mut _,
+                    (buf.len() * 4) as DWORD,
+                    TRUE,
+                    NOTIFY_FILTER,
+                    &mut returned,
+                    ptr::null_mut(),
+                    None,
+                )
+            };
+            if read == FALSE {
+                debug!(
+                    "Stopped watching {}: {}",
+                    self.root.display(),
+                    io::Error::last_os_error()
+                );
+                return;
+            }
+            // Nothing is returned when more changed than the buffer could hold
+            if returned == 0 {
+                continue;
+            }
+
+            let mut offset = 0;
+            loop {
+                let info = unsafe {
+                    &*((buf.as_ptr() as *const u8).offset(offset as isize)
+                        as *const FILE_NOTIFY_INFORMATION)
+                };
+                let name = unsafe {
+                    slice::from_raw_parts(info.FileName.as_ptr(), info.FileNameLength as usize / 2)
+                };
+                let path = self.root.join(OsString::from_wide(name));
+                let change = match info.Action {
+                    FILE_ACTION_ADDED | FILE_ACTION_RENAMED_NEW_NAME => Change::Created,
+                    FILE_ACTION_REMOVED | FILE_ACTION_RENAMED_OLD_NAME => Change::Removed,
+                    _ => Change::Modified,
+                };
+                if changes.send((path, change)).is_err() {
+                    return;
+                }
+                if info.NextEntryOffset == 0 {
+                    break;
+                }
+                offset += info.NextEntryOffset as usize;
+            }
+        }
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
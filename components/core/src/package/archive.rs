@@ -12,21 +12,42 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::HashMap;
 use std::error;
-use std::path::{Path, PathBuf};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, read_link, rename, symlink_metadata, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
 use std::result;
 use std::str::{self, FromStr};
 
 use libarchive::archive::{Entry, ExtractOption, ExtractOptions, ReadFilter, ReadFormat};
 use libarchive::reader::{self, Reader};
 use libarchive::writer;
+use libarchive3_sys::ffi;
+use libc::{c_int, c_void};
 use regex::Regex;
+use tempfile::{self, Builder};
+use time;
 
-use super::metadata::{MetaFile, PackageType};
-use super::{Identifiable, PackageIdent, PackageTarget};
-use crypto::{artifact, hash};
+use super::list::INSTALL_TMP_PREFIX;
+use super::manifest::{file_mode, FileManifest, ManifestEntry};
+use super::metadata::{parse_licenses, read_metafile, MetaFile, PackageType, INSTALL_METAFILES};
+use super::{FullyQualifiedPackageIdent, Identifiable, PackageIdent, PackageTarget};
+use crypto::keys::parse_name_with_rev;
+use crypto::provider::CryptoProvider;
+use crypto::{artifact, hash, SigKeyPair};
 use error::{Error, Result};
+use fs;
+
+const ARCHIVE_OK: c_int = 0;
+const AE_IFREG: u32 = 0o100000;
+const AE_IFLNK: u32 = 0o120000;
+const AE_IFDIR: u32 = 0o040000;
+/// The filesystem block size assumed when estimating the room a package needs once unpacked
+const BLOCK_SIZE: u64 = 4096;
 
 lazy_static! {
     static ref METAFILE_REGXS: HashMap<MetaFile, Regex> = {
@@ -142,6 +163,70 @@ lazy_static! {
 
 type Metadata = HashMap<MetaFile, String>;
 
+/// How the tarball at the heart of an artifact is compressed, as told by its first few bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadCompression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl PayloadCompression {
+    /// Returns the compression format whose magic bytes `bytes` starts with, if any.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(PayloadCompression::Xz)
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(PayloadCompression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(PayloadCompression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a reader builder for tarballs compressed in this format.
+    ///
+    /// # Failures
+    ///
+    /// * The format can't be read by the libarchive bindings, as is the case for zstd
+    fn reader_builder(&self) -> Result<reader::Builder> {
+        let filter = match *self {
+            PayloadCompression::Gzip => ReadFilter::Gzip,
+            PayloadCompression::Xz => ReadFilter::Xz,
+            PayloadCompression::Zstd => {
+                return Err(Error::ArchiveCompressionUnsupported(self.to_string()))
+            }
+        };
+        let mut builder = reader::Builder::new();
+        builder.support_format(ReadFormat::Gnutar)?;
+        builder.support_filter(filter)?;
+        Ok(builder)
+    }
+}
+
+impl fmt::Display for PayloadCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            PayloadCompression::Gzip => "gzip",
+            PayloadCompression::Xz => "xz",
+            PayloadCompression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What an artifact holds, as read by `PackageArchive::metadata`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArchiveMetadata {
+    pub ident: PackageIdent,
+    pub target: PackageTarget,
+    /// Runtime dependencies, empty if the artifact has no `DEPS` metafile
+    pub deps: Vec<PackageIdent>,
+    /// Ports the package exposes, empty if the artifact has no `EXPOSES` metafile
+    pub exposes: Vec<u16>,
+}
+
 #[derive(Debug)]
 pub struct PackageArchive {
     pub path: PathBuf,
@@ -156,6 +241,102 @@ impl PackageArchive {
         }
     }
 
+    /// Builds a signed artifact of a package from an on-disk package tree, laid out as the
+    /// package's installed path would be, and writes it into `dst_dir` under its usual file
+    /// name. The tree is archived as an xz-compressed tarball, placed under `hab/pkgs/` at the
+    /// package's installed path, and signed with the given origin key.
+    ///
+    /// `IDENT` and `TARGET` metafiles are added to the archive if the tree doesn't have them,
+    /// with the given identifier and the active target. A `FILES` manifest of everything in the
+    /// archive, hashed as it is archived, is signed with the same key and added in place of any
+    /// the tree has, so the installed package can be checked with `PackageInstall::verify`.
+    ///
+    /// # Failures
+    ///
+    /// * The identifier doesn't match the tree's `IDENT` metafile
+    /// * The tree has a FIFO, socket, or device node, which can't be part of a package
+    /// * The tree can't be read or the archive can't be written
+    pub fn build<P1: ?Sized, P2: ?Sized>(
+        src: &P1,
+        ident: &FullyQualifiedPackageIdent,
+        pair: &SigKeyPair,
+        dst_dir: &P2,
+    ) -> Result<Self>
+    where
+        P1: AsRef<Path>,
+        P2: AsRef<Path>,
+    {
+        let src = src.as_ref();
+        let ident = ident.as_ident();
+        let tree_ident = match read_metafile(src, &MetaFile::Ident) {
+            Ok(body) => Some(PackageIdent::from_str(&body)?),
+            Err(Error::MetaFileNotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(ref found) = tree_ident {
+            if found != ident {
+                return Err(Error::ArchiveWriteFailed(format!(
+                    "{} has an IDENT metafile for {}, not {}",
+                    src.display(),
+                    found,
+                    ident
+                )));
+            }
+        }
+        let target = match read_metafile(src, &MetaFile::Target) {
+            Ok(body) => PackageTarget::from_str(&body)?,
+            Err(Error::MetaFileNotFound(_)) => PackageTarget::active_target().clone(),
+            Err(e) => return Err(e),
+        };
+
+        let dst_dir = dst_dir.as_ref();
+        create_dir_all(dst_dir)?;
+        let staging = Builder::new().prefix(".hart-build").tempdir_in(dst_dir)?;
+        let payload_path = staging.path().join("payload.tar.xz");
+        {
+            let prefix = fs::pkg_install_path(ident, None::<&Path>);
+            let prefix = prefix.to_string_lossy();
+            let prefix = prefix.trim_left_matches('/').replace('\\', "/");
+            let mut payload = PayloadWriter::create(&payload_path)?;
+            let mut dir = String::new();
+            for part in prefix.split('/') {
+                dir = if dir.is_empty() {
+                    part.to_string()
+                } else {
+                    format!("{}/{}", dir, part)
+                };
+                payload.add_dir(&dir, 0o755)?;
+            }
+            let mut entries = Vec::new();
+            if tree_ident.is_none() {
+                entries.push(payload.add_metafile(
+                    &prefix,
+                    MetaFile::Ident,
+                    format!("{}\n", ident).as_bytes(),
+                )?);
+            }
+            if !src.join(MetaFile::Target.to_string()).is_file() {
+                entries.push(payload.add_metafile(
+                    &prefix,
+                    MetaFile::Target,
+                    format!("{}\n", target).as_bytes(),
+                )?);
+            }
+            payload.add_tree(src, src, &prefix, &mut entries)?;
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            let manifest = FileManifest {
+                ident: ident.clone(),
+                entries: entries,
+            };
+            payload.add_metafile(&prefix, MetaFile::Files, manifest.sign(pair)?.as_bytes())?;
+            payload.close()?;
+        }
+
+        let archive_path = dst_dir.join(ident.archive_name_with_target(&target)?);
+        artifact::sign(&payload_path, &archive_path, pair)?;
+        Ok(PackageArchive::new(archive_path))
+    }
+
     /// Calculate and return the checksum of the package archive in base64 format.
     ///
     /// # Failures
@@ -165,6 +346,11 @@ impl PackageArchive {
         hash::hash_file(&self.path)
     }
 
+    /// Calculate and return the checksum of the package archive with the given `CryptoProvider`.
+    pub fn checksum_with<C: CryptoProvider + ?Sized>(&self, provider: &C) -> Result<String> {
+        provider.hash_file(&self.path)
+    }
+
     pub fn cflags(&mut self) -> Result<Option<String>> {
         match self.read_metadata(MetaFile::CFlags) {
             Ok(data) => Ok(data.cloned()),
@@ -216,13 +402,7 @@ impl PackageArchive {
 
     pub fn exposes(&mut self) -> Result<Vec<u16>> {
         match self.read_metadata(MetaFile::Exposes) {
-            Ok(Some(data)) => {
-                let ports: Vec<u16> = data
-                    .split(" ")
-                    .filter_map(|port| port.parse::<u16>().ok())
-                    .collect();
-                Ok(ports)
-            }
+            Ok(Some(data)) => Ok(parse_exposes(data)),
             Ok(None) => Ok(vec![]),
             Err(e) => Err(e),
         }
@@ -236,6 +416,16 @@ impl PackageArchive {
         }
     }
 
+    /// Returns the licenses the package declares in its `MANIFEST` metafile, from the
+    /// `pkg_license` of its plan. An archive without a `MANIFEST` declares none.
+    pub fn licenses(&mut self) -> Result<Vec<String>> {
+        match self.read_metadata(MetaFile::Manifest) {
+            Ok(Some(data)) => Ok(parse_licenses(data)),
+            Ok(None) => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn ld_run_path(&mut self) -> Result<Option<String>> {
         match self.read_metadata(MetaFile::LdRunPath) {
             Ok(data) => Ok(data.cloned()),
@@ -297,6 +487,61 @@ impl PackageArchive {
         }
     }
 
+    /// Returns the identifier, target, runtime dependencies, and exposed ports of the artifact.
+    ///
+    /// Only the metafiles needed are read from the archive, and reading stops as soon as they
+    /// have all been found, so this is much cheaper than unpacking the artifact, or than reading
+    /// all of its metadata, to find out what it is.
+    ///
+    /// # Failures
+    ///
+    /// * If an `IDENT` or `TARGET` metafile is not found in the archive
+    /// * If the archive cannot be read
+    pub fn metadata(&self) -> Result<ArchiveMetadata> {
+        let wanted = [
+            MetaFile::Ident,
+            MetaFile::Target,
+            MetaFile::Deps,
+            MetaFile::Exposes,
+        ];
+        let found = match self.metadata {
+            Some(ref metadata) => metadata.clone(),
+            None => read_metafiles(&self.path, &wanted)?,
+        };
+        let ident = match found.get(&MetaFile::Ident) {
+            Some(data) => PackageIdent::from_str(data)?,
+            None => return Err(Error::MetaFileNotFound(MetaFile::Ident)),
+        };
+        let target = match found.get(&MetaFile::Target) {
+            Some(data) => PackageTarget::from_str(data)?,
+            None => return Err(Error::MetaFileNotFound(MetaFile::Target)),
+        };
+        let deps = match found.get(&MetaFile::Deps) {
+            Some(data) => parse_deps(data, &MetaFile::Deps)?,
+            None => vec![],
+        };
+        let exposes = found
+            .get(&MetaFile::Exposes)
+            .map(|data| parse_exposes(data))
+            .unwrap_or_default();
+        Ok(ArchiveMetadata {
+            ident: ident,
+            target: target,
+            deps: deps,
+            exposes: exposes,
+        })
+    }
+
+    /// Returns how the archive's payload is compressed.
+    ///
+    /// # Failures
+    ///
+    /// * If the archive cannot be read
+    /// * If the payload isn't compressed in any known format
+    pub fn compression(&self) -> Result<PayloadCompression> {
+        sniff_compression(&mut artifact::get_archive_reader(&self.path)?)
+    }
+
     /// A plain string representation of the archive's file name.
     pub fn file_name(&self) -> String {
         self.path
@@ -316,51 +561,123 @@ impl PackageArchive {
         artifact::verify(&self.path, cache_key_path)
     }
 
+    /// Signs the artifact with another origin key without rebuilding its payload, such as when
+    /// promoting a package built elsewhere under an origin of our own. With `append`, the
+    /// signature is added to the existing ones; otherwise it replaces them. The artifact is
+    /// rewritten in place.
+    ///
+    /// # Failures
+    ///
+    /// * The artifact's header can't be read
+    /// * The secret key for the origin can't be used
+    /// * With `append`, the artifact is already signed by the key, carries a timestamped
+    ///   signature, or is hashed with an algorithm other than BLAKE2b
+    pub fn resign(&self, pair: &SigKeyPair, append: bool) -> Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let staging = Builder::new().prefix(".hart-resign").tempdir_in(dir)?;
+        let resigned = staging.path().join("resigned.hart");
+        if append {
+            artifact::add_signature(&self.path, &resigned, pair)?;
+        } else {
+            artifact::replace_signatures(&self.path, &resigned, pair)?;
+        }
+        rename(&resigned, &self.path)?;
+        Ok(())
+    }
+
+    /// Estimates the room the package needs once unpacked, from the sizes of its entries, each
+    /// rounded up to a whole filesystem block, and one inode per entry. The whole payload is
+    /// read to add them up, but nothing is written.
+    ///
+    /// # Failures
+    ///
+    /// * The payload can't be read
+    /// * An entry would be unpacked outside of the filesystem root, as `unpack` refuses
+    pub fn unpacked_size(&self) -> Result<fs::SpaceNeeded> {
+        let mut tar_reader = artifact::get_archive_reader(&self.path)?;
+        let builder = sniff_compression(&mut tar_reader)?.reader_builder()?;
+        space_needed(&mut builder.open_stream(tar_reader)?)
+    }
+
     /// Given a package name and a path to a file as an `&str`, unpack
     /// the package.
     ///
+    /// The payload is copied aside and the room it needs is added up from the copy before it's
+    /// extracted from the copy, so the artifact is only read once, and what is extracted is what
+    /// was checked even if the artifact is replaced meanwhile.
+    ///
     /// # Failures
     ///
+    /// * There isn't room to unpack the package under `fs_root_path`
     /// * If the package cannot be unpacked
     pub fn unpack(&self, fs_root_path: Option<&Path>) -> Result<()> {
         let root = fs_root_path.unwrap_or(Path::new("/"));
-        let tar_reader = artifact::get_archive_reader(&self.path)?;
-        let mut builder = reader::Builder::new();
-        builder.support_format(ReadFormat::Gnutar)?;
-        builder.support_filter(ReadFilter::Xz)?;
-        let mut reader = builder.open_stream(tar_reader)?;
-        let writer = writer::Disk::new();
-        let mut extract_options = ExtractOptions::new();
-        extract_options.add(ExtractOption::Time);
-        extract_options.add(ExtractOption::Permissions);
-        writer.set_options(&extract_options)?;
-        writer.set_standard_lookup()?;
-        writer.write(&mut reader, Some(root.to_string_lossy().as_ref()))?;
-        writer.close()?;
-        Ok(())
+        let package_root_path = fs::pkg_root_path(Some(root));
+        create_dir_all(&package_root_path)?;
+        let mut payload = tempfile::tempfile_in(&package_root_path)?;
+        io::copy(&mut artifact::get_archive_reader(&self.path)?, &mut payload)?;
+        extract_payload(payload, root, root)
     }
 
-    fn read_deps(&mut self, file: MetaFile) -> Result<Vec<PackageIdent>> {
-        let mut deps: Vec<PackageIdent> = vec![];
-
-        // For now, all deps files but SERVICES need fully-qualified
-        // package identifiers
-        let must_be_fully_qualified = { file != MetaFile::Services };
-
-        match self.read_metadata(file) {
-            Ok(Some(body)) => {
-                let ids: Vec<String> = body.lines().map(|d| d.to_string()).collect();
-                for id in &ids {
-                    let package = PackageIdent::from_str(id)?;
-                    if !package.fully_qualified() && must_be_fully_qualified {
-                        return Err(Error::FullyQualifiedPackageIdentRequired(
-                            package.to_string(),
-                        ));
-                    }
-                    deps.push(package);
-                }
-                Ok(deps)
+    /// Verifies and unpacks the package, reading the artifact only once. Its payload is copied
+    /// aside as it is hashed, and only extracted from the copy once its signature has been
+    /// verified, so nothing is written from an artifact which fails verification, and what is
+    /// extracted is what was verified even if the artifact is replaced meanwhile. The room the
+    /// package needs is added up from the verified copy too, before anything is extracted. The
+    /// package is extracted beside its final location and then moved into place. Returns the
+    /// signer and hash, as `verify` does.
+    ///
+    /// # Failures
+    ///
+    /// * Fails if it cannot verify the signature for any reason
+    /// * The artifact is signed by a key of another origin than the package's
+    /// * There isn't room to unpack the package under `fs_root_path`
+    /// * If the package cannot be unpacked
+    pub fn unpack_verified<P: AsRef<Path>>(
+        &self,
+        cache_key_path: &P,
+        fs_root_path: Option<&Path>,
+    ) -> Result<(String, String)> {
+        let root = fs_root_path.unwrap_or(Path::new("/"));
+        let package_root_path = fs::pkg_root_path(Some(root));
+        create_dir_all(&package_root_path)?;
+        let staging = Builder::new()
+            .prefix(INSTALL_TMP_PREFIX)
+            .tempdir_in(&package_root_path)?;
+        let mut payload = tempfile::tempfile_in(staging.path())?;
+        let verified = {
+            let mut verifying = artifact::open_verifying(&self.path, cache_key_path)?;
+            io::copy(&mut verifying, &mut payload)?;
+            verifying.finish()?
+        };
+        extract_payload(payload, &package_root_path, staging.path())?;
+
+        let ident = unpacked_ident(&fs::pkg_root_path(Some(staging.path())))?;
+        if parse_name_with_rev(&verified.0)?.0 != ident.origin() {
+            return Err(Error::CryptoError(format!(
+                "Artifact for {} is signed by {}, which is not a key of its origin",
+                ident, verified.0
+            )));
+        }
+        let installed_path = fs::long_path(fs::pkg_install_path(&ident, Some(root)));
+        if !installed_path.is_dir() {
+            if let Some(parent) = installed_path.parent() {
+                create_dir_all(parent)?;
             }
+            rename(
+                fs::long_path(fs::pkg_install_path(&ident, Some(staging.path()))),
+                &installed_path,
+            )?;
+        }
+        Ok(verified)
+    }
+
+    fn read_deps(&mut self, file: MetaFile) -> Result<Vec<PackageIdent>> {
+        match self.read_metadata(file.clone()) {
+            Ok(Some(body)) => parse_deps(body, &file),
             Ok(None) => Ok(vec![]),
             Err(Error::MetaFileNotFound(_)) => Ok(vec![]),
             Err(e) => Err(e),
@@ -371,62 +688,384 @@ impl PackageArchive {
         if let Some(ref files) = self.metadata {
             return Ok(files.get(&file));
         }
-        let mut metadata = Metadata::new();
-        let mut matched_count = 0u8;
-        let tar_reader = artifact::get_archive_reader(&self.path)?;
-        let mut builder = reader::Builder::new();
-        builder.support_format(ReadFormat::Gnutar)?;
-        builder.support_filter(ReadFilter::Xz)?;
-        let mut reader = builder.open_stream(tar_reader)?;
+        let all: Vec<MetaFile> = METAFILE_REGXS.keys().cloned().collect();
+        self.metadata = Some(read_metafiles(&self.path, &all)?);
+        Ok(self.metadata.as_ref().unwrap().get(&file))
+    }
+}
+
+/// Reads the `wanted` metafiles from the archive at `path`, stopping as soon as all of them have
+/// been read. Metafiles which the archive doesn't have are left out.
+fn read_metafiles(path: &Path, wanted: &[MetaFile]) -> Result<Metadata> {
+    let mut metadata = Metadata::new();
+    let mut tar_reader = artifact::get_archive_reader(path)?;
+    let builder = sniff_compression(&mut tar_reader)?.reader_builder()?;
+    let mut reader = builder.open_stream(tar_reader)?;
+    loop {
+        let mut matched_type: Option<MetaFile> = None;
+        if let Some(entry) = reader.next_header() {
+            for matched in wanted {
+                if METAFILE_REGXS[matched].is_match(entry.pathname()) {
+                    matched_type = Some(matched.clone());
+                    break;
+                }
+            }
+        } else {
+            break;
+        }
+
+        if matched_type.is_none() {
+            continue;
+        }
+
+        let mut buf = String::new();
         loop {
-            let mut matched_type: Option<MetaFile> = None;
-            if let Some(entry) = reader.next_header() {
-                for (matched, regx) in METAFILE_REGXS.iter() {
-                    if regx.is_match(entry.pathname()) {
-                        matched_type = Some((*matched).clone());
-                        matched_count += 1;
-                        break;
+            match reader.read_block() {
+                Ok(Some(bytes)) => {
+                    match str::from_utf8(bytes) {
+                        Ok(content) => {
+                            // You used to trim. Now you don't, because you were trimming
+                            // in the wrong place. Sometimes a buffer ends (or starts!) with
+                            // a newline.
+                            buf.push_str(content);
+                        }
+                        Err(_) => return Err(Error::MetaFileMalformed(matched_type.unwrap())),
                     }
                 }
-            } else {
-                break;
+                Ok(None) => {
+                    // Hey, before you go - we are trimming whitespace for you. This
+                    // is handy, because later on, you just want the string you want.
+                    metadata.insert(matched_type.unwrap(), String::from(buf.trim()));
+                    break;
+                }
+                Err(_) => return Err(Error::MetaFileMalformed(matched_type.unwrap())),
             }
+        } //inner loop
 
-            if matched_type.is_none() {
+        if metadata.len() == wanted.len() {
+            break;
+        }
+    }
+    Ok(metadata)
+}
+
+/// Adds up the room a payload needs once unpacked, as `PackageArchive::unpacked_size` describes,
+/// reading only the headers of its entries. Fails on the first entry whose path or hard link
+/// target would take it outside of the root it's unpacked under, so a payload is always checked
+/// through here before anything is extracted from it.
+fn space_needed<R: Reader>(reader: &mut R) -> Result<fs::SpaceNeeded> {
+    let mut needed = fs::SpaceNeeded::default();
+    while let Some(entry) = reader.next_header() {
+        check_entry_path(entry.pathname())?;
+        let hardlink = unsafe { ffi::archive_entry_hardlink(entry.entry()) };
+        if !hardlink.is_null() {
+            check_entry_path(&unsafe { CStr::from_ptr(hardlink) }.to_string_lossy())?;
+        }
+        let size = cmp::max(entry.size(), 0) as u64;
+        needed.bytes += cmp::max((size + BLOCK_SIZE - 1) / BLOCK_SIZE, 1) * BLOCK_SIZE;
+        needed.inodes += 1;
+    }
+    Ok(needed)
+}
+
+/// Refuses the path of an entry, or of the target of a hard link, which is absolute or has a `..`
+/// in it. The archive writer joins entry paths onto the root they're unpacked under, and an
+/// absolute path replaces the root rather than being joined onto it.
+fn check_entry_path(path: &str) -> Result<()> {
+    let escapes = Path::new(path).components().any(|c| match c {
+        Component::Prefix(_) | Component::RootDir | Component::ParentDir => true,
+        Component::CurDir | Component::Normal(_) => false,
+    });
+    if escapes {
+        return Err(Error::PackageUnpackFailed(format!(
+            "The payload has an entry for {}, which is outside of the package root",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// Tells how the payload `reader` is positioned at is compressed, without consuming any of it.
+fn sniff_compression<R: BufRead>(reader: &mut R) -> Result<PayloadCompression> {
+    let magic = reader.fill_buf()?;
+    PayloadCompression::detect(magic).ok_or_else(|| {
+        let prefix: Vec<String> = magic.iter().take(6).map(|b| format!("{:02x}", b)).collect();
+        Error::ArchiveCompressionUnsupported(format!(
+            "an unknown format (magic bytes {})",
+            prefix.join(" ")
+        ))
+    })
+}
+
+/// Parses the package identifiers in a deps metafile. All deps files but `SERVICES` need
+/// fully-qualified package identifiers, for now.
+fn parse_deps(body: &str, file: &MetaFile) -> Result<Vec<PackageIdent>> {
+    let must_be_fully_qualified = *file != MetaFile::Services;
+    let mut deps: Vec<PackageIdent> = vec![];
+    for id in body.lines() {
+        let package = PackageIdent::from_str(id)?;
+        if !package.fully_qualified() && must_be_fully_qualified {
+            return Err(Error::FullyQualifiedPackageIdentRequired(
+                package.to_string(),
+            ));
+        }
+        deps.push(package);
+    }
+    Ok(deps)
+}
+
+fn parse_exposes(body: &str) -> Vec<u16> {
+    body.split(" ")
+        .filter_map(|port| port.parse::<u16>().ok())
+        .collect()
+}
+
+/// Extracts the payload copied to `payload` under `root`, once its entries have been checked
+/// by `space_needed` and the filesystem which holds `space_path` has room for them.
+fn extract_payload(mut payload: File, space_path: &Path, root: &Path) -> Result<()> {
+    payload.seek(SeekFrom::Start(0))?;
+    let compression = {
+        let mut sizing = BufReader::new(payload.try_clone()?);
+        let compression = sniff_compression(&mut sizing)?;
+        let mut reader = compression.reader_builder()?.open_stream(sizing)?;
+        fs::ensure_space(space_path, &space_needed(&mut reader)?)?;
+        compression
+    };
+    payload.seek(SeekFrom::Start(0))?;
+    let mut reader = compression
+        .reader_builder()?
+        .open_stream(BufReader::new(payload))?;
+    extract(&mut reader, root)
+}
+
+/// Extracts a package's payload under `root`, refusing entries which would be written outside
+/// of it, through a `..` in their path or a symlink on the way. Absolute paths aren't refused
+/// here, so the payload must have been checked with `space_needed` first.
+fn extract<R: Reader>(reader: &mut R, root: &Path) -> Result<()> {
+    let writer = writer::Disk::new();
+    let mut extract_options = ExtractOptions::new();
+    extract_options.add(ExtractOption::Time);
+    extract_options.add(ExtractOption::Permissions);
+    extract_options.add(ExtractOption::SecureNoDotDot);
+    extract_options.add(ExtractOption::SecureSymlinks);
+    writer.set_options(&extract_options)?;
+    writer.set_standard_lookup()?;
+    writer.write(reader, Some(root.to_string_lossy().as_ref()))?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes the xz-compressed tarball at the heart of an artifact. The `libarchive` crate can only
+/// extract archives, so this drives libarchive's writing interface directly.
+struct PayloadWriter(*mut ffi::Struct_archive);
+
+impl PayloadWriter {
+    fn create(path: &Path) -> Result<Self> {
+        let path = CString::new(path.to_string_lossy().into_owned())
+            .map_err(|e| Error::ArchiveWriteFailed(e.to_string()))?;
+        let writer = PayloadWriter(unsafe { ffi::archive_write_new() });
+        writer.check(unsafe { ffi::archive_write_set_format_gnutar(writer.0) })?;
+        writer.check(unsafe { ffi::archive_write_add_filter_xz(writer.0) })?;
+        writer.check(unsafe { ffi::archive_write_open_filename(writer.0, path.as_ptr()) })?;
+        Ok(writer)
+    }
+
+    fn add_dir(&mut self, pathname: &str, mode: u32) -> Result<()> {
+        self.add_header(pathname, AE_IFDIR, mode, 0, None)
+    }
+
+    /// Adds a metafile of the package whose installed path is at `prefix` in the archive,
+    /// returning its manifest entry.
+    fn add_metafile(
+        &mut self,
+        prefix: &str,
+        file: MetaFile,
+        content: &[u8],
+    ) -> Result<ManifestEntry> {
+        let pathname = format!("{}/{}", prefix, file);
+        self.add_header(&pathname, AE_IFREG, 0o644, content.len() as u64, None)?;
+        Ok(ManifestEntry {
+            path: PathBuf::from(file.to_string()),
+            mode: 0o644,
+            size: content.len() as u64,
+            hash: self.add_data(&mut &content[..])?,
+        })
+    }
+
+    /// Adds everything under `dir`, in order of path, under `prefix` in the archive, and a
+    /// manifest entry for every file and symbolic link to `files`, with its path relative to
+    /// `root`. A `FILES` metafile at the top of `root` is left out, as `build` writes its own.
+    fn add_tree(
+        &mut self,
+        root: &Path,
+        dir: &Path,
+        prefix: &str,
+        files: &mut Vec<ManifestEntry>,
+    ) -> Result<()> {
+        let mut entries = read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let relative = path
+                .strip_prefix(root)
+                .expect("Entry is under the directory being archived")
+                .to_path_buf();
+            if relative == Path::new(&MetaFile::Files.to_string()) {
+                continue;
+            }
+            let pathname = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+            let metadata = symlink_metadata(&path)?;
+            let file_type = metadata.file_type();
+            let (size, hash) = if file_type.is_dir() {
+                self.add_dir(&pathname, file_mode(&metadata))?;
+                self.add_tree(root, &path, &pathname, files)?;
                 continue;
+            } else if file_type.is_symlink() {
+                let target = read_link(&path)?;
+                let target = target.to_string_lossy();
+                self.add_header(&pathname, AE_IFLNK, 0o777, 0, Some(&target))?;
+                (
+                    0,
+                    hash::hash_bytes_with(target.as_bytes(), hash::HashAlgorithm::Blake2b)?,
+                )
+            } else if file_type.is_file() {
+                self.add_header(
+                    &pathname,
+                    AE_IFREG,
+                    file_mode(&metadata),
+                    metadata.len(),
+                    None,
+                )?;
+                (metadata.len(), self.add_data(&mut File::open(&path)?)?)
+            } else {
+                return Err(Error::ArchiveWriteFailed(format!(
+                    "{} isn't a regular file, directory, or symbolic link",
+                    path.display()
+                )));
+            };
+            if !INSTALL_METAFILES
+                .iter()
+                .any(|m| relative == Path::new(&m.to_string()))
+            {
+                files.push(ManifestEntry {
+                    path: relative,
+                    mode: file_mode(&metadata),
+                    size: size,
+                    hash: hash,
+                });
             }
+        }
+        Ok(())
+    }
 
-            let mut buf = String::new();
-            loop {
-                match reader.read_block() {
-                    Ok(Some(bytes)) => {
-                        match str::from_utf8(bytes) {
-                            Ok(content) => {
-                                // You used to trim. Now you don't, because you were trimming
-                                // in the wrong place. Sometimes a buffer ends (or starts!) with
-                                // a newline.
-                                buf.push_str(content);
-                            }
-                            Err(_) => return Err(Error::MetaFileMalformed(matched_type.unwrap())),
-                        }
-                    }
-                    Ok(None) => {
-                        // Hey, before you go - we are trimming whitespace for you. This
-                        // is handy, because later on, you just want the string you want.
-                        metadata.insert(matched_type.unwrap(), String::from(buf.trim()));
-                        break;
-                    }
-                    Err(_) => return Err(Error::MetaFileMalformed(matched_type.unwrap())),
-                }
-            } //inner loop
+    fn add_header(
+        &mut self,
+        pathname: &str,
+        filetype: u32,
+        mode: u32,
+        size: u64,
+        symlink: Option<&str>,
+    ) -> Result<()> {
+        let pathname =
+            CString::new(pathname).map_err(|e| Error::ArchiveWriteFailed(e.to_string()))?;
+        let symlink = match symlink {
+            Some(target) => {
+                Some(CString::new(target).map_err(|e| Error::ArchiveWriteFailed(e.to_string()))?)
+            }
+            None => None,
+        };
+        let status = unsafe {
+            let entry = ffi::archive_entry_new();
+            ffi::archive_entry_set_pathname(entry, pathname.as_ptr());
+            ffi::archive_entry_set_filetype(entry, filetype as _);
+            ffi::archive_entry_set_perm(entry, mode as _);
+            ffi::archive_entry_set_size(entry, size as _);
+            ffi::archive_entry_set_mtime(entry, time::get_time().sec as _, 0);
+            if let Some(ref target) = symlink {
+                ffi::archive_entry_set_symlink(entry, target.as_ptr());
+            }
+            let status = ffi::archive_write_header(self.0, entry);
+            ffi::archive_entry_free(entry);
+            status
+        };
+        self.check(status)
+    }
 
-            if matched_count == METAFILE_REGXS.len() as u8 {
-                break;
+    /// Writes the content of the entry whose header was just added, returning its BLAKE2b hash.
+    fn add_data<R: Read>(&mut self, content: &mut R) -> Result<String> {
+        let mut hasher = hash::Hasher::new(hash::HashAlgorithm::Blake2b)?;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let len = content.read(&mut buf)?;
+            if len == 0 {
+                return Ok(hasher.finish());
+            }
+            hasher.update(&buf[..len]);
+            let written =
+                unsafe { ffi::archive_write_data(self.0, buf.as_ptr() as *const c_void, len as _) };
+            if written < 0 {
+                return Err(self.error());
             }
         }
-        self.metadata = Some(metadata);
-        Ok(self.metadata.as_ref().unwrap().get(&file))
     }
+
+    fn close(self) -> Result<()> {
+        self.check(unsafe { ffi::archive_write_close(self.0) })
+    }
+
+    fn check(&self, status: c_int) -> Result<()> {
+        if status == ARCHIVE_OK {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn error(&self) -> Error {
+        let msg = unsafe {
+            let msg = ffi::archive_error_string(self.0);
+            if msg.is_null() {
+                "unknown libarchive error".to_string()
+            } else {
+                CStr::from_ptr(msg).to_string_lossy().into_owned()
+            }
+        };
+        Error::ArchiveWriteFailed(msg)
+    }
+}
+
+impl Drop for PayloadWriter {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::archive_write_free(self.0);
+        }
+    }
+}
+
+/// Returns the identifier of the only package unpacked into a package root, which is laid out as
+/// `ORIGIN/NAME/VERSION/RELEASE`.
+fn unpacked_ident(package_root_path: &Path) -> Result<FullyQualifiedPackageIdent> {
+    let mut parts = Vec::new();
+    let mut path = package_root_path.to_path_buf();
+    for _ in 0..4 {
+        let entries = read_dir(&path)?.collect::<io::Result<Vec<_>>>()?;
+        if entries.len() != 1 {
+            return Err(Error::PackageUnpackFailed(format!(
+                "Expected a single package to be unpacked, found {} entries in {}",
+                entries.len(),
+                path.display()
+            )));
+        }
+        let name = entries[0].file_name().to_string_lossy().into_owned();
+        path.push(&name);
+        parts.push(name);
+    }
+    FullyQualifiedPackageIdent::try_from(PackageIdent::new(
+        parts[0].clone(),
+        parts[1].clone(),
+        Some(parts[2].clone()),
+        Some(parts[3].clone()),
+    ))
 }
 
 pub trait FromArchive: Sized {
@@ -437,8 +1076,9 @@ pub trait FromArchive: Sized {
 
 #[cfg(test)]
 mod test {
-    use super::super::target;
+    use super::super::{target, PackageInstall};
     use super::*;
+    use std::fs::{copy, read, write};
     use std::path::PathBuf;
 
     #[test]
@@ -470,6 +1110,71 @@ mod test {
         let _ = hart.tdeps().unwrap();
     }
 
+    #[test]
+    fn reading_artifact_metadata_summary() {
+        let mut hart = PackageArchive::new(
+            fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"),
+        );
+        let metadata = hart.metadata().unwrap();
+        assert_eq!(
+            metadata.ident,
+            PackageIdent::from_str("happyhumans/possums/8.1.4/20160427165340").unwrap()
+        );
+        assert_eq!(metadata.target, target::X86_64_LINUX);
+        assert_eq!(metadata.deps, hart.deps().unwrap());
+        assert_eq!(metadata.exposes, hart.exposes().unwrap());
+        // Reading everything first gives the same answer
+        assert_eq!(hart.metadata().unwrap(), metadata);
+    }
+
+    #[test]
+    fn detecting_payload_compression() {
+        let hart = PackageArchive::new(
+            fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"),
+        );
+        assert_eq!(hart.compression().unwrap(), PayloadCompression::Xz);
+        assert_eq!(
+            PayloadCompression::detect(&[0x1f, 0x8b, 0x08, 0x00]),
+            Some(PayloadCompression::Gzip)
+        );
+        assert_eq!(PayloadCompression::detect(b"ustar"), None);
+    }
+
+    #[test]
+    fn reading_artifact_with_unsupported_compression() {
+        use std::io::Write;
+
+        let dir = Builder::new().prefix("harts").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        let payload = dir.path().join("payload.tar.zst");
+        File::create(&payload)
+            .unwrap()
+            .write_all(&[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x00])
+            .unwrap();
+        let hart_path = dir
+            .path()
+            .join("core-zstd-1.0.0-20180701000000-x86_64-linux.hart");
+        artifact::sign(&payload, &hart_path, &pair).unwrap();
+        let mut hart = PackageArchive::new(hart_path);
+
+        assert_eq!(hart.compression().unwrap(), PayloadCompression::Zstd);
+        match hart.ident() {
+            Err(Error::ArchiveCompressionUnsupported(ref format)) => assert_eq!(format, "zstd"),
+            other => panic!("expected ArchiveCompressionUnsupported, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unpacked_size_counts_every_entry() {
+        let hart = PackageArchive::new(
+            fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"),
+        );
+        let needed = hart.unpacked_size().unwrap();
+        assert!(needed.inodes > 0);
+        assert_eq!(needed.bytes % BLOCK_SIZE, 0);
+        assert!(needed.bytes >= needed.inodes * BLOCK_SIZE);
+    }
+
     #[test]
     fn reading_artifact_large_tdeps() {
         let mut hart = PackageArchive::new(
@@ -479,6 +1184,391 @@ mod test {
         assert_eq!(1024, tdeps.len());
     }
 
+    #[test]
+    fn unpack_verified_installs_package() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        copy(
+            fixtures().join("happyhumans-20160424223347.pub"),
+            cache.path().join("happyhumans-20160424223347.pub"),
+        )
+        .unwrap();
+        let hart = PackageArchive::new(
+            fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"),
+        );
+
+        let (signer, _) = hart
+            .unpack_verified(&cache.path(), Some(fs_root.path()))
+            .unwrap();
+        assert_eq!(signer, "happyhumans-20160424223347");
+        let ident =
+            FullyQualifiedPackageIdent::from_str("happyhumans/possums/8.1.4/20160427165340")
+                .unwrap();
+        assert!(fs::pkg_install_path(&ident, Some(fs_root.path()))
+            .join("IDENT")
+            .is_file());
+        // Nothing is left behind from unpacking besides the package
+        assert_eq!(
+            read_dir(fs::pkg_root_path(Some(fs_root.path())))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn unpack_verified_without_key_installs_nothing() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let hart = PackageArchive::new(
+            fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"),
+        );
+
+        assert!(hart
+            .unpack_verified(&cache.path(), Some(fs_root.path()))
+            .is_err());
+        assert_eq!(
+            read_dir(fs::pkg_root_path(Some(fs_root.path())))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn unpack_verified_tampered_artifact_installs_nothing() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        copy(
+            fixtures().join("happyhumans-20160424223347.pub"),
+            cache.path().join("happyhumans-20160424223347.pub"),
+        )
+        .unwrap();
+        let mut content =
+            read(fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"))
+                .unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        let path = cache.path().join("tampered.hart");
+        write(&path, &content).unwrap();
+
+        assert!(PackageArchive::new(path)
+            .unpack_verified(&cache.path(), Some(fs_root.path()))
+            .is_err());
+        assert_eq!(
+            read_dir(fs::pkg_root_path(Some(fs_root.path())))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn unpack_verified_checks_the_signature_before_reading_the_payload() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        copy(
+            fixtures().join("happyhumans-20160424223347.pub"),
+            cache.path().join("happyhumans-20160424223347.pub"),
+        )
+        .unwrap();
+        let content =
+            read(fixtures().join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart"))
+                .unwrap();
+        let header_len = content
+            .iter()
+            .enumerate()
+            .filter(|&(_, b)| *b == b'\n')
+            .nth(4)
+            .unwrap()
+            .0
+            + 1;
+        let mut content = content[..header_len].to_vec();
+        content.extend_from_slice(b"not a compressed payload");
+        let path = cache.path().join("garbled.hart");
+        write(&path, &content).unwrap();
+
+        match PackageArchive::new(path).unpack_verified(&cache.path(), Some(fs_root.path())) {
+            Err(Error::CryptoError(_)) => (),
+            other => panic!("expected CryptoError, got {:?}", other),
+        }
+    }
+
+    /// Signs an artifact whose payload has an empty file at each of `files` and a hard link for
+    /// each of `links`, with their paths just as they're given.
+    fn crafted_artifact(
+        dir: &Path,
+        pair: &SigKeyPair,
+        files: &[&str],
+        links: &[(&str, &str)],
+    ) -> PathBuf {
+        let payload_path = dir.join("payload.tar.xz");
+        let mut payload = PayloadWriter::create(&payload_path).unwrap();
+        for file in files {
+            payload.add_header(file, AE_IFREG, 0o644, 0, None).unwrap();
+        }
+        for &(link, target) in links {
+            let link = CString::new(link).unwrap();
+            let target = CString::new(target).unwrap();
+            unsafe {
+                let entry = ffi::archive_entry_new();
+                ffi::archive_entry_set_pathname(entry, link.as_ptr());
+                ffi::archive_entry_set_hardlink(entry, target.as_ptr());
+                ffi::archive_entry_set_filetype(entry, AE_IFREG as _);
+                ffi::archive_entry_set_perm(entry, 0o644);
+                assert_eq!(ffi::archive_write_header(payload.0, entry), ARCHIVE_OK);
+                ffi::archive_entry_free(entry);
+            }
+        }
+        payload.close().unwrap();
+        let hart_path = dir.join("core-evil-1.0.0-20180101000000-x86_64-linux.hart");
+        artifact::sign(&payload_path, &hart_path, pair).unwrap();
+        hart_path
+    }
+
+    #[test]
+    fn unpack_refuses_entries_outside_the_root() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let outside = Builder::new().prefix("outside").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let prefix = "hab/pkgs/core/evil/1.0.0/20180101000000";
+        let ident_path = format!("{}/IDENT", prefix);
+        let absolute = outside
+            .path()
+            .join("escaped")
+            .to_string_lossy()
+            .into_owned();
+        let dotdot = format!("{}/../../../../../../escaped", prefix);
+        let link = format!("{}/passwd", prefix);
+
+        let (ident_path, link) = (ident_path.as_str(), link.as_str());
+        let cases: Vec<(Vec<&str>, Vec<(&str, &str)>)> = vec![
+            (vec![ident_path, absolute.as_str()], vec![]),
+            (vec![ident_path, dotdot.as_str()], vec![]),
+            (vec![ident_path], vec![(link, "/etc/passwd")]),
+            (vec![ident_path], vec![(link, "../../../../../../etc/passwd")]),
+        ];
+        for &(ref files, ref links) in &cases {
+            let hart_path = crafted_artifact(cache.path(), &pair, files, links);
+            match PackageArchive::new(hart_path.clone())
+                .unpack_verified(&cache.path(), Some(fs_root.path()))
+            {
+                Err(Error::PackageUnpackFailed(_)) => (),
+                other => panic!("expected PackageUnpackFailed, got {:?}", other),
+            }
+            match PackageArchive::new(hart_path).unpack(Some(fs_root.path())) {
+                Err(Error::PackageUnpackFailed(_)) => (),
+                other => panic!("expected PackageUnpackFailed, got {:?}", other),
+            }
+            assert!(!outside.path().join("escaped").exists());
+            assert!(!fs_root.path().join("hab/pkgs/core").exists());
+        }
+    }
+
+    #[test]
+    fn unpack_verified_refuses_a_signer_of_another_origin() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("mallory").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let hart_path = crafted_artifact(
+            cache.path(),
+            &pair,
+            &["hab/pkgs/core/evil/1.0.0/20180101000000/IDENT"],
+            &[],
+        );
+
+        match PackageArchive::new(hart_path).unpack_verified(&cache.path(), Some(fs_root.path())) {
+            Err(Error::CryptoError(ref msg)) => assert!(msg.contains("not a key of its origin")),
+            other => panic!("expected CryptoError, got {:?}", other),
+        }
+        assert!(!fs_root.path().join("hab/pkgs/core").exists());
+    }
+
+    #[test]
+    fn build_creates_verifiable_artifact() {
+        use std::io::Write;
+
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        create_dir_all(src.path().join("bin")).unwrap();
+        File::create(src.path().join("bin/hello"))
+            .unwrap()
+            .write_all(b"#!/bin/sh\necho hello\n")
+            .unwrap();
+        let ident = PackageIdent::from_str("core/hello/1.0.0/20180701000000").unwrap();
+        let exact = FullyQualifiedPackageIdent::try_from(ident.clone()).unwrap();
+
+        let mut hart = PackageArchive::build(src.path(), &exact, &pair, dst.path()).unwrap();
+
+        assert_eq!(
+            hart.path,
+            dst.path().join(
+                ident
+                    .archive_name_with_target(PackageTarget::active_target())
+                    .unwrap()
+            )
+        );
+        assert_eq!(hart.ident().unwrap(), ident);
+        assert_eq!(&hart.target().unwrap(), PackageTarget::active_target());
+        hart.verify(&cache.path()).unwrap();
+        hart.unpack_verified(&cache.path(), Some(fs_root.path()))
+            .unwrap();
+        let mut content = String::new();
+        File::open(fs::pkg_install_path(&exact, Some(fs_root.path())).join("bin/hello"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "#!/bin/sh\necho hello\n");
+        let installed = PackageInstall::load(&ident, Some(fs_root.path())).unwrap();
+        let verification = installed.verify(cache.path()).unwrap();
+        assert_eq!(verification.signer, Some(pair.name_with_rev()));
+        assert!(verification.is_intact());
+        assert_eq!(
+            installed
+                .files()
+                .unwrap()
+                .map(|f| f.path)
+                .collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("IDENT"),
+                PathBuf::from("TARGET"),
+                PathBuf::from("bin/hello"),
+            ]
+        );
+        // Only the artifact is left behind
+        assert_eq!(read_dir(dst.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn build_refuses_special_files() {
+        use std::os::unix::net::UnixListener;
+
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        let _socket = UnixListener::bind(src.path().join("hello.sock")).unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/hello/1.0.0/20180701000000").unwrap();
+
+        match PackageArchive::build(src.path(), &ident, &pair, dst.path()) {
+            Err(Error::ArchiveWriteFailed(_)) => (),
+            other => panic!("expected ArchiveWriteFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_for_aarch64_target() {
+        use std::io::Write;
+
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        File::create(src.path().join("TARGET"))
+            .unwrap()
+            .write_all(b"aarch64-linux\n")
+            .unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/hello/1.0.0/20180701000000").unwrap();
+
+        let mut hart = PackageArchive::build(src.path(), &ident, &pair, dst.path()).unwrap();
+
+        assert_eq!(
+            hart.file_name(),
+            "core-hello-1.0.0-20180701000000-aarch64-linux.hart"
+        );
+        assert_eq!(hart.target().unwrap(), target::AARCH64_LINUX);
+    }
+
+    #[test]
+    fn licenses_are_read_from_manifest() {
+        use std::io::Write;
+
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/hello/1.0.0/20180701000000").unwrap();
+        let mut unlicensed = PackageArchive::build(src.path(), &ident, &pair, dst.path()).unwrap();
+        assert!(unlicensed.licenses().unwrap().is_empty());
+
+        File::create(src.path().join("MANIFEST"))
+            .unwrap()
+            .write_all(b"# core / hello\n\n* __License__: Apache-2.0 MIT\n")
+            .unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/hello/1.0.0/20180702000000").unwrap();
+        let mut hart = PackageArchive::build(src.path(), &ident, &pair, dst.path()).unwrap();
+        assert_eq!(hart.licenses().unwrap(), vec!["Apache-2.0", "MIT"]);
+    }
+
+    #[test]
+    fn resign_replaces_or_appends_signatures() {
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let upstream = SigKeyPair::generate_pair_for_origin("upstream").unwrap();
+        let internal = SigKeyPair::generate_pair_for_origin("internal").unwrap();
+        internal.to_pair_files(cache.path()).unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("upstream/hello/1.0.0/20180701000000").unwrap();
+        let mut hart = PackageArchive::build(src.path(), &ident, &upstream, dst.path()).unwrap();
+        let payload_checksum =
+            hash::hash_reader(&mut artifact::get_archive_reader(&hart.path).unwrap()).unwrap();
+        assert!(hart.verify(&cache.path()).is_err());
+
+        hart.resign(&internal, true).unwrap();
+        let signers: Vec<String> = artifact::artifact_signatures(&hart.path)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.key_name)
+            .collect();
+        assert_eq!(
+            signers,
+            vec![upstream.name_with_rev(), internal.name_with_rev()]
+        );
+        assert!(hart.resign(&internal, true).is_err());
+
+        hart.resign(&internal, false).unwrap();
+        assert_eq!(
+            hart.verify(&cache.path()).unwrap().0,
+            internal.name_with_rev()
+        );
+        assert_eq!(
+            hash::hash_reader(&mut artifact::get_archive_reader(&hart.path).unwrap()).unwrap(),
+            payload_checksum
+        );
+        assert_eq!(hart.ident().unwrap(), *ident.as_ident());
+        // Only the artifact is left behind
+        assert_eq!(read_dir(dst.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn build_requires_matching_ident() {
+        use std::io::Write;
+
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        File::create(src.path().join("IDENT"))
+            .unwrap()
+            .write_all(b"core/goodbye/1.0.0/20180701000000\n")
+            .unwrap();
+        let ident =
+            FullyQualifiedPackageIdent::from_str("core/hello/1.0.0/20180701000000").unwrap();
+
+        match PackageArchive::build(src.path(), &ident, &pair, src.path()) {
+            Err(Error::ArchiveWriteFailed(_)) => (),
+            other => panic!("expected ArchiveWriteFailed, got {:?}", other),
+        }
+    }
+
     #[test]
     fn reading_artifact_target() {
         let mut hart = PackageArchive::new(
@@ -0,0 +1,378 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity audits of installed packages.
+//!
+//! An audit checks the files of each installed package against the signed `FILES` manifest it
+//! was installed with, or the checksums in its `MANIFEST` if it has none, as
+//! `PackageInstall::verify` does, and reports whatever differs as findings. The manifest covers a
+//! package's hooks and configuration templates too, so a package which has neither, or whose
+//! manifest can't be verified, is reported as well: nothing it runs or renders can be traced back
+//! to the key of its origin.
+//!
+//! Audits run on demand with `Auditor::audit`, or in the background on a schedule with
+//! `Auditor::schedule`. Either way, an observer is told about each finding as it is made.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::install::DEFAULT_CFG_FILE;
+use super::list::all_packages;
+use super::manifest::ModifiedFile;
+use super::{PackageIdent, PackageInstall};
+use error::Result;
+use fs;
+
+/// What is wrong with an installed file, or with a package as a whole.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FindingKind {
+    /// The content or size of the file differs from the manifest
+    Tampered,
+    /// The file is in the manifest but isn't installed
+    Missing,
+    /// The file is installed but isn't in the manifest
+    Unexpected,
+    /// Only the permission bits of the file differ from the manifest
+    PermissionDrift { expected: u32, actual: u32 },
+    /// The package has neither a `FILES` manifest nor checksums in its `MANIFEST`, or its
+    /// manifest's signature can't be verified, so none of its files could be checked
+    Unverifiable(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct AuditFinding {
+    pub ident: PackageIdent,
+    /// The path of the file, relative to the package's installed path. A finding about the
+    /// package as a whole has none.
+    pub path: Option<PathBuf>,
+    pub kind: FindingKind,
+}
+
+impl AuditFinding {
+    /// Whether the finding is about one of the package's hooks or configuration templates, which
+    /// the Supervisor runs or renders for the package's service.
+    pub fn is_hook_or_config(&self) -> bool {
+        match self.path {
+            Some(ref path) => {
+                path.starts_with("hooks")
+                    || path.starts_with("config")
+                    || path == Path::new(DEFAULT_CFG_FILE)
+            }
+            None => false,
+        }
+    }
+}
+
+/// What an audit tells its observer as it goes along.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditEvent {
+    /// An audit of this many packages started
+    Started {
+        packages: usize,
+    },
+    Finding(AuditFinding),
+    Finished {
+        packages: usize,
+        findings: usize,
+    },
+    /// A scheduled audit couldn't be run, such as when the packages can't be listed
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Auditor {
+    cache_key_path: PathBuf,
+    fs_root_path: PathBuf,
+}
+
+impl Auditor {
+    /// Returns an auditor of the packages installed under `fs_root_path`, or `/` if it isn't
+    /// given, which verifies their manifests with the origin keys in `cache_key_path`.
+    pub fn new<P>(cache_key_path: P, fs_root_path: Option<&Path>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Auditor {
+            cache_key_path: cache_key_path.into(),
+            fs_root_path: fs_root_path.map_or(PathBuf::from("/"), |p| p.into()),
+        }
+    }
+
+    /// Audits every installed package, calling `observer` as the audit starts, with each
+    /// finding, and as it finishes. Returns all of the findings.
+    ///
+    /// # Failures
+    ///
+    /// * The installed packages can't be listed or loaded
+    pub fn audit<F>(&self, mut observer: F) -> Result<Vec<AuditFinding>>
+    where
+        F: FnMut(&AuditEvent),
+    {
+        let package_root_path = fs::pkg_root_path(Some(&self.fs_root_path));
+        let idents = if package_root_path.is_dir() {
+            all_packages(&package_root_path)?
+        } else {
+            vec![]
+        };
+        observer(&AuditEvent::Started {
+            packages: idents.len(),
+        });
+        let mut findings = Vec::new();
+        for ident in &idents {
+            for finding in self.audit_package(ident)? {
+                observer(&AuditEvent::Finding(finding.clone()));
+                findings.push(finding);
+            }
+        }
+        observer(&AuditEvent::Finished {
+            packages: idents.len(),
+            findings: findings.len(),
+        });
+        Ok(findings)
+    }
+
+    /// Audits one installed package, returning its findings: missing files, then files which
+    /// differ from the manifest, then unexpected files, each ordered by path.
+    ///
+    /// # Failures
+    ///
+    /// * The package isn't installed
+    pub fn audit_package(&self, ident: &PackageIdent) -> Result<Vec<AuditFinding>> {
+        let install = PackageInstall::load(ident, Some(&self.fs_root_path))?;
+        let ident = install.ident();
+        let finding = |path: &Path, kind: FindingKind| AuditFinding {
+            ident: ident.clone(),
+            path: Some(path.to_path_buf()),
+            kind: kind,
+        };
+        let verification = match install.verify(&self.cache_key_path) {
+            Ok(verification) => verification,
+            Err(e) => {
+                return Ok(vec![AuditFinding {
+                    ident: ident.clone(),
+                    path: None,
+                    kind: FindingKind::Unverifiable(e.to_string()),
+                }])
+            }
+        };
+
+        let mut findings = Vec::new();
+        for entry in &verification.missing {
+            findings.push(finding(&entry.path, FindingKind::Missing));
+        }
+        for modified in &verification.modified {
+            findings.push(finding(
+                &modified.expected.path,
+                modification_kind(modified),
+            ));
+        }
+        for entry in &verification.unexpected {
+            findings.push(finding(&entry.path, FindingKind::Unexpected));
+        }
+        Ok(findings)
+    }
+
+    /// Audits every installed package in the background right away and then every `interval`
+    /// until the returned schedule is stopped or dropped. An audit which can't be run is reported
+    /// to `observer` as failed, and the next one is run as usual.
+    pub fn schedule<F>(self, interval: Duration, mut observer: F) -> AuditSchedule
+    where
+        F: FnMut(&AuditEvent) + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || loop {
+            if let Err(e) = self.audit(&mut observer) {
+                observer(&AuditEvent::Failed(e.to_string()));
+            }
+            match stop_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                // Stopped, or the schedule was dropped
+                _ => break,
+            }
+        });
+        AuditSchedule {
+            stop: stop_tx,
+            handle: handle,
+        }
+    }
+}
+
+/// Audits running in the background, as returned by `Auditor::schedule`.
+pub struct AuditSchedule {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl AuditSchedule {
+    /// Stops scheduling audits, waiting for one which is running to finish.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn modification_kind(modified: &ModifiedFile) -> FindingKind {
+    let (expected, actual) = (&modified.expected, &modified.actual);
+    if expected.hash == actual.hash && expected.size == actual.size {
+        FindingKind::PermissionDrift {
+            expected: expected.mode,
+            actual: actual.mode,
+        }
+    } else {
+        FindingKind::Tampered
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use std::fs::{self as stdfs, File, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use crypto::hash::{hash_file_with, HashAlgorithm};
+    use crypto::SigKeyPair;
+    use package::manifest::write_signed_manifest;
+    use package::test_support::testing_package_install;
+
+    fn write_file(path: &Path, content: &str) {
+        stdfs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut f = File::create(path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn audit_reports_findings_to_the_observer() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let redis = testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+        let prefix = redis.installed_path().to_path_buf();
+        write_file(&prefix.join("bin/redis-server"), "binary");
+        write_file(&prefix.join("bin/redis-cli"), "binary");
+        write_file(&prefix.join("hooks/run"), "exec redis-server");
+        write_signed_manifest(&redis, &pair).unwrap();
+        testing_package_install("core/unsigned/1.0.0/20180608202239", fs_root.path());
+
+        write_file(&prefix.join("hooks/run"), "exec evil");
+        stdfs::remove_file(prefix.join("bin/redis-cli")).unwrap();
+        stdfs::set_permissions(
+            prefix.join("bin/redis-server"),
+            Permissions::from_mode(0o777),
+        )
+        .unwrap();
+
+        let auditor = Auditor::new(cache.path(), Some(fs_root.path()));
+        let mut events = Vec::new();
+        let findings = auditor.audit(|event| events.push(event.clone())).unwrap();
+
+        let redis_ident = PackageIdent::from_str("core/redis/4.0.10/20180608202239").unwrap();
+        let kinds: Vec<(Option<PathBuf>, FindingKind)> = findings
+            .iter()
+            .filter(|f| f.ident == redis_ident)
+            .map(|f| (f.path.clone(), f.kind.clone()))
+            .collect();
+        assert_eq!(kinds.len(), 3);
+        assert_eq!(
+            kinds[0],
+            (Some(PathBuf::from("bin/redis-cli")), FindingKind::Missing)
+        );
+        match kinds[1] {
+            (ref path, FindingKind::PermissionDrift { actual: 0o777, .. }) => {
+                assert_eq!(path, &Some(PathBuf::from("bin/redis-server")))
+            }
+            ref other => panic!("Expected permission drift, got {:?}", other),
+        }
+        assert_eq!(
+            kinds[2],
+            (Some(PathBuf::from("hooks/run")), FindingKind::Tampered)
+        );
+        assert!(findings.iter().any(|f| f.is_hook_or_config()));
+        assert!(findings.iter().any(|f| match f.kind {
+            FindingKind::Unverifiable(_) => f.path.is_none(),
+            _ => false,
+        }));
+
+        assert_eq!(events.len(), findings.len() + 2);
+        assert_eq!(events[0], AuditEvent::Started { packages: 2 });
+        assert_eq!(
+            events.last(),
+            Some(&AuditEvent::Finished {
+                packages: 2,
+                findings: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn packages_with_manifest_checksums_are_audited() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let redis = testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+        let prefix = redis.installed_path().to_path_buf();
+        write_file(&prefix.join("hooks/run"), "exec redis-server");
+        let mut checksums = String::from("core redis\n==========\n\nFiles\n-----\n");
+        for file in &["IDENT", "TARGET", "hooks/run"] {
+            checksums.push_str(&format!(
+                "{}  /hab/pkgs/core/redis/4.0.10/20180608202239/{}\n",
+                hash_file_with(prefix.join(file), HashAlgorithm::Sha256).unwrap(),
+                file
+            ));
+        }
+        write_file(&prefix.join("MANIFEST"), &checksums);
+        let auditor = Auditor::new(cache.path(), Some(fs_root.path()));
+        let ident = redis.ident().clone();
+        assert_eq!(auditor.audit_package(&ident).unwrap(), vec![]);
+
+        write_file(&prefix.join("hooks/run"), "exec evil");
+
+        assert_eq!(
+            auditor.audit_package(&ident).unwrap(),
+            vec![AuditFinding {
+                ident: ident.clone(),
+                path: Some(PathBuf::from("hooks/run")),
+                kind: FindingKind::Tampered,
+            }]
+        );
+    }
+
+    #[test]
+    fn scheduled_audits_run_until_stopped() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        testing_package_install("core/unsigned", fs_root.path());
+        let (tx, rx) = mpsc::channel();
+
+        let schedule = Auditor::new(cache.path(), Some(fs_root.path())).schedule(
+            Duration::from_millis(10),
+            move |event| {
+                if let AuditEvent::Finished { .. } = *event {
+                    let _ = tx.send(());
+                }
+            },
+        );
+        for _ in 0..2 {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        schedule.stop();
+    }
+}
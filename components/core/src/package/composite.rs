@@ -0,0 +1,197 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Composite packages, which bundle services meant to run together.
+//!
+//! A composite has no content of its own. Its `SERVICES` metafile lists its members as given in
+//! its plan, `RESOLVED_SERVICES` lists the releases it was built with, and `BIND_MAP` wires the
+//! binds of members to the other members which satisfy them.
+
+use std::path::Path;
+
+use super::metadata::{BindMapping, PackageType};
+use super::{Identifiable, PackageIdent, PackageInstall};
+use error::{Error, Result};
+
+/// A service of a composite package.
+#[derive(Debug, PartialEq)]
+pub struct CompositeMember {
+    /// The identifier as given in the plan of the composite
+    pub ident: PackageIdent,
+    /// The release the composite was built with, if it recorded one
+    pub resolved: Option<PackageIdent>,
+    /// The binds of the service which other members satisfy
+    pub binds: Vec<BindMapping>,
+}
+
+impl CompositeMember {
+    /// The identifier of the release to run, which is the resolved release when there is one.
+    pub fn run_ident(&self) -> &PackageIdent {
+        self.resolved.as_ref().unwrap_or(&self.ident)
+    }
+}
+
+/// A composite package and the services it bundles, in the order its plan gives them.
+#[derive(Debug, PartialEq)]
+pub struct Composite {
+    pub ident: PackageIdent,
+    pub members: Vec<CompositeMember>,
+}
+
+impl Composite {
+    /// Loads the composite package `ident` installed under `fs_root_path`, or `/` if it isn't
+    /// given.
+    ///
+    /// # Failures
+    ///
+    /// * The package isn't installed
+    /// * The package isn't a composite
+    /// * The composite metafiles are malformed
+    pub fn load(ident: &PackageIdent, fs_root_path: Option<&Path>) -> Result<Self> {
+        Self::from_install(&PackageInstall::load(ident, fs_root_path)?)
+    }
+
+    /// Reads the members of an installed composite package and how their binds are wired.
+    ///
+    /// # Failures
+    ///
+    /// * The package isn't a composite
+    /// * The composite metafiles are malformed
+    /// * A bind is mapped for a service which isn't a member, or to one
+    pub fn from_install(install: &PackageInstall) -> Result<Self> {
+        match install.pkg_type()? {
+            PackageType::Composite => (),
+            PackageType::Standalone => {
+                return Err(Error::CompositePackageExpected(install.ident.to_string()))
+            }
+        }
+        let services = install.pkg_services()?;
+        let resolved = install.resolved_services()?;
+        let mut bind_map = install.bind_map()?;
+
+        let is_member = |ident: &PackageIdent| services.iter().any(|s| s == ident);
+        for (service, binds) in &bind_map {
+            if !is_member(service) || binds.iter().any(|b| !is_member(&b.satisfying_service)) {
+                return Err(Error::MetaFileBadBind);
+            }
+        }
+
+        let members = services
+            .iter()
+            .map(|service| CompositeMember {
+                ident: service.clone(),
+                resolved: resolved.iter().find(|r| r.satisfies(service)).cloned(),
+                binds: bind_map.remove(service).unwrap_or_default(),
+            })
+            .collect();
+        Ok(Composite {
+            ident: install.ident.clone(),
+            members: members,
+        })
+    }
+
+    /// Returns the member with the given identifier, as given in the plan of the composite.
+    pub fn member(&self, ident: &PackageIdent) -> Option<&CompositeMember> {
+        self.members.iter().find(|m| m.ident == *ident)
+    }
+
+    /// Returns the members whose binds `ident` satisfies, with the names of those binds.
+    pub fn bound_to<'a>(&'a self, ident: &'a PackageIdent) -> Vec<(&'a CompositeMember, &'a str)> {
+        self.members
+            .iter()
+            .flat_map(move |member| {
+                member
+                    .binds
+                    .iter()
+                    .filter(move |b| b.satisfying_service == *ident)
+                    .map(move |b| (member, b.bind_name.as_str()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::metadata::MetaFile;
+    use package::test_support::testing_package_install;
+
+    fn write_metafile(install: &PackageInstall, metafile: MetaFile, content: &str) {
+        let mut f = File::create(install.installed_path().join(metafile.to_string())).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn members_are_read_with_their_binds() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/stack/1.0.0/20180701000000", fs_root.path());
+        write_metafile(&install, MetaFile::Type, "composite");
+        write_metafile(&install, MetaFile::Services, "core/db\ncore/app/2.0.0");
+        write_metafile(
+            &install,
+            MetaFile::ResolvedServices,
+            "core/db/9.6.3/20180601000000\ncore/app/2.0.0/20180602000000",
+        );
+        write_metafile(&install, MetaFile::BindMap, "core/app=database:core/db");
+
+        let composite = Composite::load(&ident("core/stack"), Some(fs_root.path())).unwrap();
+        assert_eq!(composite.ident, install.ident);
+        assert_eq!(composite.members.len(), 2);
+
+        let db = composite.member(&ident("core/db")).unwrap();
+        assert_eq!(db.run_ident(), &ident("core/db/9.6.3/20180601000000"));
+        assert!(db.binds.is_empty());
+        let app = composite.member(&ident("core/app/2.0.0")).unwrap();
+        assert_eq!(
+            app.binds,
+            vec![BindMapping {
+                bind_name: "database".to_string(),
+                satisfying_service: ident("core/db"),
+            }]
+        );
+        assert_eq!(
+            composite.bound_to(&ident("core/db")),
+            vec![(app, "database")]
+        );
+    }
+
+    #[test]
+    fn standalone_packages_and_foreign_binds_are_rejected() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let standalone = testing_package_install("core/db", fs_root.path());
+        match Composite::from_install(&standalone) {
+            Err(Error::CompositePackageExpected(_)) => (),
+            other => panic!("Expected a composite error, got {:?}", other),
+        }
+
+        let install = testing_package_install("core/stack", fs_root.path());
+        write_metafile(&install, MetaFile::Type, "composite");
+        write_metafile(&install, MetaFile::Services, "core/app");
+        write_metafile(&install, MetaFile::BindMap, "core/app=database:core/db");
+        match Composite::from_install(&install) {
+            Err(Error::MetaFileBadBind) => (),
+            other => panic!("Expected a bad bind error, got {:?}", other),
+        }
+    }
+}
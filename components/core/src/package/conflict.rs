@@ -0,0 +1,225 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of conflicting dependencies between packages which are meant to run together.
+//!
+//! Packages pin their dependencies to exact releases, so two packages can each be fine alone but
+//! depend on different releases of the same package, such as two builds of `core/openssl`, which
+//! tends to surface as services crashing once they run side by side. A conflict is reported with
+//! a path of dependencies leading to each of the releases involved, so it's clear which packages
+//! pulled them in.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+
+use super::{PackageGraph, PackageIdent, PackageInstall};
+use error::Result;
+
+/// Different releases of one package among the dependencies of packages which are meant to run
+/// together.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DependencyConflict {
+    /// The origin and name of the package, such as `core/openssl`
+    pub package: String,
+    /// A path of dependencies to each of the releases, ordered by release. Each path starts at
+    /// one of the packages being run together and ends at the release.
+    pub paths: Vec<Vec<PackageIdent>>,
+}
+
+impl fmt::Display for DependencyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths: Vec<String> = self
+            .paths
+            .iter()
+            .map(|path| {
+                path.iter()
+                    .map(|ident| ident.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            })
+            .collect();
+        write!(
+            f,
+            "conflicting releases of {}: {}",
+            self.package,
+            paths.join(", ")
+        )
+    }
+}
+
+/// Returns the packages which `roots`, packages in `graph` meant to run together, depend on
+/// different releases of, directly or not, ordered by origin and name. The roots themselves are
+/// included, so two releases of the same package can't run together either.
+pub fn conflicts(graph: &PackageGraph, roots: &[PackageIdent]) -> Vec<DependencyConflict> {
+    let mut releases: BTreeMap<String, BTreeMap<&PackageIdent, Vec<PackageIdent>>> =
+        BTreeMap::new();
+    for root in roots {
+        for (ident, path) in paths_from(graph, root) {
+            releases
+                .entry(format!("{}/{}", ident.origin, ident.name))
+                .or_insert_with(BTreeMap::new)
+                .entry(ident)
+                .or_insert(path);
+        }
+    }
+    releases
+        .into_iter()
+        .filter(|&(_, ref paths)| paths.len() > 1)
+        .map(|(package, paths)| DependencyConflict {
+            package: package,
+            paths: paths.into_iter().map(|(_, path)| path).collect(),
+        })
+        .collect()
+}
+
+/// Returns the conflicts between the dependencies of packages installed under `fs_root_path`, or
+/// `/` if it isn't given, which are meant to run together. An ident which isn't fully qualified
+/// stands for the release it loads, which is the latest installed unless it is held.
+///
+/// # Failures
+///
+/// * One of the packages isn't installed
+/// * The metafiles of an installed package can't be read
+pub fn check(
+    idents: &[PackageIdent],
+    fs_root_path: Option<&Path>,
+) -> Result<Vec<DependencyConflict>> {
+    let roots = idents
+        .iter()
+        .map(|ident| PackageInstall::load(ident, fs_root_path).map(|install| install.ident))
+        .collect::<Result<Vec<PackageIdent>>>()?;
+    let graph = PackageGraph::from_installed(fs_root_path)?;
+    Ok(conflicts(&graph, &roots))
+}
+
+/// Returns the shortest path of direct dependencies from `root` to each package it depends on,
+/// and to itself. A dependency which is only recorded as transitive gets a path straight from
+/// the root.
+fn paths_from<'a>(
+    graph: &'a PackageGraph,
+    root: &'a PackageIdent,
+) -> Vec<(&'a PackageIdent, Vec<PackageIdent>)> {
+    let mut parents: HashMap<&PackageIdent, Option<&PackageIdent>> = HashMap::new();
+    let mut order = vec![root];
+    let mut queue = VecDeque::new();
+    parents.insert(root, None);
+    queue.push_back(root);
+    while let Some(ident) = queue.pop_front() {
+        for dep in graph.deps(ident) {
+            if !parents.contains_key(dep) {
+                parents.insert(dep, Some(ident));
+                order.push(dep);
+                queue.push_back(dep);
+            }
+        }
+    }
+    for dep in graph.tdeps(root) {
+        if !parents.contains_key(dep) {
+            parents.insert(dep, Some(root));
+            order.push(dep);
+        }
+    }
+
+    order
+        .into_iter()
+        .map(|ident| {
+            let mut path = vec![ident.clone()];
+            let mut next = parents[ident];
+            while let Some(parent) = next {
+                path.push(parent.clone());
+                next = parents[parent];
+            }
+            path.reverse();
+            (ident, path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::test_support::testing_package_install;
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn conflicting_releases_are_reported_with_paths() {
+        let mut graph = PackageGraph::new();
+        let old_openssl = ident("core/openssl/1.0.2/20170513215106");
+        let new_openssl = ident("core/openssl/1.1.0/20180608102213");
+        let glibc = ident("core/glibc/2.27/20180608041157");
+        let curl = ident("core/curl/7.60.0/20180608102213");
+        let nginx = ident("core/nginx/1.15.0/20180608040224");
+        let redis = ident("core/redis/4.0.10/20180608202239");
+        graph.add_package(old_openssl.clone(), &[glibc.clone()]);
+        graph.add_package(new_openssl.clone(), &[glibc.clone()]);
+        graph.add_package(curl.clone(), &[new_openssl.clone(), glibc.clone()]);
+        graph.add_package(nginx.clone(), &[old_openssl.clone()]);
+        graph.add_package(redis.clone(), &[curl.clone()]);
+
+        assert!(conflicts(&graph, &[nginx.clone()]).is_empty());
+        assert_eq!(
+            conflicts(&graph, &[nginx.clone(), redis.clone()]),
+            vec![DependencyConflict {
+                package: "core/openssl".to_string(),
+                paths: vec![
+                    vec![nginx.clone(), old_openssl.clone()],
+                    vec![redis.clone(), curl.clone(), new_openssl.clone()],
+                ],
+            }]
+        );
+        assert_eq!(conflicts(&graph, &[old_openssl, new_openssl]).len(), 1);
+    }
+
+    #[test]
+    fn check_installed_packages() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        testing_package_install("core/glibc/2.22/20170513201042", fs_root.path());
+        testing_package_install("core/glibc/2.27/20180608041157", fs_root.path());
+        for &(pkg, glibc) in &[
+            (
+                "core/redis/4.0.10/20180608202239",
+                "core/glibc/2.27/20180608041157",
+            ),
+            (
+                "core/nginx/1.15.0/20180608040224",
+                "core/glibc/2.22/20170513201042",
+            ),
+        ] {
+            let install = testing_package_install(pkg, fs_root.path());
+            for metafile in &["DEPS", "TDEPS"] {
+                let mut f = File::create(install.installed_path().join(metafile)).unwrap();
+                f.write_all(glibc.as_bytes()).unwrap();
+            }
+        }
+
+        let found = check(
+            &[ident("core/redis"), ident("core/nginx")],
+            Some(fs_root.path()),
+        )
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].package, "core/glibc");
+        assert!(check(&[ident("core/zlib")], Some(fs_root.path())).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Constraints on the versions of a package which are acceptable.
+//!
+//! A constraint is a comma-separated list of comparisons, all of which a version must satisfy,
+//! such as `>=1.2, <2.0`. The comparison operators are `=`, `>`, `>=`, `<` and `<=`, along with
+//! `~`, which accepts any version from the one given up to, but not including, the next increment
+//! of its second-to-last part: `~1.4` is the same as `>=1.4, <1.5`, and `~1.4.2` the same as
+//! `>=1.4.2, <1.5`. A version with a single part is incremented itself, so `~1` means `>=1, <2`.
+//!
+//! Versions are compared the same way as in a `PackageIdent`, by `ident::version_sort`.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+
+use super::ident::version_sort;
+use error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VersionOp {
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Tilde,
+}
+
+impl fmt::Display for VersionOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match *self {
+            VersionOp::Eq => "=",
+            VersionOp::Gt => ">",
+            VersionOp::Gte => ">=",
+            VersionOp::Lt => "<",
+            VersionOp::Lte => "<=",
+            VersionOp::Tilde => "~",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// A single comparison against a version, such as `>=1.2`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionComparator {
+    pub op: VersionOp,
+    pub version: String,
+}
+
+impl VersionComparator {
+    /// Returns whether `version` satisfies this comparison. A version which can't be compared,
+    /// such as one which isn't numeric, never does.
+    pub fn matches(&self, version: &str) -> bool {
+        let ordering = match version_sort(version, &self.version) {
+            Ok(ordering) => ordering,
+            Err(_) => return false,
+        };
+        match self.op {
+            VersionOp::Eq => ordering == Ordering::Equal,
+            VersionOp::Gt => ordering == Ordering::Greater,
+            VersionOp::Gte => ordering != Ordering::Less,
+            VersionOp::Lt => ordering == Ordering::Less,
+            VersionOp::Lte => ordering != Ordering::Greater,
+            VersionOp::Tilde => {
+                ordering != Ordering::Less
+                    && match version_sort(version, &tilde_upper_bound(&self.version)) {
+                        Ok(ordering) => ordering == Ordering::Less,
+                        Err(_) => false,
+                    }
+            }
+        }
+    }
+}
+
+impl fmt::Display for VersionComparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op, self.version)
+    }
+}
+
+impl FromStr for VersionComparator {
+    type Err = Error;
+
+    fn from_str(value: &str) -> result::Result<Self, Self::Err> {
+        let value = value.trim();
+        let (op, version) = if value.starts_with(">=") {
+            (VersionOp::Gte, &value[2..])
+        } else if value.starts_with("<=") {
+            (VersionOp::Lte, &value[2..])
+        } else if value.starts_with('>') {
+            (VersionOp::Gt, &value[1..])
+        } else if value.starts_with('<') {
+            (VersionOp::Lt, &value[1..])
+        } else if value.starts_with('~') {
+            (VersionOp::Tilde, &value[1..])
+        } else if value.starts_with('=') {
+            (VersionOp::Eq, &value[1..])
+        } else {
+            (VersionOp::Eq, value)
+        };
+        let version = version.trim();
+        if !is_valid_version(version) {
+            return Err(Error::InvalidVersionConstraint(value.to_string()));
+        }
+        Ok(VersionComparator {
+            op: op,
+            version: version.to_string(),
+        })
+    }
+}
+
+/// A set of comparisons which a version must all satisfy, such as `>=1.2, <2.0`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionConstraint {
+    comparators: Vec<VersionComparator>,
+}
+
+impl VersionConstraint {
+    /// Returns whether `version` satisfies every comparison in the constraint.
+    pub fn matches(&self, version: &str) -> bool {
+        self.comparators.iter().all(|c| c.matches(version))
+    }
+
+    pub fn comparators(&self) -> &[VersionComparator] {
+        &self.comparators
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let comparators: Vec<String> = self.comparators.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", comparators.join(", "))
+    }
+}
+
+impl FromStr for VersionConstraint {
+    type Err = Error;
+
+    fn from_str(value: &str) -> result::Result<Self, Self::Err> {
+        if value.trim().is_empty() {
+            return Err(Error::InvalidVersionConstraint(value.to_string()));
+        }
+        let comparators = value
+            .split(',')
+            .map(|c| c.parse())
+            .collect::<Result<Vec<VersionComparator>>>()?;
+        Ok(VersionConstraint {
+            comparators: comparators,
+        })
+    }
+}
+
+/// Only dotted numeric versions are allowed in a constraint, since they are the only versions
+/// `version_sort` can order.
+fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_digit(10)))
+}
+
+/// The first version which `~version` no longer accepts.
+fn tilde_upper_bound(version: &str) -> String {
+    let mut parts: Vec<u64> = version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect();
+    if parts.len() > 1 {
+        parts.pop();
+    }
+    if let Some(last) = parts.last_mut() {
+        *last += 1;
+    }
+    let parts: Vec<String> = parts.iter().map(|p| p.to_string()).collect();
+    parts.join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let constraint: VersionConstraint = ">=1.2, <2.0".parse().unwrap();
+        assert_eq!(
+            constraint.comparators(),
+            &[
+                VersionComparator {
+                    op: VersionOp::Gte,
+                    version: "1.2".to_string(),
+                },
+                VersionComparator {
+                    op: VersionOp::Lt,
+                    version: "2.0".to_string(),
+                },
+            ]
+        );
+        assert_eq!(constraint.to_string(), ">=1.2, <2.0");
+        assert_eq!(
+            "1.4.2".parse::<VersionConstraint>().unwrap().to_string(),
+            "=1.4.2"
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("".parse::<VersionConstraint>().is_err());
+        assert!(">=".parse::<VersionConstraint>().is_err());
+        assert!(">=1.2,".parse::<VersionConstraint>().is_err());
+        assert!("!1.2".parse::<VersionConstraint>().is_err());
+        assert!("~1.x".parse::<VersionConstraint>().is_err());
+    }
+
+    #[test]
+    fn range_matches() {
+        let constraint: VersionConstraint = ">=1.2, <2.0".parse().unwrap();
+        assert!(constraint.matches("1.2"));
+        assert!(constraint.matches("1.2.0"));
+        assert!(constraint.matches("1.10.3"));
+        assert!(!constraint.matches("1.1.9"));
+        assert!(!constraint.matches("2.0.0"));
+        assert!(!constraint.matches("master"));
+    }
+
+    #[test]
+    fn tilde_matches() {
+        let constraint: VersionConstraint = "~1.4".parse().unwrap();
+        assert!(constraint.matches("1.4"));
+        assert!(constraint.matches("1.4.7"));
+        assert!(!constraint.matches("1.3.9"));
+        assert!(!constraint.matches("1.5"));
+
+        let constraint: VersionConstraint = "~1.4.2".parse().unwrap();
+        assert!(constraint.matches("1.4.9"));
+        assert!(!constraint.matches("1.4.1"));
+        assert!(!constraint.matches("1.5.0"));
+
+        let constraint: VersionConstraint = "~1".parse().unwrap();
+        assert!(constraint.matches("1.9"));
+        assert!(!constraint.matches("2.0"));
+    }
+}
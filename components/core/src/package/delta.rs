@@ -0,0 +1,794 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Binary deltas between two releases of a package.
+//!
+//! A delta holds everything needed to build the installed files of one release of a package from
+//! the installed files of an earlier release, so a node which already has the earlier release
+//! only has to download what changed. Each file of the new release is described by a list of
+//! operations: copying a range of bytes from the file at the same path in the old release, or
+//! writing bytes carried in the delta itself. The old file is split into blocks of
+//! `DELTA_BLOCK_SIZE` bytes, which are found at any offset of the new file with rsync's rolling
+//! checksum, so bytes inserted or removed only cost the bytes around them rather than every block
+//! after them.
+//!
+//! The delta is signed with an origin key as an artifact is, and the header names both releases,
+//! so it can only be applied on top of the release it was made from:
+//!
+//! ```text
+//! HART-DELTA-1
+//! core/redis/4.0.9/20180601000000
+//! core/redis/4.0.10/20180701000000
+//!
+//! file 755 1207824 20590a52c4f00588c500328b16d466c982a26fabaa5fa4dcc83052dd0a84f233 bin/redis-server
+//! copy 0 1048576
+//! data 16384
+//! <16384 bytes>
+//! copy 1064960 142864
+//! end
+//! link b80c4f412f9a0a7727b6e6f115e1b5fa3bae79ad2fcf47f769ed4e42cfb12265 bin/redis-check-aof
+//! redis-server
+//! ```
+//!
+//! Every rebuilt file is checked against the size and BLAKE2b hash recorded for it, as it is
+//! written, before the new release is moved into place. Empty directories aren't carried over.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self as stdfs, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::list::temp_package_directory;
+use super::manifest::{file_mode, FileManifest, ManifestEntry};
+use super::metadata::MetaFile;
+use super::{FullyQualifiedPackageIdent, PackageIdent, PackageInstall};
+use crypto::keys::parse_name_with_rev;
+use crypto::{artifact, hash, SigKeyPair};
+use error::{Error, Result};
+use fs;
+use tempfile;
+
+pub static DELTA_FORMAT_VERSION: &'static str = "HART-DELTA-1";
+/// The size of the blocks files are compared in
+pub const DELTA_BLOCK_SIZE: usize = 16 * 1024;
+/// The most bytes carried by a single `data` operation
+const MAX_DATA_LEN: usize = 1024 * 1024;
+
+/// Writes a signed delta to `dst` which builds the release `to` from the release `from` of the
+/// same package.
+pub fn create_delta<P: AsRef<Path>>(
+    from: &PackageInstall,
+    to: &PackageInstall,
+    dst: P,
+    pair: &SigKeyPair,
+) -> Result<()> {
+    check_releases(from.ident(), to.ident())?;
+    let dst = dst.as_ref();
+    let body_path = dst.with_extension("body.tmp");
+    let result = write_delta_body(from, to, &body_path).and_then(|_| {
+        artifact::sign(&body_path, dst, pair)?;
+        Ok(())
+    });
+    let _ = stdfs::remove_file(&body_path);
+    result
+}
+
+/// Verifies a delta with the origin keys in `cache_key_path` and applies it to the release it
+/// was made from, which must be installed under `fs_root_path`. Returns the new release, which
+/// is only moved into place once every one of its files has been rebuilt and checked.
+///
+/// The delta is copied aside as it is verified and applied from the copy, so what is applied is
+/// what was verified even if the delta is replaced meanwhile.
+pub fn apply_delta<P1: ?Sized, P2: ?Sized>(
+    delta: &P1,
+    cache_key_path: &P2,
+    fs_root_path: Option<&Path>,
+) -> Result<PackageInstall>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let package_root_path = fs::pkg_root_path(fs_root_path);
+    stdfs::create_dir_all(&package_root_path)?;
+    let mut body = tempfile::tempfile_in(&package_root_path)?;
+    let (signer, _) = {
+        let mut verifying = artifact::open_verifying(delta, cache_key_path)?;
+        io::copy(&mut verifying, &mut body)?;
+        verifying.finish()?
+    };
+    body.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(body);
+    let version = read_line(&mut reader)?;
+    if version.as_ref().map(|v| v.as_str()) != Some(DELTA_FORMAT_VERSION) {
+        return Err(malformed(&format!(
+            "unsupported format version {:?}",
+            version
+        )));
+    }
+    let from_ident = PackageIdent::from_str(&expect_line(&mut reader)?)?;
+    let to_ident = PackageIdent::from_str(&expect_line(&mut reader)?)?;
+    let to_exact = check_releases(&from_ident, &to_ident)?;
+    if parse_name_with_rev(&signer)?.0 != to_ident.origin {
+        return Err(Error::CryptoError(format!(
+            "Delta for {} is signed by {}, which is not a key of its origin",
+            to_ident, signer
+        )));
+    }
+    if !expect_line(&mut reader)?.is_empty() {
+        return Err(malformed("missing end of header"));
+    }
+
+    let from = PackageInstall::load(&from_ident, fs_root_path)?;
+    let installed_path = fs::pkg_install_path(&to_exact, fs_root_path);
+    if installed_path.is_dir() {
+        return PackageInstall::load(&to_ident, fs_root_path);
+    }
+    let staging = temp_package_directory(&installed_path)?;
+    while let Some(line) = read_line(&mut reader)? {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("file"), Some(rest)) => {
+                let entry = parse_entry(rest)?;
+                apply_file(&mut reader, &entry, from.installed_path(), staging.path())?;
+            }
+            (Some("link"), Some(rest)) => {
+                let mut parts = rest.splitn(2, ' ');
+                let (hash, path) = match (parts.next(), parts.next()) {
+                    (Some(hash), Some(path)) => (hash, path),
+                    _ => return Err(malformed(&line)),
+                };
+                let target = expect_line(&mut reader)?;
+                apply_link(hash, Path::new(path), &target, staging.path())?;
+            }
+            _ => return Err(malformed(&line)),
+        }
+    }
+    stdfs::rename(staging.path(), &installed_path)?;
+    // The staging directory is gone now that it has been moved into place
+    let _ = staging.into_path();
+    PackageInstall::load(&to_ident, fs_root_path)
+}
+
+fn write_delta_body(from: &PackageInstall, to: &PackageInstall, dst: &Path) -> Result<()> {
+    let mut entries = FileManifest::generate(to)?.entries;
+    // A manifest leaves out its own metafile, but it is part of the release all the same
+    let files_path = to.installed_path().join(MetaFile::Files.to_string());
+    if files_path.is_file() {
+        let metadata = stdfs::metadata(&files_path)?;
+        entries.push(ManifestEntry {
+            path: PathBuf::from(MetaFile::Files.to_string()),
+            mode: file_mode(&metadata),
+            size: metadata.len(),
+            hash: hash::hash_file(&files_path)?,
+        });
+    }
+
+    let mut writer = BufWriter::new(File::create(dst)?);
+    write!(
+        writer,
+        "{}\n{}\n{}\n\n",
+        DELTA_FORMAT_VERSION,
+        from.ident(),
+        to.ident()
+    )?;
+    for entry in entries {
+        let new_path = to.installed_path().join(&entry.path);
+        if stdfs::symlink_metadata(&new_path)?.file_type().is_symlink() {
+            write!(
+                writer,
+                "link {} {}\n{}\n",
+                entry.hash,
+                entry.path.display(),
+                stdfs::read_link(&new_path)?.display()
+            )?;
+            continue;
+        }
+        write!(
+            writer,
+            "file {:o} {} {} {}\n",
+            entry.mode,
+            entry.size,
+            entry.hash,
+            entry.path.display()
+        )?;
+        write_file_ops(
+            &mut writer,
+            &from.installed_path().join(&entry.path),
+            &new_path,
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the operations which build the file at `new_path` from the file at `old_path`, if
+/// there is one. The new file is scanned a byte at a time with a rolling checksum, so blocks of
+/// the old file are found wherever they are in the new one.
+fn write_file_ops<W: Write>(writer: &mut W, old_path: &Path, new_path: &Path) -> Result<()> {
+    let index = if stdfs::symlink_metadata(old_path)
+        .map(|m| m.is_file())
+        .unwrap_or(false)
+    {
+        BlockIndex::new(old_path)?
+    } else {
+        BlockIndex::default()
+    };
+    let mut input = File::open(new_path)?;
+    let mut ops = FileOps::new(writer);
+    let mut buf: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    let mut eof = false;
+    let mut checksum: Option<RollingChecksum> = None;
+    loop {
+        // Keep a whole block and the byte after it in the buffer, to roll the checksum onto
+        if !eof && buf.len() - pos <= DELTA_BLOCK_SIZE {
+            buf.drain(..pos);
+            pos = 0;
+            let filled = buf.len();
+            buf.resize(DELTA_BLOCK_SIZE * 4, 0);
+            let len = read_block(&mut input, &mut buf[filled..])?;
+            eof = filled + len < buf.len();
+            buf.truncate(filled + len);
+        }
+        let window_len = cmp::min(DELTA_BLOCK_SIZE, buf.len() - pos);
+        if window_len == 0 {
+            break;
+        }
+        let window = &buf[pos..pos + window_len];
+        let sum = checksum.unwrap_or_else(|| RollingChecksum::new(window));
+        if let Some(offset) = index.find(sum.digest(), window) {
+            ops.copy(offset, window_len as u64)?;
+            pos += window_len;
+            checksum = None;
+            continue;
+        }
+        let out = buf[pos];
+        ops.data(out)?;
+        checksum = Some(match buf.get(pos + DELTA_BLOCK_SIZE) {
+            Some(&next) if window_len == DELTA_BLOCK_SIZE => sum.rolled(out, next),
+            // The window shrinks once it reaches the end of the file
+            _ => sum.shrunk(out),
+        });
+        pos += 1;
+    }
+    ops.finish()
+}
+
+/// The operations of one file, as `write_file_ops` finds them. Copies of adjacent ranges are
+/// merged and data is gathered into operations of up to `MAX_DATA_LEN` bytes.
+struct FileOps<'a, W: 'a + Write> {
+    writer: &'a mut W,
+    copy: Option<(u64, u64)>,
+    data: Vec<u8>,
+}
+
+impl<'a, W: Write> FileOps<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        FileOps {
+            writer: writer,
+            copy: None,
+            data: Vec::new(),
+        }
+    }
+
+    fn copy(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.write_data()?;
+        let pending = self.copy;
+        self.copy = match pending {
+            Some((start, copy_len)) if start + copy_len == offset => Some((start, copy_len + len)),
+            Some(_) => {
+                self.write_copy()?;
+                Some((offset, len))
+            }
+            None => Some((offset, len)),
+        };
+        Ok(())
+    }
+
+    fn data(&mut self, byte: u8) -> Result<()> {
+        self.write_copy()?;
+        self.data.push(byte);
+        if self.data.len() >= MAX_DATA_LEN {
+            self.write_data()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.write_copy()?;
+        self.write_data()?;
+        write!(self.writer, "end\n")?;
+        Ok(())
+    }
+
+    fn write_copy(&mut self) -> Result<()> {
+        if let Some((start, len)) = self.copy.take() {
+            write!(self.writer, "copy {} {}\n", start, len)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self) -> Result<()> {
+        if !self.data.is_empty() {
+            write!(self.writer, "data {}\n", self.data.len())?;
+            self.writer.write_all(&self.data)?;
+            write!(self.writer, "\n")?;
+            self.data.clear();
+        }
+        Ok(())
+    }
+}
+
+/// rsync's rolling checksum of a window of bytes: the sum of the bytes and the sum of those
+/// running sums, each kept to 16 bits. It can be moved along by a byte, or have its first byte
+/// dropped, without going over the rest of the window again.
+#[derive(Clone, Copy, Debug)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut sum = RollingChecksum {
+            a: 0,
+            b: 0,
+            len: len,
+        };
+        for (i, &byte) in window.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(u32::from(byte));
+            sum.b = sum
+                .b
+                .wrapping_add((len - i as u32).wrapping_mul(u32::from(byte)));
+        }
+        sum
+    }
+
+    /// Returns the checksum of the window moved along by a byte, dropping `out` and adding `next`.
+    fn rolled(self, out: u8, next: u8) -> Self {
+        let a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(next));
+        RollingChecksum {
+            a: a,
+            b: self
+                .b
+                .wrapping_sub(self.len.wrapping_mul(u32::from(out)))
+                .wrapping_add(a),
+            len: self.len,
+        }
+    }
+
+    /// Returns the checksum of the window without its first byte, `out`.
+    fn shrunk(self, out: u8) -> Self {
+        RollingChecksum {
+            a: self.a.wrapping_sub(u32::from(out)),
+            b: self.b.wrapping_sub(self.len.wrapping_mul(u32::from(out))),
+            len: self.len - 1,
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// The blocks of an old file by their rolling checksum, each with its BLAKE2b hash, offset and
+/// length. Only the last block can be shorter than `DELTA_BLOCK_SIZE`.
+#[derive(Default)]
+struct BlockIndex(HashMap<u32, Vec<(String, u64, usize)>>);
+
+impl BlockIndex {
+    fn new(path: &Path) -> Result<Self> {
+        let mut index: HashMap<u32, Vec<(String, u64, usize)>> = HashMap::new();
+        let mut input = BufReader::new(File::open(path)?);
+        let mut block = vec![0u8; DELTA_BLOCK_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = read_block(&mut input, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            let block = &block[..len];
+            index
+                .entry(RollingChecksum::new(block).digest())
+                .or_insert_with(Vec::new)
+                .push((hash::hash_bytes(block), offset, len));
+            offset += len as u64;
+            if len < DELTA_BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok(BlockIndex(index))
+    }
+
+    /// Returns the offset of a block of the old file which is the same as `window`, whose
+    /// rolling checksum is `digest`. Its hash is only taken when the checksum matches a block.
+    fn find(&self, digest: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.0.get(&digest)?;
+        let hash = hash::hash_bytes(window);
+        candidates
+            .iter()
+            .find(|&&(ref block_hash, _, len)| len == window.len() && *block_hash == hash)
+            .map(|&(_, offset, _)| offset)
+    }
+}
+
+/// Fill `buf` from `input`, returning fewer bytes than its length only once `input` is exhausted.
+fn read_block<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::IO(e)),
+        }
+    }
+    Ok(filled)
+}
+
+fn apply_file<R: BufRead>(
+    reader: &mut R,
+    entry: &ManifestEntry,
+    old_root: &Path,
+    new_root: &Path,
+) -> Result<()> {
+    let new_path = fs::join_safe(new_root, &entry.path)?;
+    if let Some(parent) = new_path.parent() {
+        stdfs::create_dir_all(parent)?;
+    }
+    let mut output = CheckedWriter::new(BufWriter::new(File::create(&new_path)?), entry)?;
+    let mut old: Option<File> = None;
+    loop {
+        let line = expect_line(reader)?;
+        let mut parts = line.split(' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("end"), None, None) => break,
+            (Some("copy"), Some(offset), Some(len)) => {
+                let offset = offset.parse::<u64>()?;
+                let len = output.reserve(len.parse::<u64>()?)?;
+                if old.is_none() {
+                    old = Some(File::open(fs::join_safe(old_root, &entry.path)?)?);
+                }
+                let old = old.as_mut().unwrap();
+                old.seek(SeekFrom::Start(offset))?;
+                copy_exact(&mut old.take(len), &mut output, len)?;
+            }
+            (Some("data"), Some(len), None) => {
+                let len = len.parse::<u64>()?;
+                if len > MAX_DATA_LEN as u64 {
+                    return Err(malformed(&format!(
+                        "data of {} bytes is longer than the {} allowed",
+                        len, MAX_DATA_LEN
+                    )));
+                }
+                let len = output.reserve(len)?;
+                copy_exact(&mut Read::by_ref(reader).take(len), &mut output, len)?;
+                if expect_line(reader)? != "" {
+                    return Err(malformed("data is longer than its length"));
+                }
+            }
+            _ => return Err(malformed(&line)),
+        }
+    }
+    output.finish()?;
+    set_mode(&new_path, entry.mode)
+}
+
+/// Writes a rebuilt file, hashing it as it is written so it can be checked against its entry in
+/// the delta without reading it back, and refusing operations which would make it larger than
+/// the entry says it is.
+struct CheckedWriter<'a, W: Write> {
+    inner: W,
+    entry: &'a ManifestEntry,
+    hasher: hash::Hasher,
+    written: u64,
+}
+
+impl<'a, W: Write> CheckedWriter<'a, W> {
+    fn new(inner: W, entry: &'a ManifestEntry) -> Result<Self> {
+        Ok(CheckedWriter {
+            inner: inner,
+            entry: entry,
+            hasher: hash::Hasher::new(hash::HashAlgorithm::Blake2b)?,
+            written: 0,
+        })
+    }
+
+    /// Returns `len` if an operation writing that many more bytes keeps the file within its size.
+    fn reserve(&self, len: u64) -> Result<u64> {
+        match self.written.checked_add(len) {
+            Some(total) if total <= self.entry.size => Ok(len),
+            _ => Err(malformed(&format!(
+                "operations on {} run past its size of {} bytes",
+                self.entry.path.display(),
+                self.entry.size
+            ))),
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.inner.flush()?;
+        let computed_hash = self.hasher.finish();
+        if self.written != self.entry.size || computed_hash != self.entry.hash {
+            return Err(Error::PackageUnpackFailed(format!(
+                "Rebuilt {} doesn't match the delta (expected: {}, computed: {})",
+                self.entry.path.display(),
+                self.entry.hash,
+                computed_hash
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for CheckedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn apply_link(hash: &str, path: &Path, target: &str, new_root: &Path) -> Result<()> {
+    if hash::hash_string(target) != hash {
+        return Err(Error::PackageUnpackFailed(format!(
+            "Link {} doesn't match the delta",
+            path.display()
+        )));
+    }
+    let new_path = fs::join_safe(new_root, path)?;
+    if let Some(parent) = new_path.parent() {
+        stdfs::create_dir_all(parent)?;
+    }
+    symlink(target, &new_path)
+}
+
+fn copy_exact<R: Read, W: Write>(input: &mut R, output: &mut W, len: u64) -> Result<()> {
+    if io::copy(input, output)? != len {
+        return Err(malformed("operation runs past the end of its input"));
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    stdfs::set_permissions(path, stdfs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = stdfs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    stdfs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn symlink(target: &str, path: &Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn symlink(target: &str, path: &Path) -> Result<()> {
+    ::std::os::windows::fs::symlink_file(target, path)?;
+    Ok(())
+}
+
+fn parse_entry(rest: &str) -> Result<ManifestEntry> {
+    let mut parts = rest.splitn(4, ' ');
+    let mode = parts.next().and_then(|m| u32::from_str_radix(m, 8).ok());
+    let size = parts.next().and_then(|s| s.parse::<u64>().ok());
+    match (mode, size, parts.next(), parts.next()) {
+        (Some(mode), Some(size), Some(hash), Some(path)) => Ok(ManifestEntry {
+            path: PathBuf::from(path),
+            mode: mode,
+            size: size,
+            hash: hash.to_string(),
+        }),
+        _ => Err(malformed(rest)),
+    }
+}
+
+fn check_releases(from: &PackageIdent, to: &PackageIdent) -> Result<FullyQualifiedPackageIdent> {
+    FullyQualifiedPackageIdent::try_from(from.clone())?;
+    let to_exact = FullyQualifiedPackageIdent::try_from(to.clone())?;
+    if from.origin != to.origin || from.name != to.name {
+        return Err(Error::PackageUnpackFailed(format!(
+            "A delta can only be made between releases of the same package, not {} and {}",
+            from, to
+        )));
+    }
+    Ok(to_exact)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn expect_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    read_line(reader)?.ok_or_else(|| malformed("unexpected end of delta"))
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::PackageUnpackFailed(format!("Malformed package delta: {}", msg))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use tempfile::Builder;
+
+    use super::super::test_support::testing_package_install;
+    use super::*;
+
+    fn write_file(path: &Path, content: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut f = File::create(path).unwrap();
+        f.write_all(content).unwrap();
+    }
+
+    /// Installs two releases of a package whose large binary only differs in one block
+    fn install_releases(fs_root: &Path) -> (PackageInstall, PackageInstall) {
+        let old = testing_package_install("core/redis/4.0.9/20180601000000", fs_root);
+        let new = testing_package_install("core/redis/4.0.10/20180701000000", fs_root);
+        let mut binary = vec![7u8; DELTA_BLOCK_SIZE * 8 + 100];
+        write_file(&old.installed_path().join("bin/redis-server"), &binary);
+        binary[DELTA_BLOCK_SIZE * 3 + 5] = 8;
+        write_file(&new.installed_path().join("bin/redis-server"), &binary);
+        write_file(&new.installed_path().join("etc/redis.conf"), b"port 6379\n");
+        (old, new)
+    }
+
+    #[test]
+    fn create_and_apply_delta() {
+        let build_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let node_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let (old, new) = install_releases(build_root.path());
+        let (_, unreleased) = install_releases(node_root.path());
+        fs::remove_dir_all(unreleased.installed_path()).unwrap();
+        let delta = cache.path().join("redis.delta");
+
+        create_delta(&old, &new, &delta, &pair).unwrap();
+        // Only the changed block of the binary is carried in the delta
+        assert!(fs::metadata(&delta).unwrap().len() < (DELTA_BLOCK_SIZE * 2) as u64);
+        let applied = apply_delta(&delta, cache.path(), Some(node_root.path())).unwrap();
+
+        assert_eq!(applied.ident(), new.ident());
+        assert_eq!(
+            FileManifest::generate(&applied).unwrap().entries,
+            FileManifest::generate(&new).unwrap().entries
+        );
+    }
+
+    #[test]
+    fn bytes_inserted_before_a_block_cost_only_themselves() {
+        let build_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let node_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let mut seed = 7u32;
+        let binary: Vec<u8> = (0..DELTA_BLOCK_SIZE * 8 + 100)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&binary);
+        for root in &[build_root.path(), node_root.path()] {
+            let old = testing_package_install("core/redis/4.0.9/20180601000000", root);
+            write_file(&old.installed_path().join("bin/redis-server"), &binary);
+        }
+        let old = PackageInstall::load(
+            &PackageIdent::from_str("core/redis/4.0.9/20180601000000").unwrap(),
+            Some(build_root.path()),
+        )
+        .unwrap();
+        let new = testing_package_install("core/redis/4.0.10/20180701000000", build_root.path());
+        write_file(&new.installed_path().join("bin/redis-server"), &shifted);
+        let delta = cache.path().join("redis.delta");
+
+        create_delta(&old, &new, &delta, &pair).unwrap();
+        assert!(fs::metadata(&delta).unwrap().len() < 4096);
+        let applied = apply_delta(&delta, cache.path(), Some(node_root.path())).unwrap();
+
+        assert_eq!(
+            FileManifest::generate(&applied).unwrap().entries,
+            FileManifest::generate(&new).unwrap().entries
+        );
+    }
+
+    #[test]
+    fn apply_delta_refuses_oversized_data() {
+        let node_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        testing_package_install("core/redis/4.0.9/20180601000000", node_root.path());
+        let content = vec![1u8; MAX_DATA_LEN + 1];
+        let mut body = format!(
+            "{}\ncore/redis/4.0.9/20180601000000\ncore/redis/4.0.10/20180701000000\n\n\
+             file 644 {} {} bin/redis-server\ndata {}\n",
+            DELTA_FORMAT_VERSION,
+            content.len(),
+            hash::hash_bytes(&content),
+            content.len()
+        )
+        .into_bytes();
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\nend\n");
+        let body_path = cache.path().join("redis.delta.body");
+        write_file(&body_path, &body);
+        let delta = cache.path().join("redis.delta");
+        artifact::sign(&body_path, &delta, &pair).unwrap();
+
+        match apply_delta(&delta, cache.path(), Some(node_root.path())) {
+            Err(Error::PackageUnpackFailed(ref msg)) => assert!(msg.contains("allowed")),
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(i) => panic!("Should not apply successfully, install_ident={}", i.ident()),
+        }
+        assert!(!::fs::pkg_install_path(
+            &FullyQualifiedPackageIdent::from_str("core/redis/4.0.10/20180701000000").unwrap(),
+            Some(node_root.path())
+        )
+        .exists());
+    }
+
+    #[test]
+    fn apply_delta_without_old_release() {
+        let build_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let node_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let (old, new) = install_releases(build_root.path());
+        let delta = cache.path().join("redis.delta");
+        create_delta(&old, &new, &delta, &pair).unwrap();
+
+        match apply_delta(&delta, cache.path(), Some(node_root.path())) {
+            Err(Error::PackageNotFound(ref ident)) => assert_eq!(ident, old.ident()),
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(i) => panic!("Should not apply successfully, install_ident={}", i.ident()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comparison of two installed packages, usually two releases of the same package, for reviewing
+//! what a new release changes.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::manifest::{FileManifest, ManifestDiff};
+use super::{PackageIdent, PackageInstall};
+use error::Result;
+
+/// Changes to the direct dependencies of a package. Dependencies are matched by origin and name,
+/// so a dependency on a different release of the same package is a change rather than a removal
+/// and an addition.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DependencyDiff {
+    pub added: Vec<PackageIdent>,
+    pub removed: Vec<PackageIdent>,
+    /// Dependencies on another release, as they were and as they are
+    pub changed: Vec<(PackageIdent, PackageIdent)>,
+}
+
+impl DependencyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Changes to the configuration a package exports, by export name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExportsDiff {
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    /// Exports whose configuration key changed, as it was and as it is
+    pub changed: BTreeMap<String, (String, String)>,
+}
+
+impl ExportsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Everything which differs between two installed packages, as returned by `diff`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PackageDiff {
+    pub from: PackageIdent,
+    pub to: PackageIdent,
+    pub files: ManifestDiff,
+    pub deps: DependencyDiff,
+    pub exports: ExportsDiff,
+}
+
+impl PackageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.deps.is_empty() && self.exports.is_empty()
+    }
+}
+
+/// Compares the installed package `from` with the installed package `to`: the files under their
+/// installed paths, their direct dependencies, and the configuration they export. Files are
+/// hashed as they are installed now, so local modifications show up as changes.
+///
+/// An optional `fs_root` path may be provided to find packages which are mounted on a filesystem
+/// not currently rooted at `/`.
+pub fn diff(
+    from: &PackageIdent,
+    to: &PackageIdent,
+    fs_root_path: Option<&Path>,
+) -> Result<PackageDiff> {
+    let from = PackageInstall::load(from, fs_root_path)?;
+    let to = PackageInstall::load(to, fs_root_path)?;
+    let files = FileManifest::generate(&from)?.diff(&FileManifest::generate(&to)?);
+    Ok(PackageDiff {
+        files: files,
+        deps: diff_deps(&from.deps()?, &to.deps()?),
+        exports: diff_exports(
+            from.exports()?.into_iter().collect(),
+            to.exports()?.into_iter().collect(),
+        ),
+        from: from.ident().clone(),
+        to: to.ident().clone(),
+    })
+}
+
+fn diff_deps(from: &[PackageIdent], to: &[PackageIdent]) -> DependencyDiff {
+    let key = |ident: &PackageIdent| format!("{}/{}", ident.origin, ident.name);
+    let from: BTreeMap<String, &PackageIdent> = from.iter().map(|d| (key(d), d)).collect();
+    let to: BTreeMap<String, &PackageIdent> = to.iter().map(|d| (key(d), d)).collect();
+    let mut diff = DependencyDiff::default();
+    for (name, dep) in &from {
+        match to.get(name) {
+            Some(other) if dep != other => diff.changed.push(((*dep).clone(), (*other).clone())),
+            Some(_) => {}
+            None => diff.removed.push((*dep).clone()),
+        }
+    }
+    for (name, dep) in &to {
+        if !from.contains_key(name) {
+            diff.added.push((*dep).clone());
+        }
+    }
+    diff
+}
+
+fn diff_exports(from: BTreeMap<String, String>, mut to: BTreeMap<String, String>) -> ExportsDiff {
+    let mut diff = ExportsDiff::default();
+    for (name, key) in from {
+        match to.remove(&name) {
+            Some(other) => {
+                if key != other {
+                    diff.changed.insert(name, (key, other));
+                }
+            }
+            None => {
+                diff.removed.insert(name, key);
+            }
+        }
+    }
+    diff.added = to;
+    diff
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::super::test_support::testing_package_install;
+    use super::*;
+
+    fn write_file(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut f = File::create(path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn diff_two_releases() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let old = testing_package_install("core/redis/4.0.9/20180601000000", fs_root.path());
+        let new = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        write_file(&old.installed_path().join("bin/redis-server"), "old");
+        write_file(&new.installed_path().join("bin/redis-server"), "new");
+        write_file(&old.installed_path().join("README"), "readme");
+        write_file(&new.installed_path().join("README"), "readme");
+        write_file(&new.installed_path().join("bin/redis-cli"), "cli");
+        write_file(
+            &old.installed_path().join("DEPS"),
+            "core/glibc/2.22/20170513201042\ncore/openssl/1.0.2/20180601000000\n",
+        );
+        write_file(
+            &new.installed_path().join("DEPS"),
+            "core/glibc/2.27/20180608041157\ncore/zlib/1.2.11/20180608050617\n",
+        );
+        write_file(
+            &old.installed_path().join("EXPORTS"),
+            "port=port\nhost=host\n",
+        );
+        write_file(&new.installed_path().join("EXPORTS"), "port=server.port\n");
+
+        let diff = diff(old.ident(), new.ident(), Some(fs_root.path())).unwrap();
+
+        assert!(!diff.is_empty());
+        // The metafiles change along with the binary
+        assert_eq!(
+            diff.files
+                .changed
+                .iter()
+                .map(|c| c.expected.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+            vec!["DEPS", "EXPORTS", "IDENT", "bin/redis-server"]
+        );
+        assert_eq!(diff.files.added.len(), 1);
+        assert_eq!(diff.files.added[0].path, Path::new("bin/redis-cli"));
+        assert_eq!(
+            diff.deps.changed,
+            vec![(
+                PackageIdent::from_str("core/glibc/2.22/20170513201042").unwrap(),
+                PackageIdent::from_str("core/glibc/2.27/20180608041157").unwrap(),
+            )]
+        );
+        assert_eq!(
+            diff.deps.removed,
+            vec![PackageIdent::from_str("core/openssl/1.0.2/20180601000000").unwrap()]
+        );
+        assert_eq!(
+            diff.deps.added,
+            vec![PackageIdent::from_str("core/zlib/1.2.11/20180608050617").unwrap()]
+        );
+        assert_eq!(
+            diff.exports.changed.get("port"),
+            Some(&("port".to_string(), "server.port".to_string()))
+        );
+        assert_eq!(diff.exports.removed.get("host"), Some(&"host".to_string()));
+        assert!(diff.exports.added.is_empty());
+    }
+
+    #[test]
+    fn diff_package_with_itself() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        write_file(&pkg.installed_path().join("bin/redis-server"), "binary");
+
+        assert!(diff(pkg.ident(), pkg.ident(), Some(fs_root.path()))
+            .unwrap()
+            .is_empty());
+    }
+}
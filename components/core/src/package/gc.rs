@@ -0,0 +1,316 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Removal of installed packages which nothing needs any more.
+//!
+//! A package is kept if it is one of the roots given in a `GcPolicy`, such as the packages of
+//! the services which are loaded, if it is one of the latest `keep_latest` releases of its
+//! origin and name, if it is held, or if a kept package depends on it, directly or not. Every
+//! other installed package is garbage.
+//!
+//! Collecting garbage holds the packages lock, so it never runs while packages are being
+//! installed, and the lock on each removed package's cached artifact while removing it.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{read_dir, remove_dir, remove_dir_all};
+use std::path::Path;
+
+use super::hold;
+use super::store::{self, ContentStore};
+use super::{FullyQualifiedPackageIdent, Identifiable, PackageGraph, PackageIdent, PackageInstall};
+use error::Result;
+use fs;
+
+/// How many releases of each package are kept unless the policy says otherwise
+pub const DEFAULT_KEEP_LATEST: usize = 1;
+
+/// What to keep when collecting garbage.
+#[derive(Clone, Debug)]
+pub struct GcPolicy {
+    /// Packages which are in use. An ident which isn't fully qualified keeps the latest installed
+    /// release which satisfies it.
+    pub roots: Vec<PackageIdent>,
+    /// How many of the latest releases of every package to keep, whether they are in use or not
+    pub keep_latest: usize,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        GcPolicy {
+            roots: Vec::new(),
+            keep_latest: DEFAULT_KEEP_LATEST,
+        }
+    }
+}
+
+/// The packages which `collect` removed, or would have removed in a dry run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcReport {
+    /// Packages ordered so that each comes before any of its dependencies
+    pub removed: Vec<PackageIdent>,
+    /// The disk space the removed packages took up, in bytes. Content the removed packages
+    /// shared through the content store is counted once the store drops it, which a dry run
+    /// doesn't do.
+    pub reclaimed: u64,
+}
+
+/// Returns the packages in `graph` which the policy doesn't keep, ordered so that each
+/// comes before any of its dependencies.
+pub fn garbage(graph: &PackageGraph, policy: &GcPolicy) -> Vec<PackageIdent> {
+    let nodes: Vec<&PackageIdent> = graph.idents().iter().collect();
+    let mut kept: HashSet<&PackageIdent> = HashSet::new();
+
+    let mut releases: BTreeMap<(&str, &str), Vec<&PackageIdent>> = BTreeMap::new();
+    for &ident in &nodes {
+        releases
+            .entry((ident.origin.as_str(), ident.name.as_str()))
+            .or_insert_with(Vec::new)
+            .push(ident);
+    }
+    for idents in releases.values_mut() {
+        idents.sort_by(|a, b| b.by_parts_cmp(a));
+        kept.extend(idents.iter().take(policy.keep_latest));
+    }
+    for root in &policy.roots {
+        let matching = nodes.iter().cloned().filter(|i| i.satisfies(root));
+        if root.fully_qualified() {
+            kept.extend(matching);
+        } else if let Some(latest) = matching.max_by(|a, b| a.by_parts_cmp(b)) {
+            kept.insert(latest);
+        }
+    }
+    for ident in kept.clone() {
+        kept.extend(graph.tdeps(ident));
+    }
+
+    // Dependents go first, so a collection which stops part way never leaves a package behind
+    // without its dependencies
+    let order = graph
+        .toposort()
+        .unwrap_or_else(|_| graph.idents().iter().collect());
+    order
+        .into_iter()
+        .rev()
+        .filter(|i| !kept.contains(i))
+        .cloned()
+        .collect()
+}
+
+/// Removes the packages installed under `fs_root_path`, or `/` if it isn't given, which the
+/// policy doesn't keep. Held releases are always kept. With `dry_run`, nothing is removed, and
+/// the report says what would have been.
+pub fn collect(policy: &GcPolicy, dry_run: bool, fs_root_path: Option<&Path>) -> Result<GcReport> {
+    let _packages_lock = if dry_run {
+        fs::lock_packages_shared(fs_root_path)?
+    } else {
+        fs::lock_packages(fs_root_path)?
+    };
+    let graph = PackageGraph::from_installed(fs_root_path)?;
+    let mut policy = policy.clone();
+    policy.roots.extend(hold::holds(fs_root_path)?);
+    let store = ContentStore::new(fs_root_path);
+    let mut report = GcReport::default();
+    for ident in garbage(&graph, &policy) {
+        let installed_path = match FullyQualifiedPackageIdent::try_from(ident.clone()) {
+            Ok(exact) => fs::pkg_install_path(&exact, fs_root_path),
+            // Only installed releases, which are fully qualified, are collected
+            Err(_) => continue,
+        };
+        if !installed_path.is_dir() {
+            // A dependency which was never installed
+            continue;
+        }
+        report.reclaimed += if store.exists() {
+            store::unshared_size(&installed_path)?
+        } else {
+            PackageInstall::load(&ident, fs_root_path)?.size_on_disk()?
+        };
+        if !dry_run {
+            let _artifact_lock = fs::lock_cache_artifact(&ident.archive_name()?, fs_root_path)?;
+            remove_dir_all(&installed_path)?;
+            remove_empty_parents(&installed_path, &fs::pkg_root_path(fs_root_path));
+        }
+        report.removed.push(ident);
+    }
+    if !dry_run {
+        report.reclaimed += store.prune()?;
+    }
+    Ok(report)
+}
+
+/// Removes the version, name, and origin directories above a removed release once they are
+/// empty.
+fn remove_empty_parents(installed_path: &Path, package_root_path: &Path) {
+    let mut dir = installed_path.parent();
+    while let Some(parent) = dir {
+        if parent == package_root_path {
+            break;
+        }
+        let is_empty = read_dir(parent)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if !is_empty || remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::test_support::testing_package_install;
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    fn exact(s: &str) -> FullyQualifiedPackageIdent {
+        FullyQualifiedPackageIdent::from_str(s).unwrap()
+    }
+
+    fn set_tdeps(install: &PackageInstall, tdeps: &str) {
+        for metafile in &["DEPS", "TDEPS"] {
+            let mut f = File::create(install.installed_path().join(metafile)).unwrap();
+            f.write_all(tdeps.as_bytes()).unwrap();
+        }
+    }
+
+    fn install_releases(fs_root: &Path) {
+        testing_package_install("core/glibc/2.22/20170513201042", fs_root);
+        testing_package_install("core/glibc/2.27/20180608041157", fs_root);
+        let old = testing_package_install("core/redis/3.2.4/20170514150022", fs_root);
+        set_tdeps(&old, "core/glibc/2.22/20170513201042\n");
+        let new = testing_package_install("core/redis/4.0.10/20180608202239", fs_root);
+        set_tdeps(&new, "core/glibc/2.27/20180608041157\n");
+        testing_package_install("core/nginx/1.15.0/20180608040224", fs_root);
+    }
+
+    #[test]
+    fn garbage_keeps_roots_latest_releases_and_their_deps() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        install_releases(fs_root.path());
+        let graph = PackageGraph::from_installed(Some(fs_root.path())).unwrap();
+
+        let policy = GcPolicy {
+            roots: vec![ident("core/redis/3.2.4/20170514150022")],
+            keep_latest: 0,
+        };
+        assert_eq!(
+            garbage(&graph, &policy),
+            vec![
+                ident("core/redis/4.0.10/20180608202239"),
+                ident("core/nginx/1.15.0/20180608040224"),
+                ident("core/glibc/2.27/20180608041157"),
+            ]
+        );
+
+        let policy = GcPolicy {
+            roots: vec![ident("core/redis")],
+            ..GcPolicy::default()
+        };
+        assert_eq!(
+            garbage(&graph, &policy),
+            vec![
+                ident("core/redis/3.2.4/20170514150022"),
+                ident("core/glibc/2.22/20170513201042"),
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage_orders_releases_by_version() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        testing_package_install("core/redis/3.10.0/20170514150022", fs_root.path());
+        testing_package_install("core/redis/3.9.0/20180608202239", fs_root.path());
+        let graph = PackageGraph::from_installed(Some(fs_root.path())).unwrap();
+
+        assert_eq!(
+            garbage(&graph, &GcPolicy::default()),
+            vec![ident("core/redis/3.9.0/20180608202239")]
+        );
+        let policy = GcPolicy {
+            roots: vec![ident("core/redis")],
+            keep_latest: 0,
+        };
+        assert_eq!(
+            garbage(&graph, &policy),
+            vec![ident("core/redis/3.9.0/20180608202239")]
+        );
+    }
+
+    #[test]
+    fn collect_removes_garbage_unless_dry_run() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        install_releases(fs_root.path());
+        let old_redis = exact("core/redis/3.2.4/20170514150022");
+
+        let report = collect(&GcPolicy::default(), true, Some(fs_root.path())).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        assert!(report.reclaimed > 0);
+        assert!(fs::pkg_install_path(&old_redis, Some(fs_root.path())).is_dir());
+
+        let removed = collect(&GcPolicy::default(), false, Some(fs_root.path())).unwrap();
+        assert_eq!(removed, report);
+        assert!(!fs::pkg_install_path(&old_redis, Some(fs_root.path())).exists());
+        // The emptied version directory goes too
+        assert!(!fs::pkg_install_path(&old_redis, Some(fs_root.path()))
+            .parent()
+            .unwrap()
+            .exists());
+        assert!(collect(&GcPolicy::default(), false, Some(fs_root.path()))
+            .unwrap()
+            .removed
+            .is_empty());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn collect_prunes_content_store() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        install_releases(fs_root.path());
+        let store = ContentStore::new(Some(fs_root.path()));
+        store.migrate().unwrap();
+        let new_redis = ident("core/redis/4.0.10/20180608202239");
+
+        let report = collect(&GcPolicy::default(), false, Some(fs_root.path())).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        assert!(report.reclaimed > 0);
+        // What the kept packages share stays in the store
+        let install = PackageInstall::load(&new_redis, Some(fs_root.path())).unwrap();
+        assert_eq!(store.add(&install).unwrap(), store::StoreReport::default());
+        assert_eq!(store.prune().unwrap(), 0);
+    }
+
+    #[test]
+    fn collect_keeps_held_releases() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        install_releases(fs_root.path());
+        hold::hold(&ident("core/redis/3.2.4"), Some(fs_root.path())).unwrap();
+
+        let report = collect(&GcPolicy::default(), false, Some(fs_root.path())).unwrap();
+        assert!(report.removed.is_empty());
+        assert!(fs::pkg_install_path(
+            &exact("core/glibc/2.22/20170513201042"),
+            Some(fs_root.path())
+        )
+        .is_dir());
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A graph of the dependencies between packages.
+//!
+//! Every package in a `PackageGraph` points at its direct dependencies, as listed in its `DEPS`
+//! metafile. The `TDEPS` metafile of a package is merged in as well, so that the transitive
+//! dependencies of a package are complete even when some of its intermediate dependencies aren't
+//! installed, and a dependency which is referred to but isn't installed is still a node of the
+//! graph.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+use super::list::all_packages;
+use super::{FullyQualifiedPackageIdent, PackageIdent, PackageInstall};
+use error::{Error, Result};
+use fs;
+
+/// The disk space taken up by an installed package, as returned by `PackageGraph::disk_usage`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiskUsage {
+    pub ident: PackageIdent,
+    /// The size of the package's own installed files
+    pub own: u64,
+    /// The size of the package's own installed files plus its share of each of its installed
+    /// transitive dependencies, which are split evenly between every installed package which
+    /// depends on them
+    pub attributed: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PackageGraph {
+    nodes: Vec<PackageIdent>,
+    index: HashMap<PackageIdent, usize>,
+    /// The direct dependencies of each node
+    deps: Vec<BTreeSet<usize>>,
+    /// The direct dependents of each node
+    rdeps: Vec<BTreeSet<usize>>,
+    /// The direct and recorded transitive dependencies of each node
+    edges: Vec<BTreeSet<usize>>,
+    /// The reverse of `edges`
+    redges: Vec<BTreeSet<usize>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+impl PackageGraph {
+    pub fn new() -> Self {
+        PackageGraph::default()
+    }
+
+    /// Builds the graph of every package installed under `fs_root_path`, or `/` if it isn't
+    /// given, from their `DEPS` and `TDEPS` metafiles.
+    pub fn from_installed(fs_root_path: Option<&Path>) -> Result<Self> {
+        let mut graph = PackageGraph::new();
+        let package_root_path = fs::pkg_root_path(fs_root_path);
+        if !package_root_path.is_dir() {
+            return Ok(graph);
+        }
+        let mut idents = all_packages(&package_root_path)?;
+        idents.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+        for ident in idents {
+            let pkg_install = PackageInstall::load(&ident, fs_root_path)?;
+            let deps = pkg_install.deps()?;
+            let tdeps = pkg_install.tdeps()?;
+            graph.add_package(ident.clone(), &deps);
+            graph.add_transitive_deps(&ident, &tdeps);
+        }
+        Ok(graph)
+    }
+
+    /// Adds a package and its direct dependencies to the graph. Adding a package which is
+    /// already in the graph adds to its dependencies.
+    pub fn add_package(&mut self, ident: PackageIdent, deps: &[PackageIdent]) {
+        let i = self.node(ident);
+        for dep in deps {
+            let d = self.node(dep.clone());
+            self.deps[i].insert(d);
+            self.rdeps[d].insert(i);
+            self.edges[i].insert(d);
+            self.redges[d].insert(i);
+        }
+    }
+
+    /// Records the transitive dependencies of a package, which need not be reachable through
+    /// the direct dependencies already in the graph.
+    pub fn add_transitive_deps(&mut self, ident: &PackageIdent, tdeps: &[PackageIdent]) {
+        let i = self.node(ident.clone());
+        for dep in tdeps {
+            let d = self.node(dep.clone());
+            self.edges[i].insert(d);
+            self.redges[d].insert(i);
+        }
+    }
+
+    pub fn contains(&self, ident: &PackageIdent) -> bool {
+        self.index.contains_key(ident)
+    }
+
+    pub fn idents(&self) -> &[PackageIdent] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the direct dependencies of a package.
+    pub fn deps(&self, ident: &PackageIdent) -> Vec<&PackageIdent> {
+        match self.index.get(ident) {
+            Some(&i) => self.resolve(&self.deps[i]),
+            None => vec![],
+        }
+    }
+
+    /// Returns the packages which depend directly on a package.
+    pub fn rdeps(&self, ident: &PackageIdent) -> Vec<&PackageIdent> {
+        match self.index.get(ident) {
+            Some(&i) => self.resolve(&self.rdeps[i]),
+            None => vec![],
+        }
+    }
+
+    /// Returns every package which a package depends on, directly or not.
+    pub fn tdeps(&self, ident: &PackageIdent) -> Vec<&PackageIdent> {
+        match self.index.get(ident) {
+            Some(&i) => self.reachable(i, &self.edges),
+            None => vec![],
+        }
+    }
+
+    /// Returns every package which depends on a package, directly or not.
+    pub fn trdeps(&self, ident: &PackageIdent) -> Vec<&PackageIdent> {
+        match self.index.get(ident) {
+            Some(&i) => self.reachable(i, &self.redges),
+            None => vec![],
+        }
+    }
+
+    /// Returns every package in the graph, ordered so that each package comes after all of its
+    /// dependencies. Fails with `Error::DependencyCycle` if there's no such order.
+    pub fn toposort(&self) -> Result<Vec<&PackageIdent>> {
+        let mut pending: Vec<usize> = self.edges.iter().map(|e| e.len()).collect();
+        let mut ready: VecDeque<usize> =
+            (0..self.nodes.len()).filter(|&i| pending[i] == 0).collect();
+        let mut sorted = Vec::with_capacity(self.nodes.len());
+        while let Some(i) = ready.pop_front() {
+            sorted.push(&self.nodes[i]);
+            for &j in &self.redges[i] {
+                pending[j] -= 1;
+                if pending[j] == 0 {
+                    ready.push_back(j);
+                }
+            }
+        }
+        if sorted.len() < self.nodes.len() {
+            return Err(Error::DependencyCycle(
+                self.find_cycle().unwrap_or_default(),
+            ));
+        }
+        Ok(sorted)
+    }
+
+    /// Returns a cycle in the graph, if there is one, as a path of packages which starts and
+    /// ends with the same package.
+    pub fn find_cycle(&self) -> Option<Vec<PackageIdent>> {
+        let mut marks = vec![Mark::Unvisited; self.nodes.len()];
+        let mut path = Vec::new();
+        for i in 0..self.nodes.len() {
+            if marks[i] == Mark::Unvisited {
+                if let Some(cycle) = self.visit(i, &mut marks, &mut path) {
+                    return Some(cycle.into_iter().map(|i| self.nodes[i].clone()).collect());
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the disk space taken up by every package in the graph which is installed under
+    /// `fs_root_path`, or `/` if it isn't given, ordered from the most space attributed to a
+    /// package to the least.
+    ///
+    /// A dependency shared by several packages is charged to each of them in equal parts, so
+    /// the attributed sizes of packages which nothing depends on add up to (nearly, as shares are
+    /// rounded down) the space taken up by every installed package.
+    pub fn disk_usage(&self, fs_root_path: Option<&Path>) -> Result<Vec<DiskUsage>> {
+        let mut sizes = vec![None; self.nodes.len()];
+        for (i, ident) in self.nodes.iter().enumerate() {
+            if let Ok(exact) = FullyQualifiedPackageIdent::try_from(ident.clone()) {
+                if fs::pkg_install_path(&exact, fs_root_path).is_dir() {
+                    sizes[i] = Some(PackageInstall::load(ident, fs_root_path)?.size_on_disk()?);
+                }
+            }
+        }
+        let mut usage = Vec::new();
+        for (i, size) in sizes.iter().enumerate() {
+            let own = match *size {
+                Some(own) => own,
+                None => continue,
+            };
+            let mut attributed = own;
+            // Each dependency, and each of its dependents, is counted once however many paths
+            // lead to it
+            let deps: HashSet<&PackageIdent> = self.tdeps(&self.nodes[i]).into_iter().collect();
+            for dep in deps {
+                let dep_size = match sizes[self.index[dep]] {
+                    Some(dep_size) => dep_size,
+                    None => continue,
+                };
+                let dependents = self
+                    .trdeps(dep)
+                    .into_iter()
+                    .filter(|d| sizes[self.index[*d]].is_some())
+                    .collect::<HashSet<_>>()
+                    .len() as u64;
+                attributed += dep_size / dependents;
+            }
+            usage.push(DiskUsage {
+                ident: self.nodes[i].clone(),
+                own: own,
+                attributed: attributed,
+            });
+        }
+        usage.sort_by(|a, b| {
+            b.attributed
+                .cmp(&a.attributed)
+                .then_with(|| a.ident.to_string().cmp(&b.ident.to_string()))
+        });
+        Ok(usage)
+    }
+
+    fn node(&mut self, ident: PackageIdent) -> usize {
+        if let Some(&i) = self.index.get(&ident) {
+            return i;
+        }
+        let i = self.nodes.len();
+        self.index.insert(ident.clone(), i);
+        self.nodes.push(ident);
+        self.deps.push(BTreeSet::new());
+        self.rdeps.push(BTreeSet::new());
+        self.edges.push(BTreeSet::new());
+        self.redges.push(BTreeSet::new());
+        i
+    }
+
+    fn resolve(&self, nodes: &BTreeSet<usize>) -> Vec<&PackageIdent> {
+        nodes.iter().map(|&i| &self.nodes[i]).collect()
+    }
+
+    /// Every node reachable from `start` by following `edges`, in breadth-first order.
+    fn reachable(&self, start: usize, edges: &[BTreeSet<usize>]) -> Vec<&PackageIdent> {
+        let mut seen = vec![false; self.nodes.len()];
+        seen[start] = true;
+        let mut queue: VecDeque<usize> = edges[start].iter().cloned().collect();
+        let mut found = Vec::new();
+        while let Some(i) = queue.pop_front() {
+            if seen[i] {
+                continue;
+            }
+            seen[i] = true;
+            found.push(&self.nodes[i]);
+            queue.extend(edges[i].iter().filter(|&&j| !seen[j]));
+        }
+        found
+    }
+
+    fn visit(&self, i: usize, marks: &mut [Mark], path: &mut Vec<usize>) -> Option<Vec<usize>> {
+        marks[i] = Mark::Visiting;
+        path.push(i);
+        for &j in &self.edges[i] {
+            match marks[j] {
+                Mark::Visiting => {
+                    let start = path.iter().position(|&k| k == j).unwrap();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(j);
+                    return Some(cycle);
+                }
+                Mark::Unvisited => {
+                    if let Some(cycle) = self.visit(j, marks, path) {
+                        return Some(cycle);
+                    }
+                }
+                Mark::Done => {}
+            }
+        }
+        path.pop();
+        marks[i] = Mark::Done;
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::metadata::MetaFile;
+    use package::test_support::testing_package_install;
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    fn to_strings(idents: Vec<&PackageIdent>) -> Vec<String> {
+        idents.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn toposort_orders_deps_first() {
+        let mut graph = PackageGraph::new();
+        graph.add_package(
+            ident("core/redis/4.0.10/20180701000000"),
+            &[
+                ident("core/openssl/1.0.2/20180701000000"),
+                ident("core/glibc/2.27/20180701000000"),
+            ],
+        );
+        graph.add_package(
+            ident("core/openssl/1.0.2/20180701000000"),
+            &[ident("core/glibc/2.27/20180701000000")],
+        );
+
+        assert_eq!(
+            to_strings(graph.toposort().unwrap()),
+            vec![
+                "core/glibc/2.27/20180701000000",
+                "core/openssl/1.0.2/20180701000000",
+                "core/redis/4.0.10/20180701000000",
+            ]
+        );
+        assert_eq!(
+            to_strings(graph.rdeps(&ident("core/glibc/2.27/20180701000000"))),
+            vec![
+                "core/redis/4.0.10/20180701000000",
+                "core/openssl/1.0.2/20180701000000",
+            ]
+        );
+        assert_eq!(
+            to_strings(graph.trdeps(&ident("core/openssl/1.0.2/20180701000000"))),
+            vec!["core/redis/4.0.10/20180701000000"]
+        );
+    }
+
+    #[test]
+    fn tdeps_include_recorded_transitive_deps() {
+        let mut graph = PackageGraph::new();
+        let redis = ident("core/redis/4.0.10/20180701000000");
+        graph.add_package(redis.clone(), &[ident("core/openssl/1.0.2/20180701000000")]);
+        // openssl isn't installed, so its own deps are only known from redis' TDEPS
+        graph.add_transitive_deps(
+            &redis,
+            &[
+                ident("core/openssl/1.0.2/20180701000000"),
+                ident("core/glibc/2.27/20180701000000"),
+            ],
+        );
+
+        assert_eq!(
+            to_strings(graph.deps(&redis)),
+            vec!["core/openssl/1.0.2/20180701000000"]
+        );
+        assert_eq!(
+            to_strings(graph.tdeps(&redis)),
+            vec![
+                "core/openssl/1.0.2/20180701000000",
+                "core/glibc/2.27/20180701000000",
+            ]
+        );
+        assert_eq!(
+            to_strings(graph.trdeps(&ident("core/glibc/2.27/20180701000000"))),
+            vec!["core/redis/4.0.10/20180701000000"]
+        );
+    }
+
+    #[test]
+    fn cycles_are_detected() {
+        let mut graph = PackageGraph::new();
+        graph.add_package(
+            ident("core/a/1.0/20180701000000"),
+            &[ident("core/b/1.0/20180701000000")],
+        );
+        graph.add_package(
+            ident("core/b/1.0/20180701000000"),
+            &[ident("core/c/1.0/20180701000000")],
+        );
+        assert!(graph.find_cycle().is_none());
+
+        graph.add_package(
+            ident("core/c/1.0/20180701000000"),
+            &[ident("core/a/1.0/20180701000000")],
+        );
+        match graph.toposort() {
+            Err(Error::DependencyCycle(cycle)) => assert_eq!(
+                cycle.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+                vec![
+                    "core/a/1.0/20180701000000",
+                    "core/b/1.0/20180701000000",
+                    "core/c/1.0/20180701000000",
+                    "core/a/1.0/20180701000000",
+                ]
+            ),
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(_) => panic!("Sorted a graph with a cycle"),
+        }
+    }
+
+    #[test]
+    fn graph_of_installed_packages() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let glibc = testing_package_install("core/glibc/2.27/20180701000000", fs_root.path());
+        let redis = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        for metafile in &[MetaFile::Deps, MetaFile::TDeps] {
+            let mut f = File::create(redis.installed_path().join(metafile.to_string())).unwrap();
+            f.write_all(b"core/glibc/2.27/20180701000000\n").unwrap();
+        }
+
+        let graph = PackageGraph::from_installed(Some(fs_root.path())).unwrap();
+        assert_eq!(graph.len(), 2);
+        assert_eq!(graph.rdeps(glibc.ident()), vec![redis.ident()]);
+        assert_eq!(
+            graph.toposort().unwrap(),
+            vec![glibc.ident(), redis.ident()]
+        );
+    }
+
+    #[test]
+    fn disk_usage_splits_shared_deps() {
+        fn write_file(install: &PackageInstall, name: &str, size: usize) {
+            let mut f = File::create(install.installed_path().join(name)).unwrap();
+            f.write_all(&vec![0u8; size]).unwrap();
+        }
+
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let glibc = testing_package_install("core/glibc/2.27/20180701000000", fs_root.path());
+        let redis = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        let nginx = testing_package_install("core/nginx/1.15.0/20180701000000", fs_root.path());
+        write_file(&glibc, "libc.so", 10000);
+        write_file(&redis, "redis-server", 3000);
+        write_file(&nginx, "nginx", 1000);
+        for install in &[&redis, &nginx] {
+            write_file(install, "DEPS", 0);
+            let mut f = File::create(install.installed_path().join("TDEPS")).unwrap();
+            f.write_all(b"core/glibc/2.27/20180701000000\n").unwrap();
+        }
+
+        let graph = PackageGraph::from_installed(Some(fs_root.path())).unwrap();
+        let usage = graph.disk_usage(Some(fs_root.path())).unwrap();
+
+        assert_eq!(
+            usage
+                .iter()
+                .map(|u| u.ident.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["glibc", "redis", "nginx"]
+        );
+        let glibc_size = glibc.size_on_disk().unwrap();
+        assert_eq!(usage[0].own, glibc_size);
+        assert_eq!(usage[0].attributed, glibc_size);
+        assert_eq!(usage[1].own, redis.size_on_disk().unwrap());
+        assert_eq!(usage[1].attributed, usage[1].own + glibc_size / 2);
+        assert_eq!(usage[2].attributed, usage[2].own + glibc_size / 2);
+    }
+
+    #[test]
+    fn disk_usage_counts_deps_reached_twice_once() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let glibc = testing_package_install("core/glibc/2.27/20180701000000", fs_root.path());
+        let openssl = testing_package_install("core/openssl/1.0.2/20180701000000", fs_root.path());
+        let redis = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        for &(install, deps) in &[
+            (&openssl, "core/glibc/2.27/20180701000000\n"),
+            (
+                &redis,
+                "core/glibc/2.27/20180701000000\ncore/openssl/1.0.2/20180701000000\n",
+            ),
+        ] {
+            for metafile in &["DEPS", "TDEPS"] {
+                let mut f = File::create(install.installed_path().join(metafile)).unwrap();
+                f.write_all(deps.as_bytes()).unwrap();
+            }
+        }
+
+        let graph = PackageGraph::from_installed(Some(fs_root.path())).unwrap();
+        let usage = graph.disk_usage(Some(fs_root.path())).unwrap();
+        let redis_usage = usage.iter().find(|u| u.ident == *redis.ident()).unwrap();
+        let glibc_size = glibc.size_on_disk().unwrap();
+        let openssl_size = openssl.size_on_disk().unwrap();
+        assert_eq!(
+            redis_usage.attributed,
+            redis_usage.own + glibc_size / 2 + openssl_size
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Holds, which freeze a package at a known-good release.
+//!
+//! A hold names one installed release of a package, and there is at most one hold for each
+//! origin and name. While a package is held, loading it by an ident which the held release
+//! satisfies resolves to the held release rather than the latest one installed, and garbage
+//! collection keeps the held release and its dependencies. Holds are kept in a file under the
+//! Habitat root, one fully-qualified ident per line.
+
+use std::fs::{create_dir_all, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{Identifiable, PackageIdent, PackageInstall};
+use error::Result;
+use fs;
+
+/// Returns every held release, ordered by ident.
+pub fn holds(fs_root_path: Option<&Path>) -> Result<Vec<PackageIdent>> {
+    let mut body = String::new();
+    match File::open(fs::holds_path(fs_root_path)) {
+        Ok(mut file) => file.read_to_string(&mut body)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut holds = body
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(PackageIdent::from_str)
+        .collect::<Result<Vec<PackageIdent>>>()?;
+    holds.sort();
+    Ok(holds)
+}
+
+/// Returns the held release of the package `ident` names, if its origin and name are held.
+pub fn held(ident: &PackageIdent, fs_root_path: Option<&Path>) -> Result<Option<PackageIdent>> {
+    Ok(holds(fs_root_path)?
+        .into_iter()
+        .find(|held| held.origin == ident.origin && held.name == ident.name))
+}
+
+/// Holds the installed release which `ident` resolves to, such as the latest installed release
+/// when only an origin and name are given, and returns it. The hold replaces any other hold on
+/// the same origin and name.
+///
+/// # Failures
+///
+/// * No installed release satisfies `ident`
+pub fn hold(ident: &PackageIdent, fs_root_path: Option<&Path>) -> Result<PackageIdent> {
+    let release = PackageInstall::load(ident, fs_root_path)?.ident().clone();
+    let _holds_lock = fs::lock_holds(fs_root_path)?;
+    let mut holds: Vec<PackageIdent> = holds(fs_root_path)?
+        .into_iter()
+        .filter(|held| held.origin != release.origin || held.name != release.name)
+        .collect();
+    holds.push(release.clone());
+    write_holds(&mut holds, fs_root_path)?;
+    Ok(release)
+}
+
+/// Removes the holds on releases which satisfy `ident`, returning the releases which were held.
+pub fn unhold(ident: &PackageIdent, fs_root_path: Option<&Path>) -> Result<Vec<PackageIdent>> {
+    let _holds_lock = fs::lock_holds(fs_root_path)?;
+    let (released, mut kept): (Vec<PackageIdent>, Vec<PackageIdent>) = holds(fs_root_path)?
+        .into_iter()
+        .partition(|held| held.satisfies(ident));
+    if !released.is_empty() {
+        write_holds(&mut kept, fs_root_path)?;
+    }
+    Ok(released)
+}
+
+fn write_holds(holds: &mut Vec<PackageIdent>, fs_root_path: Option<&Path>) -> Result<()> {
+    holds.sort();
+    let path = fs::holds_path(fs_root_path);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut body = String::new();
+    for held in holds.iter() {
+        body.push_str(&format!("{}\n", held));
+    }
+    fs::atomic_write(path, body.as_bytes(), 0o644)
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::test_support::testing_package_install;
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn held_release_is_resolved_until_unheld() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let root = Some(fs_root.path());
+        testing_package_install("core/redis/3.2.4/20170514150022", fs_root.path());
+        testing_package_install("core/nginx/1.15.0/20180608040224", fs_root.path());
+
+        assert!(holds(root).unwrap().is_empty());
+        assert_eq!(
+            hold(&ident("core/redis"), root).unwrap(),
+            ident("core/redis/3.2.4/20170514150022")
+        );
+        testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+
+        assert_eq!(
+            PackageInstall::load(&ident("core/redis"), root)
+                .unwrap()
+                .ident(),
+            &ident("core/redis/3.2.4/20170514150022")
+        );
+        assert_eq!(
+            PackageInstall::load_at_least(&ident("core/redis/3.0.0"), root)
+                .unwrap()
+                .ident(),
+            &ident("core/redis/3.2.4/20170514150022")
+        );
+        // Asking for a release the hold doesn't satisfy still finds it
+        assert_eq!(
+            PackageInstall::load(&ident("core/redis/4.0.10"), root)
+                .unwrap()
+                .ident(),
+            &ident("core/redis/4.0.10/20180608202239")
+        );
+
+        // Holding another release of the same package replaces the hold
+        hold(&ident("core/redis/4.0.10"), root).unwrap();
+        hold(&ident("core/nginx"), root).unwrap();
+        assert_eq!(
+            holds(root).unwrap(),
+            vec![
+                ident("core/nginx/1.15.0/20180608040224"),
+                ident("core/redis/4.0.10/20180608202239"),
+            ]
+        );
+
+        assert_eq!(
+            unhold(&ident("core/nginx"), root).unwrap(),
+            vec![ident("core/nginx/1.15.0/20180608040224")]
+        );
+        assert_eq!(held(&ident("core/nginx"), root).unwrap(), None);
+        assert!(unhold(&ident("core/zlib"), root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn holds_made_at_once_are_all_kept() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let names: Vec<String> = (0..8).map(|i| format!("pkg{}", i)).collect();
+        for name in &names {
+            testing_package_install(
+                &format!("core/{}/1.0.0/20180101000000", name),
+                fs_root.path(),
+            );
+        }
+
+        let handles: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let fs_root = fs_root.path().to_path_buf();
+                let name = name.clone();
+                thread::spawn(move || {
+                    hold(&ident(&format!("core/{}", name)), Some(fs_root.as_path())).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(holds(Some(fs_root.path())).unwrap().len(), names.len());
+        assert_eq!(
+            unhold(&ident("core/pkg0"), Some(fs_root.path())).unwrap(),
+            vec![ident("core/pkg0/1.0.0/20180101000000")]
+        );
+        assert_eq!(holds(Some(fs_root.path())).unwrap().len(), names.len() - 1);
+    }
+
+    #[test]
+    fn hold_requires_installed_release() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        testing_package_install("core/redis/3.2.4/20170514150022", fs_root.path());
+
+        assert!(hold(&ident("core/redis/4.0.10"), Some(fs_root.path())).is_err());
+        assert!(holds(Some(fs_root.path())).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hooks which a package runs once, when it is installed.
+//!
+//! The `install` hook runs first, then the `post-install` hook. Each hook's exit status is kept
+//! in a metafile next to the package's other metafiles, so a package whose hook failed is known
+//! to be unusable until its hooks are run again, and a hook which succeeded isn't run twice.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use super::metadata::{read_metafile, MetaFile};
+use super::PackageInstall;
+use error::{Error, Result};
+use fs;
+#[cfg(target_os = "linux")]
+use os::capabilities::{self, Capability};
+#[cfg(target_os = "linux")]
+use os::cgroup::Cgroup;
+#[cfg(target_os = "linux")]
+use os::process::{self, ProcessGroup};
+#[cfg(target_os = "linux")]
+use os::users::Credentials;
+
+/// The hooks run when a package is installed, in the order they run.
+pub const INSTALL_HOOKS: [InstallHook; 2] = [InstallHook::Install, InstallHook::PostInstall];
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum InstallHook {
+    Install,
+    PostInstall,
+}
+
+impl InstallHook {
+    /// The name of the hook, and of its file in the package's `hooks` directory
+    pub fn name(&self) -> &'static str {
+        match *self {
+            InstallHook::Install => "install",
+            InstallHook::PostInstall => "post-install",
+        }
+    }
+
+    /// Returns the path of the hook in an installed package, whether the package has the hook or
+    /// not.
+    pub fn path(&self, install: &PackageInstall) -> PathBuf {
+        install.installed_path().join("hooks").join(self.name())
+    }
+
+    /// Returns the exit status the hook last ran with, or None if it hasn't run.
+    pub fn status(&self, install: &PackageInstall) -> Result<Option<i32>> {
+        match read_metafile(install.installed_path(), &self.status_metafile()) {
+            Ok(body) => i32::from_str(&body)
+                .map(Some)
+                .map_err(|_| Error::MetaFileMalformed(self.status_metafile())),
+            Err(Error::MetaFileNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs the hook from the package's installed path, in the package's runtime environment,
+    /// and records its exit status. A package without the hook succeeds without running
+    /// anything.
+    ///
+    /// # Failures
+    ///
+    /// * The hook can't be started, or exits with a non-zero status
+    pub fn run(&self, install: &PackageInstall) -> Result<()> {
+        self.run_with(install, |_| Ok(()))
+    }
+
+    /// Runs the hook as `run` does, confined as `options` ask.
+    ///
+    /// # Failures
+    ///
+    /// * The hook can't be put in its cgroup, or its capabilities can't be dropped
+    /// * The hook can't be started, such as when it can't switch to its `credentials`, or it
+    ///   exits with a non-zero status
+    #[cfg(target_os = "linux")]
+    pub fn run_with_options(&self, install: &PackageInstall, options: &RunOptions) -> Result<()> {
+        self.run_with(install, |command| {
+            if let Some(cgroup) = options.cgroup {
+                cgroup.attach_command(command)?;
+            }
+            match (options.keep_capabilities, options.credentials) {
+                (Some(keep), credentials) => capabilities::keep_only(command, keep, credentials)?,
+                (None, Some(credentials)) => credentials.apply(command),
+                (None, None) => (),
+            }
+            if let Some(group) = options.process_group {
+                process::set_process_group(command, group);
+            }
+            Ok(())
+        })
+    }
+
+    fn run_with<F>(&self, install: &PackageInstall, prepare: F) -> Result<()>
+    where
+        F: FnOnce(&mut Command) -> Result<()>,
+    {
+        let path = fs::long_path(self.path(install));
+        if !path.is_file() {
+            return self.record(install, 0);
+        }
+        let mut command = hook_command(&path);
+        command
+            .current_dir(install.installed_path())
+            .envs(install.environment_for_command()?);
+        prepare(&mut command)?;
+        let output = command.output().map_err(|e| {
+            Error::InstallHookFailed(format!(
+                "{} {} hook could not be started: {}",
+                install.ident(),
+                self.name(),
+                e
+            ))
+        })?;
+        // A hook killed by a signal has no exit code
+        let code = output.status.code().unwrap_or(-1);
+        self.record(install, code)?;
+        if code == 0 {
+            Ok(())
+        } else {
+            Err(Error::InstallHookFailed(format!(
+                "{} {} hook exited with status {}: {}",
+                install.ident(),
+                self.name(),
+                code,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+
+    fn status_metafile(&self) -> MetaFile {
+        match *self {
+            InstallHook::Install => MetaFile::InstallHookStatus,
+            InstallHook::PostInstall => MetaFile::PostInstallHookStatus,
+        }
+    }
+
+    fn record(&self, install: &PackageInstall, code: i32) -> Result<()> {
+        let path = install
+            .installed_path()
+            .join(self.status_metafile().to_string());
+        fs::atomic_write(path, format!("{}\n", code).as_bytes(), 0o644)
+    }
+}
+
+/// How a hook is confined when it's run with `InstallHook::run_with_options`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct RunOptions<'a> {
+    /// The cgroup the hook, and whatever it starts, is held to the limits of
+    pub cgroup: Option<&'a Cgroup>,
+    /// The only capabilities the hook keeps, with `no_new_privs` set so it can't gain others.
+    /// They're kept when the hook switches to its `credentials`, so a hook which runs as a user
+    /// other than root has them too.
+    pub keep_capabilities: Option<&'a [Capability]>,
+    /// The user, group and supplementary groups the hook runs as, rather than those of the
+    /// process which runs it
+    pub credentials: Option<&'a Credentials>,
+    /// The process group the hook is started in, rather than that of the process which runs it
+    pub process_group: Option<ProcessGroup>,
+}
+
+/// Runs each of the package's install hooks which hasn't already succeeded, stopping at the
+/// first which fails. Running the hooks again retries the one which failed.
+pub fn run_install_hooks(install: &PackageInstall) -> Result<()> {
+    for hook in INSTALL_HOOKS.iter() {
+        if hook.status(install)? != Some(0) {
+            hook.run(install)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the first of the package's install hooks which failed the last time it ran, if any.
+/// A package with a failed hook shouldn't be used until its hooks have been run again.
+pub fn failed_install_hook(install: &PackageInstall) -> Result<Option<InstallHook>> {
+    for hook in INSTALL_HOOKS.iter() {
+        match hook.status(install)? {
+            Some(0) | None => continue,
+            Some(_) => return Ok(Some(*hook)),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(windows)]
+fn hook_command(path: &Path) -> Command {
+    let mut command = Command::new("powershell.exe");
+    command
+        .arg("-NonInteractive")
+        .arg("-ExecutionPolicy")
+        .arg("Bypass")
+        .arg("-File")
+        .arg(path);
+    command
+}
+
+#[cfg(not(windows))]
+fn hook_command(path: &Path) -> Command {
+    Command::new(path)
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::test_support::testing_package_install;
+
+    fn write_hook(install: &PackageInstall, hook: InstallHook, script: &str) {
+        let path = hook.path(install);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn failed_hook_is_retried() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        write_hook(
+            &install,
+            InstallHook::Install,
+            "#!/bin/sh\necho nope >&2\nexit 3\n",
+        );
+        write_hook(
+            &install,
+            InstallHook::PostInstall,
+            "#!/bin/sh\necho ran >> post-install.log\n",
+        );
+
+        match run_install_hooks(&install) {
+            Err(Error::InstallHookFailed(msg)) => assert!(msg.ends_with("status 3: nope")),
+            other => panic!("expected InstallHookFailed, got {:?}", other),
+        }
+        assert_eq!(InstallHook::Install.status(&install).unwrap(), Some(3));
+        assert_eq!(InstallHook::PostInstall.status(&install).unwrap(), None);
+        assert_eq!(
+            failed_install_hook(&install).unwrap(),
+            Some(InstallHook::Install)
+        );
+
+        write_hook(&install, InstallHook::Install, "#!/bin/sh\nexit 0\n");
+        run_install_hooks(&install).unwrap();
+        assert_eq!(failed_install_hook(&install).unwrap(), None);
+        assert_eq!(InstallHook::PostInstall.status(&install).unwrap(), Some(0));
+
+        // Hooks which succeeded don't run again
+        run_install_hooks(&install).unwrap();
+        assert_eq!(
+            fs::read_to_string(install.installed_path().join("post-install.log")).unwrap(),
+            "ran\n"
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn hook_run_as_another_user_keeps_its_capabilities() {
+        // Only root can switch users, and hand capabilities over as it does
+        if !::fs::am_i_root() {
+            return;
+        }
+        let credentials = match Credentials::for_user("nobody", None, &[]) {
+            Ok(credentials) => credentials,
+            Err(_) => return,
+        };
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        fs::set_permissions(fs_root.path(), Permissions::from_mode(0o755)).unwrap();
+        let install = testing_package_install("core/nginx/1.15.2/20180801000000", fs_root.path());
+        // CAP_NET_BIND_SERVICE, and nothing else, is effective for the hook as `nobody`
+        write_hook(
+            &install,
+            InstallHook::Install,
+            "#!/bin/sh\n[ \"$(id -u)\" != 0 ] || exit 1\n\
+             grep -q '^CapEff:[[:space:]]*0000000000000400$' /proc/self/status || exit 2\n",
+        );
+        let options = RunOptions {
+            keep_capabilities: Some(&[Capability::NetBindService][..]),
+            credentials: Some(&credentials),
+            ..Default::default()
+        };
+
+        InstallHook::Install
+            .run_with_options(&install, &options)
+            .unwrap();
+        assert_eq!(InstallHook::Install.status(&install).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn package_without_hooks_succeeds() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/glibc/2.27/20180608041157", fs_root.path());
+
+        run_install_hooks(&install).unwrap();
+        assert_eq!(InstallHook::Install.status(&install).unwrap(), Some(0));
+        assert_eq!(failed_install_hook(&install).unwrap(), None);
+    }
+}
@@ -19,6 +19,7 @@ use std::result;
 use std::str::FromStr;
 
 use regex::Regex;
+use serde;
 
 use error::{Error, Result};
 use package::PackageTarget;
@@ -140,7 +141,9 @@ impl PackageIdent {
     /// Compare two `PackageIdent`s component by component:
     /// i.e. start with origin, then name, then version, then
     /// release. The first component to be not equal, then return
-    /// the greater/lesser
+    /// the greater/lesser. Versions which `version_sort` can't sort come before those it can,
+    /// ordered by their text, so that any two fully qualified identifiers have an order, and
+    /// only equal ones compare as equal.
     ///
     /// TODO: This should probably be the natural implementation of `Ord::cmp`.
     /// To be investigated why we have a different implementation
@@ -152,14 +155,20 @@ impl PackageIdent {
         if self.name != other.name {
             return self.name.cmp(&other.name);
         }
-        match version_sort(
-            self.version.as_ref().unwrap(),
-            other.version.as_ref().unwrap(),
-        ) {
-            Ok(Ordering::Equal) => self.release.cmp(&other.release),
-            Ok(ordering) => ordering,
-            Err(_) => Ordering::Less,
-        }
+        let version = self.version.as_ref().unwrap().as_str();
+        let other_version = other.version.as_ref().unwrap().as_str();
+        let sortable = |v: &str| version_sort(v, v).is_ok();
+        let ordering = match (sortable(version), sortable(other_version)) {
+            (true, true) => {
+                version_sort(version, other_version).expect("Both versions can be sorted")
+            }
+            (false, false) => version.cmp(other_version),
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+        };
+        ordering
+            .then_with(|| self.release.cmp(&other.release))
+            .then_with(|| version.cmp(other_version))
     }
 
     fn archive_name_impl(&self, ref target: &PackageTarget) -> Result<String> {
@@ -347,6 +356,131 @@ impl<'a> From<&'a PackageIdent> for Cow<'a, PackageIdent> {
     }
 }
 
+/// A package identifier which is known to have both a version and a release, and so names
+/// exactly one release of a package.
+///
+/// APIs which only make sense for an exact release, such as finding a package's installed path,
+/// its hooks, or verifying it, can take this type rather than checking a `PackageIdent` at run
+/// time.
+///
+/// # Examples
+///
+/// ```
+/// use std::str::FromStr;
+/// use habitat_core::package::{FullyQualifiedPackageIdent, PackageIdent};
+///
+/// let ident = PackageIdent::from_str("core/redis/4.0.10/20180701125610").unwrap();
+/// let fully_qualified = FullyQualifiedPackageIdent::try_from(ident).unwrap();
+/// assert_eq!("4.0.10", fully_qualified.version());
+///
+/// let fuzzy = PackageIdent::from_str("core/redis").unwrap();
+/// assert!(FullyQualifiedPackageIdent::try_from(fuzzy).is_err());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FullyQualifiedPackageIdent(PackageIdent);
+
+impl FullyQualifiedPackageIdent {
+    /// Converts a package identifier which has a version and a release. This has the signature of
+    /// `std::convert::TryFrom::try_from`, which isn't stable on the Rust release this crate
+    /// supports, so it can become an implementation of that trait once it is.
+    ///
+    /// # Failures
+    ///
+    /// * The identifier is missing its version or release
+    pub fn try_from(ident: PackageIdent) -> Result<Self> {
+        if ident.fully_qualified() {
+            Ok(FullyQualifiedPackageIdent(ident))
+        } else {
+            Err(Error::FullyQualifiedPackageIdentRequired(ident.to_string()))
+        }
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.0.origin
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn version(&self) -> &str {
+        self.0
+            .version
+            .as_ref()
+            .expect("Fully qualified identifiers have a version")
+    }
+
+    pub fn release(&self) -> &str {
+        self.0
+            .release
+            .as_ref()
+            .expect("Fully qualified identifiers have a release")
+    }
+
+    pub fn as_ident(&self) -> &PackageIdent {
+        &self.0
+    }
+}
+
+impl fmt::Display for FullyQualifiedPackageIdent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for FullyQualifiedPackageIdent {
+    type Err = Error;
+
+    fn from_str(value: &str) -> result::Result<Self, Self::Err> {
+        FullyQualifiedPackageIdent::try_from(PackageIdent::from_str(value)?)
+    }
+}
+
+impl AsRef<PackageIdent> for FullyQualifiedPackageIdent {
+    fn as_ref(&self) -> &PackageIdent {
+        &self.0
+    }
+}
+
+impl From<FullyQualifiedPackageIdent> for PackageIdent {
+    fn from(ident: FullyQualifiedPackageIdent) -> PackageIdent {
+        ident.0
+    }
+}
+
+impl PartialOrd for FullyQualifiedPackageIdent {
+    fn partial_cmp(&self, other: &FullyQualifiedPackageIdent) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Unlike `PackageIdent`'s, this is a total order, by origin, name, version, and release, as
+/// described for `PackageIdent::by_parts_cmp`.
+impl Ord for FullyQualifiedPackageIdent {
+    fn cmp(&self, other: &FullyQualifiedPackageIdent) -> Ordering {
+        self.0.by_parts_cmp(&other.0)
+    }
+}
+
+impl serde::Serialize for FullyQualifiedPackageIdent {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&self.0, serializer)
+    }
+}
+
+impl<'d> serde::Deserialize<'d> for FullyQualifiedPackageIdent {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'d>,
+    {
+        let ident: PackageIdent = serde::Deserialize::deserialize(deserializer)?;
+        FullyQualifiedPackageIdent::try_from(ident).map_err(serde::de::Error::custom)
+    }
+}
+
 /// An iterator over the [`&str`] slices of a [`PackageIdent`].
 ///
 /// This `struct` is created by the [`iter`] method on [`PackageIdent`], see its documentation for
@@ -777,6 +911,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fully_qualified_ident() {
+        let ident = PackageIdent::from_str("core/redis/4.0.10/20180701125610").unwrap();
+        let exact = FullyQualifiedPackageIdent::try_from(ident.clone()).unwrap();
+
+        assert_eq!("core", exact.origin());
+        assert_eq!("redis", exact.name());
+        assert_eq!("4.0.10", exact.version());
+        assert_eq!("20180701125610", exact.release());
+        assert_eq!("core/redis/4.0.10/20180701125610", exact.to_string());
+        assert_eq!(ident, PackageIdent::from(exact));
+    }
+
+    #[test]
+    fn fully_qualified_ident_requires_version_and_release() {
+        for fuzzy in &["core/redis", "core/redis/4.0.10"] {
+            match FullyQualifiedPackageIdent::from_str(fuzzy) {
+                Err(Error::FullyQualifiedPackageIdentRequired(ref s)) => assert_eq!(s, fuzzy),
+                other => panic!("Should not have parsed {}, returned={:?}", fuzzy, other),
+            }
+        }
+    }
+
+    #[test]
+    fn fully_qualified_idents_are_totally_ordered() {
+        let mut idents: Vec<FullyQualifiedPackageIdent> = [
+            "core/redis/4.0.10/20180701125610",
+            "acme/redis/4.0.10/20180701125610",
+            "core/redis/master/20180701125610",
+            "core/redis/4.0.9/20180701125610",
+            "core/redis/develop/20180701125610",
+            "core/redis/4.0.10/20180601125610",
+            "core/redis/4.0.10.0/20180601125610",
+            "core/apache/1.0.0/20180701125610",
+        ]
+        .iter()
+        .map(|s| FullyQualifiedPackageIdent::from_str(s).unwrap())
+        .collect();
+        idents.sort();
+
+        assert_eq!(
+            idents.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            vec![
+                "acme/redis/4.0.10/20180701125610",
+                "core/apache/1.0.0/20180701125610",
+                "core/redis/develop/20180701125610",
+                "core/redis/master/20180701125610",
+                "core/redis/4.0.9/20180701125610",
+                "core/redis/4.0.10/20180601125610",
+                "core/redis/4.0.10.0/20180601125610",
+                "core/redis/4.0.10/20180701125610",
+            ]
+        );
+        for a in &idents {
+            for b in &idents {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{} and {}", a, b);
+                assert_eq!(a.partial_cmp(b), Some(a.cmp(b)));
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b);
+            }
+        }
+    }
+
     #[test]
     fn archive_name_with_target() {
         let ident = PackageIdent::from_str("tom-petty/the_last__dj/1.0.0/20180701125610").unwrap();
@@ -788,6 +984,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn archive_name_with_aarch64_target() {
+        let ident = PackageIdent::from_str("core/redis/4.0.10/20180701125610").unwrap();
+        let target = PackageTarget::from_str("aarch64-linux").unwrap();
+
+        assert_eq!(
+            String::from("core-redis-4.0.10-20180701125610-aarch64-linux.hart"),
+            ident.archive_name_with_target(&target).unwrap()
+        );
+    }
+
     #[test]
     fn archive_name_with_target_with_fuzzy_ident() {
         let ident = PackageIdent::from_str("acme/not-enough").unwrap();
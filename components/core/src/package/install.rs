@@ -16,17 +16,27 @@ use std::cmp::{Ordering, PartialOrd};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self as stdfs, File};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::vec;
 
+use regex::Regex;
 use toml;
 use toml::Value;
 
-use super::list::package_list_for_ident;
-use super::metadata::{parse_key_value, read_metafile, Bind, BindMapping, MetaFile, PackageType};
-use super::{Identifiable, PackageIdent};
+use super::constraint::VersionConstraint;
+use super::hold;
+use super::list::{glob_regex, list_installed, package_list_for_ident, PackageFilter};
+use super::manifest::{self, FileManifest, InstallVerification, ManifestEntry};
+use super::metadata::{
+    parse_key_value, parse_licenses, read_metafile, Bind, BindMapping, EnvVar, Export, MetaFile,
+    PackageLicenses, PackageType, RuntimeEnv, ENV_PATH_SEPARATOR,
+};
+use super::{FullyQualifiedPackageIdent, Identifiable, PackageIdent};
 use error::{Error, Result};
 use fs;
 
@@ -37,6 +47,15 @@ use std;
 
 pub const DEFAULT_CFG_FILE: &'static str = "default.toml";
 const PATH_KEY: &'static str = "PATH";
+/// The most metafiles remembered in `READ_METAFILES` at once.
+const MAX_READ_METAFILES: usize = 1024;
+
+lazy_static! {
+    /// Metafiles read by any `PackageInstall` in this process, by path. As each installed path
+    /// belongs to one fully qualified package under one filesystem root, loading the same
+    /// package again finds the metafiles read through earlier loads here.
+    static ref READ_METAFILES: Mutex<ReadMetaFiles> = Mutex::new(ReadMetaFiles::default());
+}
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct PackageInstall {
@@ -44,6 +63,8 @@ pub struct PackageInstall {
     fs_root_path: PathBuf,
     package_root_path: PathBuf,
     pub installed_path: PathBuf,
+    #[serde(skip)]
+    metafiles: MetaFileCache,
 }
 
 // The docs recommend implementing `From` instead, but that feels a
@@ -82,6 +103,90 @@ impl PackageInstall {
         Ok(package_install)
     }
 
+    /// Verifies an installation of a package whose version satisfies a constraint, such as
+    /// `>=1.2, <2.0`, and returns the latest such release.
+    ///
+    /// The version of the given ident, if any, must satisfy the constraint as well, and its
+    /// release is matched exactly.
+    ///
+    /// An optional `fs_root` path may be provided to search for a package that is mounted on a
+    /// filesystem not currently rooted at `/`.
+    pub fn load_matching(
+        ident: &PackageIdent,
+        constraint: &VersionConstraint,
+        fs_root_path: Option<&Path>,
+    ) -> Result<PackageInstall> {
+        let fs_root_path = fs_root_path.map_or(PathBuf::from("/"), |p| p.into());
+        let package_root_path = fs::pkg_root_path(Some(&fs_root_path));
+        if !package_root_path.exists() {
+            return Err(Error::PackageNotFound(ident.clone()));
+        }
+
+        let pl = package_list_for_ident(&package_root_path, ident)?;
+        let held = hold::held(ident, Some(fs_root_path.as_path()))?;
+        let latest = held_or_latest(
+            pl.iter().filter(|&p| {
+                p.satisfies(ident) && p.version.as_ref().map_or(false, |v| constraint.matches(v))
+            }),
+            held.as_ref(),
+        );
+        match latest {
+            Some(id) => Ok(PackageInstall {
+                installed_path: fs::pkg_install_path(
+                    &FullyQualifiedPackageIdent::try_from(id.clone())?,
+                    Some(&fs_root_path),
+                )
+                .into(),
+                fs_root_path: fs_root_path,
+                package_root_path: package_root_path,
+                ident: id,
+                metafiles: MetaFileCache::default(),
+            }),
+            None => Err(Error::PackageNotFound(ident.clone())),
+        }
+    }
+
+    /// Returns the installed packages which match a query, ordered by origin and name and then
+    /// from the oldest release to the latest.
+    ///
+    /// A query is a partial package identifier whose parts may contain the wildcards `*`, which
+    /// matches any run of characters, and `?`, which matches any one character, such as
+    /// `core/postg*` or `*/nginx/1.15.*`. Parts which are left off match anything, and a query
+    /// without an origin, such as `nginx`, matches packages of that name in every origin.
+    ///
+    /// An optional `fs_root` path may be provided to search for packages which are mounted on a
+    /// filesystem not currently rooted at `/`.
+    pub fn search(query: &str, fs_root_path: Option<&Path>) -> Result<Vec<PackageIdent>> {
+        let mut parts: Vec<&str> = query.trim().split('/').collect();
+        if parts.len() == 1 {
+            parts.insert(0, "*");
+        }
+        if parts.len() > 4 || parts.iter().any(|p| p.is_empty()) {
+            return Err(Error::InvalidPackageIdent(query.to_string()));
+        }
+        let patterns = parts
+            .iter()
+            .map(|p| glob_regex(p))
+            .collect::<Result<Vec<Regex>>>()?;
+
+        let matches: Vec<PackageIdent> = list_installed(&PackageFilter::new(), fs_root_path)?
+            .into_iter()
+            .filter(|ident| {
+                let values = [
+                    Some(ident.origin.as_str()),
+                    Some(ident.name.as_str()),
+                    ident.version.as_ref().map(|v| v.as_str()),
+                    ident.release.as_ref().map(|r| r.as_str()),
+                ];
+                patterns
+                    .iter()
+                    .zip(values.iter())
+                    .all(|(pattern, value)| value.map_or(false, |v| pattern.is_match(v)))
+            })
+            .collect();
+        Ok(matches)
+    }
+
     fn resolve_package_install<T>(
         ident: &PackageIdent,
         fs_root_path: Option<T>,
@@ -99,33 +204,33 @@ impl PackageInstall {
         if ident.fully_qualified() {
             if pl.iter().any(|ref p| p.satisfies(ident)) {
                 Ok(PackageInstall {
-                    installed_path: fs::pkg_install_path(&ident, Some(&fs_root_path)),
+                    installed_path: fs::pkg_install_path(
+                        &FullyQualifiedPackageIdent::try_from(ident.clone())?,
+                        Some(&fs_root_path),
+                    )
+                    .into(),
                     fs_root_path: fs_root_path,
                     package_root_path: package_root_path,
                     ident: ident.clone(),
+                    metafiles: MetaFileCache::default(),
                 })
             } else {
                 Err(Error::PackageNotFound(ident.clone()))
             }
         } else {
-            let latest: Option<PackageIdent> =
-                pl.iter()
-                    .filter(|&p| p.satisfies(ident))
-                    .fold(None, |winner, b| match winner {
-                        Some(a) => match a.partial_cmp(&b) {
-                            Some(Ordering::Greater) => Some(a),
-                            Some(Ordering::Equal) => Some(a),
-                            Some(Ordering::Less) => Some(b.clone()),
-                            None => Some(a),
-                        },
-                        None => Some(b.clone()),
-                    });
+            let held = hold::held(ident, Some(fs_root_path.as_path()))?;
+            let latest = held_or_latest(pl.iter().filter(|&p| p.satisfies(ident)), held.as_ref());
             if let Some(id) = latest {
                 Ok(PackageInstall {
-                    installed_path: fs::pkg_install_path(&id, Some(&fs_root_path)),
+                    installed_path: fs::pkg_install_path(
+                        &FullyQualifiedPackageIdent::try_from(id.clone())?,
+                        Some(&fs_root_path),
+                    )
+                    .into(),
                     fs_root_path: PathBuf::from(fs_root_path),
                     package_root_path: package_root_path,
                     ident: id.clone(),
+                    metafiles: MetaFileCache::default(),
                 })
             } else {
                 Err(Error::PackageNotFound(ident.clone()))
@@ -161,25 +266,26 @@ impl PackageInstall {
         }
 
         let pl = package_list_for_ident(&package_root_path, &original_ident)?;
-        let latest: Option<PackageIdent> = pl
-            .iter()
-            .filter(|ref p| p.origin == ident.origin && p.name == ident.name)
-            .fold(None, |winner, b| match winner {
-                Some(a) => match a.cmp(&b) {
-                    Ordering::Greater | Ordering::Equal => Some(a),
-                    Ordering::Less => Some(b.clone()),
-                },
-                None => match b.cmp(&ident) {
-                    Ordering::Greater | Ordering::Equal => Some(b.clone()),
-                    Ordering::Less => None,
-                },
-            });
+        let held = hold::held(&ident, Some(fs_root_path.as_path()))?;
+        let latest = held_or_latest(
+            pl.iter().filter(|p| {
+                p.origin == ident.origin
+                    && p.name == ident.name
+                    && Ord::cmp(*p, &ident) != Ordering::Less
+            }),
+            held.as_ref(),
+        );
         match latest {
             Some(id) => Ok(PackageInstall {
-                installed_path: fs::pkg_install_path(&id, Some(&fs_root_path)),
+                installed_path: fs::pkg_install_path(
+                    &FullyQualifiedPackageIdent::try_from(id.clone())?,
+                    Some(&fs_root_path),
+                )
+                .into(),
                 fs_root_path: fs_root_path,
                 package_root_path: package_root_path,
                 ident: id.clone(),
+                metafiles: MetaFileCache::default(),
             }),
             None => Err(Error::PackageNotFound(original_ident.clone())),
         }
@@ -196,6 +302,7 @@ impl PackageInstall {
             fs_root_path: fs_root_path,
             package_root_path: package_root_path,
             installed_path: installed_path,
+            metafiles: MetaFileCache::default(),
         }
     }
 
@@ -229,33 +336,70 @@ impl PackageInstall {
         self.read_deps(MetaFile::Services)
     }
 
+    /// Which releases of its services was a composite package built
+    /// with? These are the fully-qualified counterparts of
+    /// `pkg_services`.
+    pub fn resolved_services(&self) -> Result<Vec<PackageIdent>> {
+        self.read_deps(MetaFile::ResolvedServices)
+    }
+
     /// Constructs and returns a `HashMap` of environment variable/value key pairs of all
     /// environment variables needed to properly run a command from the context of this package.
     pub fn environment_for_command(&self) -> Result<HashMap<String, String>> {
-        let mut env = self.runtime_environment()?;
-        // Remove any pre-existing PATH key as this is either from an older package or is
-        // present for backwards compatibility with older Habitat releases.
-        env.remove(PATH_KEY);
-
-        let mut paths = self.runtime_paths()?;
+        Ok(self.runtime_env()?.to_map())
+    }
+
+    /// Composes the full environment the package's programs run in: every variable from the
+    /// `RUNTIME_ENVIRONMENT` metafile, with the separators given in the `ENVIRONMENT_SEP`
+    /// metafile, and a `PATH` assembled from the runtime path entries of the package and its
+    /// transitive dependencies. Path entries keep the precedence of their first appearance and
+    /// appear only once.
+    pub fn runtime_env(&self) -> Result<RuntimeEnv> {
+        let separators = match self.read_metafile(MetaFile::EnvironmentSep) {
+            Ok(body) => parse_key_value(&body)?,
+            Err(Error::MetaFileNotFound(MetaFile::EnvironmentSep)) => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut runtime_env = RuntimeEnv::default();
+        for (key, value) in self.runtime_environment()? {
+            // Skip any pre-existing PATH key as this is either from an older package or is
+            // present for backwards compatibility with older Habitat releases.
+            if key == PATH_KEY {
+                continue;
+            }
+            let separator = separators.get(&key).and_then(|sep| sep.chars().next());
+            runtime_env.insert(EnvVar {
+                key: key,
+                value: value,
+                separator: separator,
+            });
+        }
 
         // Let's join the paths to the FS_ROOT
         // In most cases, this does nothing and should only mutate
         // the paths in a windows studio where FS_ROOT_PATH will
         // be the studio root path (ie c:\hab\studios\...)
-        for path in &mut paths {
-            *path = fs::fs_rooted_path(&path, Some(&*self.fs_root_path));
-        }
+        let mut seen = HashSet::new();
+        let paths: Vec<PathBuf> = self
+            .runtime_paths()?
+            .into_iter()
+            .map(|path| fs::fs_rooted_path(&path, Some(&*self.fs_root_path)))
+            .filter(|path| seen.insert(path.clone()))
+            .collect();
 
         let joined = env::join_paths(paths)?
             .into_string()
             .map_err(|s| Error::InvalidPathString(s))?;
         // Only insert a PATH entry if the resulting path string is non-empty
         if !joined.is_empty() {
-            env.insert(PATH_KEY.to_string(), joined);
+            runtime_env.insert(EnvVar {
+                key: PATH_KEY.to_string(),
+                value: joined,
+                separator: Some(ENV_PATH_SEPARATOR),
+            });
         }
 
-        Ok(env)
+        Ok(runtime_env)
     }
 
     /// Returns all the package's binds, required and then optional
@@ -266,38 +410,14 @@ impl PackageInstall {
         Ok(all_binds)
     }
 
+    /// Returns the binds which must be satisfied before the package's service can start
     pub fn binds(&self) -> Result<Vec<Bind>> {
-        match self.read_metafile(MetaFile::Binds) {
-            Ok(body) => {
-                let mut binds = Vec::new();
-                for line in body.lines() {
-                    match Bind::from_str(line) {
-                        Ok(bind) => binds.push(bind),
-                        Err(_) => return Err(Error::MetaFileMalformed(MetaFile::Binds)),
-                    }
-                }
-                Ok(binds)
-            }
-            Err(Error::MetaFileNotFound(MetaFile::Binds)) => Ok(Vec::new()),
-            Err(e) => Err(e),
-        }
+        self.read_binds(MetaFile::Binds, true)
     }
 
+    /// Returns the binds which the package's service can start without
     pub fn binds_optional(&self) -> Result<Vec<Bind>> {
-        match self.read_metafile(MetaFile::BindsOptional) {
-            Ok(body) => {
-                let mut binds = Vec::new();
-                for line in body.lines() {
-                    match Bind::from_str(line) {
-                        Ok(bind) => binds.push(bind),
-                        Err(_) => return Err(Error::MetaFileMalformed(MetaFile::BindsOptional)),
-                    }
-                }
-                Ok(binds)
-            }
-            Err(Error::MetaFileNotFound(MetaFile::BindsOptional)) => Ok(Vec::new()),
-            Err(e) => Err(e),
-        }
+        self.read_binds(MetaFile::BindsOptional, false)
     }
 
     /// Returns the bind mappings for a composite package.
@@ -345,6 +465,38 @@ impl PackageInstall {
         }
     }
 
+    /// Returns the licenses the package declares, from the `pkg_license` of its plan. A package
+    /// built without a `MANIFEST` declares none.
+    pub fn declared_licenses(&self) -> Result<Vec<String>> {
+        match self.read_metafile(MetaFile::Manifest) {
+            Ok(body) => Ok(parse_licenses(&body)),
+            Err(Error::MetaFileNotFound(MetaFile::Manifest)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the licenses declared by the package and then by each of its transitive
+    /// dependencies, in the order of its `TDEPS`, for taking an inventory of the licenses of
+    /// everything the package runs with.
+    ///
+    /// # Failures
+    ///
+    /// * A transitive dependency isn't installed
+    pub fn licenses(&self) -> Result<Vec<PackageLicenses>> {
+        let mut licenses = vec![PackageLicenses {
+            ident: self.ident.clone(),
+            licenses: self.declared_licenses()?,
+        }];
+        for tdep in self.tdeps()? {
+            let install = PackageInstall::load(&tdep, Some(&self.fs_root_path))?;
+            licenses.push(PackageLicenses {
+                licenses: install.declared_licenses()?,
+                ident: tdep,
+            });
+        }
+        Ok(licenses)
+    }
+
     /// Return the direct dependencies of the package
     pub fn deps(&self) -> Result<Vec<PackageIdent>> {
         self.read_deps(MetaFile::Deps)
@@ -373,6 +525,20 @@ impl PackageInstall {
         }
     }
 
+    /// Returns the mappings defined by the `pkg_exports` plan variable as typed `Export` values,
+    /// in the order they appear in the package's `EXPORTS` metafile.
+    pub fn typed_exports(&self) -> Result<Vec<Export>> {
+        match self.read_metafile(MetaFile::Exports) {
+            Ok(body) => body
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(Export::from_str)
+                .collect(),
+            Err(Error::MetaFileNotFound(MetaFile::Exports)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
     /// A vector of ports we expose
     pub fn exposes(&self) -> Result<Vec<String>> {
         match self.read_metafile(MetaFile::Exposes) {
@@ -395,11 +561,52 @@ impl PackageInstall {
         &self.ident
     }
 
+    /// Re-hashes the package's installed files and compares them with the checksums recorded in
+    /// its signed `FILES` manifest, reporting any file which is missing, modified, or unexpected.
+    /// The manifest's signature is verified with the origin keys found in `cache_key_path`. A
+    /// package without a `FILES` manifest is checked against the SHA-256 checksums in its
+    /// `MANIFEST`, as every package built by the Habitat build program has.
+    ///
+    /// # Failures
+    ///
+    /// * The package has neither a `FILES` manifest nor checksums in its `MANIFEST`
+    /// * The `FILES` manifest's signature can't be verified
+    /// * An installed file can't be read
+    pub fn verify<P: AsRef<Path>>(&self, cache_key_path: P) -> Result<InstallVerification> {
+        manifest::verify_install(self, cache_key_path)
+    }
+
+    /// Returns the number of bytes taken up by the package's installed files, not counting its
+    /// dependencies. Symbolic links count as the size of the link itself, not of what it points
+    /// to.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        dir_size(&self.installed_path)
+    }
+
+    /// Returns the files of the package, ordered by path, with the mode, size, and hash recorded
+    /// for each in its `FILES` manifest. Paths are relative to the package's installed path.
+    ///
+    /// The files are listed as the manifest recorded them at install time, without walking or
+    /// re-hashing the installed path and without verifying the manifest's signature; use `verify`
+    /// to check them against what is installed now.
+    ///
+    /// # Failures
+    ///
+    /// * The package has no `FILES` manifest, or it is malformed
+    pub fn files(&self) -> Result<vec::IntoIter<ManifestEntry>> {
+        let manifest = FileManifest::parse(&self.read_metafile(MetaFile::Files)?)?;
+        Ok(manifest.entries.into_iter())
+    }
+
     /// Returns the path elements of the package's `PATH` metafile if it exists, or an empty `Vec`
     /// if not found.
     ///
     /// If no value for `PATH` can be found, return an empty `Vec`.
     pub fn paths(&self) -> Result<Vec<PathBuf>> {
+        let pkg_prefix = fs::pkg_install_path(
+            &FullyQualifiedPackageIdent::try_from(self.ident().clone())?,
+            None::<&Path>,
+        );
         match self.read_metafile(MetaFile::Path) {
             Ok(body) => {
                 if body.is_empty() {
@@ -412,7 +619,6 @@ impl PackageInstall {
                 // was merged (in https://github.com/habitat-sh/habitat/pull/4067, released in
                 // Habitat 0.50.0, 2017-11-30) which produced `PATH` metafiles containing extra
                 // path entries.
-                let pkg_prefix = fs::pkg_install_path(self.ident(), None::<&Path>);
                 let v = env::split_paths(&body)
                     .filter(|p| p.starts_with(&pkg_prefix))
                     .collect();
@@ -426,7 +632,6 @@ impl PackageInstall {
                     // Habitat 0.53.0, 2018-02-05) which stopped producing `PATH` metafiles. This
                     // workaround attempts to fallback to the `RUNTIME_ENVIRONMENT` metafile and
                     // use the value of the `PATH` key as a stand-in for the `PATH` metafile.
-                    let pkg_prefix = fs::pkg_install_path(self.ident(), None::<&Path>);
                     match self.read_metafile(MetaFile::RuntimeEnvironment) {
                         Ok(ref body) => {
                             match Self::parse_runtime_environment_metafile(body)?.get(PATH_KEY) {
@@ -491,7 +696,7 @@ impl PackageInstall {
     /// # Errors
     ///
     /// * If a metafile exists but cannot be properly parsed
-    fn runtime_paths(&self) -> Result<Vec<PathBuf>> {
+    pub fn runtime_paths(&self) -> Result<Vec<PathBuf>> {
         match self.read_metafile(MetaFile::RuntimePath) {
             Ok(body) => {
                 if body.is_empty() {
@@ -584,6 +789,30 @@ impl PackageInstall {
         }
     }
 
+    /// Returns the channel the package was installed from, or None if the package was installed
+    /// without recording one, such as from a local .hart file
+    pub fn channel(&self) -> Result<Option<String>> {
+        match self.read_metafile(MetaFile::Channel) {
+            Ok(body) => Ok(Some(body)),
+            Err(Error::MetaFileNotFound(MetaFile::Channel)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the channel the package was installed from in its `CHANNEL` metafile, replacing
+    /// any channel recorded before. The metafile isn't part of the package's `FILES` manifest,
+    /// so recording a channel doesn't affect verification.
+    pub fn set_channel(&self, channel: &str) -> Result<()> {
+        fs::atomic_write(
+            self.installed_path.join(MetaFile::Channel.to_string()),
+            format!("{}\n", channel).as_bytes(),
+            0o644,
+        )?;
+        self.metafiles
+            .insert(MetaFile::Channel, channel.to_string());
+        Ok(())
+    }
+
     /// Returns the group that the package is specified to run as
     /// or None if the package doesn't contain a SVC_GROUP Metafile
     pub fn svc_group(&self) -> Result<Option<String>> {
@@ -594,7 +823,7 @@ impl PackageInstall {
         }
     }
 
-    /// Read the contents of a given metafile.
+    /// Read the contents of a given metafile, from the cache if it has been read before.
     ///
     /// # Failures
     ///
@@ -602,7 +831,12 @@ impl PackageInstall {
     /// * Contents of the metafile could not be read
     /// * Contents of the metafile are unreadable or malformed
     fn read_metafile(&self, file: MetaFile) -> Result<String> {
-        read_metafile(&self.installed_path, &file)
+        if let Some(body) = self.metafiles.get(&file) {
+            return Ok(body);
+        }
+        let body = read_metafile_shared(&self.installed_path, &file)?;
+        self.metafiles.insert(file, body.clone());
+        Ok(body)
     }
 
     /// Reads metafiles containing dependencies represented by package identifiers separated by new
@@ -643,6 +877,26 @@ impl PackageInstall {
         }
     }
 
+    /// Reads a metafile of binds, one per line, marking each as required or not.
+    fn read_binds(&self, file: MetaFile, required: bool) -> Result<Vec<Bind>> {
+        match self.read_metafile(file.clone()) {
+            Ok(body) => body
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    Bind::from_str(line)
+                        .map(|bind| Bind {
+                            required: required,
+                            ..bind
+                        })
+                        .map_err(|_| Error::MetaFileMalformed(file.clone()))
+                })
+                .collect(),
+            Err(Error::MetaFileNotFound(_)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
     #[cfg(test)]
     fn target(&self) -> Result<PackageTarget> {
         match self.read_metafile(MetaFile::Target) {
@@ -652,6 +906,171 @@ impl PackageInstall {
     }
 }
 
+/// The contents of the metafiles of a `PackageInstall` which have been read so far.
+///
+/// Metafiles are only read when one of the accessors which needs them is called, and only once
+/// for each `PackageInstall`, as a package doesn't change once it is installed. A metafile which
+/// is missing isn't remembered, so it will be looked for again the next time it is needed.
+#[derive(Debug, Default)]
+struct MetaFileCache(Mutex<HashMap<MetaFile, String>>);
+
+impl MetaFileCache {
+    fn get(&self, file: &MetaFile) -> Option<String> {
+        self.0
+            .lock()
+            .expect("MetaFile cache poisoned")
+            .get(file)
+            .cloned()
+    }
+
+    fn insert(&self, file: MetaFile, body: String) {
+        self.0
+            .lock()
+            .expect("MetaFile cache poisoned")
+            .insert(file, body);
+    }
+}
+
+impl Clone for MetaFileCache {
+    fn clone(&self) -> Self {
+        MetaFileCache(Mutex::new(
+            self.0.lock().expect("MetaFile cache poisoned").clone(),
+        ))
+    }
+}
+
+// Two installs of the same package are equal whichever of their metafiles have been read.
+impl PartialEq for MetaFileCache {
+    fn eq(&self, _other: &MetaFileCache) -> bool {
+        true
+    }
+}
+
+impl Eq for MetaFileCache {}
+
+/// The contents of a metafile as it was read, along with its modification time and size then, so
+/// a metafile which has been rewritten since is read again.
+#[derive(Debug)]
+struct ReadMetaFile {
+    modified: SystemTime,
+    len: u64,
+    body: String,
+    last_used: u64,
+}
+
+/// The metafiles read through `read_metafile_shared`, by path. At most `MAX_READ_METAFILES` are
+/// kept; past that, the one used longest ago is forgotten to make room for the next.
+#[derive(Debug, Default)]
+struct ReadMetaFiles {
+    files: HashMap<PathBuf, ReadMetaFile>,
+    uses: u64,
+}
+
+impl ReadMetaFiles {
+    /// Returns the remembered body of the metafile at `path`, if it was read when it had the
+    /// given modification time and size.
+    fn get(&mut self, path: &Path, modified: SystemTime, len: u64) -> Option<String> {
+        self.uses += 1;
+        let uses = self.uses;
+        if let Some(read) = self.files.get_mut(path) {
+            if read.modified == modified && read.len == len {
+                read.last_used = uses;
+                return Some(read.body.clone());
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, path: PathBuf, modified: SystemTime, len: u64, body: String) {
+        if self.files.len() >= MAX_READ_METAFILES && !self.files.contains_key(&path) {
+            let oldest = self
+                .files
+                .iter()
+                .min_by_key(|&(_, read)| read.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.files.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        let read = ReadMetaFile {
+            modified: modified,
+            len: len,
+            body: body,
+            last_used: self.uses,
+        };
+        self.files.insert(path, read);
+    }
+}
+
+/// Reads a metafile from an installed path as `metadata::read_metafile` does, but only once per
+/// process unless the file's modification time or size changes.
+fn read_metafile_shared(installed_path: &Path, file: &MetaFile) -> Result<String> {
+    let path = fs::long_path(installed_path.join(file.to_string()));
+    let stamp = stdfs::metadata(&path).and_then(|m| Ok((m.modified()?, m.len())));
+    let (modified, len) = match stamp {
+        Ok(stamp) => stamp,
+        // Missing metafiles, or filesystems without modification times, aren't cached
+        Err(_) => return read_metafile(installed_path, file),
+    };
+    if let Some(body) = READ_METAFILES
+        .lock()
+        .expect("Read metafiles poisoned")
+        .get(&path, modified, len)
+    {
+        return Ok(body);
+    }
+    let body = read_metafile(installed_path, file)?;
+    READ_METAFILES
+        .lock()
+        .expect("Read metafiles poisoned")
+        .insert(path, modified, len, body.clone());
+    Ok(body)
+}
+
+/// The total size of the files under a directory.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in stdfs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = stdfs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            size += dir_size(&path)?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Returns the latest of a set of package identifiers.
+fn latest_of<'a, I>(idents: I) -> Option<PackageIdent>
+where
+    I: Iterator<Item = &'a PackageIdent>,
+{
+    idents.fold(None, |winner, b| match winner {
+        Some(a) => match a.partial_cmp(&b) {
+            Some(Ordering::Greater) => Some(a),
+            Some(Ordering::Equal) => Some(a),
+            Some(Ordering::Less) => Some(b.clone()),
+            None => Some(a),
+        },
+        None => Some(b.clone()),
+    })
+}
+
+/// Returns the held release if it is one of the candidates, otherwise the latest candidate.
+fn held_or_latest<'a, I>(candidates: I, held: Option<&PackageIdent>) -> Option<PackageIdent>
+where
+    I: Iterator<Item = &'a PackageIdent>,
+{
+    let candidates: Vec<&PackageIdent> = candidates.collect();
+    match held {
+        Some(held) if candidates.contains(&held) => Some(held.clone()),
+        _ => latest_of(candidates.into_iter()),
+    }
+}
+
 impl fmt::Display for PackageInstall {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.ident)
@@ -667,6 +1086,7 @@ mod test {
     use toml;
 
     use super::*;
+    use crypto::SigKeyPair;
     use package::test_support::{fixture_path, testing_package_install};
 
     /// Write the given contents into the specified metadata file for
@@ -732,7 +1152,8 @@ mod test {
 
     /// Returns the prefix path for a `PackageInstall`, making sure to not include any `FS_ROOT`.
     fn pkg_prefix_for(pkg_install: &PackageInstall) -> PathBuf {
-        fs::pkg_install_path(pkg_install.ident(), None::<&Path>)
+        let ident = FullyQualifiedPackageIdent::try_from(pkg_install.ident().clone()).unwrap();
+        fs::pkg_install_path(&ident, None::<&Path>).into()
     }
 
     /// Returns a `PackageTarget` that does not match the active target of this system.
@@ -753,6 +1174,7 @@ mod test {
             fs_root_path: PathBuf::from(""),
             package_root_path: PathBuf::from(""),
             installed_path: fixture_path,
+            metafiles: MetaFileCache::default(),
         };
 
         let cfg = package_install.default_cfg().unwrap();
@@ -1129,6 +1551,289 @@ core/bar=pub:core/publish sub:core/subscribe
         }
     }
 
+    #[test]
+    fn load_matching_returns_latest_release_satisfying_constraint() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        for ident_s in &[
+            "dream-theater/systematic-chaos/1.2.3/20180704142702",
+            "dream-theater/systematic-chaos/1.4.0/20180704142702",
+            "dream-theater/systematic-chaos/1.4.1/20180704142702",
+            "dream-theater/systematic-chaos/2.0.0/20180704142702",
+        ] {
+            testing_package_install(ident_s, fs_root.path());
+        }
+        let ident = PackageIdent::from_str("dream-theater/systematic-chaos").unwrap();
+
+        let loaded = PackageInstall::load_matching(
+            &ident,
+            &VersionConstraint::from_str(">=1.2, <2.0").unwrap(),
+            Some(fs_root.path()),
+        )
+        .unwrap();
+        assert_eq!(loaded.ident().version, Some("1.4.1".to_string()));
+
+        let loaded = PackageInstall::load_matching(
+            &ident,
+            &VersionConstraint::from_str("~1.2").unwrap(),
+            Some(fs_root.path()),
+        )
+        .unwrap();
+        assert_eq!(loaded.ident().version, Some("1.2.3".to_string()));
+
+        match PackageInstall::load_matching(
+            &ident,
+            &VersionConstraint::from_str(">2.0").unwrap(),
+            Some(fs_root.path()),
+        ) {
+            Err(Error::PackageNotFound(ref err_ident)) => assert_eq!(&ident, err_ident),
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(i) => panic!("Should not load successfully, install_ident={}", &i),
+        }
+    }
+
+    #[test]
+    fn metafiles_are_read_once() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/exporty", fs_root.path());
+        assert!(pkg_install.exports().unwrap().is_empty());
+        write_metafile(&pkg_install, MetaFile::Exports, "port=port\n");
+        assert_eq!(pkg_install.exports().unwrap().len(), 1);
+
+        // Once it has been read, a metafile isn't read again
+        write_metafile(&pkg_install, MetaFile::Exports, "port=port\nhost=host\n");
+        assert_eq!(pkg_install.exports().unwrap().len(), 1);
+        assert_eq!(pkg_install.clone().exports().unwrap().len(), 1);
+        let reloaded = PackageInstall::load(pkg_install.ident(), Some(fs_root.path())).unwrap();
+        assert_eq!(reloaded.exports().unwrap().len(), 2);
+        assert_eq!(reloaded, pkg_install);
+    }
+
+    #[test]
+    fn licenses_include_tdeps() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let glibc = testing_package_install("core/glibc/2.27/20180608041157", fs_root.path());
+        write_metafile(
+            &glibc,
+            MetaFile::Manifest,
+            "# core / glibc\n\n* __License__: GPL-2.0 LGPL-2.1\n",
+        );
+        testing_package_install("core/linux-headers/4.15.9/20180608041107", fs_root.path());
+        let redis = testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+        write_metafile(
+            &redis,
+            MetaFile::Manifest,
+            "# core / redis\n\n* __License__: BSD-3-Clause\n",
+        );
+        write_metafile(
+            &redis,
+            MetaFile::TDeps,
+            "core/glibc/2.27/20180608041157\ncore/linux-headers/4.15.9/20180608041107\n",
+        );
+
+        let licenses = redis.licenses().unwrap();
+        assert_eq!(
+            licenses
+                .iter()
+                .map(|l| (l.ident.to_string(), l.licenses.join(" ")))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "core/redis/4.0.10/20180608202239".to_string(),
+                    "BSD-3-Clause".to_string()
+                ),
+                (
+                    "core/glibc/2.27/20180608041157".to_string(),
+                    "GPL-2.0 LGPL-2.1".to_string()
+                ),
+                (
+                    "core/linux-headers/4.15.9/20180608041107".to_string(),
+                    "".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_and_binds_are_typed() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/bindy", fs_root.path());
+        write_metafile(
+            &pkg_install,
+            MetaFile::Exports,
+            "port=server.port\nhost=server.host\n",
+        );
+        write_metafile(&pkg_install, MetaFile::Binds, "database=port host\n");
+        write_metafile(&pkg_install, MetaFile::BindsOptional, "cache=port\n");
+
+        assert_eq!(
+            pkg_install.exports().unwrap()["port"],
+            "server.port".to_string()
+        );
+        assert_eq!(
+            pkg_install.typed_exports().unwrap(),
+            vec![
+                Export {
+                    name: "port".to_string(),
+                    config_path: "server.port".to_string(),
+                },
+                Export {
+                    name: "host".to_string(),
+                    config_path: "server.host".to_string(),
+                },
+            ]
+        );
+        let binds = pkg_install.all_binds().unwrap();
+        assert_eq!(
+            binds
+                .iter()
+                .map(|b| (b.name.as_str(), b.required))
+                .collect::<Vec<_>>(),
+            vec![("database", true), ("cache", false)]
+        );
+        assert_eq!(binds[0].exports, vec!["port", "host"]);
+    }
+
+    #[test]
+    fn metafiles_are_shared_between_loads() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/sharey", fs_root.path());
+        write_metafile(&pkg_install, MetaFile::Exports, "port=port\n");
+        let exports_path = pkg_install.installed_path().join("EXPORTS");
+        assert_eq!(pkg_install.exports().unwrap().len(), 1);
+        assert!(READ_METAFILES
+            .lock()
+            .unwrap()
+            .files
+            .contains_key(&exports_path));
+
+        let reloaded = PackageInstall::load(pkg_install.ident(), Some(fs_root.path())).unwrap();
+        assert_eq!(reloaded.exports().unwrap().len(), 1);
+
+        // A metafile which changes is read again by the next load
+        write_metafile(&pkg_install, MetaFile::Exports, "port=port\nhost=host\n");
+        let reloaded = PackageInstall::load(pkg_install.ident(), Some(fs_root.path())).unwrap();
+        assert_eq!(reloaded.exports().unwrap().len(), 2);
+        assert_eq!(
+            READ_METAFILES.lock().unwrap().files[&exports_path].body,
+            "port=port\nhost=host"
+        );
+    }
+
+    #[test]
+    fn read_metafiles_forget_the_least_recently_used() {
+        let mut read = ReadMetaFiles::default();
+        let modified = SystemTime::now();
+        for i in 0..MAX_READ_METAFILES {
+            read.insert(PathBuf::from(i.to_string()), modified, 0, i.to_string());
+        }
+        assert_eq!(read.get(Path::new("0"), modified, 0), Some("0".to_string()));
+
+        read.insert(PathBuf::from("new"), modified, 0, "new".to_string());
+        assert_eq!(read.files.len(), MAX_READ_METAFILES);
+        assert_eq!(read.get(Path::new("0"), modified, 0), Some("0".to_string()));
+        assert_eq!(read.get(Path::new("1"), modified, 0), None);
+        assert_eq!(
+            read.get(Path::new("new"), modified, 0),
+            Some("new".to_string())
+        );
+    }
+
+    #[test]
+    fn files_are_listed_from_manifest() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/listy", fs_root.path());
+        std::fs::create_dir_all(pkg_install.installed_path().join("bin")).unwrap();
+        write_metafile(&pkg_install, MetaFile::Path, "bin");
+        let mut f = File::create(pkg_install.installed_path().join("bin/listy")).unwrap();
+        f.write_all(b"binary").unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("acme").unwrap();
+        manifest::write_signed_manifest(&pkg_install, &pair).unwrap();
+        // The listing comes from the manifest, not the installed path
+        std::fs::remove_file(pkg_install.installed_path().join("bin/listy")).unwrap();
+
+        let files: Vec<ManifestEntry> = pkg_install.files().unwrap().collect();
+        assert_eq!(
+            files.iter().map(|f| f.path.as_path()).collect::<Vec<_>>(),
+            vec![
+                Path::new("IDENT"),
+                Path::new("PATH"),
+                Path::new("TARGET"),
+                Path::new("bin/listy"),
+            ]
+        );
+        assert_eq!(files[3].size, 6);
+    }
+
+    #[test]
+    fn search_matches_globs_and_partial_idents() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        for ident_s in &[
+            "core/postgresql/9.6.9/20180701000000",
+            "core/postgresql/9.6.11/20181101000000",
+            "core/postfix/3.3.1/20180701000000",
+            "core/nginx/1.15.0/20180701000000",
+            "acme/nginx/1.13.0/20180601000000",
+        ] {
+            testing_package_install(ident_s, fs_root.path());
+        }
+        let search = |query: &str| -> Vec<String> {
+            PackageInstall::search(query, Some(fs_root.path()))
+                .unwrap()
+                .iter()
+                .map(|i| i.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            search("core/postg*"),
+            vec![
+                "core/postgresql/9.6.9/20180701000000",
+                "core/postgresql/9.6.11/20181101000000",
+            ]
+        );
+        assert_eq!(
+            search("*/nginx"),
+            vec![
+                "acme/nginx/1.13.0/20180601000000",
+                "core/nginx/1.15.0/20180701000000",
+            ]
+        );
+        assert_eq!(
+            search("*/nginx/1.15.*"),
+            vec!["core/nginx/1.15.0/20180701000000"]
+        );
+        assert_eq!(search("nginx"), search("*/nginx"));
+        assert_eq!(
+            search("core/post???"),
+            vec!["core/postfix/3.3.1/20180701000000"]
+        );
+        assert!(PackageInstall::search("core//nginx", Some(fs_root.path())).is_err());
+    }
+
+    #[test]
+    fn size_on_disk_counts_installed_files() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/sizey", fs_root.path());
+        let metafiles_size = pkg_install.size_on_disk().unwrap();
+        std::fs::create_dir_all(pkg_install.installed_path().join("lib")).unwrap();
+        let mut f = File::create(pkg_install.installed_path().join("lib/libsizey.so")).unwrap();
+        f.write_all(&[0u8; 4096]).unwrap();
+
+        assert_eq!(pkg_install.size_on_disk().unwrap(), metafiles_size + 4096);
+    }
+
+    #[test]
+    fn files_without_manifest() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install = testing_package_install("acme/listy", fs_root.path());
+
+        match pkg_install.files() {
+            Err(Error::MetaFileNotFound(MetaFile::Files)) => (),
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(_) => panic!("Should not list files without a manifest"),
+        }
+    }
+
     #[test]
     fn paths_metafile_single() {
         let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
@@ -1381,6 +2086,78 @@ core/bar=pub:core/publish sub:core/subscribe
         assert_eq!(expected, alpha.legacy_runtime_paths().unwrap());
     }
 
+    #[test]
+    fn channel_is_recorded_outside_manifest() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let pkg_install =
+            testing_package_install("core/redis/4.0.10/20180701000000", fs_root.path());
+        assert_eq!(pkg_install.channel().unwrap(), None);
+
+        pkg_install.set_channel("unstable").unwrap();
+        assert_eq!(pkg_install.channel().unwrap(), Some("unstable".to_string()));
+        let reloaded = PackageInstall::load(pkg_install.ident(), Some(fs_root.path())).unwrap();
+        assert_eq!(reloaded.channel().unwrap(), Some("unstable".to_string()));
+        assert!(!FileManifest::generate(&reloaded)
+            .unwrap()
+            .entries
+            .iter()
+            .any(|e| e.path == Path::new("CHANNEL")));
+    }
+
+    #[test]
+    fn runtime_env_dedups_path_and_keeps_separators() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+
+        let other_pkg_install = testing_package_install("acme/ty-tabor", fs_root.path());
+        set_path_for(&other_pkg_install, vec!["sbin"]);
+
+        let pkg_install = testing_package_install("acme/pathy", fs_root.path());
+        set_path_for(&pkg_install, vec!["bin"]);
+        set_runtime_path_for(
+            &pkg_install,
+            vec![&pkg_install, &other_pkg_install, &pkg_install],
+        );
+        write_metafile(
+            &pkg_install,
+            MetaFile::RuntimeEnvironment,
+            "PATH=/should/be/ignored\nCLASSPATH=/a.jar:/b.jar\nFOO=bar\n",
+        );
+        write_metafile(&pkg_install, MetaFile::EnvironmentSep, "CLASSPATH=:\n");
+
+        let runtime_env = pkg_install.runtime_env().unwrap();
+
+        let fs_root_path = fs_root.path();
+        assert_eq!(
+            vec![
+                fs::fs_rooted_path(
+                    &pkg_prefix_for(&pkg_install).join("bin"),
+                    Some(fs_root_path)
+                ),
+                fs::fs_rooted_path(
+                    &pkg_prefix_for(&other_pkg_install).join("sbin"),
+                    Some(fs_root_path)
+                ),
+            ],
+            runtime_env.path()
+        );
+        assert_eq!(
+            Some(':'),
+            runtime_env.get("CLASSPATH").and_then(|var| var.separator)
+        );
+        assert_eq!(None, runtime_env.get("FOO").unwrap().separator);
+        assert_eq!(
+            vec!["CLASSPATH", "FOO", "PATH"],
+            runtime_env
+                .iter()
+                .map(|var| var.key.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            runtime_env.to_map(),
+            pkg_install.environment_for_command().unwrap()
+        );
+    }
+
     #[test]
     fn environment_for_command_missing_all_metafiles() {
         let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
@@ -0,0 +1,569 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Installation of a set of packages, such as a package and its transitive dependencies, with
+//! independent packages downloaded and unpacked concurrently.
+//!
+//! The packages to install are given as a `PackageGraph`, and a package is only started once all
+//! of its direct dependencies in the graph are installed, so an installation which fails part way
+//! never leaves a package installed without its dependencies. Up to `parallelism` packages are
+//! downloaded and unpacked at a time, each on its own worker thread. This crate doesn't carry an
+//! HTTP client, so artifacts are downloaded through a `PackageTransport`, which callers implement
+//! with the client they already use to talk to Builder.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, rename};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::hooks::{failed_install_hook, run_install_hooks};
+use super::store::ContentStore;
+use super::{PackageArchive, PackageGraph, PackageIdent, PackageInstall, PackageTarget};
+use error::{Error, Result};
+use fs;
+
+/// How many packages are installed at a time unless the installer is told otherwise
+pub const DEFAULT_INSTALL_PARALLELISM: usize = 4;
+
+pub trait PackageTransport {
+    /// Downloads the artifact of a fully-qualified package into `dst_dir`, returning the path of
+    /// the downloaded .hart file.
+    fn fetch(&self, ident: &PackageIdent, dst_dir: &Path) -> Result<PathBuf>;
+
+    /// The channel artifacts are fetched from, which is recorded with each package installed. A
+    /// transport which doesn't fetch from a channel records nothing.
+    fn channel(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The progress of a single package, as reported to the callback given to
+/// `ParallelInstaller::install`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InstallProgress {
+    Downloading,
+    Unpacking,
+    /// The package's install hooks are running
+    RunningHooks,
+    Installed,
+    /// The package was installed before the installer got to it
+    AlreadyInstalled,
+    Failed(String),
+}
+
+impl InstallProgress {
+    fn is_finished(&self) -> bool {
+        match *self {
+            InstallProgress::Downloading
+            | InstallProgress::Unpacking
+            | InstallProgress::RunningHooks => false,
+            _ => true,
+        }
+    }
+}
+
+pub struct ParallelInstaller<T> {
+    transport: Arc<T>,
+    parallelism: usize,
+    cache_key_path: PathBuf,
+    fs_root_path: PathBuf,
+    content_store: bool,
+}
+
+impl<T> ParallelInstaller<T>
+where
+    T: PackageTransport + Send + Sync + 'static,
+{
+    /// Returns an installer which verifies artifacts with the origin keys in `cache_key_path`
+    /// and installs them under `fs_root_path`, or `/` if it isn't given.
+    pub fn new<P>(transport: T, cache_key_path: P, fs_root_path: Option<&Path>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        ParallelInstaller {
+            transport: Arc::new(transport),
+            parallelism: DEFAULT_INSTALL_PARALLELISM,
+            cache_key_path: cache_key_path.into(),
+            fs_root_path: fs_root_path.map_or(PathBuf::from("/"), |p| p.into()),
+            content_store: false,
+        }
+    }
+
+    /// Sets whether the packages installed share file content with other installed packages
+    /// through the content store. See the `store` module.
+    pub fn with_content_store(mut self, enabled: bool) -> Self {
+        self.content_store = enabled;
+        self
+    }
+
+    /// Sets how many packages are downloaded and unpacked at a time, which is at least one.
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    pub fn parallelism(&self) -> usize {
+        self.parallelism
+    }
+
+    /// Installs every package in `graph` which isn't installed yet, calling `progress` on this
+    /// thread as each package moves along. Returns the packages which were installed, in the
+    /// order they were installed.
+    ///
+    /// # Failures
+    ///
+    /// * The packages in `graph` depend on each other in a cycle
+    /// * Any package fails to download, verify, or unpack, or one of its install hooks fails.
+    ///   Packages which are already being installed are finished first, but nothing which
+    ///   depends on the failed package is started. An installed package whose install hook
+    ///   failed before isn't usable, so its hooks are run again. A package whose worker panics
+    ///   fails as well.
+    pub fn install<F>(&self, graph: &PackageGraph, mut progress: F) -> Result<Vec<PackageIdent>>
+    where
+        F: FnMut(&PackageIdent, &InstallProgress),
+    {
+        graph.toposort()?;
+        if graph.is_empty() {
+            return Ok(vec![]);
+        }
+        // Garbage collection waits for the installs to finish before removing any packages
+        let _packages_lock = fs::lock_packages_shared(Some(&self.fs_root_path))?;
+        let (job_tx, job_rx) = mpsc::channel::<PackageIdent>();
+        let (event_tx, event_rx) = mpsc::channel::<(PackageIdent, InstallProgress)>();
+        let workers = self.spawn_workers(
+            self.parallelism.min(graph.len()),
+            Arc::new(Mutex::new(job_rx)),
+            event_tx,
+        );
+
+        let mut pending: HashMap<&PackageIdent, usize> = graph
+            .idents()
+            .iter()
+            .map(|ident| (ident, graph.deps(ident).len()))
+            .collect();
+        let mut in_flight = 0;
+        for ident in graph.idents() {
+            if pending[ident] == 0 {
+                job_tx
+                    .send(ident.clone())
+                    .expect("Install workers exited early");
+                in_flight += 1;
+            }
+        }
+
+        let mut installed = Vec::new();
+        let mut failure = None;
+        while in_flight > 0 {
+            let (ident, event) = match event_rx.recv() {
+                Ok(received) => received,
+                // Every worker is gone, so what's in flight will never finish
+                Err(_) => {
+                    failure = failure.or_else(|| Some("Install workers exited early".to_string()));
+                    break;
+                }
+            };
+            progress(&ident, &event);
+            if !event.is_finished() {
+                continue;
+            }
+            in_flight -= 1;
+            match event {
+                InstallProgress::Failed(msg) => {
+                    if failure.is_none() {
+                        failure = Some(format!("{}: {}", ident, msg));
+                    }
+                    continue;
+                }
+                InstallProgress::Installed => installed.push(ident.clone()),
+                _ => {}
+            }
+            if failure.is_some() {
+                continue;
+            }
+            for dependent in graph.rdeps(&ident) {
+                let count = pending
+                    .get_mut(dependent)
+                    .expect("Dependent is in the graph");
+                *count -= 1;
+                if *count == 0 {
+                    job_tx
+                        .send(dependent.clone())
+                        .expect("Install workers exited early");
+                    in_flight += 1;
+                }
+            }
+        }
+
+        drop(job_tx);
+        for worker in workers {
+            let _ = worker.join();
+        }
+        match failure {
+            Some(msg) => Err(Error::PackageUnpackFailed(msg)),
+            None => Ok(installed),
+        }
+    }
+
+    fn spawn_workers(
+        &self,
+        count: usize,
+        jobs: Arc<Mutex<Receiver<PackageIdent>>>,
+        events: Sender<(PackageIdent, InstallProgress)>,
+    ) -> Vec<thread::JoinHandle<()>> {
+        (0..count)
+            .map(|_| {
+                let jobs = jobs.clone();
+                let events = events.clone();
+                let transport = self.transport.clone();
+                let cache_key_path = self.cache_key_path.clone();
+                let fs_root_path = self.fs_root_path.clone();
+                let content_store = self.content_store;
+                thread::spawn(move || loop {
+                    let ident = match jobs.lock().expect("Install job queue poisoned").recv() {
+                        Ok(ident) => ident,
+                        // The installer has nothing left to hand out
+                        Err(_) => break,
+                    };
+                    let report = |event: InstallProgress| {
+                        let _ = events.send((ident.clone(), event));
+                    };
+                    // A panic fails the package, rather than leaving the installer waiting on it
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        install_one(
+                            &*transport,
+                            &ident,
+                            &cache_key_path,
+                            &fs_root_path,
+                            content_store,
+                            &report,
+                        )
+                    }));
+                    report(match result {
+                        Ok(Ok(event)) => event,
+                        Ok(Err(e)) => InstallProgress::Failed(e.to_string()),
+                        Err(cause) => InstallProgress::Failed(panic_message(cause)),
+                    });
+                })
+            })
+            .collect()
+    }
+}
+
+/// Describes the panic of a worker, from the message it panicked with if it has one.
+fn panic_message(cause: Box<Any + Send>) -> String {
+    match cause
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| cause.downcast_ref::<String>().cloned())
+    {
+        Some(msg) => format!("Install worker panicked: {}", msg),
+        None => "Install worker panicked".to_string(),
+    }
+}
+
+/// Downloads and unpacks a single package, returning its final progress.
+fn install_one<T, F>(
+    transport: &T,
+    ident: &PackageIdent,
+    cache_key_path: &Path,
+    fs_root_path: &Path,
+    content_store: bool,
+    report: &F,
+) -> Result<InstallProgress>
+where
+    T: PackageTransport,
+    F: Fn(InstallProgress),
+{
+    if !ident.fully_qualified() {
+        return Err(Error::FullyQualifiedPackageIdentRequired(ident.to_string()));
+    }
+    if let Ok(install) = PackageInstall::load(ident, Some(fs_root_path)) {
+        if failed_install_hook(&install)?.is_none() {
+            return Ok(InstallProgress::AlreadyInstalled);
+        }
+        // The package isn't usable until the hook which failed is retried
+        report(InstallProgress::RunningHooks);
+        run_install_hooks(&install)?;
+        return Ok(InstallProgress::Installed);
+    }
+    report(InstallProgress::Downloading);
+    let dst_dir = fs::cache_artifact_path(Some(fs_root_path));
+    create_dir_all(&dst_dir)?;
+    // The artifact is downloaded somewhere of its own, so a slow download holds up nothing else,
+    // and only moved into the cache and unpacked under a lock on that one artifact
+    let download_dir = fs::TempDirInCache::new(Some(fs_root_path))?;
+    let downloaded = transport.fetch(ident, download_dir.path())?;
+    let file_name = downloaded
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| {
+            Error::PackageUnpackFailed(format!(
+                "{} was downloaded to {}, which isn't an artifact",
+                ident,
+                downloaded.display()
+            ))
+        })?;
+    // A transport which hands back some other package's artifact fails before anything of it
+    // is cached or installed
+    let metadata = PackageArchive::new(downloaded.clone()).metadata()?;
+    if metadata.ident != *ident || metadata.target != *PackageTarget::active_target() {
+        return Err(Error::PackageUnpackFailed(format!(
+            "{} was requested, but the artifact downloaded is {} for {}",
+            ident, metadata.ident, metadata.target
+        )));
+    }
+    let archive_path = dst_dir.join(&file_name);
+    {
+        let _artifact_lock = fs::lock_cache_artifact(&file_name, Some(fs_root_path))?;
+        rename(&downloaded, &archive_path)?;
+        report(InstallProgress::Unpacking);
+        let archive = PackageArchive::new(archive_path);
+        archive.unpack_verified(&cache_key_path, Some(fs_root_path))?;
+    }
+    let install = PackageInstall::load(ident, Some(fs_root_path))?;
+    if let Some(channel) = transport.channel() {
+        install.set_channel(channel)?;
+    }
+    report(InstallProgress::RunningHooks);
+    run_install_hooks(&install)?;
+    // Hooks may still change the package's files, so its content is stored once they are done
+    if content_store {
+        ContentStore::new(Some(fs_root_path)).add(&install)?;
+    }
+    Ok(InstallProgress::Installed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::copy;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use tempfile::Builder;
+
+    use super::super::test_support::{fixture_path, testing_package_install};
+    use super::*;
+
+    /// Serves the one .hart fixture, refusing every other package but `core/imposter`, which it
+    /// wrongly serves the fixture for, and recording every request and whether the artifact
+    /// cache, or the artifact, was locked while it was fetched
+    struct FixtureTransport {
+        requests: Mutex<Vec<PackageIdent>>,
+        fs_root: PathBuf,
+        locked_while_fetching: Mutex<bool>,
+    }
+
+    impl FixtureTransport {
+        fn new(fs_root: &Path) -> Self {
+            FixtureTransport {
+                requests: Mutex::new(Vec::new()),
+                fs_root: fs_root.to_path_buf(),
+                locked_while_fetching: Mutex::new(false),
+            }
+        }
+    }
+
+    impl PackageTransport for FixtureTransport {
+        fn fetch(&self, ident: &PackageIdent, dst_dir: &Path) -> Result<PathBuf> {
+            self.requests.lock().unwrap().push(ident.clone());
+            let artifacts = fs::cache_artifact_path(Some(&self.fs_root));
+            for lock in &[
+                artifacts.join(".artifacts.lock"),
+                artifacts
+                    .join(".locks")
+                    .join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart.lock"),
+            ] {
+                if fs::FileLock::try_exclusive(lock)?.is_none() {
+                    *self.locked_while_fetching.lock().unwrap() = true;
+                }
+            }
+            if ident.to_string() == "core/panics/1.0.0/20180701000000" {
+                panic!("the transport fell over");
+            }
+            let file_name = "happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart";
+            if ident.to_string() != "happyhumans/possums/8.1.4/20160427165340"
+                && ident.to_string() != "core/imposter/1.0.0/20180701000000"
+            {
+                return Err(Error::PackageNotFound(ident.clone()));
+            }
+            let dst = dst_dir.join(file_name);
+            copy(fixture_path(file_name), &dst)?;
+            Ok(dst)
+        }
+    }
+
+    fn ident(s: &str) -> PackageIdent {
+        PackageIdent::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn install_graph_in_dependency_order() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        copy(
+            fixture_path("happyhumans-20160424223347.pub"),
+            cache.path().join("happyhumans-20160424223347.pub"),
+        )
+        .unwrap();
+        testing_package_install("core/glibc/2.27/20180701000000", fs_root.path());
+        let mut graph = PackageGraph::new();
+        graph.add_package(
+            ident("happyhumans/possums/8.1.4/20160427165340"),
+            &[ident("core/glibc/2.27/20180701000000")],
+        );
+        let installer = ParallelInstaller::new(
+            FixtureTransport::new(fs_root.path()),
+            cache.path(),
+            Some(fs_root.path()),
+        )
+        .with_parallelism(2);
+
+        let mut events = Vec::new();
+        let installed = installer
+            .install(&graph, |ident, event| {
+                events.push((ident.to_string(), event.clone()))
+            })
+            .unwrap();
+
+        assert_eq!(
+            installed,
+            vec![ident("happyhumans/possums/8.1.4/20160427165340")]
+        );
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "core/glibc/2.27/20180701000000".to_string(),
+                    InstallProgress::AlreadyInstalled,
+                ),
+                (
+                    "happyhumans/possums/8.1.4/20160427165340".to_string(),
+                    InstallProgress::Downloading,
+                ),
+                (
+                    "happyhumans/possums/8.1.4/20160427165340".to_string(),
+                    InstallProgress::Unpacking,
+                ),
+                (
+                    "happyhumans/possums/8.1.4/20160427165340".to_string(),
+                    InstallProgress::RunningHooks,
+                ),
+                (
+                    "happyhumans/possums/8.1.4/20160427165340".to_string(),
+                    InstallProgress::Installed,
+                ),
+            ]
+        );
+        assert!(PackageInstall::load(
+            &ident("happyhumans/possums/8.1.4/20160427165340"),
+            Some(fs_root.path())
+        )
+        .is_ok());
+        // Nothing was locked while the artifact was downloaded, and it ended up in the cache
+        assert!(!*installer.transport.locked_while_fetching.lock().unwrap());
+        assert!(fs::cache_artifact_path(Some(fs_root.path()))
+            .join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart")
+            .is_file());
+    }
+
+    #[test]
+    fn dependents_of_a_failed_package_are_not_installed() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let mut graph = PackageGraph::new();
+        graph.add_package(
+            ident("happyhumans/possums/8.1.4/20160427165340"),
+            &[ident("core/glibc/2.27/20180701000000")],
+        );
+        let installer = ParallelInstaller::new(
+            FixtureTransport::new(fs_root.path()),
+            cache.path(),
+            Some(fs_root.path()),
+        );
+
+        match installer.install(&graph, |_, _| ()) {
+            Err(Error::PackageUnpackFailed(msg)) => {
+                assert!(msg.starts_with("core/glibc/2.27/20180701000000"))
+            }
+            Err(e) => panic!("Wrong error returned, error={:?}", e),
+            Ok(installed) => panic!("Should not install successfully, installed={:?}", installed),
+        }
+        assert_eq!(
+            *installer.transport.requests.lock().unwrap(),
+            vec![ident("core/glibc/2.27/20180701000000")]
+        );
+    }
+
+    #[test]
+    fn an_artifact_of_another_package_is_not_installed() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        copy(
+            fixture_path("happyhumans-20160424223347.pub"),
+            cache.path().join("happyhumans-20160424223347.pub"),
+        )
+        .unwrap();
+        let mut graph = PackageGraph::new();
+        graph.add_package(ident("core/imposter/1.0.0/20180701000000"), &[]);
+        let installer = ParallelInstaller::new(
+            FixtureTransport::new(fs_root.path()),
+            cache.path(),
+            Some(fs_root.path()),
+        );
+
+        match installer.install(&graph, |_, _| ()) {
+            Err(Error::PackageUnpackFailed(msg)) => assert!(
+                msg.contains("the artifact downloaded is happyhumans/possums/8.1.4/20160427165340")
+            ),
+            other => panic!(
+                "Expected the wrong artifact to fail the install, got {:?}",
+                other
+            ),
+        }
+        assert!(!fs::pkg_root_path(Some(fs_root.path()))
+            .join("happyhumans")
+            .exists());
+        assert!(!fs::cache_artifact_path(Some(fs_root.path()))
+            .join("happyhumans-possums-8.1.4-20160427165340-x86_64-linux.hart")
+            .exists());
+    }
+
+    #[test]
+    fn a_panicking_worker_fails_its_package() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let mut graph = PackageGraph::new();
+        graph.add_package(
+            ident("happyhumans/possums/8.1.4/20160427165340"),
+            &[ident("core/panics/1.0.0/20180701000000")],
+        );
+        let installer = ParallelInstaller::new(
+            FixtureTransport::new(fs_root.path()),
+            cache.path(),
+            Some(fs_root.path()),
+        );
+
+        match installer.install(&graph, |_, _| ()) {
+            Err(Error::PackageUnpackFailed(msg)) => {
+                assert!(msg.ends_with("Install worker panicked: the transport fell over"))
+            }
+            other => panic!("Expected the panic to fail the install, got {:?}", other),
+        }
+    }
+}
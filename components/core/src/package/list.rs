@@ -17,11 +17,13 @@ use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use super::constraint::VersionConstraint;
 use super::metadata::{read_metafile, MetaFile};
 use super::{PackageIdent, PackageTarget};
 
 use error::{Error, Result};
 
+use regex::{self, Regex};
 use tempfile::Builder;
 use tempfile::TempDir;
 
@@ -58,6 +60,117 @@ pub fn all_packages(path: &Path) -> Result<Vec<PackageIdent>> {
     Ok(package_list)
 }
 
+/// Which installed packages `list_installed` returns. A package must match every part of the
+/// filter which is set, and it matches packages of the active target unless another is set.
+#[derive(Clone, Debug, Default)]
+pub struct PackageFilter {
+    origin: Option<String>,
+    name: Option<Regex>,
+    name_literal: Option<String>,
+    version: Option<VersionConstraint>,
+    target: Option<PackageTarget>,
+}
+
+impl PackageFilter {
+    pub fn new() -> Self {
+        PackageFilter::default()
+    }
+
+    /// Matches packages of the given origin.
+    pub fn origin<S: Into<String>>(mut self, origin: S) -> Self {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// Matches packages whose name matches a glob, where `*` matches any run of characters and
+    /// `?` matches any one character, such as `postg*`.
+    pub fn name(mut self, glob: &str) -> Result<Self> {
+        self.name = Some(glob_regex(glob)?);
+        self.name_literal = if glob.contains('*') || glob.contains('?') {
+            None
+        } else {
+            Some(glob.to_string())
+        };
+        Ok(self)
+    }
+
+    /// Matches packages whose version satisfies a constraint.
+    pub fn version(mut self, constraint: VersionConstraint) -> Self {
+        self.version = Some(constraint);
+        self
+    }
+
+    /// Matches packages built for the given target rather than the active one.
+    pub fn target(mut self, target: PackageTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+}
+
+/// Returns the packages installed under `fs_root_path`, or `/` if it isn't given, which match a
+/// filter, ordered by origin and name and then from the oldest release to the latest.
+///
+/// Only the parts of the packages root which can hold a match are read: a filter by origin, or
+/// by a name without wildcards, reads just those directories, and the `TARGET` metafiles of
+/// versions which don't satisfy the version constraint aren't read at all.
+pub fn list_installed(
+    filter: &PackageFilter,
+    fs_root_path: Option<&Path>,
+) -> Result<Vec<PackageIdent>> {
+    let package_root_path = ::fs::pkg_root_path(fs_root_path);
+    let target = filter
+        .target
+        .as_ref()
+        .unwrap_or(PackageTarget::active_target());
+    let mut packages = vec![];
+    for origin in child_dirs(&package_root_path, filter.origin.as_ref())? {
+        let origin_path = package_root_path.join(&origin);
+        for name in child_dirs(&origin_path, filter.name_literal.as_ref())? {
+            if !filter.name.as_ref().map_or(true, |n| n.is_match(&name)) {
+                continue;
+            }
+            let name_path = origin_path.join(&name);
+            for version in child_dirs(&name_path, None)? {
+                if !filter
+                    .version
+                    .as_ref()
+                    .map_or(true, |c| c.matches(&version))
+                {
+                    continue;
+                }
+                let version_path = name_path.join(&version);
+                for release in child_dirs(&version_path, None)? {
+                    let release_path = version_path.join(&release);
+                    if let Some(ident) =
+                        package_ident_from_dir(&origin, &name, &version, target, &release_path)
+                    {
+                        packages.push(ident);
+                    }
+                }
+            }
+        }
+    }
+    // Installed packages are fully qualified, and `by_parts_cmp` orders any two of those, even
+    // when their versions can't be compared
+    packages.sort_by(|a, b| a.by_parts_cmp(b));
+    Ok(packages)
+}
+
+/// Translates a glob, where `*` matches any run of characters and `?` matches any one
+/// character, into a regular expression matching the whole of an identifier part.
+pub fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Ok(Regex::new(&pattern)?)
+}
+
 /// Returns a vector of package idents built from the contents of
 /// the given directory, using the given origin to restrict the
 /// search.
@@ -299,6 +412,29 @@ fn package_ident_from_dir(
     }
 }
 
+/// Returns the names of the directories in `dir`, or just `only` when it is given and is one of
+/// them. A directory which doesn't exist has none.
+fn child_dirs(dir: &Path, only: Option<&String>) -> Result<Vec<String>> {
+    if let Some(name) = only {
+        return Ok(if is_existing_dir(&dir.join(name))? {
+            vec![name.clone()]
+        } else {
+            vec![]
+        });
+    }
+    let mut names = vec![];
+    if !is_existing_dir(dir)? {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if fs::metadata(entry.path())?.is_dir() {
+            names.push(filename_from_entry(entry));
+        }
+    }
+    Ok(names)
+}
+
 fn filename_from_entry(entry: fs::DirEntry) -> String {
     return entry.file_name().to_string_lossy().into_owned().to_string();
 }
@@ -322,6 +458,7 @@ mod test {
 
     use fs;
     use std::fs::File;
+    use std::io::Write;
     use tempfile::Builder;
 
     #[test]
@@ -372,6 +509,95 @@ mod test {
         }
     }
 
+    #[test]
+    fn list_installed_applies_every_filter() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        for ident_s in &[
+            "core/postgresql/9.6.9/20180701000000",
+            "core/postgresql/10.4/20180801000000",
+            "core/postfix/3.3.1/20180701000000",
+            "acme/postgresql/9.6.9/20180601000000",
+        ] {
+            testing_package_install(ident_s, fs_root.path());
+        }
+        let foreign =
+            testing_package_install("core/postgresql/9.5.0/20170101000000", fs_root.path());
+        let other_target = if PackageTarget::active_target().to_string() == "x86_64-windows" {
+            "x86_64-linux"
+        } else {
+            "x86_64-windows"
+        };
+        File::create(foreign.installed_path().join(MetaFile::Target.to_string()))
+            .unwrap()
+            .write_all(other_target.as_bytes())
+            .unwrap();
+        let list = |filter: PackageFilter| -> Vec<String> {
+            list_installed(&filter, Some(fs_root.path()))
+                .unwrap()
+                .iter()
+                .map(|i| i.to_string())
+                .collect()
+        };
+
+        assert_eq!(list(PackageFilter::new()).len(), 4);
+        assert_eq!(
+            list(PackageFilter::new().origin("core").name("post*").unwrap()),
+            vec![
+                "core/postfix/3.3.1/20180701000000",
+                "core/postgresql/9.6.9/20180701000000",
+                "core/postgresql/10.4/20180801000000",
+            ]
+        );
+        assert_eq!(
+            list(
+                PackageFilter::new()
+                    .name("postgresql")
+                    .unwrap()
+                    .version(">=10".parse().unwrap())
+            ),
+            vec!["core/postgresql/10.4/20180801000000"]
+        );
+        assert_eq!(
+            list(
+                PackageFilter::new()
+                    .origin("core")
+                    .target(PackageTarget::from_str(other_target).unwrap())
+            ),
+            vec!["core/postgresql/9.5.0/20170101000000"]
+        );
+        assert!(list(PackageFilter::new().origin("nobody")).is_empty());
+    }
+
+    #[test]
+    fn list_installed_orders_versions_which_cannot_be_compared() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        for ident_s in &[
+            "core/redis/master/20180701000000",
+            "core/redis/4.0.10/20180801000000",
+            "core/redis/develop/20180701000000",
+            "core/redis/3.2.4/20170514150022",
+            "core/redis/master/20180601000000",
+        ] {
+            testing_package_install(ident_s, fs_root.path());
+        }
+
+        let list: Vec<String> = list_installed(&PackageFilter::new(), Some(fs_root.path()))
+            .unwrap()
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            list,
+            vec![
+                "core/redis/develop/20180701000000",
+                "core/redis/master/20180601000000",
+                "core/redis/master/20180701000000",
+                "core/redis/3.2.4/20170514150022",
+                "core/redis/4.0.10/20180801000000",
+            ]
+        );
+    }
+
     #[test]
     fn create_temp_package_directory_in_same_parentdir() {
         let p = Path::new("/tmp/foo");
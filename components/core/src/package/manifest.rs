@@ -0,0 +1,905 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signed manifests of the files in an installed package.
+//!
+//! A manifest records the path, mode, size, and BLAKE2b hash of every file under a package's
+//! installed path and is signed with an origin key, so what is under `pkgs/` can be audited after
+//! installation. It is kept in the package's `FILES` metafile with this format:
+//!
+//! ```text
+//! FILES-1
+//! core/redis/4.0.10/20180608202239
+//! core-20180119235000
+//! BLAKE2b
+//! signed BLAKE2b signature
+//!
+//! 755 1207824 20590a52c4f00588c500328b16d466c982a26fabaa5fa4dcc83052dd0a84f233 bin/redis-server
+//! 644 31 b80c4f412f9a0a7727b6e6f115e1b5fa3bae79ad2fcf47f769ed4e42cfb12265 IDENT
+//! ```
+//!
+//! The signature covers the BLAKE2b hash of the package identifier line and every entry line, so
+//! a manifest can't be moved onto another package. Directories aren't listed and symbolic links
+//! are recorded with the hash of their target path and a size of zero. Paths are written with
+//! `%`, whitespace, control characters and every byte outside of ASCII percent-encoded, so any
+//! path fits on one line and is read back exactly.
+//!
+//! Packages built by the Habitat build program don't have a `FILES` manifest. Their `MANIFEST`
+//! metafile ends with the SHA-256 checksum of every regular file the package was built with, and
+//! `verify_install` checks an installed package against those when it has no `FILES` manifest:
+//!
+//! ```text
+//! Files
+//! -----
+//! 4cc8037f90192a8eecdb9b386a289d35be3c8cd7f92bd6b1d0e2d783dea592c6  /hab/pkgs/core/redis/4.0.10/20180608202239/IDENT
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::{self as stdfs, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+
+use base64;
+
+use super::metadata::{read_metafile, MetaFile, INSTALL_METAFILES};
+use super::{FullyQualifiedPackageIdent, PackageIdent, PackageInstall};
+use crypto::hash::{hash_bytes_with, hash_file_with, HashAlgorithm};
+use crypto::keys::{parse_name_with_rev, FsKeyCache, KeyCache};
+use crypto::provider::{CryptoProvider, SodiumCryptoProvider};
+use crypto::{secure_eq, SigKeyPair, SIG_HASH_TYPE};
+use error::{Error, Result};
+use fs;
+
+pub static FILE_MANIFEST_FORMAT_VERSION: &'static str = "FILES-1";
+
+/// A single file recorded in a `FileManifest`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    /// The path of the file, relative to the package's installed path
+    pub path: PathBuf,
+    /// The permission bits of the file
+    pub mode: u32,
+    pub size: u64,
+    /// The BLAKE2b hash of the file's content, or of a symbolic link's target. Files checked
+    /// against the checksums in a package's `MANIFEST` have their SHA-256 hash instead.
+    pub hash: String,
+}
+
+impl ManifestEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{:o} {} {} {}",
+            self.mode,
+            self.size,
+            self.hash,
+            escape_path(&self.path)
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let mut parts = line.splitn(4, ' ');
+        let mode = parts.next().and_then(|m| u32::from_str_radix(m, 8).ok());
+        let size = parts.next().and_then(|s| s.parse::<u64>().ok());
+        let hash = parts.next();
+        let path = parts.next();
+        match (mode, size, hash, path) {
+            (Some(mode), Some(size), Some(hash), Some(path)) => Ok(ManifestEntry {
+                path: unescape_path(path)?,
+                mode: mode,
+                size: size,
+                hash: hash.to_string(),
+            }),
+            _ => Err(Error::MetaFileMalformed(MetaFile::Files)),
+        }
+    }
+}
+
+/// The files of an installed package, ordered by path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileManifest {
+    pub ident: PackageIdent,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl FileManifest {
+    /// Builds a manifest by hashing every file under the package's installed path, leaving out
+    /// the `FILES` metafile itself and the metafiles written when the package is installed.
+    pub fn generate(install: &PackageInstall) -> Result<Self> {
+        Self::generate_with(install, &SodiumCryptoProvider)
+    }
+
+    /// Builds a manifest as `generate` does, hashing files with the given `CryptoProvider`.
+    pub fn generate_with<C>(install: &PackageInstall, provider: &C) -> Result<Self>
+    where
+        C: CryptoProvider + ?Sized,
+    {
+        let mut entries = Vec::new();
+        collect_entries(
+            install.installed_path(),
+            install.installed_path(),
+            provider,
+            &mut entries,
+        )?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(FileManifest {
+            ident: install.ident().clone(),
+            entries: entries,
+        })
+    }
+
+    /// Returns the manifest in its signed text form, signed with the given origin key.
+    pub fn sign(&self, pair: &SigKeyPair) -> Result<String> {
+        self.sign_with(pair, &SodiumCryptoProvider)
+    }
+
+    /// Returns the manifest in its signed text form, signed with the given `CryptoProvider`.
+    pub fn sign_with<C>(&self, pair: &SigKeyPair, provider: &C) -> Result<String>
+    where
+        C: CryptoProvider + ?Sized,
+    {
+        let body = self.signed_body();
+        let signature = provider.sign(provider.hash_bytes(body.as_bytes())?.as_bytes(), pair)?;
+        Ok(format!(
+            "{}\n{}\n{}\n{}\n\n{}\n",
+            FILE_MANIFEST_FORMAT_VERSION,
+            pair.name_with_rev(),
+            SIG_HASH_TYPE,
+            base64::encode(&signature),
+            body
+        ))
+    }
+
+    /// Parses a signed manifest without verifying its signature, for reading what a package
+    /// which has already been verified contains.
+    pub fn parse(content: &str) -> Result<Self> {
+        Self::parse_signed(content).map(|(_, _, manifest)| manifest)
+    }
+
+    /// Parses a signed manifest and verifies its signature with the origin keys found in
+    /// `cache_key_path`, refusing a key which has been revoked there. Returns the name with revision of the signing key and the manifest.
+    pub fn verify<P>(content: &str, cache_key_path: P) -> Result<(String, Self)>
+    where
+        P: AsRef<Path>,
+    {
+        Self::verify_with(content, cache_key_path, &SodiumCryptoProvider)
+    }
+
+    /// Parses and verifies a signed manifest as `verify` does, with the given `CryptoProvider`.
+    pub fn verify_with<P, C>(
+        content: &str,
+        cache_key_path: P,
+        provider: &C,
+    ) -> Result<(String, Self)>
+    where
+        P: AsRef<Path>,
+        C: CryptoProvider + ?Sized,
+    {
+        let (key_name, signature, manifest) = Self::parse_signed(content)?;
+        FsKeyCache::new(cache_key_path.as_ref())
+            .revocation_list()?
+            .check(&key_name)?;
+        let pair = SigKeyPair::get_pair_for(&key_name, cache_key_path.as_ref())?;
+        let signed_hash = provider.verify(signature.as_slice(), &pair)?;
+        let computed_hash = provider.hash_bytes(manifest.signed_body().as_bytes())?;
+        if !secure_eq(&signed_hash, &computed_hash) {
+            return Err(Error::CryptoError(format!(
+                "Manifest for {} is invalid, hashes don't match (computed: {})",
+                manifest.ident, computed_hash
+            )));
+        }
+        Ok((key_name, manifest))
+    }
+
+    /// Splits a signed manifest into the name with revision of its signing key, its signature
+    /// and the manifest itself.
+    fn parse_signed(content: &str) -> Result<(String, Vec<u8>, Self)> {
+        let mut lines = content.lines();
+        let mut next_line = || {
+            lines
+                .next()
+                .map(|l| l.trim())
+                .ok_or_else(|| Error::MetaFileMalformed(MetaFile::Files))
+        };
+        let version = next_line()?;
+        if version != FILE_MANIFEST_FORMAT_VERSION {
+            return Err(Error::CryptoError(format!(
+                "Unsupported manifest format version: {}",
+                version
+            )));
+        }
+        let key_name = next_line()?.to_string();
+        parse_name_with_rev(&key_name)?;
+        let hash_type = next_line()?;
+        if hash_type != SIG_HASH_TYPE {
+            return Err(Error::CryptoError(format!(
+                "Unsupported signature type: {}",
+                hash_type
+            )));
+        }
+        let signature = base64::decode(next_line()?)
+            .map_err(|e| Error::CryptoError(format!("Can't decode signature: {}", e)))?;
+        if !next_line()?.is_empty() {
+            return Err(Error::MetaFileMalformed(MetaFile::Files));
+        }
+        let ident = PackageIdent::from_str(next_line()?)?;
+        let mut entries = Vec::new();
+        while let Ok(line) = next_line() {
+            if !line.is_empty() {
+                entries.push(ManifestEntry::from_line(line)?);
+            }
+        }
+        let manifest = FileManifest {
+            ident: ident,
+            entries: entries,
+        };
+        Ok((key_name, signature, manifest))
+    }
+
+    /// Compares this manifest with another of the same or a different release of a package,
+    /// matching entries by path.
+    pub fn diff(&self, other: &FileManifest) -> ManifestDiff {
+        let ours: BTreeMap<&Path, &ManifestEntry> =
+            self.entries.iter().map(|e| (e.path.as_path(), e)).collect();
+        let theirs: BTreeMap<&Path, &ManifestEntry> = other
+            .entries
+            .iter()
+            .map(|e| (e.path.as_path(), e))
+            .collect();
+        let mut diff = ManifestDiff::default();
+        for (path, entry) in &ours {
+            match theirs.get(path) {
+                Some(other) if entry != other => diff.changed.push(ModifiedFile {
+                    expected: (*entry).clone(),
+                    actual: (*other).clone(),
+                }),
+                Some(_) => {}
+                None => diff.removed.push((*entry).clone()),
+            }
+        }
+        for (path, entry) in &theirs {
+            if !ours.contains_key(path) {
+                diff.added.push((*entry).clone());
+            }
+        }
+        diff
+    }
+
+    /// Returns a hash of the manifest's package identifier and entries, which identifies the
+    /// content of the package as a whole.
+    ///
+    /// # Failures
+    ///
+    /// * The algorithm isn't supported by this build
+    pub fn digest(&self, algorithm: HashAlgorithm) -> Result<String> {
+        hash_bytes_with(self.signed_body().as_bytes(), algorithm)
+    }
+
+    fn signed_body(&self) -> String {
+        let mut body = self.ident.to_string();
+        for entry in &self.entries {
+            body.push('\n');
+            body.push_str(&entry.to_line());
+        }
+        body
+    }
+}
+
+/// A file whose size, mode, or content differs between two manifests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModifiedFile {
+    pub expected: ManifestEntry,
+    pub actual: ManifestEntry,
+}
+
+/// The differences between two manifests, as returned by `FileManifest::diff`. Every list is
+/// ordered by path.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ManifestDiff {
+    /// Files which are only in the other manifest
+    pub added: Vec<ManifestEntry>,
+    /// Files which are only in this manifest
+    pub removed: Vec<ManifestEntry>,
+    /// Files in both manifests which differ, as they are in this manifest and in the other
+    pub changed: Vec<ModifiedFile>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The outcome of checking an installed package's files against its signed manifest, as
+/// returned by `PackageInstall::verify`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InstallVerification {
+    /// The name with revision of the key which signed the manifest, or `None` if the package was
+    /// checked against the checksums in its `MANIFEST`, which were only covered by the artifact's
+    /// signature when it was installed
+    pub signer: Option<String>,
+    /// Files in the manifest which are no longer installed
+    pub missing: Vec<ManifestEntry>,
+    /// Files which no longer match the manifest
+    pub modified: Vec<ModifiedFile>,
+    /// Files which are installed but aren't in the manifest
+    pub unexpected: Vec<ManifestEntry>,
+}
+
+impl InstallVerification {
+    /// Returns whether the installed files match the manifest exactly.
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Re-hashes an installed package's files and compares them with its signed `FILES` manifest,
+/// whose signature is verified with the origin keys found in `cache_key_path`. A package without
+/// a `FILES` manifest is compared with the checksums in its `MANIFEST` instead.
+pub fn verify_install<P>(install: &PackageInstall, cache_key_path: P) -> Result<InstallVerification>
+where
+    P: AsRef<Path>,
+{
+    let (signer, recorded) = match read_signed_manifest(install, cache_key_path) {
+        Ok(signed) => signed,
+        Err(Error::MetaFileNotFound(MetaFile::Files)) => return verify_checksums(install),
+        Err(e) => return Err(e),
+    };
+    let diff = recorded.diff(&FileManifest::generate(install)?);
+    Ok(InstallVerification {
+        signer: Some(signer),
+        missing: diff.removed,
+        modified: diff.changed,
+        unexpected: diff.added,
+    })
+}
+
+/// Compares an installed package's regular files with the SHA-256 checksums in the `Files`
+/// section of its `MANIFEST`. Entries only carry a checksum, so a modified file is reported with
+/// its installed mode and size on both sides and a missing one with a mode and size of zero.
+/// Symbolic links aren't checksummed when a package is built, so they aren't reported as
+/// unexpected.
+fn verify_checksums(install: &PackageInstall) -> Result<InstallVerification> {
+    let root = install.installed_path();
+    let recorded = read_checksums(install)?;
+    let mut verification = InstallVerification {
+        signer: None,
+        missing: Vec::new(),
+        modified: Vec::new(),
+        unexpected: Vec::new(),
+    };
+    for (path, hash) in &recorded {
+        let metadata = match stdfs::symlink_metadata(root.join(path)) {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                verification.missing.push(ManifestEntry {
+                    path: path.clone(),
+                    mode: 0,
+                    size: 0,
+                    hash: hash.clone(),
+                });
+                continue;
+            }
+            Err(e) => return Err(Error::from(e)),
+        };
+        let actual = checksum_entry(root, path, &metadata)?;
+        if &actual.hash != hash {
+            verification.modified.push(ModifiedFile {
+                expected: ManifestEntry {
+                    hash: hash.clone(),
+                    ..actual.clone()
+                },
+                actual: actual,
+            });
+        }
+    }
+    let mut installed = Vec::new();
+    collect_files(root, root, &mut installed)?;
+    installed.sort();
+    for path in installed {
+        if !recorded.contains_key(&path) {
+            let metadata = stdfs::symlink_metadata(root.join(&path))?;
+            verification
+                .unexpected
+                .push(checksum_entry(root, &path, &metadata)?);
+        }
+    }
+    Ok(verification)
+}
+
+/// Reads the checksums in the `Files` section of an installed package's `MANIFEST`, by path
+/// relative to the package's installed path.
+fn read_checksums(install: &PackageInstall) -> Result<BTreeMap<PathBuf, String>> {
+    let content = read_metafile(install.installed_path(), &MetaFile::Manifest)?;
+    let ident = FullyQualifiedPackageIdent::try_from(install.ident().clone())?;
+    let prefix = fs::pkg_install_path(&ident, None::<&Path>);
+    let lines: Vec<&str> = content.lines().map(|l| l.trim_right()).collect();
+    // The section is the last one, after the plan, which could have a heading of its own
+    let start = lines
+        .windows(2)
+        .rposition(|w| w[0] == "Files" && w[1] == "-----")
+        .ok_or_else(|| Error::MetaFileMalformed(MetaFile::Manifest))?;
+    let mut checksums = BTreeMap::new();
+    for line in lines[start + 2..].iter().filter(|l| !l.is_empty()) {
+        let mut parts = line.splitn(2, "  ");
+        let (hash, path) = match (parts.next(), parts.next()) {
+            (Some(hash), Some(path)) => (hash, Path::new(path)),
+            _ => return Err(Error::MetaFileMalformed(MetaFile::Manifest)),
+        };
+        let path = path
+            .strip_prefix(&prefix)
+            .map_err(|_| Error::MetaFileMalformed(MetaFile::Manifest))?;
+        checksums.insert(path.to_path_buf(), hash.to_lowercase());
+    }
+    Ok(checksums)
+}
+
+/// Returns an entry for an installed file with its SHA-256 hash. Anything but a regular file has
+/// no hash, so it never matches a recorded checksum.
+fn checksum_entry(root: &Path, path: &Path, metadata: &Metadata) -> Result<ManifestEntry> {
+    let (size, hash) = if metadata.is_file() {
+        (
+            metadata.len(),
+            hash_file_with(root.join(path), HashAlgorithm::Sha256)?,
+        )
+    } else {
+        (0, String::new())
+    };
+    Ok(ManifestEntry {
+        path: path.to_path_buf(),
+        mode: file_mode(metadata),
+        size: size,
+        hash: hash,
+    })
+}
+
+/// Lists the regular files under an installed package's path, relative to it, leaving out the
+/// `MANIFEST` and the metafiles written when the package is installed.
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for dir_entry in stdfs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let metadata = stdfs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            collect_files(root, &path, files)?;
+            continue;
+        }
+        let relative = path
+            .strip_prefix(root)
+            .expect("Entry is under the directory being walked")
+            .to_path_buf();
+        if !metadata.is_file()
+            || relative == Path::new(&MetaFile::Manifest.to_string())
+            || INSTALL_METAFILES
+                .iter()
+                .any(|m| relative == Path::new(&m.to_string()))
+        {
+            continue;
+        }
+        files.push(relative);
+    }
+    Ok(())
+}
+
+/// Generates a manifest of an installed package's files, signs it with the given origin key and
+/// writes it to the package's `FILES` metafile.
+pub fn write_signed_manifest(install: &PackageInstall, pair: &SigKeyPair) -> Result<FileManifest> {
+    let manifest = FileManifest::generate(install)?;
+    fs::atomic_write(
+        install.installed_path().join(MetaFile::Files.to_string()),
+        manifest.sign(pair)?.as_bytes(),
+        0o644,
+    )?;
+    Ok(manifest)
+}
+
+/// Reads an installed package's `FILES` metafile and verifies its signature with the origin keys
+/// found in `cache_key_path`. Returns the name with revision of the signing key and the manifest.
+pub fn read_signed_manifest<P>(
+    install: &PackageInstall,
+    cache_key_path: P,
+) -> Result<(String, FileManifest)>
+where
+    P: AsRef<Path>,
+{
+    let content = read_metafile(install.installed_path(), &MetaFile::Files)?;
+    let (signer, manifest) = FileManifest::verify(&content, cache_key_path)?;
+    if &manifest.ident != install.ident() {
+        return Err(Error::CryptoError(format!(
+            "Manifest is for {}, not {}",
+            manifest.ident,
+            install.ident()
+        )));
+    }
+    Ok((signer, manifest))
+}
+
+fn collect_entries<C>(
+    root: &Path,
+    dir: &Path,
+    provider: &C,
+    entries: &mut Vec<ManifestEntry>,
+) -> Result<()>
+where
+    C: CryptoProvider + ?Sized,
+{
+    for dir_entry in stdfs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let metadata = stdfs::symlink_metadata(&path)?;
+        let relative = path
+            .strip_prefix(root)
+            .expect("Entry is under the directory being walked")
+            .to_path_buf();
+        if metadata.is_dir() {
+            collect_entries(root, &path, provider, entries)?;
+            continue;
+        }
+        if relative == Path::new(&MetaFile::Files.to_string())
+            || INSTALL_METAFILES
+                .iter()
+                .any(|m| relative == Path::new(&m.to_string()))
+        {
+            continue;
+        }
+        let (size, hash) = if metadata.file_type().is_symlink() {
+            let target = stdfs::read_link(&path)?;
+            (0, provider.hash_bytes(target.to_string_lossy().as_bytes())?)
+        } else {
+            (metadata.len(), provider.hash_file(&path)?)
+        };
+        entries.push(ManifestEntry {
+            path: relative,
+            mode: file_mode(&metadata),
+            size: size,
+            hash: hash,
+        });
+    }
+    Ok(())
+}
+
+/// Percent-encodes the bytes of a path which can't be written as they are on a manifest line.
+fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for &b in path_bytes(path).iter() {
+        if b <= b' ' || b >= 0x7f || b == b'%' {
+            escaped.push_str(&format!("%{:02X}", b));
+        } else {
+            escaped.push(b as char);
+        }
+    }
+    escaped
+}
+
+fn unescape_path(escaped: &str) -> Result<PathBuf> {
+    let bytes = escaped.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1..i + 3) {
+            Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => {
+                let hex = str::from_utf8(hex).expect("Hex digits are ASCII");
+                unescaped.push(u8::from_str_radix(hex, 16).expect("Hex digits parse"));
+                i += 3;
+            }
+            _ => return Err(Error::MetaFileMalformed(MetaFile::Files)),
+        }
+    }
+    path_from_bytes(unescaped)
+}
+
+#[cfg(not(windows))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// Windows paths are written as UTF-8, which every path of a package is in practice.
+#[cfg(windows)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(windows))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(windows)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| Error::MetaFileMalformed(MetaFile::Files))
+}
+
+/// Returns the permission bits of a file, as they are recorded in a manifest.
+#[cfg(not(windows))]
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Returns the permission bits of a file, as they are recorded in a manifest.
+#[cfg(windows)]
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use tempfile::Builder;
+
+    use super::super::test_support::testing_package_install;
+    use super::*;
+    use crypto::hash;
+    use crypto::provider::FakeCryptoProvider;
+
+    fn write_file(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut f = File::create(path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn signed_manifest_round_trip() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+
+        let written = write_signed_manifest(&install, &pair).unwrap();
+        let (signer, manifest) = read_signed_manifest(&install, cache.path()).unwrap();
+
+        assert_eq!(signer, pair.name_with_rev());
+        assert_eq!(manifest, written);
+        let entry = manifest
+            .entries
+            .iter()
+            .find(|e| e.path == Path::new("bin/redis-server"))
+            .unwrap();
+        assert_eq!(entry.size, 6);
+        assert_eq!(entry.hash, hash::hash_string("binary"));
+        assert!(!manifest
+            .entries
+            .iter()
+            .any(|e| e.path == Path::new("FILES")));
+    }
+
+    #[test]
+    fn entry_paths_round_trip() {
+        let entry = ManifestEntry {
+            path: PathBuf::from("share/100% new\nline "),
+            mode: 0o644,
+            size: 0,
+            hash: hash::hash_string(""),
+        };
+        let line = entry.to_line();
+        assert!(line.ends_with(" share/100%25%20new%0Aline%20"));
+        assert_eq!(ManifestEntry::from_line(&line).unwrap(), entry);
+        assert!(ManifestEntry::from_line("644 0 abc share/100%2").is_err());
+        assert!(ManifestEntry::from_line("644 0 abc share/%+1").is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn signed_manifest_round_trip_with_unusual_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        let share = install.installed_path().join("share");
+        let names: Vec<&OsStr> = vec![
+            OsStr::from_bytes(b"caf\xe9"),
+            OsStr::new("new\nline"),
+            OsStr::new(" spaced "),
+        ];
+        for name in &names {
+            write_file(&share.join(name), "data");
+        }
+
+        let written = write_signed_manifest(&install, &pair).unwrap();
+        let (_, manifest) = read_signed_manifest(&install, cache.path()).unwrap();
+
+        assert_eq!(manifest, written);
+        for name in &names {
+            assert!(manifest
+                .entries
+                .iter()
+                .any(|e| e.path == Path::new("share").join(name)));
+        }
+        assert!(install.verify(cache.path()).unwrap().is_intact());
+    }
+
+    #[test]
+    #[should_panic(expected = "hashes don't match")]
+    fn tampered_manifest_fails_verification() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+
+        let signed = FileManifest::generate(&install)
+            .unwrap()
+            .sign(&pair)
+            .unwrap();
+        let tampered = signed.replace(&hash::hash_string("binary"), &hash::hash_string("evil"));
+        FileManifest::verify(&tampered, cache.path()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "has been revoked")]
+    fn manifest_signed_by_revoked_key_fails_verification() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+        write_signed_manifest(&install, &pair).unwrap();
+        let key_cache = FsKeyCache::new(cache.path());
+        let mut revoked = key_cache.revocation_list().unwrap();
+        revoked.revoke(&pair.name_with_rev()).unwrap();
+        key_cache.write_revocation_list(&revoked).unwrap();
+
+        install.verify(cache.path()).unwrap();
+    }
+
+    #[test]
+    fn verify_install_reports_changed_files() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+        write_file(&install.installed_path().join("bin/redis-cli"), "binary");
+        write_signed_manifest(&install, &pair).unwrap();
+        assert!(install.verify(cache.path()).unwrap().is_intact());
+
+        write_file(&install.installed_path().join("bin/redis-server"), "evil");
+        fs::remove_file(install.installed_path().join("bin/redis-cli")).unwrap();
+        write_file(&install.installed_path().join("bin/backdoor"), "evil");
+        let report = install.verify(cache.path()).unwrap();
+
+        assert_eq!(report.signer, Some(pair.name_with_rev()));
+        assert!(!report.is_intact());
+        assert_eq!(
+            report.missing.iter().map(|e| &e.path).collect::<Vec<_>>(),
+            vec![Path::new("bin/redis-cli")]
+        );
+        assert_eq!(report.modified.len(), 1);
+        assert_eq!(
+            report.modified[0].actual.path,
+            Path::new("bin/redis-server")
+        );
+        assert_eq!(
+            report.modified[0].expected.hash,
+            hash::hash_string("binary")
+        );
+        assert_eq!(report.modified[0].actual.hash, hash::hash_string("evil"));
+        assert_eq!(
+            report
+                .unexpected
+                .iter()
+                .map(|e| &e.path)
+                .collect::<Vec<_>>(),
+            vec![Path::new("bin/backdoor")]
+        );
+    }
+
+    #[test]
+    fn verify_install_without_files_manifest_checks_manifest_checksums() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/redis/4.0.10/20180608202239", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+        write_file(&install.installed_path().join("bin/redis-cli"), "binary");
+        write_file(
+            &install.installed_path().join("MANIFEST"),
+            "core redis\n\
+             ==========\n\
+             \n\
+             Files\n\
+             -----\n\
+             9a3a45d01531a20e89ac6ae10b0b0beb0492acd7216a368aa062d1a5fecaf9cd  \
+             /hab/pkgs/core/redis/4.0.10/20180608202239/bin/redis-cli\n\
+             9a3a45d01531a20e89ac6ae10b0b0beb0492acd7216a368aa062d1a5fecaf9cd  \
+             /hab/pkgs/core/redis/4.0.10/20180608202239/bin/redis-server\n",
+        );
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let report = install.verify(cache.path()).unwrap();
+        assert_eq!(report.signer, None);
+        assert_eq!(
+            report
+                .unexpected
+                .iter()
+                .map(|e| &e.path)
+                .collect::<Vec<_>>(),
+            vec![Path::new("IDENT"), Path::new("TARGET")]
+        );
+
+        write_file(&install.installed_path().join("bin/redis-server"), "evil");
+        fs::remove_file(install.installed_path().join("bin/redis-cli")).unwrap();
+        let report = install.verify(cache.path()).unwrap();
+
+        assert_eq!(
+            report.missing.iter().map(|e| &e.path).collect::<Vec<_>>(),
+            vec![Path::new("bin/redis-cli")]
+        );
+        assert_eq!(report.modified.len(), 1);
+        assert_eq!(
+            report.modified[0].expected.hash,
+            "9a3a45d01531a20e89ac6ae10b0b0beb0492acd7216a368aa062d1a5fecaf9cd"
+        );
+        assert_eq!(
+            report.modified[0].actual.hash,
+            "b5c1fb2efc6d6b4674c2fdcc48ce01b43a3b7c03763c0c3355de0099ee0f8c73"
+        );
+    }
+
+    #[test]
+    fn verify_install_without_any_manifest_fails() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+
+        match install.verify(cache.path()) {
+            Err(Error::MetaFileNotFound(MetaFile::Manifest)) => (),
+            other => panic!("Wrong result returned, result={:?}", other),
+        }
+    }
+
+    #[test]
+    fn generate_and_sign_with_fake_provider() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let install = testing_package_install("core/redis", fs_root.path());
+        write_file(&install.installed_path().join("bin/redis-server"), "binary");
+        let pair = SigKeyPair::new("core".to_string(), "20180119235000".to_string(), None, None);
+        let provider = FakeCryptoProvider;
+
+        let manifest = FileManifest::generate_with(&install, &provider).unwrap();
+        let entry = manifest
+            .entries
+            .iter()
+            .find(|e| e.path == Path::new("bin/redis-server"))
+            .unwrap();
+        assert_eq!(entry.hash, provider.hash_bytes(b"binary").unwrap());
+        assert_eq!(
+            manifest.sign_with(&pair, &provider).unwrap(),
+            manifest.sign_with(&pair, &provider).unwrap()
+        );
+    }
+}
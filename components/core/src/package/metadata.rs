@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std;
-use std::collections::HashMap;
+use std::collections::{btree_map, BTreeMap, HashMap};
 use std::env;
 use std::fmt;
 use std::fs::File;
@@ -24,13 +24,14 @@ use std::str::FromStr;
 use std::vec::IntoIter;
 
 use error::{Error, Result};
+use fs;
 use package::PackageIdent;
 
 #[cfg(not(windows))]
-const ENV_PATH_SEPARATOR: char = ':';
+pub const ENV_PATH_SEPARATOR: char = ':';
 
 #[cfg(windows)]
-const ENV_PATH_SEPARATOR: char = ';';
+pub const ENV_PATH_SEPARATOR: char = ';';
 
 pub fn parse_key_value(s: &str) -> Result<HashMap<String, String>> {
     Ok(HashMap::from_iter(
@@ -40,9 +41,68 @@ pub fn parse_key_value(s: &str) -> Result<HashMap<String, String>> {
     ))
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// Returns the licenses listed on the `* __License__:` line of a package's `MANIFEST` metafile,
+/// which are the `pkg_license` of its plan.
+pub fn parse_licenses(manifest: &str) -> Vec<String> {
+    manifest
+        .lines()
+        .filter_map(|line| line.trim().splitn(2, "__License__:").nth(1))
+        .flat_map(|licenses| licenses.split_whitespace())
+        .map(|license| license.to_string())
+        .collect()
+}
+
+/// The licenses a package declares, as returned by `PackageInstall::licenses`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PackageLicenses {
+    pub ident: PackageIdent,
+    /// The licenses, as SPDX license identifiers where the plan used them. Empty if the package
+    /// declares none.
+    pub licenses: Vec<String>,
+}
+
+/// A value from a package's configuration which it exports to the services bound to it, as
+/// listed in the `EXPORTS` metafile.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Export {
+    /// The name bound services know the value by
+    pub name: String,
+    /// The key of the value in the package's configuration, such as `server.port`
+    pub config_path: String,
+}
+
+impl FromStr for Export {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(config_path)) if !name.is_empty() && !config_path.is_empty() => {
+                Ok(Export {
+                    name: name.to_string(),
+                    config_path: config_path.to_string(),
+                })
+            }
+            _ => Err(Error::MetaFileMalformed(MetaFile::Exports)),
+        }
+    }
+}
+
+impl fmt::Display for Export {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.config_path)
+    }
+}
+
+/// A bind a package's service declares, as listed in the `BINDS` and `BINDS_OPTIONAL` metafiles.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Bind {
-    pub service: String,
+    /// The name of the bind
+    pub name: String,
+    /// Whether the service can't start until the bind is satisfied. A bind parsed from a line
+    /// is required unless it is marked otherwise, as binds read from `BINDS_OPTIONAL` are.
+    pub required: bool,
+    /// The exports a service must provide to satisfy the bind
     pub exports: Vec<String>,
 }
 
@@ -51,16 +111,17 @@ impl FromStr for Bind {
 
     fn from_str(line: &str) -> Result<Self> {
         let mut parts = line.split('=');
-        let service = match parts.next() {
+        let name = match parts.next() {
             None => return Err(Error::MetaFileBadBind),
-            Some(service) => service.to_string(),
+            Some(name) => name.to_string(),
         };
         let exports = match parts.next() {
             None => return Err(Error::MetaFileBadBind),
-            Some(exports) => exports.split(' ').map(|t| t.to_string()).collect(),
+            Some(exports) => exports.split_whitespace().map(|t| t.to_string()).collect(),
         };
         Ok(Bind {
-            service: service,
+            name: name,
+            required: true,
             exports: exports,
         })
     }
@@ -69,7 +130,7 @@ impl FromStr for Bind {
 impl fmt::Display for Bind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let formatted_exports = self.exports.join(" ");
-        write!(f, "[{}]={}", self.service, formatted_exports)
+        write!(f, "[{}]={}", self.name, formatted_exports)
     }
 }
 
@@ -103,7 +164,7 @@ impl FromStr for BindMapping {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct EnvVar {
     pub key: String,
     pub value: String,
@@ -165,23 +226,70 @@ impl IntoIterator for PkgEnv {
     }
 }
 
+/// The full environment a package's programs run in, by variable name, as composed by
+/// `PackageInstall::runtime_env`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RuntimeEnv {
+    vars: BTreeMap<String, EnvVar>,
+}
+
+impl RuntimeEnv {
+    pub fn get(&self, key: &str) -> Option<&EnvVar> {
+        self.vars.get(key)
+    }
+
+    /// Sets a variable, replacing any value it had before.
+    pub fn insert(&mut self, var: EnvVar) {
+        self.vars.insert(var.key.clone(), var);
+    }
+
+    /// Returns the entries of the `PATH` variable, in order of precedence.
+    pub fn path(&self) -> Vec<PathBuf> {
+        match self.vars.get("PATH") {
+            Some(var) => env::split_paths(&var.value).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Iterates over the variables, ordered by name.
+    pub fn iter(&self) -> btree_map::Values<String, EnvVar> {
+        self.vars.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Returns the variables as plain names and values, ready to hand to a child process.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.vars
+            .values()
+            .map(|var| (var.key.clone(), var.value.clone()))
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum MetaFile {
     BindMap, // Composite-only
     Binds,
     BindsOptional,
     CFlags,
+    Channel, // Written at install time
     Config,
     Deps,
     Environment,
     EnvironmentSep,
     Exports,
     Exposes,
+    Files,
     Ident,
+    InstallHookStatus, // Written at install time
     LdFlags,
     LdRunPath,
     Manifest,
     Path,
+    PostInstallHookStatus, // Written at install time
     ResolvedServices, // Composite-only
     RuntimeEnvironment,
     RuntimePath,
@@ -200,17 +308,21 @@ impl fmt::Display for MetaFile {
             MetaFile::Binds => "BINDS",
             MetaFile::BindsOptional => "BINDS_OPTIONAL",
             MetaFile::CFlags => "CFLAGS",
+            MetaFile::Channel => "CHANNEL",
             MetaFile::Config => "default.toml",
             MetaFile::Deps => "DEPS",
             MetaFile::Environment => "ENVIRONMENT",
             MetaFile::EnvironmentSep => "ENVIRONMENT_SEP",
             MetaFile::Exports => "EXPORTS",
             MetaFile::Exposes => "EXPOSES",
+            MetaFile::Files => "FILES",
             MetaFile::Ident => "IDENT",
+            MetaFile::InstallHookStatus => "INSTALL_HOOK_STATUS",
             MetaFile::LdFlags => "LDFLAGS",
             MetaFile::LdRunPath => "LD_RUN_PATH",
             MetaFile::Manifest => "MANIFEST",
             MetaFile::Path => "PATH",
+            MetaFile::PostInstallHookStatus => "POST_INSTALL_HOOK_STATUS",
             MetaFile::ResolvedServices => "RESOLVED_SERVICES",
             MetaFile::RuntimeEnvironment => "RUNTIME_ENVIRONMENT",
             MetaFile::RuntimePath => "RUNTIME_PATH",
@@ -225,6 +337,14 @@ impl fmt::Display for MetaFile {
     }
 }
 
+/// Metafiles which are written into a package's installed path when it is installed, rather than
+/// being part of the package as it was built
+pub const INSTALL_METAFILES: [MetaFile; 3] = [
+    MetaFile::Channel,
+    MetaFile::InstallHookStatus,
+    MetaFile::PostInstallHookStatus,
+];
+
 /// Read a metadata file from within a package directory if it exists
 ///
 /// Returns the contents of the file
@@ -248,7 +368,7 @@ pub fn read_metafile<P: AsRef<Path>>(installed_path: P, file: &MetaFile) -> Resu
 ///
 /// Useful for fallback logic for dealing with older Habitat packages.
 fn existing_metafile<P: AsRef<Path>>(installed_path: P, file: &MetaFile) -> Option<PathBuf> {
-    let filepath = installed_path.as_ref().join(file.to_string());
+    let filepath = fs::long_path(installed_path.as_ref().join(file.to_string()));
     match std::fs::metadata(&filepath) {
         Ok(_) => Some(filepath),
         Err(_) => None,
@@ -417,6 +537,39 @@ port=front-end.port
         assert!(output.is_err());
     }
 
+    #[test]
+    fn can_parse_licenses() {
+        let manifest = "# core / glibc\n\n* __Maintainer__: The Habitat Maintainers\n\
+                        * __License__: GPL-2.0 LGPL-2.1\n";
+        assert_eq!(parse_licenses(manifest), vec!["GPL-2.0", "LGPL-2.1"]);
+        assert!(parse_licenses("# core / glibc\n").is_empty());
+    }
+
+    #[test]
+    fn can_parse_an_export() {
+        let export: Export = "port=server.port".parse().unwrap();
+        assert_eq!(export.name, "port");
+        assert_eq!(export.config_path, "server.port");
+        assert_eq!(export.to_string(), "port=server.port");
+
+        assert!("port".parse::<Export>().is_err());
+        assert!("port=".parse::<Export>().is_err());
+    }
+
+    #[test]
+    fn can_parse_a_bind() {
+        let bind: Bind = "database=port host".parse().unwrap();
+        assert_eq!(
+            bind,
+            Bind {
+                name: "database".to_string(),
+                required: true,
+                exports: vec!["port".to_string(), "host".to_string()],
+            }
+        );
+        assert!("database".parse::<Bind>().is_err());
+    }
+
     #[test]
     fn can_read_metafile() {
         let pkg_root = Builder::new().prefix("pkg-root").tempdir().unwrap();
@@ -13,17 +13,36 @@
 // limitations under the License.
 
 pub mod archive;
+pub mod audit;
+pub mod composite;
+pub mod conflict;
+pub mod constraint;
+pub mod delta;
+pub mod diff;
+pub mod gc;
+pub mod graph;
+pub mod hold;
+pub mod hooks;
 pub mod ident;
+pub mod installer;
 pub mod install;
 pub mod list;
+pub mod manifest;
 pub mod metadata;
+pub mod offline;
 pub mod plan;
+pub mod sbom;
+pub mod store;
 pub mod target;
 
 pub use self::archive::{FromArchive, PackageArchive};
-pub use self::ident::{Identifiable, PackageIdent};
+pub use self::constraint::VersionConstraint;
+pub use self::diff::{diff, PackageDiff};
+pub use self::graph::PackageGraph;
+pub use self::ident::{FullyQualifiedPackageIdent, Identifiable, PackageIdent};
 pub use self::install::PackageInstall;
-pub use self::list::all_packages;
+pub use self::list::{all_packages, list_installed, PackageFilter};
+pub use self::manifest::FileManifest;
 pub use self::plan::Plan;
 pub use self::target::PackageTarget;
 
@@ -70,7 +89,8 @@ pub mod test_support {
                 );
             }
         }
-        let pkg_install_path = fs::pkg_install_path(&pkg_ident, Some(fs_root));
+        let exact = FullyQualifiedPackageIdent::try_from(pkg_ident.clone()).unwrap();
+        let pkg_install_path = fs::pkg_install_path(&exact, Some(fs_root));
 
         create_dir_all(&pkg_install_path).unwrap();
         write_file(
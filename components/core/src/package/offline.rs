@@ -0,0 +1,240 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolution of packages from a local directory of artifacts, for installing where Builder can't
+//! be reached.
+//!
+//! An `OfflineResolver` looks at the metadata inside each .hart file in its directory rather than
+//! trusting file names, only considers artifacts for the active package target, and only hands
+//! out artifacts whose signatures verify with the origin keys it is given. It is also a
+//! `PackageTransport`, so a `ParallelInstaller` can install from it directly.
+
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use super::installer::PackageTransport;
+use super::{Identifiable, PackageArchive, PackageIdent, PackageTarget};
+use error::{Error, Result};
+use fs;
+
+pub struct OfflineResolver {
+    artifact_path: PathBuf,
+    cache_key_path: PathBuf,
+}
+
+impl OfflineResolver {
+    /// Returns a resolver for the artifacts in `artifact_path`, which verifies them with the
+    /// origin keys in `cache_key_path`.
+    pub fn new<P1, P2>(artifact_path: P1, cache_key_path: P2) -> Self
+    where
+        P1: Into<PathBuf>,
+        P2: Into<PathBuf>,
+    {
+        OfflineResolver {
+            artifact_path: artifact_path.into(),
+            cache_key_path: cache_key_path.into(),
+        }
+    }
+
+    /// Returns the identifier and path of every artifact in the directory which was built for the
+    /// active package target, ordered by identifier. Files which can't be read as artifacts, or
+    /// whose identifiers aren't fully qualified, are skipped.
+    pub fn artifacts(&self) -> Result<Vec<(PackageIdent, PathBuf)>> {
+        let mut artifacts = Vec::new();
+        for entry in read_dir(&self.artifact_path)? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().map_or(true, |ext| ext != "hart") {
+                continue;
+            }
+            match PackageArchive::new(path.clone()).metadata() {
+                Ok(ref metadata) if metadata.target == *PackageTarget::active_target() => {
+                    artifacts.push((metadata.ident.clone(), path));
+                }
+                Ok(metadata) => debug!("Skipping {} built for {}", path.display(), metadata.target),
+                Err(e) => debug!("Skipping unreadable artifact {}: {}", path.display(), e),
+            }
+        }
+        Ok(sorted_artifacts(artifacts))
+    }
+
+    /// Returns the artifact of the latest release satisfying `ident` whose signature verifies. A
+    /// later release which fails verification is passed over for an earlier one.
+    ///
+    /// # Failures
+    ///
+    /// * No artifact in the directory satisfies `ident`
+    /// * No artifact satisfying `ident` can be verified, in which case the error from verifying
+    ///   the latest is returned
+    pub fn resolve(&self, ident: &PackageIdent) -> Result<PackageArchive> {
+        let mut candidates: Vec<(PackageIdent, PathBuf)> = self
+            .artifacts()?
+            .into_iter()
+            .filter(|&(ref candidate, _)| candidate.satisfies(ident))
+            .collect();
+        // `artifacts` only returns fully qualified identifiers, which can be ordered
+        candidates.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut first_error = None;
+        for (candidate, path) in candidates {
+            let archive = PackageArchive::new(path);
+            match archive.verify(&self.cache_key_path) {
+                Ok(_) => return Ok(archive),
+                Err(e) => {
+                    debug!("Passing over {} which can't be verified: {}", candidate, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| Error::PackageNotFound(ident.clone())))
+    }
+}
+
+/// Orders artifacts by identifier, leaving out any whose identifier isn't fully qualified. Every
+/// artifact should have a version and release, and identifiers without them can't be ordered.
+fn sorted_artifacts(mut artifacts: Vec<(PackageIdent, PathBuf)>) -> Vec<(PackageIdent, PathBuf)> {
+    artifacts.retain(|&(ref ident, ref path)| {
+        if !ident.fully_qualified() {
+            debug!(
+                "Skipping {}, whose identifier {} isn't fully qualified",
+                path.display(),
+                ident
+            );
+        }
+        ident.fully_qualified()
+    });
+    artifacts.sort();
+    artifacts
+}
+
+impl PackageTransport for OfflineResolver {
+    fn fetch(&self, ident: &PackageIdent, dst_dir: &Path) -> Result<PathBuf> {
+        let archive = self.resolve(ident)?;
+        let dst = dst_dir.join(archive.file_name());
+        if dst != archive.path {
+            fs::copy_cow(&archive.path, &dst)?;
+        }
+        Ok(dst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    use tempfile::Builder;
+
+    use super::*;
+    use crypto::SigKeyPair;
+    use package::FullyQualifiedPackageIdent;
+
+    fn build_hart(ident: &str, pair: &SigKeyPair, dst: &Path) -> PathBuf {
+        let src = Builder::new().prefix("src").tempdir().unwrap();
+        File::create(src.path().join("README"))
+            .unwrap()
+            .write_all(ident.as_bytes())
+            .unwrap();
+        let ident = FullyQualifiedPackageIdent::from_str(ident).unwrap();
+        PackageArchive::build(src.path(), &ident, pair, dst)
+            .unwrap()
+            .path
+    }
+
+    #[test]
+    fn resolve_latest_verifiable_release() {
+        let artifacts = Builder::new().prefix("artifacts").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        let untrusted = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        build_hart("core/redis/3.2.4/20170514150022", &pair, artifacts.path());
+        build_hart("core/redis/4.0.10/20180608202239", &pair, artifacts.path());
+        build_hart(
+            "core/redis/4.0.11/20180801000000",
+            &untrusted,
+            artifacts.path(),
+        );
+        build_hart("core/nginx/1.15.0/20180608040224", &pair, artifacts.path());
+        File::create(artifacts.path().join("junk.hart")).unwrap();
+        let resolver = OfflineResolver::new(artifacts.path(), cache.path());
+
+        assert_eq!(resolver.artifacts().unwrap().len(), 4);
+        let mut latest = resolver
+            .resolve(&PackageIdent::from_str("core/redis").unwrap())
+            .unwrap();
+        assert_eq!(
+            latest.ident().unwrap(),
+            PackageIdent::from_str("core/redis/4.0.10/20180608202239").unwrap()
+        );
+        let mut pinned = resolver
+            .resolve(&PackageIdent::from_str("core/redis/3.2.4").unwrap())
+            .unwrap();
+        assert_eq!(
+            pinned.ident().unwrap(),
+            PackageIdent::from_str("core/redis/3.2.4/20170514150022").unwrap()
+        );
+        match resolver.resolve(&PackageIdent::from_str("core/zlib").unwrap()) {
+            Err(Error::PackageNotFound(_)) => (),
+            other => panic!("expected PackageNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fetch_copies_resolved_artifact() {
+        let artifacts = Builder::new().prefix("artifacts").tempdir().unwrap();
+        let dst = Builder::new().prefix("dst").tempdir().unwrap();
+        let cache = Builder::new().prefix("key_cache").tempdir().unwrap();
+        let pair = SigKeyPair::generate_pair_for_origin("core").unwrap();
+        pair.to_pair_files(cache.path()).unwrap();
+        build_hart("core/redis/4.0.10/20180608202239", &pair, artifacts.path());
+        let resolver = OfflineResolver::new(artifacts.path(), cache.path());
+
+        let fetched = resolver
+            .fetch(
+                &PackageIdent::from_str("core/redis/4.0.10/20180608202239").unwrap(),
+                dst.path(),
+            )
+            .unwrap();
+        assert_eq!(fetched.parent(), Some(dst.path()));
+        PackageArchive::new(fetched).verify(&cache.path()).unwrap();
+    }
+    #[test]
+    fn artifacts_without_a_release_are_skipped() {
+        let artifact = |ident: &str| {
+            (
+                PackageIdent::from_str(ident).unwrap(),
+                PathBuf::from(format!("{}.hart", ident.replace('/', "-"))),
+            )
+        };
+
+        let sorted = sorted_artifacts(vec![
+            artifact("core/redis/4.0.10/20180608202239"),
+            artifact("core/redis"),
+            artifact("core/redis/3.2.4"),
+            artifact("core/redis/3.2.4/20170514150022"),
+        ]);
+
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|&(ref ident, _)| ident.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "core/redis/3.2.4/20170514150022",
+                "core/redis/4.0.10/20180608202239",
+            ]
+        );
+    }
+}
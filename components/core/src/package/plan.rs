@@ -12,60 +12,249 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::BufRead;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
+use super::PackageIdent;
 use error::{Error, Result};
 
+/// The metadata declared at the top of a `plan.sh` or `plan.ps1`.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Plan {
     pub name: String,
     pub origin: String,
     pub version: Option<String>,
+    #[serde(default)]
+    pub deps: Vec<PackageIdent>,
+    #[serde(default)]
+    pub build_deps: Vec<PackageIdent>,
+    /// The configuration keys exported to other services, by export name
+    #[serde(default)]
+    pub exports: BTreeMap<String, String>,
+    #[serde(default)]
+    pub exposes: Vec<String>,
+    /// The exports each required bind needs, by bind name
+    #[serde(default)]
+    pub binds: BTreeMap<String, Vec<String>>,
+    /// The exports each optional bind needs, by bind name
+    #[serde(default)]
+    pub binds_optional: BTreeMap<String, Vec<String>>,
 }
 
 impl Plan {
+    /// Parses the `pkg_*` variables of a plan. Both the Bash syntax of a `plan.sh`, with arrays
+    /// such as `pkg_deps=(core/glibc core/openssl)` and associative arrays such as
+    /// `pkg_exports=([port]=srv.port)`, and the PowerShell syntax of a `plan.ps1`, with arrays
+    /// such as `$pkg_deps=@("core/openssl")` and hashtables such as
+    /// `$pkg_exports=@{port="srv.port"}`, are understood. Arrays may span several lines.
+    ///
+    /// Variables are taken as they are written, without expanding any references to other
+    /// variables or running any of the plan.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let content = String::from_utf8_lossy(bytes);
         let mut name: Option<String> = None;
         let mut origin: Option<String> = None;
-        let mut version: Option<String> = None;
-        for line in bytes.lines() {
-            if let Ok(line) = line {
-                // Rather than just blindly accepting values, let's trim all the
-                // whitespace first, verify that we actually have 2 things separated
-                // by an equal sign, and strip out quotes of any kind.
-                //
-                // To do this properly, we probably need some kind of bash parser,
-                // or a plan file syntax that's in a different language that we do
-                // have a parser for (LUA!), but both of those things are beyond the
-                // scope of this task.
-                let parts: Vec<&str> = line.splitn(2, "=").map(|x| x.trim()).collect();
-
-                if parts.len() != 2 {
-                    continue;
+        let mut plan = Plan {
+            name: String::new(),
+            origin: String::new(),
+            version: None,
+            deps: Vec::new(),
+            build_deps: Vec::new(),
+            exports: BTreeMap::new(),
+            exposes: Vec::new(),
+            binds: BTreeMap::new(),
+            binds_optional: BTreeMap::new(),
+        };
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
+            // To do this properly, we probably need some kind of bash parser, or a plan file
+            // syntax that's in a different language that we do have a parser for (LUA!), but
+            // both of those things are beyond the scope of this task.
+            let (key, mut val) = match assignment(line) {
+                Some(assignment) => assignment,
+                None => continue,
+            };
+            if let Some(close) = compound_close(&val) {
+                while closing_index(&val, close).is_none() {
+                    match lines.next() {
+                        Some(next) => {
+                            val.push('\n');
+                            val.push_str(next);
+                        }
+                        // An unterminated array is only an error if it's one of the variables we read
+                        None => break,
+                    }
                 }
+            }
+
+            match key.as_str() {
+                "pkg_name" => name = Some(scalar(&val)),
+                "pkg_origin" => origin = Some(scalar(&val)),
+                "pkg_version" => plan.version = Some(scalar(&val)),
+                "pkg_deps" => plan.deps = idents(&val)?,
+                "pkg_build_deps" => plan.build_deps = idents(&val)?,
+                "pkg_exports" => plan.exports = table(&val)?,
+                "pkg_exposes" => plan.exposes = words(compound_body(&val)?),
+                "pkg_binds" => plan.binds = binds(&val)?,
+                "pkg_binds_optional" => plan.binds_optional = binds(&val)?,
+                _ => (),
+            }
+        }
+
+        match (name, origin) {
+            (Some(name), Some(origin)) => {
+                plan.name = name;
+                plan.origin = origin;
+                Ok(plan)
+            }
+            _ => Err(Error::PlanMalformed),
+        }
+    }
+}
+
+/// Splits a line which assigns a value to a variable into the variable's name and the value.
+/// The `$` in front of a PowerShell variable is left out of its name.
+fn assignment(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    let line = if line.starts_with('$') {
+        &line[1..]
+    } else {
+        line
+    };
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next().map(|k| k.trim()).unwrap_or("");
+    let val = parts.next()?;
+    if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((key.to_string(), val.trim().to_string()))
+}
+
+/// The character which ends a value if it is an array, associative array, or hashtable.
+fn compound_close(val: &str) -> Option<char> {
+    if val.starts_with('(') || val.starts_with("@(") {
+        Some(')')
+    } else if val.starts_with("@{") {
+        Some('}')
+    } else {
+        None
+    }
+}
 
-                let mut val = parts[1].replace("\"", "");
-                val = val.replace("'", "");
+/// Finds the first `close` character which is neither quoted nor in a comment.
+fn closing_index(val: &str, close: char) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut comment = false;
+    for (i, c) in val.char_indices() {
+        match (quote, c) {
+            _ if comment => comment = c != '\n',
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => comment = true,
+            (None, c) if c == close => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
 
-                match parts[0] {
-                    "pkg_name" => name = Some(val),
-                    "pkg_origin" => origin = Some(val),
-                    "pkg_version" => version = Some(val),
-                    _ => (),
+/// The contents of an array, associative array, or hashtable, between its brackets. A plain
+/// value is treated as an array of one element.
+fn compound_body(val: &str) -> Result<&str> {
+    match compound_close(val) {
+        Some(close) => {
+            let start = if val.starts_with('@') { 2 } else { 1 };
+            match closing_index(val, close) {
+                Some(end) => Ok(&val[start..end]),
+                None => Err(Error::PlanMalformed),
+            }
+        }
+        None => Ok(val),
+    }
+}
+
+fn scalar(val: &str) -> String {
+    val.replace("\"", "").replace("'", "")
+}
+
+/// Splits the contents of an array into its elements, which are separated by whitespace or
+/// commas, removing quotes and comments.
+fn words(body: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let mut comment = false;
+    for c in body.chars() {
+        match (quote, c) {
+            _ if comment => comment = c != '\n',
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') if word.is_empty() => comment = true,
+            (None, c) if c.is_whitespace() || c == ',' => {
+                if !word.is_empty() {
+                    words.push(word.clone());
+                    word.clear();
                 }
             }
+            (None, c) => word.push(c),
         }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn idents(val: &str) -> Result<Vec<PackageIdent>> {
+    words(compound_body(val)?)
+        .iter()
+        .map(|w| PackageIdent::from_str(w).map_err(|_| Error::PlanMalformed))
+        .collect()
+}
 
-        if name.is_none() || origin.is_none() {
-            return Err(Error::PlanMalformed);
+/// Parses a Bash associative array, `([key]=value ...)`, or a PowerShell hashtable,
+/// `@{key="value"; ...}`.
+fn table(val: &str) -> Result<BTreeMap<String, String>> {
+    let body = compound_body(val)?;
+    let mut table = BTreeMap::new();
+    if val.starts_with("@{") {
+        for entry in body.split(|c| c == '\n' || c == ';') {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let mut parts = entry.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => {
+                    table.insert(scalar(key.trim()), scalar(value.trim()));
+                }
+                _ => return Err(Error::PlanMalformed),
+            }
         }
+    } else {
+        for word in words(body) {
+            let mut parts = word.trim_left_matches('[').splitn(2, "]=");
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => {
+                    table.insert(key.to_string(), value.to_string());
+                }
+                _ => return Err(Error::PlanMalformed),
+            }
+        }
+    }
+    Ok(table)
+}
 
-        Ok(Plan {
-            name: name.unwrap(),
-            origin: origin.unwrap(),
-            version: version,
+fn binds(val: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    Ok(table(val)?
+        .into_iter()
+        .map(|(bind, exports)| {
+            let exports = exports.split_whitespace().map(|e| e.to_string()).collect();
+            (bind, exports)
         })
-    }
+        .collect())
 }
 
 #[cfg(test)]
@@ -188,4 +377,91 @@ mod test {
         assert_eq!(plan.name, "testapp".to_string());
         assert_eq!(plan.version, Some("0.1.3".to_string()));
     }
+
+    #[test]
+    fn parsing_plan_sh_deps_exports_and_binds() {
+        let content = r#"
+        pkg_origin=core
+        pkg_name=redis
+        pkg_version=4.0.10
+        pkg_deps=(core/glibc/2.27/20180608041157 core/openssl) # runtime
+        pkg_build_deps=(
+          core/make
+          "core/gcc"
+        )
+        pkg_exports=(
+          [port]=port
+          [password]="requirepass"
+        )
+        pkg_exposes=(port)
+        pkg_binds=([leader]="port password")
+        pkg_binds_optional=([sentinel]=port)
+        pkg_bin_dirs=(bin)
+        "#;
+        let plan = Plan::from_bytes(content.as_bytes()).unwrap();
+        assert_eq!(plan.name, "redis");
+        assert_eq!(
+            plan.deps,
+            vec![
+                PackageIdent::from_str("core/glibc/2.27/20180608041157").unwrap(),
+                PackageIdent::from_str("core/openssl").unwrap(),
+            ]
+        );
+        assert_eq!(
+            plan.build_deps,
+            vec![
+                PackageIdent::from_str("core/make").unwrap(),
+                PackageIdent::from_str("core/gcc").unwrap(),
+            ]
+        );
+        assert_eq!(plan.exports.get("port"), Some(&"port".to_string()));
+        assert_eq!(
+            plan.exports.get("password"),
+            Some(&"requirepass".to_string())
+        );
+        assert_eq!(plan.exposes, vec!["port".to_string()]);
+        assert_eq!(
+            plan.binds.get("leader"),
+            Some(&vec!["port".to_string(), "password".to_string()])
+        );
+        assert_eq!(
+            plan.binds_optional.get("sentinel"),
+            Some(&vec!["port".to_string()])
+        );
+    }
+
+    #[test]
+    fn parsing_plan_ps1_deps_exports_and_binds() {
+        let content = r#"
+        $pkg_name="redis"
+        $pkg_origin="core"
+        $pkg_version="3.0.504"
+        $pkg_deps=@("core/visual-cpp-redist-2013", "core/openssl")
+        $pkg_exports=@{
+            port="port"
+            "password"="requirepass"
+        }
+        $pkg_binds=@{ leader="port password" }
+        "#;
+        let plan = Plan::from_bytes(content.as_bytes()).unwrap();
+        assert_eq!(plan.origin, "core");
+        assert_eq!(plan.version, Some("3.0.504".to_string()));
+        assert_eq!(
+            plan.deps,
+            vec![
+                PackageIdent::from_str("core/visual-cpp-redist-2013").unwrap(),
+                PackageIdent::from_str("core/openssl").unwrap(),
+            ]
+        );
+        assert_eq!(plan.exports.len(), 2);
+        assert_eq!(
+            plan.exports.get("password"),
+            Some(&"requirepass".to_string())
+        );
+        assert_eq!(
+            plan.binds.get("leader"),
+            Some(&vec!["port".to_string(), "password".to_string()])
+        );
+        assert!(plan.build_deps.is_empty());
+    }
 }
@@ -0,0 +1,392 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Software bills of materials for installed packages.
+//!
+//! A bill of materials lists an installed package and every one of its transitive dependencies,
+//! with the licenses each declares, the dependencies between them, and a hash of each package's
+//! content, and can be written as a CycloneDX 1.4 JSON document or an SPDX 2.2 tag-value document.
+//!
+//! The hash of a package is taken over its file manifest (see `FileManifest::digest`), which is
+//! generated from the files installed now, so a package which has been modified since it was
+//! installed has a different hash than the release it came from.
+
+use std::fmt::Write;
+use std::iter;
+use std::path::Path;
+
+use serde_json;
+use time;
+
+use super::manifest::FileManifest;
+use super::{PackageIdent, PackageInstall};
+use crypto::hash::HashAlgorithm;
+use error::Result;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SbomFormat {
+    /// A CycloneDX 1.4 JSON document
+    CycloneDx,
+    /// An SPDX 2.2 tag-value document
+    Spdx,
+}
+
+/// A package in a bill of materials.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SbomComponent {
+    pub ident: PackageIdent,
+    /// The licenses the package declares, as SPDX license identifiers where the plan used them
+    pub licenses: Vec<String>,
+    /// The direct dependencies of the package
+    pub deps: Vec<PackageIdent>,
+    /// The BLAKE2b hash of the package's file manifest
+    pub blake2b: String,
+    /// The SHA-256 hash of the package's file manifest
+    pub sha256: String,
+}
+
+/// A bill of materials for an installed package and its transitive dependencies.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sbom {
+    package: SbomComponent,
+    tdeps: Vec<SbomComponent>,
+}
+
+impl Sbom {
+    /// Builds the bill of materials of an installed package. Every one of its transitive
+    /// dependencies must be installed as well.
+    ///
+    /// An optional `fs_root` path may be provided to find packages which are mounted on a
+    /// filesystem not currently rooted at `/`.
+    pub fn generate(ident: &PackageIdent, fs_root_path: Option<&Path>) -> Result<Self> {
+        let install = PackageInstall::load(ident, fs_root_path)?;
+        let mut tdeps = Vec::new();
+        for tdep in install.tdeps()? {
+            tdeps.push(component(&PackageInstall::load(&tdep, fs_root_path)?)?);
+        }
+        Ok(Sbom {
+            package: component(&install)?,
+            tdeps: tdeps,
+        })
+    }
+
+    /// The package the bill of materials is for.
+    pub fn package(&self) -> &SbomComponent {
+        &self.package
+    }
+
+    /// The transitive dependencies of the package.
+    pub fn tdeps(&self) -> &[SbomComponent] {
+        &self.tdeps
+    }
+
+    /// The package the bill of materials is for, followed by its transitive dependencies.
+    pub fn components(&self) -> impl Iterator<Item = &SbomComponent> {
+        iter::once(&self.package).chain(self.tdeps.iter())
+    }
+
+    pub fn render(&self, format: SbomFormat) -> String {
+        match format {
+            SbomFormat::CycloneDx => self.to_cyclonedx(),
+            SbomFormat::Spdx => self.to_spdx(),
+        }
+    }
+
+    /// Returns the bill of materials as a CycloneDX 1.4 JSON document.
+    pub fn to_cyclonedx(&self) -> String {
+        let bom = CycloneDxBom {
+            bom_format: "CycloneDX",
+            spec_version: "1.4",
+            version: 1,
+            metadata: CycloneDxMetadata {
+                timestamp: time::now_utc().rfc3339().to_string(),
+                component: CycloneDxComponent::from(self.package()),
+            },
+            components: self.tdeps.iter().map(CycloneDxComponent::from).collect(),
+            dependencies: self
+                .components()
+                .map(|c| CycloneDxDependency {
+                    reference: c.ident.to_string(),
+                    depends_on: c.deps.iter().map(|d| d.to_string()).collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&bom).expect("SBOM serializes to JSON")
+    }
+
+    /// Returns the bill of materials as an SPDX 2.2 tag-value document.
+    pub fn to_spdx(&self) -> String {
+        let package = self.package();
+        let mut doc = String::new();
+        let _ = write!(
+            doc,
+            "SPDXVersion: SPDX-2.2\n\
+             DataLicense: CC0-1.0\n\
+             SPDXID: SPDXRef-DOCUMENT\n\
+             DocumentName: {}\n\
+             DocumentNamespace: https://spdx.org/spdxdocs/{}-{}\n\
+             Creator: Tool: habitat_core-{}\n\
+             Created: {}\n\
+             Relationship: SPDXRef-DOCUMENT DESCRIBES {}\n",
+            package.ident,
+            package.ident.to_string().replace('/', "-"),
+            package.blake2b,
+            env!("CARGO_PKG_VERSION"),
+            time::now_utc().rfc3339(),
+            spdx_id(&package.ident),
+        );
+        for component in self.components() {
+            let licenses = if component.licenses.is_empty() {
+                "NOASSERTION".to_string()
+            } else {
+                component.licenses.join(" AND ")
+            };
+            let _ = write!(
+                doc,
+                "\nPackageName: {}\n\
+                 SPDXID: {}\n\
+                 PackageVersion: {}\n\
+                 PackageSupplier: Organization: {}\n\
+                 PackageDownloadLocation: NOASSERTION\n\
+                 FilesAnalyzed: false\n\
+                 PackageChecksum: SHA256: {}\n\
+                 PackageChecksum: BLAKE2b-256: {}\n\
+                 PackageLicenseConcluded: NOASSERTION\n\
+                 PackageLicenseDeclared: {}\n\
+                 PackageCopyrightText: NOASSERTION\n",
+                component.ident.name,
+                spdx_id(&component.ident),
+                release_version(&component.ident),
+                component.ident.origin,
+                component.sha256,
+                component.blake2b,
+                licenses,
+            );
+            for dep in &component.deps {
+                let _ = write!(
+                    doc,
+                    "Relationship: {} DEPENDS_ON {}\n",
+                    spdx_id(&component.ident),
+                    spdx_id(dep)
+                );
+            }
+        }
+        doc
+    }
+}
+
+fn component(install: &PackageInstall) -> Result<SbomComponent> {
+    let manifest = FileManifest::generate(install)?;
+    Ok(SbomComponent {
+        ident: install.ident().clone(),
+        licenses: install.declared_licenses()?,
+        deps: install.deps()?,
+        blake2b: manifest.digest(HashAlgorithm::Blake2b)?,
+        sha256: manifest.digest(HashAlgorithm::Sha256)?,
+    })
+}
+
+/// The version and release of a package, as the version of an SBOM component.
+fn release_version(ident: &PackageIdent) -> String {
+    format!(
+        "{}-{}",
+        ident.version.as_ref().map_or("", |v| v.as_str()),
+        ident.release.as_ref().map_or("", |r| r.as_str())
+    )
+}
+
+/// SPDX identifiers may only contain letters, numbers, `.`, and `-`.
+fn spdx_id(ident: &PackageIdent) -> String {
+    let id: String = ident
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("SPDXRef-Package-{}", id)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CycloneDxBom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: CycloneDxMetadata,
+    components: Vec<CycloneDxComponent>,
+    dependencies: Vec<CycloneDxDependency>,
+}
+
+#[derive(Serialize)]
+struct CycloneDxMetadata {
+    timestamp: String,
+    component: CycloneDxComponent,
+}
+
+#[derive(Serialize)]
+struct CycloneDxComponent {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(rename = "bom-ref")]
+    bom_ref: String,
+    group: String,
+    name: String,
+    version: String,
+    licenses: Vec<CycloneDxLicense>,
+    hashes: Vec<CycloneDxHash>,
+}
+
+impl<'a> From<&'a SbomComponent> for CycloneDxComponent {
+    fn from(component: &'a SbomComponent) -> Self {
+        CycloneDxComponent {
+            kind: "application",
+            bom_ref: component.ident.to_string(),
+            group: component.ident.origin.clone(),
+            name: component.ident.name.clone(),
+            version: release_version(&component.ident),
+            licenses: component
+                .licenses
+                .iter()
+                .map(|l| CycloneDxLicense {
+                    license: CycloneDxLicenseName { name: l.clone() },
+                })
+                .collect(),
+            hashes: vec![
+                CycloneDxHash {
+                    alg: "BLAKE2b-256",
+                    content: component.blake2b.clone(),
+                },
+                CycloneDxHash {
+                    alg: "SHA-256",
+                    content: component.sha256.clone(),
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CycloneDxLicense {
+    license: CycloneDxLicenseName,
+}
+
+#[derive(Serialize)]
+struct CycloneDxLicenseName {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct CycloneDxHash {
+    alg: &'static str,
+    content: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CycloneDxDependency {
+    #[serde(rename = "ref")]
+    reference: String,
+    depends_on: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use serde_json;
+    use tempfile::Builder;
+
+    use super::*;
+    use package::metadata::MetaFile;
+    use package::test_support::testing_package_install;
+
+    fn write_metafile(install: &PackageInstall, metafile: MetaFile, content: &str) {
+        let path = install.installed_path().join(metafile.to_string());
+        let mut f = File::create(path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn install_redis(fs_root: &Path) -> PackageInstall {
+        let glibc = testing_package_install("core/glibc/2.27/20180608041157", fs_root);
+        write_metafile(
+            &glibc,
+            MetaFile::Manifest,
+            "# core / glibc\n\n* __License__: GPL-2.0 LGPL-2.1\n",
+        );
+        let redis = testing_package_install("core/redis/4.0.10/20180608202239", fs_root);
+        write_metafile(
+            &redis,
+            MetaFile::Manifest,
+            "# core / redis\n\n* __License__: BSD-3-Clause\n",
+        );
+        write_metafile(&redis, MetaFile::Deps, "core/glibc/2.27/20180608041157\n");
+        write_metafile(&redis, MetaFile::TDeps, "core/glibc/2.27/20180608041157\n");
+        redis
+    }
+
+    #[test]
+    fn sbom_lists_package_and_tdeps() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let redis = install_redis(fs_root.path());
+
+        let sbom = Sbom::generate(redis.ident(), Some(fs_root.path())).unwrap();
+
+        assert_eq!(sbom.components().count(), 2);
+        assert_eq!(sbom.package().ident, *redis.ident());
+        assert_eq!(sbom.package().licenses, vec!["BSD-3-Clause"]);
+        assert_eq!(sbom.tdeps()[0].licenses, vec!["GPL-2.0", "LGPL-2.1"]);
+        assert_eq!(
+            sbom.package().blake2b,
+            FileManifest::generate(&redis)
+                .unwrap()
+                .digest(HashAlgorithm::Blake2b)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn sbom_documents() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let redis = install_redis(fs_root.path());
+        let sbom = Sbom::generate(redis.ident(), Some(fs_root.path())).unwrap();
+
+        let bom: serde_json::Value =
+            serde_json::from_str(&sbom.render(SbomFormat::CycloneDx)).unwrap();
+        assert_eq!(bom["bomFormat"], "CycloneDX");
+        assert_eq!(
+            bom["metadata"]["component"]["bom-ref"],
+            "core/redis/4.0.10/20180608202239"
+        );
+        assert_eq!(bom["components"][0]["name"], "glibc");
+        assert_eq!(bom["components"][0]["hashes"][0]["alg"], "BLAKE2b-256");
+        assert_eq!(
+            bom["dependencies"][0]["dependsOn"][0],
+            "core/glibc/2.27/20180608041157"
+        );
+
+        let doc = sbom.render(SbomFormat::Spdx);
+        assert!(doc.starts_with("SPDXVersion: SPDX-2.2\n"));
+        assert!(doc.contains("PackageLicenseDeclared: GPL-2.0 AND LGPL-2.1\n"));
+        assert!(doc.contains(
+            "Relationship: SPDXRef-Package-core-redis-4.0.10-20180608202239 DEPENDS_ON \
+             SPDXRef-Package-core-glibc-2.27-20180608041157\n"
+        ));
+    }
+}
@@ -0,0 +1,424 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A content-addressed store which installed packages share file content through.
+//!
+//! Adding a package to the store hashes each of its files. The first file with a given content
+//! and permissions is hard-linked into the store, and every later file with the same content and
+//! permissions is replaced by a hard link to the stored copy, so many releases of a large package
+//! take little more room than one. Installed packages must never be changed in place, since a
+//! change to one link of a file is a change to all of them.
+//!
+//! Content stays in the store for as long as any package links to it; `ContentStore::prune`
+//! drops what nothing links to any more, which garbage collection does after removing packages.
+//! The store must be on the same filesystem as the packages, since hard links can't cross
+//! filesystems. Adding and pruning both hold the store's lock, so a prune never drops content
+//! which a package is being linked to.
+
+use std::fs::{self as stdfs, Metadata};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::list::all_packages;
+use super::metadata::INSTALL_METAFILES;
+use super::PackageInstall;
+use crypto::hash;
+use error::Result;
+use fs;
+
+/// What adding packages to the store did.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreReport {
+    /// How many files were replaced by links to content which was already stored
+    pub linked: usize,
+    /// The disk space the replaced files took up, in bytes
+    pub saved: u64,
+}
+
+pub struct ContentStore {
+    path: PathBuf,
+    fs_root_path: PathBuf,
+}
+
+impl ContentStore {
+    /// Returns the store for the packages installed under `fs_root_path`, or `/` if it isn't
+    /// given.
+    pub fn new(fs_root_path: Option<&Path>) -> Self {
+        let fs_root_path = fs_root_path.map_or(PathBuf::from("/"), |p| p.into());
+        ContentStore {
+            path: fs::content_store_path(Some(&fs_root_path)),
+            fs_root_path: fs_root_path,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether any package has been added to the store.
+    pub fn exists(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Links the files of an installed package with the store. The metafiles which are written
+    /// after a package is installed, such as its channel, are left alone.
+    ///
+    /// # Failures
+    ///
+    /// * A file can't be read or hashed
+    /// * A file can't be hard-linked, such as when the store is on another filesystem
+    pub fn add(&self, install: &PackageInstall) -> Result<StoreReport> {
+        let _lock = fs::lock_content_store(Some(&self.fs_root_path))?;
+        let mut report = StoreReport::default();
+        let installed_path = install.installed_path();
+        for path in dir_entries(installed_path)? {
+            let is_install_metafile = INSTALL_METAFILES
+                .iter()
+                .any(|m| path == installed_path.join(m.to_string()));
+            if !is_install_metafile {
+                self.add_path(&path, &mut report)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Adds every installed package to the store, for moving an existing installation over to
+    /// storing content once. Adding a package which is already in the store changes nothing, so
+    /// a migration which stops part way can be run again.
+    pub fn migrate(&self) -> Result<StoreReport> {
+        let mut report = StoreReport::default();
+        let package_root_path = fs::pkg_root_path(Some(&self.fs_root_path));
+        if !package_root_path.is_dir() {
+            return Ok(report);
+        }
+        for ident in all_packages(&package_root_path)? {
+            let install = PackageInstall::load(&ident, Some(&self.fs_root_path))?;
+            let added = self.add(&install)?;
+            report.linked += added.linked;
+            report.saved += added.saved;
+        }
+        Ok(report)
+    }
+
+    /// Removes the content which no installed package links to any more, returning the disk
+    /// space it took up, in bytes.
+    pub fn prune(&self) -> Result<u64> {
+        if !self.exists() {
+            return Ok(0);
+        }
+        let _lock = fs::lock_content_store(Some(&self.fs_root_path))?;
+        let mut freed = 0;
+        for prefix in stdfs::read_dir(&self.path)? {
+            let prefix = prefix?.path();
+            for object in stdfs::read_dir(&prefix)? {
+                let object = object?.path();
+                if file_info(&object)?.links == 1 {
+                    freed += stdfs::metadata(&object)?.len();
+                    stdfs::remove_file(&object)?;
+                }
+            }
+            // Fails harmlessly when the prefix directory still has content
+            let _ = stdfs::remove_dir(&prefix);
+        }
+        Ok(freed)
+    }
+
+    fn add_path(&self, path: &Path, report: &mut StoreReport) -> Result<()> {
+        let metadata = match stdfs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            // A temporary link left behind by an earlier add, and removed since it was listed
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_dir() {
+            for entry in dir_entries(path)? {
+                self.add_path(&entry, report)?;
+            }
+        } else if metadata.is_file() {
+            if self.add_file(path)? {
+                report.linked += 1;
+                report.saved += metadata.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores a file, or replaces it with a link to the same content already stored. Returns
+    /// whether it was replaced.
+    ///
+    /// The file is pinned by a link of its own first, and it's the pinned file which is hashed
+    /// and stored, so a file which is swapped for another part way through is never stored
+    /// under the other's hash. It's only replaced if it's still the pinned file.
+    fn add_file(&self, path: &Path) -> Result<bool> {
+        let pin = TempLink::new(path, path, "pin")?;
+        let file = stdfs::File::open(&pin.path)?;
+        let metadata = file.metadata()?;
+        let hash = hash::hash_reader(&mut BufReader::new(file))?;
+        let object =
+            self.path
+                .join(&hash[..2])
+                .join(format!("{}-{}", hash, permissions_key(&metadata)));
+        stdfs::create_dir_all(object.parent().expect("Object is in a prefix directory"))?;
+        match stdfs::hard_link(&pin.path, &object) {
+            Ok(()) => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+        let pinned = file_info(&pin.path)?.id;
+        if pinned == file_info(&object)?.id {
+            return Ok(false);
+        }
+        // Link beside the file and then rename over it, so the file is never missing
+        let tmp = TempLink::new(&object, path, "store")?;
+        if file_info(path)?.id != pinned {
+            return Ok(false);
+        }
+        stdfs::rename(&tmp.path, path)?;
+        Ok(true)
+    }
+}
+
+/// A hard link beside a file, which is removed again when it's dropped unless it has been
+/// renamed away.
+struct TempLink {
+    path: PathBuf,
+}
+
+impl TempLink {
+    /// Links `target` as a hidden file beside `beside`, named after it with `suffix` and a count
+    /// which is unique in this process. A file which already has the name is left alone, as it
+    /// may be one of the package's own, and the next count is tried instead.
+    fn new(target: &Path, beside: &Path, suffix: &str) -> Result<Self> {
+        static TMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let file_name = beside
+            .file_name()
+            .expect("Files have names")
+            .to_string_lossy();
+        loop {
+            let path = beside.with_file_name(format!(
+                ".{}.{}-{}-{}",
+                file_name,
+                suffix,
+                process::id(),
+                TMP_COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            match stdfs::hard_link(target, &path) {
+                Ok(()) => return Ok(TempLink { path: path }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for TempLink {
+    fn drop(&mut self) {
+        // Fails harmlessly when the link was renamed over the file it stands in for
+        let _ = stdfs::remove_file(&self.path);
+    }
+}
+
+/// The paths of the entries of a directory, listed before any of them are worked on so that
+/// the temporary links made beside them aren't listed too.
+fn dir_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in stdfs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    Ok(paths)
+}
+
+/// Returns the disk space the files under `dir` take up which no other package shares through
+/// the store, in bytes. This is what removing the directory frees right away.
+pub fn unshared_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in stdfs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = stdfs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            size += unshared_size(&path)?;
+        } else if !metadata.is_file() || file_info(&path)?.links == 1 {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// The identity of a file, which all of its hard links share, and how many links it has.
+struct FileInfo {
+    id: (u64, u64),
+    links: u64,
+}
+
+#[cfg(not(windows))]
+fn file_info(path: &Path) -> Result<FileInfo> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = stdfs::metadata(path)?;
+    Ok(FileInfo {
+        id: (metadata.dev(), metadata.ino()),
+        links: metadata.nlink(),
+    })
+}
+
+#[cfg(windows)]
+fn file_info(path: &Path) -> Result<FileInfo> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+    let file = stdfs::File::open(path)?;
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(FileInfo {
+        id: (
+            info.dwVolumeSerialNumber as u64,
+            (info.nFileIndexHigh as u64) << 32 | info.nFileIndexLow as u64,
+        ),
+        links: info.nNumberOfLinks as u64,
+    })
+}
+
+/// Links to stored content share its permissions, so files with the same content but different
+/// permissions are stored apart.
+#[cfg(not(windows))]
+fn permissions_key(metadata: &Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    format!("{:o}", metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(windows)]
+fn permissions_key(metadata: &Metadata) -> String {
+    if metadata.permissions().readonly() {
+        "ro".to_string()
+    } else {
+        "rw".to_string()
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod test {
+    use std::fs::{self as stdfs, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use tempfile::Builder;
+
+    use super::*;
+    use package::test_support::testing_package_install;
+
+    const CONTENT: &'static [u8] = b"a large shared library, or near enough";
+
+    fn install_with_lib(ident: &str, fs_root: &Path) -> PackageInstall {
+        let install = testing_package_install(ident, fs_root);
+        let lib = install.installed_path().join("lib");
+        stdfs::create_dir_all(&lib).unwrap();
+        stdfs::write(lib.join("libbig.so"), CONTENT).unwrap();
+        install
+    }
+
+    fn inode(install: &PackageInstall) -> u64 {
+        stdfs::metadata(install.installed_path().join("lib/libbig.so"))
+            .unwrap()
+            .ino()
+    }
+
+    #[test]
+    fn releases_share_stored_content() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let store = ContentStore::new(Some(fs_root.path()));
+        let old = install_with_lib("core/big/1.0.0/20180101000000", fs_root.path());
+        let new = install_with_lib("core/big/1.0.1/20180201000000", fs_root.path());
+        let other = install_with_lib("core/other/1.0.0/20180101000000", fs_root.path());
+        let exe = other.installed_path().join("lib/libbig.so");
+        stdfs::set_permissions(&exe, Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(store.add(&old).unwrap(), StoreReport::default());
+        // The library and the TARGET metafile are shared
+        let report = store.add(&new).unwrap();
+        assert_eq!(report.linked, 2);
+        assert!(report.saved > CONTENT.len() as u64);
+        assert_eq!(inode(&old), inode(&new));
+        assert_eq!(
+            stdfs::read(new.installed_path().join("lib/libbig.so")).unwrap(),
+            CONTENT
+        );
+        // Adding a package again changes nothing
+        assert_eq!(store.add(&new).unwrap(), StoreReport::default());
+        assert_eq!(
+            stdfs::read_dir(new.installed_path().join("lib"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        // The same content with other permissions is stored apart
+        store.add(&other).unwrap();
+        assert_ne!(inode(&other), inode(&old));
+        assert_eq!(stdfs::metadata(&exe).unwrap().mode() & 0o777, 0o755);
+        assert_eq!(unshared_size(&new.installed_path().join("lib")).unwrap(), 0);
+    }
+
+    #[test]
+    fn add_keeps_files_named_like_its_links() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let store = ContentStore::new(Some(fs_root.path()));
+        let old = install_with_lib("core/big/1.0.0/20180101000000", fs_root.path());
+        let new = install_with_lib("core/big/1.0.1/20180201000000", fs_root.path());
+        let lib = new.installed_path().join("lib");
+        let taken = format!(".libbig.so.pin-{}-0", process::id());
+        stdfs::write(lib.join(".libbig.so.pin"), b"not a link").unwrap();
+        stdfs::write(lib.join(&taken), b"not a link either").unwrap();
+
+        store.add(&old).unwrap();
+        store.add(&new).unwrap();
+        assert_eq!(inode(&old), inode(&new));
+        assert_eq!(
+            stdfs::read(lib.join(".libbig.so.pin")).unwrap(),
+            b"not a link"
+        );
+        assert_eq!(stdfs::read(lib.join(&taken)).unwrap(), b"not a link either");
+        let names: Vec<String> = stdfs::read_dir(&lib)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names.len(), 3, "Links were left behind: {:?}", names);
+    }
+
+    #[test]
+    fn prune_drops_content_nothing_links_to() {
+        let fs_root = Builder::new().prefix("fs-root").tempdir().unwrap();
+        let store = ContentStore::new(Some(fs_root.path()));
+        let old = install_with_lib("core/big/1.0.0/20180101000000", fs_root.path());
+        let new = install_with_lib("core/big/1.0.1/20180201000000", fs_root.path());
+        assert_eq!(store.migrate().unwrap().linked, 2);
+
+        stdfs::remove_dir_all(old.installed_path()).unwrap();
+        assert!(store.prune().unwrap() < CONTENT.len() as u64);
+        assert_eq!(
+            stdfs::read(new.installed_path().join("lib/libbig.so")).unwrap(),
+            CONTENT
+        );
+
+        stdfs::remove_dir_all(new.installed_path()).unwrap();
+        assert!(store.prune().unwrap() >= CONTENT.len() as u64);
+        assert_eq!(stdfs::read_dir(store.path()).unwrap().count(), 0);
+    }
+}
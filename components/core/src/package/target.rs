@@ -257,6 +257,29 @@ macro_rules! supported_package_targets {
 // the third and fourth values are used by the Rust compiler at build time and never exposed in
 // code at runtime.
 supported_package_targets! {
+    /// Represents a [XNU kernel]-based system (more commonly referred to as [Darwin] or [macOS])
+    /// running on the [64-bit] execution state of the [ARMv8-A][arm] [instruction set
+    /// architecture][isa], commonly known as [AArch64], such as Apple silicon.
+    ///
+    /// [XNU kernel]: https://en.wikipedia.org/wiki/XNU
+    /// [Darwin]: https://en.wikipedia.org/wiki/Darwin_(operating_system)
+    /// [macOS]: https://en.wikipedia.org/wiki/MacOS
+    /// [64-bit]: https://en.wikipedia.org/wiki/64-bit_computing
+    /// [arm]: https://en.wikipedia.org/wiki/ARM_architecture
+    /// [isa]: https://en.wikipedia.org/wiki/Instruction_set_architecture
+    /// [AArch64]: https://en.wikipedia.org/wiki/AArch64
+    ("aarch64-darwin", Aarch64_Darwin, AARCH64_DARWIN, "aarch64", "macos");
+
+    /// Represents a [Linux kernel]-based system running on the [64-bit] execution state of the
+    /// [ARMv8-A][arm] [instruction set architecture][isa], commonly known as [AArch64].
+    ///
+    /// [Linux kernel]: https://en.wikipedia.org/wiki/Linux_kernel
+    /// [64-bit]: https://en.wikipedia.org/wiki/64-bit_computing
+    /// [arm]: https://en.wikipedia.org/wiki/ARM_architecture
+    /// [isa]: https://en.wikipedia.org/wiki/Instruction_set_architecture
+    /// [AArch64]: https://en.wikipedia.org/wiki/AArch64
+    ("aarch64-linux", Aarch64_Linux, AARCH64_LINUX, "aarch64", "linux");
+
     /// Represents a [XNU kernel]-based system (more commonly referred to as [Darwin] or [macOS])
     /// running on a [64-bit] version of the [x86][x] [instruction set architecture][isa], commonly
     /// known as [x86_64].
@@ -614,6 +637,19 @@ mod test {
         assert_eq!("darwin", Type::X86_64_Darwin.system());
     }
 
+    #[test]
+    fn aarch64_targets() {
+        let linux = PackageTarget::from_str("aarch64-linux").unwrap();
+        assert_eq!(AARCH64_LINUX, linux);
+        assert_eq!("aarch64-linux", linux.to_string());
+        assert_eq!(
+            vec!["aarch64", "darwin"],
+            AARCH64_DARWIN.iter().collect::<Vec<_>>()
+        );
+        assert!(PackageTarget::supported_targets().any(|t| *t == AARCH64_DARWIN));
+        assert!(PackageTarget::from_str("arm64-linux").is_err());
+    }
+
     #[test]
     fn type_variant() {
         assert_eq!(Some("kernel2"), Type::X86_64_Linux_Kernel2.variant());
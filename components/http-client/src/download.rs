@@ -0,0 +1,304 @@
+// Copyright (c) 2018 Chef Software Inc. and/or applicable contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Downloads of large files, such as package artifacts, which survive dropped connections.
+//!
+//! A download is written to a `.part` file next to its destination. When the connection drops,
+//! the download is resumed from the end of the `.part` file with an HTTP range request, both
+//! within a call to `ApiClient::download` and by a later call for the same destination. The
+//! `.part` file is only moved into place once it is complete and matches its checksum.
+//!
+//! The `ETag` or `Last-Modified` the server sent with the file is kept in a `.part.if-range` file
+//! and sent back as `If-Range` when resuming, so a server whose file has changed meanwhile sends
+//! all of the new one. A partial download without one, or a response whose range doesn't start
+//! where the partial download ends, starts over from the beginning.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use hab_core::crypto::hash;
+use hyper::header::{
+    ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ETag, EntityTag, Headers,
+    HttpDate, IfRange, LastModified, Range,
+};
+use hyper::status::StatusCode;
+
+use api_client::ApiClient;
+use error::{Error, Result};
+
+/// How many times a download is resumed after its connection drops before giving up
+pub const DOWNLOAD_RETRIES: usize = 5;
+
+const BUF_SIZE: usize = 64 * 1024;
+
+/// The progress of a download, as reported to the callback given to `ApiClient::download`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DownloadProgress {
+    /// The download started, after `offset` bytes which an earlier attempt had downloaded. The
+    /// total size is given when the server reports it.
+    Started {
+        offset: u64,
+        total: Option<u64>,
+    },
+    /// `downloaded` bytes have been received in all, including any from earlier attempts
+    Received {
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// The connection dropped after `downloaded` bytes, and the download is being resumed
+    Retrying {
+        downloaded: u64,
+        attempt: usize,
+    },
+    /// The download is complete, and is being checked against its checksum
+    Verifying,
+    Finished,
+}
+
+impl ApiClient {
+    /// Downloads the file at `path` to `dst`, resuming the partial download an earlier call left
+    /// behind, if any. When a checksum is given, the BLAKE2b hash of the downloaded file must
+    /// match it, as reported by Builder for artifacts.
+    ///
+    /// # Errors
+    ///
+    /// * The server responds with anything other than the file, or a part of it
+    /// * The connection drops more than `DOWNLOAD_RETRIES` times
+    /// * The download can't be written, such as when the disk is full, which isn't retried
+    /// * The downloaded file doesn't match the checksum, in which case the partial download is
+    ///   removed so the next attempt starts over
+    pub fn download<F>(
+        &self,
+        path: &str,
+        dst: &Path,
+        checksum: Option<&str>,
+        mut progress: F,
+    ) -> Result<()>
+    where
+        F: FnMut(DownloadProgress),
+    {
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = partial_path(dst);
+        let mut attempt = 0;
+        loop {
+            match self.download_remaining(path, &partial, &mut progress) {
+                Ok(()) => break,
+                Err(ref e) if is_retriable(e) && attempt < DOWNLOAD_RETRIES => {
+                    attempt += 1;
+                    debug!("Download of {} interrupted, resuming: {}", path, e);
+                    progress(DownloadProgress::Retrying {
+                        downloaded: partial_len(&partial),
+                        attempt: attempt,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(expected) = checksum {
+            progress(DownloadProgress::Verifying);
+            let actual = hash::hash_file(&partial)?;
+            if actual != expected {
+                discard(&partial)?;
+                return Err(Error::ChecksumMismatch(expected.to_string(), actual));
+            }
+        }
+        fs::rename(&partial, dst)?;
+        remove_if_exists(&validator_path(&partial)).map_err(Error::DownloadWrite)?;
+        progress(DownloadProgress::Finished);
+        Ok(())
+    }
+
+    /// Downloads the part of the file which isn't in `partial` yet, appending it.
+    fn download_remaining<F>(&self, path: &str, partial: &Path, progress: &mut F) -> Result<()>
+    where
+        F: FnMut(DownloadProgress),
+    {
+        // Without a validator the server can't tell whether the partial download is of the file
+        // it has now, so there is nothing which can be safely resumed
+        let mut request = self.get(path);
+        let offset = match read_validator(partial) {
+            Some(validator) => {
+                let offset = partial_len(partial);
+                if offset > 0 {
+                    request = request
+                        .header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]))
+                        .header(validator);
+                }
+                offset
+            }
+            None => 0,
+        };
+        let mut response = request.send()?;
+
+        let (mut file, offset, total) = match response.status {
+            StatusCode::PartialContent if offset > 0 => {
+                match response.headers.get::<ContentRange>() {
+                    Some(&ContentRange(ContentRangeSpec::Bytes {
+                        range: Some((start, _)),
+                        instance_length,
+                    })) if start == offset => {
+                        let file = OpenOptions::new()
+                            .append(true)
+                            .open(partial)
+                            .map_err(Error::DownloadWrite)?;
+                        (file, offset, instance_length)
+                    }
+                    // The server sent some other part of the file, so start over
+                    _ => {
+                        discard(partial)?;
+                        return self.download_remaining(path, partial, progress);
+                    }
+                }
+            }
+            // The server doesn't do ranges, the file changed since the partial download was
+            // started, or there was nothing to resume, so start over
+            StatusCode::Ok => {
+                let total = response.headers.get::<ContentLength>().map(|len| len.0);
+                let file = File::create(partial).map_err(Error::DownloadWrite)?;
+                write_validator(partial, &response.headers)?;
+                (file, 0, total)
+            }
+            StatusCode::RangeNotSatisfiable if offset > 0 => {
+                match response.headers.get::<ContentRange>() {
+                    Some(&ContentRange(ContentRangeSpec::Bytes {
+                        instance_length: Some(len),
+                        ..
+                    })) if len == offset => {
+                        return Ok(());
+                    }
+                    // The partial download is of some other file, so start over
+                    _ => {
+                        discard(partial)?;
+                        return self.download_remaining(path, partial, progress);
+                    }
+                }
+            }
+            status => {
+                return Err(Error::DownloadFailed(format!(
+                    "{} responded with {}",
+                    path, status
+                )))
+            }
+        };
+
+        progress(DownloadProgress::Started {
+            offset: offset,
+            total: total,
+        });
+        let mut downloaded = offset;
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let n = response.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n]).map_err(Error::DownloadWrite)?;
+            downloaded += n as u64;
+            progress(DownloadProgress::Received {
+                downloaded: downloaded,
+                total: total,
+            });
+        }
+        file.flush().map_err(Error::DownloadWrite)?;
+
+        match total {
+            Some(total) if downloaded < total => Err(Error::IO(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("connection closed after {} of {} bytes", downloaded, total),
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the path a download to `dst` is written to until it is complete.
+pub fn partial_path(dst: &Path) -> PathBuf {
+    let mut name = dst
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(OsString::new);
+    name.push(".part");
+    dst.with_file_name(name)
+}
+
+fn partial_len(partial: &Path) -> u64 {
+    partial.metadata().map(|m| m.len()).unwrap_or(0)
+}
+
+/// Returns the path the `If-Range` validator of the partial download at `partial` is kept at.
+fn validator_path(partial: &Path) -> PathBuf {
+    let mut name = partial.as_os_str().to_os_string();
+    name.push(".if-range");
+    PathBuf::from(name)
+}
+
+/// Reads the validator of a partial download, if it has one which can still be parsed.
+fn read_validator(partial: &Path) -> Option<IfRange> {
+    let mut value = String::new();
+    File::open(validator_path(partial))
+        .and_then(|mut f| f.read_to_string(&mut value))
+        .ok()?;
+    let value = value.trim();
+    if value.starts_with('"') {
+        EntityTag::from_str(value).ok().map(IfRange::EntityTag)
+    } else {
+        HttpDate::from_str(value).ok().map(IfRange::Date)
+    }
+}
+
+/// Keeps the validator a server sent with a file for resuming its download: its `ETag` unless
+/// that is weak, which `If-Range` can't use, or else its `Last-Modified` date.
+fn write_validator(partial: &Path, headers: &Headers) -> Result<()> {
+    let validator = match (headers.get::<ETag>(), headers.get::<LastModified>()) {
+        (Some(&ETag(ref tag)), _) if !tag.weak => Some(IfRange::EntityTag(tag.clone())),
+        (_, Some(&LastModified(date))) => Some(IfRange::Date(date)),
+        _ => None,
+    };
+    let path = validator_path(partial);
+    match validator {
+        Some(validator) => File::create(&path)
+            .and_then(|mut f| write!(f, "{}", validator))
+            .map_err(Error::DownloadWrite),
+        None => remove_if_exists(&path).map_err(Error::DownloadWrite),
+    }
+}
+
+/// Removes a partial download along with its validator, so the next attempt starts over.
+fn discard(partial: &Path) -> Result<()> {
+    remove_if_exists(partial)
+        .and_then(|_| remove_if_exists(&validator_path(partial)))
+        .map_err(Error::DownloadWrite)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Dropped connections and timeouts are worth resuming from; responses from the server, and
+/// failures to write the download locally such as a full disk, aren't.
+fn is_retriable(err: &Error) -> bool {
+    match *err {
+        Error::HyperError(_) | Error::IO(_) => true,
+        _ => false,
+    }
+}
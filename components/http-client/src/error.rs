@@ -27,6 +27,12 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Occurs when a downloaded file doesn't match the checksum it was expected to have.
+    ChecksumMismatch(String, String),
+    /// Occurs when a server responds to a download with something other than the file.
+    DownloadFailed(String),
+    /// Occurs when a download can't be written to disk, such as when the disk is full.
+    DownloadWrite(io::Error),
     HabitatCore(hab_core::Error),
     HyperError(hyper::error::Error),
     /// Occurs when an improper http or https proxy value is given.
@@ -42,6 +48,12 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg = match *self {
+            Error::ChecksumMismatch(ref expected, ref actual) => format!(
+                "Downloaded file has checksum {}, expected {}",
+                actual, expected
+            ),
+            Error::DownloadFailed(ref e) => format!("Download failed: {}", e),
+            Error::DownloadWrite(ref e) => format!("Can't write download: {}", e),
             Error::HabitatCore(ref e) => format!("{}", e),
             Error::HyperError(ref err) => format!("{}", err),
             Error::IO(ref e) => format!("{}", e),
@@ -58,6 +70,9 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::ChecksumMismatch(_, _) => "Downloaded file doesn't match its checksum",
+            Error::DownloadFailed(_) => "Download failed",
+            Error::DownloadWrite(_) => "Can't write download",
             Error::HabitatCore(ref err) => err.description(),
             Error::HyperError(ref err) => err.description(),
             Error::IO(ref err) => err.description(),
@@ -28,12 +28,14 @@ extern crate serde_json;
 extern crate url;
 
 pub mod api_client;
+pub mod download;
 pub mod error;
 pub mod net;
 pub mod proxy;
 pub mod util;
 
 pub use api_client::ApiClient;
+pub use download::DownloadProgress;
 pub use error::{Error, Result};
 
 #[cfg(not(target_os = "macos"))]